@@ -0,0 +1,346 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, Error, ErrorKind, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use futures::executor::block_on;
+use structopt::StructOpt;
+
+use rinites::auth::sigv4::Credentials;
+use rinites::cluster::api::{Checkpoints, CreateStreamRequest, ListStreamsResponse};
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::cluster::mirror::parse_shard_iterator;
+use rinites::http::HttpResponse;
+use rinites::shards::shard_controller::GetRecordsResponse;
+use rinites::shards::shards::ShardIteratorType;
+use rinites::shards::transactions::Isolation;
+use rinites_client::connection::{Backoff, Connection};
+use rinites_client::consumer::consumed_records;
+use rinites_client::producer::{Producer, ProducerConfig, ProducerRecord, RecordMetadata};
+
+use crate::output::{Output, ShardLag};
+
+mod output;
+
+/// how many times a request the cluster could not serve right now is sent again
+const RETRIES: u32 = 5;
+
+/// how many puts are waited for at most before reading more lines
+const MAX_PENDING_PUTS: usize = 10 * 1000;
+
+/// how long `tail -f` waits for new records, growing while none come
+const TAIL_IDLE: Backoff = Backoff { initial_ms: 50, max_ms: 1000 };
+
+/// Day to day operations on a rinites cluster
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rinites")]
+struct Opts {
+    /// any nodes of the cluster, as <host:port>,...
+    #[structopt(short, long, default_value = "127.0.0.1:8081")]
+    endpoints: String,
+
+    #[structopt(long, default_value = "30000")]
+    timeout_ms: u64,
+
+    /// key requests are signed with, for clusters with auth on
+    #[structopt(long, env = "RINITES_ACCESS_KEY_ID")]
+    access_key_id: Option<String>,
+
+    #[structopt(long, env = "RINITES_SECRET_ACCESS_KEY", hide_env_values = true)]
+    secret_access_key: Option<String>,
+
+    /// raw, json or hex
+    #[structopt(short, long, default_value = "raw")]
+    output: Output,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Puts every line of stdin as a record
+    Put {
+        stream: String,
+
+        /// partition key of every record, keyless records are spread over the shards
+        #[structopt(short = "k", long)]
+        partition_key: Option<String>,
+
+        /// splits every line in its partition key and data at the first occurrence
+        #[structopt(long, conflicts_with = "partition-key")]
+        key_separator: Option<String>,
+    },
+    /// Prints the records of one get-records from a shard iterator
+    Get {
+        stream: String,
+        shard_id: u32,
+        shard_iterator: u64,
+
+        /// read_uncommitted or read_committed
+        #[structopt(long, default_value = "read_uncommitted", parse(try_from_str = parse_isolation))]
+        isolation: Isolation,
+    },
+    /// Prints the records of a shard up to its end, or as they come with -f
+    Tail {
+        stream: String,
+        shard_id: u32,
+
+        #[structopt(short, long)]
+        follow: bool,
+
+        /// oldest, latest, or a timestamp in milliseconds since the epoch
+        #[structopt(long, default_value = "oldest", parse(try_from_str = parse_from))]
+        from: ShardIteratorType,
+
+        /// read_uncommitted or read_committed
+        #[structopt(long, default_value = "read_uncommitted", parse(try_from_str = parse_isolation))]
+        isolation: Isolation,
+    },
+    Streams(StreamsCommand),
+    Shards(ShardsCommand),
+    Groups(GroupsCommand),
+}
+
+#[derive(StructOpt, Debug)]
+enum StreamsCommand {
+    Create {
+        stream: String,
+
+        /// the default shard count of the cluster when missing
+        #[structopt(long)]
+        shards: Option<u32>,
+
+        /// three, or one per node in smaller clusters, by default
+        #[structopt(long)]
+        replication_factor: Option<usize>,
+    },
+    List,
+    Describe {
+        stream: String,
+    },
+    Delete {
+        stream: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ShardsCommand {
+    /// Closes a shard, handing its hash keys to two new shards
+    Split {
+        stream: String,
+        shard_id: u32,
+
+        /// first hash key of the second new shard, the middle of the shard by default
+        #[structopt(long)]
+        starting_hash_key: Option<u64>,
+    },
+    /// Closes two adjacent shards, handing their hash keys to a new shard
+    Merge {
+        stream: String,
+        shard_id: u32,
+        adjacent_shard_id: u32,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum GroupsCommand {
+    /// How far behind its checkpoints a consumer group is on every shard of a stream
+    Lag {
+        group: String,
+        stream: String,
+    },
+}
+
+fn parse_isolation(s: &str) -> Result<Isolation, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string()))
+        .map_err(|_| format!("unknown isolation {}, expected read_uncommitted or read_committed", s))
+}
+
+fn parse_from(s: &str) -> Result<ShardIteratorType, String> {
+    match s {
+        "oldest" => Ok(ShardIteratorType::Oldest),
+        "latest" => Ok(ShardIteratorType::Latest),
+        _ => s
+            .parse()
+            .map(|timestamp_ms| ShardIteratorType::AtTimestamp { timestamp_ms })
+            .map_err(|_| format!("{} is neither oldest, latest nor a timestamp in milliseconds", s)),
+    }
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    match run(opts) {
+        Ok(()) => {}
+        // the reader went away, as `head` does
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("rinites: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run(opts: Opts) -> std::io::Result<()> {
+    let endpoints = opts.endpoints.split(',').map(|e| e.trim().to_string()).collect();
+    let credentials = match (opts.access_key_id, opts.secret_access_key) {
+        (Some(access_key_id), Some(secret_access_key)) => Some(Credentials { access_key_id, secret_access_key }),
+        (None, None) => None,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "--access-key-id goes with --secret-access-key")),
+    };
+    let connection = Connection::new(endpoints, Duration::from_millis(opts.timeout_ms))?.with_credentials(credentials);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let output = opts.output;
+
+    match opts.command {
+        Command::Put { stream, partition_key, key_separator } => {
+            put(connection, &mut out, output, &stream, partition_key, key_separator)
+        }
+        Command::Get { stream, shard_id, shard_iterator, isolation } => {
+            let read = get_records(&connection, &stream, shard_id, shard_iterator, isolation)?;
+            let (next_shard_iterator, shard_end) = (read.next_shard_iterator, read.shard_end);
+            for record in consumed_records(shard_id, read)? {
+                output::record(&mut out, output, &record)?;
+            }
+            let end = if shard_end { ", the end of the shard" } else { "" };
+            eprintln!("next shard iterator: {}{}", next_shard_iterator, end);
+            Ok(())
+        }
+        Command::Tail { stream, shard_id, follow, from, isolation } => {
+            tail(&connection, &mut out, output, &stream, shard_id, from, isolation, follow)
+        }
+        Command::Streams(StreamsCommand::Create { stream, shards, replication_factor }) => {
+            let request = CreateStreamRequest { stream_name: stream, shard_count: shards, replication_factor, config: Default::default() };
+            let response = call(&connection, "POST", "/streams", &serde_json::to_vec(&request)?)?;
+            output::stream(&mut out, output, &serde_json::from_slice(&response.body)?)
+        }
+        Command::Streams(StreamsCommand::List) => {
+            let response = call(&connection, "GET", "/streams", b"")?;
+            let streams: ListStreamsResponse = serde_json::from_slice(&response.body)?;
+            match output {
+                Output::Json => output::json(&mut out, &streams.stream_names),
+                _ => streams.stream_names.iter().try_for_each(|name| writeln!(out, "{}", name)),
+            }
+        }
+        Command::Streams(StreamsCommand::Describe { stream }) => {
+            let stream = connection.describe_stream(&stream, RETRIES, &Backoff::default())?;
+            output::stream(&mut out, output, &stream)
+        }
+        Command::Streams(StreamsCommand::Delete { stream }) => {
+            call(&connection, "DELETE", &format!("/streams/{}", stream), b"")?;
+            Ok(())
+        }
+        Command::Shards(ShardsCommand::Split { stream, shard_id, starting_hash_key }) => {
+            let body = serde_json::json!({ "starting_hash_key": starting_hash_key });
+            let path = format!("/streams/{}/shards/{}/split", stream, shard_id);
+            let response = call(&connection, "POST", &path, &serde_json::to_vec(&body)?)?;
+            output::stream(&mut out, output, &serde_json::from_slice(&response.body)?)
+        }
+        Command::Shards(ShardsCommand::Merge { stream, shard_id, adjacent_shard_id }) => {
+            let body = serde_json::json!({ "adjacent_shard_id": adjacent_shard_id });
+            let path = format!("/streams/{}/shards/{}/merge", stream, shard_id);
+            let response = call(&connection, "POST", &path, &serde_json::to_vec(&body)?)?;
+            output::stream(&mut out, output, &serde_json::from_slice(&response.body)?)
+        }
+        Command::Groups(GroupsCommand::Lag { group, stream }) => {
+            let lags = lag(&connection, &group, &stream)?;
+            output::lags(&mut out, output, &lags)
+        }
+    }
+}
+
+fn call(connection: &Connection, method: &str, path: &str, body: &[u8]) -> std::io::Result<HttpResponse> {
+    connection.request_with_retries(method, path, body, RETRIES, &Backoff::default())
+}
+
+fn shard_iterator(connection: &Connection, stream: &str, shard_id: u32, iterator_type: ShardIteratorType) -> std::io::Result<u64> {
+    let path = format!("/streams/{}/shards/{}/get-shard-iterator", stream, shard_id);
+    let response = call(connection, "POST", &path, &serde_json::to_vec(&iterator_type)?)?;
+    parse_shard_iterator(&response.body)
+}
+
+fn get_records(connection: &Connection, stream: &str, shard_id: u32, shard_iterator: u64, isolation: Isolation) -> std::io::Result<GetRecordsResponse> {
+    let isolation = serde_json::to_value(isolation)?;
+    let path = format!("/streams/{}/shards/{}/get-records/{}?isolation={}", stream, shard_id, shard_iterator, isolation.as_str().unwrap());
+    let response = call(connection, "GET", &path, b"")?;
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+fn put(connection: Connection, out: &mut impl Write, output: Output, stream: &str, partition_key: Option<String>, key_separator: Option<String>) -> std::io::Result<()> {
+    let producer = Producer::start(connection, ProducerConfig::new(stream))?;
+    let mut print = |sent: std::io::Result<RecordMetadata>| output::sent(out, output, &sent?);
+
+    let mut pending = VecDeque::new();
+    let stdin = std::io::stdin();
+    for (n, line) in stdin.lock().split(b'\n').enumerate() {
+        let line = line?;
+        let record = match (&key_separator, &partition_key) {
+            (Some(separator), _) => {
+                let at = line
+                    .windows(separator.len())
+                    .position(|w| w == separator.as_bytes())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("line {} has no {:?} separator", n + 1, separator)))?;
+                let key = String::from_utf8(line[..at].to_vec()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                ProducerRecord::keyed(&key, line[at + separator.len()..].to_vec())
+            }
+            (None, Some(key)) => ProducerRecord::keyed(key, line),
+            (None, None) => ProducerRecord::new(line),
+        };
+        pending.push_back(producer.send(record));
+        if pending.len() >= MAX_PENDING_PUTS {
+            print(block_on(pending.pop_front().unwrap()))?;
+        }
+    }
+    pending.into_iter().try_for_each(|sent| print(block_on(sent)))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tail(connection: &Connection, out: &mut impl Write, output: Output, stream: &str, shard_id: u32, from: ShardIteratorType, isolation: Isolation, follow: bool) -> std::io::Result<()> {
+    let mut position = shard_iterator(connection, stream, shard_id, from)?;
+    let mut idle = 0;
+    loop {
+        let read = get_records(connection, stream, shard_id, position, isolation)?;
+        position = read.next_shard_iterator;
+        let shard_end = read.shard_end;
+        let records = consumed_records(shard_id, read)?;
+        for record in &records {
+            output::record(out, output, record)?;
+        }
+        out.flush()?;
+        if shard_end {
+            eprintln!("shard {} was split or merged, its children have the records put since", shard_id);
+            return Ok(());
+        }
+        if records.is_empty() {
+            if !follow {
+                return Ok(());
+            }
+            idle += 1;
+            thread::sleep(TAIL_IDLE.delay(idle));
+        } else {
+            idle = 0;
+        }
+    }
+}
+
+fn lag(connection: &Connection, group: &str, stream: &str) -> std::io::Result<Vec<ShardLag>> {
+    let metadata: StreamMetadata = connection.describe_stream(stream, RETRIES, &Backoff::default())?;
+    let response = call(connection, "GET", &format!("/groups/{}/checkpoints/{}", group, stream), b"")?;
+    let checkpoints: Checkpoints = serde_json::from_slice(&response.body)?;
+
+    metadata.shards
+        .iter()
+        .map(|shard| {
+            let latest = shard_iterator(connection, stream, shard.shard_id, ShardIteratorType::Latest)?;
+            let checkpoint = checkpoints.checkpoints.get(&shard.shard_id).copied();
+            // a group that never committed on a shard reads it from the oldest record it has
+            let from = match checkpoint {
+                Some(checkpoint) => checkpoint,
+                None => shard_iterator(connection, stream, shard.shard_id, ShardIteratorType::Oldest)?,
+            };
+            Ok(ShardLag { shard_id: shard.shard_id, checkpoint, latest, lag: latest.saturating_sub(from), closed: shard.closed })
+        })
+        .collect()
+}
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use serde_derive::Serialize;
+
+use rinites::cluster::metadata::StreamMetadata;
+use rinites_client::consumer::ConsumedRecord;
+use rinites_client::producer::RecordMetadata;
+
+/// How records are printed: their data as it is, hex encoded, or as a JSON object per line along
+/// with where they are. Everything else is printed as text, or as JSON with `Json`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Raw,
+    Json,
+    Hex,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Output::Raw),
+            "json" => Ok(Output::Json),
+            "hex" => Ok(Output::Hex),
+            _ => Err(format!("unknown output format {}, expected raw, json or hex", s)),
+        }
+    }
+}
+
+/// a record as `json` prints it, its data base64 encoded like the API has it
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    shard_id: u32,
+    sequence_number: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partition_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: &'a BTreeMap<String, String>,
+    data: String,
+}
+
+/// where a put record went, as `json` prints it
+#[derive(Serialize)]
+struct JsonSent {
+    shard_id: u32,
+    sequence_number: u64,
+}
+
+/// How far a consumer group is behind on a shard, in sequence numbers.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ShardLag {
+    pub shard_id: u32,
+    /// where the group reads the shard from next, `None` if it never committed there
+    pub checkpoint: Option<u64>,
+    /// where the next record put in the shard goes
+    pub latest: u64,
+    pub lag: u64,
+    pub closed: bool,
+}
+
+pub fn record(out: &mut impl Write, output: Output, record: &ConsumedRecord) -> std::io::Result<()> {
+    match output {
+        Output::Raw => {
+            out.write_all(&record.data)?;
+            out.write_all(b"\n")
+        }
+        Output::Hex => writeln!(out, "{}", hex::encode(&record.data)),
+        Output::Json => json(out, &JsonRecord {
+            shard_id: record.shard_id,
+            sequence_number: record.sequence_number,
+            partition_key: record.partition_key.as_deref(),
+            headers: &record.headers,
+            data: base64::encode(&record.data),
+        }),
+    }
+}
+
+pub fn sent(out: &mut impl Write, output: Output, sent: &RecordMetadata) -> std::io::Result<()> {
+    match output {
+        Output::Json => json(out, &JsonSent { shard_id: sent.shard_id, sequence_number: sent.sequence_number }),
+        _ => writeln!(out, "shard {} sequence number {}", sent.shard_id, sent.sequence_number),
+    }
+}
+
+/// `value` as one line of JSON
+pub fn json(out: &mut impl Write, value: &impl serde::Serialize) -> std::io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
+
+pub fn stream(out: &mut impl Write, output: Output, stream: &StreamMetadata) -> std::io::Result<()> {
+    if output == Output::Json {
+        return json(out, stream);
+    }
+    writeln!(out, "{}", stream.name)?;
+    for shard in &stream.shards {
+        let replicas: Vec<String> = shard.replicas.iter().map(|r| r.to_string()).collect();
+        write!(
+            out,
+            "  shard {}  hash keys {}..={}  leader {}  replicas {}",
+            shard.shard_id, shard.starting_hash_key, shard.ending_hash_key, shard.leader, replicas.join(","),
+        )?;
+        if !shard.parent_shard_ids.is_empty() {
+            let parents: Vec<String> = shard.parent_shard_ids.iter().map(|p| p.to_string()).collect();
+            write!(out, "  parents {}", parents.join(","))?;
+        }
+        if shard.closed {
+            write!(out, "  closed")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub fn lags(out: &mut impl Write, output: Output, lags: &[ShardLag]) -> std::io::Result<()> {
+    if output == Output::Json {
+        return lags.iter().try_for_each(|lag| json(out, lag));
+    }
+    writeln!(out, "{:>8} {:>20} {:>20} {:>12}", "shard", "checkpoint", "latest", "lag")?;
+    for lag in lags {
+        let checkpoint = lag.checkpoint.map_or_else(|| "-".to_string(), |c| c.to_string());
+        let closed = if lag.closed { "  closed" } else { "" };
+        writeln!(out, "{:>8} {:>20} {:>20} {:>12}{}", lag.shard_id, checkpoint, lag.latest, lag.lag, closed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rinites_client::consumer::ConsumedRecord;
+
+    use crate::output::{Output, record};
+
+    #[test]
+    fn records_print_as_they_are_in_hex_or_as_json() {
+        let mut consumed = ConsumedRecord {
+            shard_id: 2,
+            sequence_number: 41,
+            data: b"meucu_tem_oculos".to_vec(),
+            partition_key: None,
+            headers: BTreeMap::new(),
+            next_shard_iterator: 42,
+        };
+        let printed = |output: Output, consumed: &ConsumedRecord| {
+            let mut out = Vec::new();
+            record(&mut out, output, consumed).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(printed("raw".parse().unwrap(), &consumed), "meucu_tem_oculos\n");
+        assert_eq!(printed("hex".parse().unwrap(), &consumed), "6d657563755f74656d5f6f63756c6f73\n");
+        assert_eq!(
+            printed("json".parse().unwrap(), &consumed),
+            "{\"shard_id\":2,\"sequence_number\":41,\"data\":\"bWV1Y3VfdGVtX29jdWxvcw==\"}\n",
+        );
+        consumed.partition_key = Some("user-1".to_string());
+        consumed.headers.insert("source".to_string(), "billing".to_string());
+        assert_eq!(
+            printed(Output::Json, &consumed),
+            "{\"shard_id\":2,\"sequence_number\":41,\"partition_key\":\"user-1\",\"headers\":{\"source\":\"billing\"},\"data\":\"bWV1Y3VfdGVtX29jdWxvcw==\"}\n",
+        );
+        assert!("yaml".parse::<Output>().is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+
+use rinites::auth::sigv4::Credentials;
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::http::{self, HttpResponse};
+
+/// How long to wait before trying again: doubling from `initial_ms` on every attempt up to
+/// `max_ms`, and picked at random below that so that clients failing together do not retry
+/// together.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { initial_ms: 100, max_ms: 5 * 1000 }
+    }
+}
+
+impl Backoff {
+    /// the wait before attempt `attempt`, counted from 1 for the first retry
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.initial_ms.saturating_mul(1 << attempt.saturating_sub(1).min(32)).min(self.max_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(ceiling / 2, ceiling.max(1) + 1))
+    }
+}
+
+/// Answers worth sending the request again for: the cluster had nobody to serve it right now,
+/// was throttling, or the node was not the right one any more.
+pub fn is_retriable(status: u16) -> bool {
+    matches!(status, 307 | 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Any nodes of a cluster, `host:port`. Every node serves every request, so requests go to one
+/// of them and move on to the next when it cannot be reached.
+pub struct Connection {
+    pub endpoints: Vec<String>,
+    pub timeout: Duration,
+    /// the key requests are signed with, for clusters with auth on
+    pub credentials: Option<Credentials>,
+    current: AtomicUsize,
+}
+
+impl Connection {
+    pub fn new(endpoints: Vec<String>, timeout: Duration) -> std::io::Result<Connection> {
+        if endpoints.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "a connection needs at least one endpoint"));
+        }
+        Ok(Connection { endpoints, timeout, credentials: None, current: AtomicUsize::new(0) })
+    }
+
+    pub fn with_credentials(self, credentials: Option<Credentials>) -> Connection {
+        Connection { credentials, ..self }
+    }
+
+    /// Sends the request once. Nodes that cannot be reached are skipped until one answers, so
+    /// only an error when none does.
+    pub fn request(&self, method: &str, path: &str, body: &[u8]) -> std::io::Result<HttpResponse> {
+        let first = self.current.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.endpoints.len() {
+            let endpoint = (first + i) % self.endpoints.len();
+            match http::signed_request(&self.endpoints[endpoint], method, path, body, self.timeout, self.credentials.as_ref()) {
+                Ok(response) => {
+                    self.current.store(endpoint, Ordering::Relaxed);
+                    return Ok(response);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Sends the request until it gets an answer that is not worth retrying, at most
+    /// `1 + retries` times, waiting as `backoff` says in between. Answers other than a success
+    /// are errors.
+    pub fn request_with_retries(&self, method: &str, path: &str, body: &[u8], retries: u32, backoff: &Backoff) -> std::io::Result<HttpResponse> {
+        let mut attempt = 0;
+        loop {
+            let error = match self.request(method, path, body) {
+                Ok(response) if response.is_success() => return Ok(response),
+                Ok(response) if !is_retriable(response.status) => return Err(status_error(method, path, &response)),
+                Ok(response) => status_error(method, path, &response),
+                Err(e) => e,
+            };
+            if attempt >= retries {
+                return Err(error);
+            }
+            attempt += 1;
+            thread::sleep(backoff.delay(attempt));
+        }
+    }
+
+    pub fn describe_stream(&self, stream: &str, retries: u32, backoff: &Backoff) -> std::io::Result<StreamMetadata> {
+        let response = self.request_with_retries("GET", &format!("/streams/{}", stream), b"", retries, backoff)?;
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+}
+
+/// An answer other than a success, inside the `std::io::Error` a request fails with.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// the error for an answer that is not a success, of the kind matching its status
+pub fn status_error(method: &str, path: &str, response: &HttpResponse) -> Error {
+    let kind = match response.status {
+        400 => ErrorKind::InvalidInput,
+        404 | 410 => ErrorKind::NotFound,
+        409 => ErrorKind::AlreadyExists,
+        status if is_retriable(status) => ErrorKind::ResourceBusy,
+        _ => ErrorKind::Other,
+    };
+    let message = format!("{} {} answered {}: {}", method, path, response.status, String::from_utf8_lossy(&response.body));
+    Error::new(kind, StatusError { status: response.status, message })
+}
+
+/// the status of the answer that made a request fail, if it got one
+pub fn status_of(e: &Error) -> Option<u16> {
+    e.get_ref()?.downcast_ref::<StatusError>().map(|e| e.status)
+}
@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream};
+
+use rinites::cluster::api::Checkpoints;
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::cluster::mirror::parse_shard_iterator;
+use rinites::shards::shard_controller::GetRecordsResponse;
+use rinites::shards::transactions::Isolation;
+
+use crate::connection::{Backoff, Connection};
+
+/// Where a shard that has no checkpoint and no parents is read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartPosition {
+    Oldest,
+    Latest,
+}
+
+/// How a consumer reads a stream.
+#[derive(Clone, Debug)]
+pub struct ConsumerConfig {
+    pub stream: String,
+    pub isolation: Isolation,
+    pub start: StartPosition,
+    /// how long to wait after a read of every shard found nothing, growing while nothing comes
+    pub idle: Backoff,
+    /// how often the stream is looked up again for new shards
+    pub refresh_interval_ms: u64,
+    /// how many times a request that failed in a way worth retrying is sent again
+    pub retries: u32,
+    pub backoff: Backoff,
+    /// how many records the stream of records holds before it stops reading ahead
+    pub buffer: usize,
+    /// how often committed checkpoints are saved
+    pub checkpoint_interval_ms: u64,
+}
+
+impl ConsumerConfig {
+    pub fn new(stream: &str) -> ConsumerConfig {
+        ConsumerConfig {
+            stream: stream.to_string(),
+            isolation: Isolation::ReadUncommitted,
+            start: StartPosition::Oldest,
+            idle: Backoff { initial_ms: 50, max_ms: 1000 },
+            refresh_interval_ms: 10 * 1000,
+            retries: 10,
+            backoff: Backoff::default(),
+            buffer: 1000,
+            checkpoint_interval_ms: 5 * 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumedRecord {
+    pub shard_id: u32,
+    pub sequence_number: u64,
+    pub data: Vec<u8>,
+    pub partition_key: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// where the shard is read from once this record is processed
+    pub next_shard_iterator: u64,
+}
+
+/// Keeps where a consumer got to in a stream: the shard iterator to read each shard from next.
+pub trait CheckpointStore: Send {
+    fn load(&mut self, stream: &str) -> std::io::Result<BTreeMap<u32, u64>>;
+
+    /// moves the checkpoints of the shards given, leaving the others be
+    fn save(&mut self, stream: &str, checkpoints: &BTreeMap<u32, u64>) -> std::io::Result<()>;
+}
+
+/// Checkpoints in a JSON file, by stream and shard id, replaced as a whole on every save.
+pub struct FileCheckpointStore {
+    pub path: PathBuf,
+}
+
+impl FileCheckpointStore {
+    fn read(&self) -> std::io::Result<BTreeMap<String, BTreeMap<u32, u64>>> {
+        match fs::read(&self.path) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn load(&mut self, stream: &str) -> std::io::Result<BTreeMap<u32, u64>> {
+        Ok(self.read()?.remove(stream).unwrap_or_default())
+    }
+
+    fn save(&mut self, stream: &str, checkpoints: &BTreeMap<u32, u64>) -> std::io::Result<()> {
+        let mut stored = self.read()?;
+        stored.entry(stream.to_string()).or_default().extend(checkpoints);
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&stored)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Checkpoints of a consumer group, kept by the cluster. Consumers of the same group share
+/// them.
+pub struct ServerCheckpointStore {
+    pub connection: Arc<Connection>,
+    pub group: String,
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl CheckpointStore for ServerCheckpointStore {
+    fn load(&mut self, stream: &str) -> std::io::Result<BTreeMap<u32, u64>> {
+        let path = format!("/groups/{}/checkpoints/{}", self.group, stream);
+        let response = self.connection.request_with_retries("GET", &path, b"", self.retries, &self.backoff)?;
+        let stored: Checkpoints = serde_json::from_slice(&response.body)?;
+        Ok(stored.checkpoints)
+    }
+
+    fn save(&mut self, stream: &str, checkpoints: &BTreeMap<u32, u64>) -> std::io::Result<()> {
+        let path = format!("/groups/{}/checkpoints/{}", self.group, stream);
+        let body = serde_json::to_vec(&Checkpoints { checkpoints: checkpoints.clone() })?;
+        self.connection.request_with_retries("PUT", &path, &body, self.retries, &self.backoff)?;
+        Ok(())
+    }
+}
+
+struct CheckpointState {
+    store: Box<dyn CheckpointStore>,
+    stream: String,
+    /// committed and not saved yet
+    pending: BTreeMap<u32, u64>,
+    interval: Duration,
+    last_saved: Instant,
+}
+
+impl CheckpointState {
+    fn save(&mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            self.store.save(&self.stream, &self.pending)?;
+            self.pending.clear();
+        }
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for CheckpointState {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// Commits the records a consumer handed out once they are processed. Commits are saved
+/// together every `checkpoint_interval_ms`, on `flush` and once the last checkpointer is dropped;
+/// records committed but not saved yet when the process dies are read again.
+#[derive(Clone)]
+pub struct Checkpointer {
+    state: Arc<Mutex<CheckpointState>>,
+}
+
+impl Checkpointer {
+    /// Records that `record`, and everything before it in its shard, was processed.
+    pub fn commit(&self, record: &ConsumedRecord) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(record.shard_id, record.next_shard_iterator);
+        if state.last_saved.elapsed() >= state.interval {
+            state.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.state.lock().unwrap().save()
+    }
+}
+
+#[derive(Default)]
+struct ShardState {
+    /// where to read next, `None` until the shard is first read
+    position: Option<u64>,
+    /// read to the end of a closed shard
+    ended: bool,
+}
+
+/// Reads every shard of a stream. A shard that was split or merged is read to its end before its
+/// children are started, so the records of a partition key come out in the order they were put.
+/// Shards with a checkpoint are read from it.
+pub struct Consumer {
+    connection: Arc<Connection>,
+    config: ConsumerConfig,
+    checkpointer: Checkpointer,
+    stream: StreamMetadata,
+    shards: BTreeMap<u32, ShardState>,
+    refreshed: Instant,
+}
+
+impl Consumer {
+    pub fn new(connection: Arc<Connection>, config: ConsumerConfig, mut store: Box<dyn CheckpointStore>) -> std::io::Result<Consumer> {
+        let stream = connection.describe_stream(&config.stream, config.retries, &config.backoff)?;
+        let shards = store
+            .load(&config.stream)?
+            .into_iter()
+            .map(|(shard_id, position)| (shard_id, ShardState { position: Some(position), ended: false }))
+            .collect();
+        let checkpointer = Checkpointer {
+            state: Arc::new(Mutex::new(CheckpointState {
+                store,
+                stream: config.stream.clone(),
+                pending: BTreeMap::new(),
+                interval: Duration::from_millis(config.checkpoint_interval_ms),
+                last_saved: Instant::now(),
+            })),
+        };
+        Ok(Consumer { connection, config, checkpointer, stream, shards, refreshed: Instant::now() })
+    }
+
+    pub fn checkpointer(&self) -> Checkpointer {
+        self.checkpointer.clone()
+    }
+
+    /// Reads once from every shard that can be read now, returning what that found. The shards
+    /// are moved past what was read only when the whole poll succeeds; after an error the next
+    /// poll reads the same records again.
+    pub fn poll(&mut self) -> std::io::Result<Vec<ConsumedRecord>> {
+        if self.refreshed.elapsed() >= Duration::from_millis(self.config.refresh_interval_ms) {
+            self.refresh()?;
+        }
+        let mut consumed = Vec::new();
+        let mut moved = Vec::new();
+        for shard_id in self.readable_shards() {
+            let read = self.read(shard_id)?;
+            moved.push((shard_id, read.next_shard_iterator, read.shard_end));
+            consumed.extend(consumed_records(shard_id, read)?);
+        }
+        // the children of a shard that just ended may be newer than the stream as looked up
+        if moved.iter().any(|&(_, _, ended)| ended) {
+            self.refresh()?;
+        }
+        for (shard_id, position, ended) in moved {
+            let state = self.shards.entry(shard_id).or_default();
+            state.position = Some(position);
+            state.ended = ended;
+        }
+        Ok(consumed)
+    }
+
+    /// Polls from a thread of its own, feeding the records to the stream returned, until the
+    /// stream is dropped. Errors worth retrying are passed on too, and polling goes on after
+    /// waiting; the stream ends after the others, when the stream was deleted say.
+    pub fn into_stream(mut self) -> ConsumerStream {
+        let (mut sender, receiver) = mpsc::channel(self.config.buffer.max(1));
+        thread::spawn(move || {
+            let mut idle = 0;
+            loop {
+                match self.poll() {
+                    Ok(records) if records.is_empty() => {
+                        if sender.is_closed() {
+                            return;
+                        }
+                        idle += 1;
+                        thread::sleep(self.config.idle.delay(idle));
+                    }
+                    Ok(records) => {
+                        idle = 0;
+                        for record in records {
+                            if block_on(sender.send(Ok(record))).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let fatal = matches!(e.kind(), ErrorKind::NotFound | ErrorKind::InvalidInput | ErrorKind::InvalidData);
+                        if block_on(sender.send(Err(e))).is_err() || fatal {
+                            return;
+                        }
+                        thread::sleep(self.config.backoff.delay(1));
+                    }
+                }
+            }
+        });
+        ConsumerStream { receiver }
+    }
+
+    fn refresh(&mut self) -> std::io::Result<()> {
+        self.stream = self.connection.describe_stream(&self.config.stream, self.config.retries, &self.config.backoff)?;
+        self.refreshed = Instant::now();
+        Ok(())
+    }
+
+    /// the shards not read to their end whose parents are, or are gone
+    fn readable_shards(&self) -> Vec<u32> {
+        let known: HashSet<u32> = self.stream.shards.iter().map(|s| s.shard_id).collect();
+        let ended = |shard_id: &u32| !known.contains(shard_id) || self.shards.get(shard_id).is_some_and(|s| s.ended);
+        self.stream.shards
+            .iter()
+            .filter(|s| !ended(&s.shard_id) && s.parent_shard_ids.iter().all(ended))
+            .map(|s| s.shard_id)
+            .collect()
+    }
+
+    fn read(&mut self, shard_id: u32) -> std::io::Result<GetRecordsResponse> {
+        let shard_path = format!("/streams/{}/shards/{}", self.config.stream, shard_id);
+        let position = match self.shards.get(&shard_id).and_then(|s| s.position) {
+            Some(position) => position,
+            None => {
+                // children start at their beginning, not to miss what was put in them meanwhile
+                let has_parents = self.stream.shard(shard_id).is_some_and(|s| !s.parent_shard_ids.is_empty());
+                let iterator_type = match (has_parents, self.config.start) {
+                    (false, StartPosition::Latest) => "Latest",
+                    _ => "Oldest",
+                };
+                let body = format!(r#"{{"iterator_type":"{}"}}"#, iterator_type);
+                let path = format!("{}/get-shard-iterator", shard_path);
+                let response = self.connection.request_with_retries("POST", &path, body.as_bytes(), self.config.retries, &self.config.backoff)?;
+                parse_shard_iterator(&response.body)?
+            }
+        };
+        let isolation = match self.config.isolation {
+            Isolation::ReadUncommitted => "read_uncommitted",
+            Isolation::ReadCommitted => "read_committed",
+        };
+        let path = format!("{}/get-records/{}?isolation={}", shard_path, position, isolation);
+        let response = self.connection.request_with_retries("GET", &path, b"", self.config.retries, &self.config.backoff)?;
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+}
+
+/// The records of a `get-records` answer for shard `shard_id`, decoded.
+pub fn consumed_records(shard_id: u32, read: GetRecordsResponse) -> std::io::Result<Vec<ConsumedRecord>> {
+    let next_shard_iterators = read.sequence_numbers
+        .iter()
+        .skip(1)
+        .copied()
+        .chain(std::iter::once(read.next_shard_iterator));
+    let headers = read.headers.into_iter().chain(std::iter::repeat_with(BTreeMap::new));
+    let records = read.records.iter().zip(read.sequence_numbers.iter()).zip(read.partition_keys).zip(headers).zip(next_shard_iterators);
+    records
+        .map(|((((data, sequence_number), partition_key), headers), next_shard_iterator)| {
+            Ok(ConsumedRecord {
+                shard_id,
+                sequence_number: *sequence_number,
+                data: base64::decode(data).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?,
+                partition_key,
+                headers,
+                next_shard_iterator,
+            })
+        })
+        .collect()
+}
+
+/// The records of a consumer, as they are read.
+pub struct ConsumerStream {
+    receiver: mpsc::Receiver<std::io::Result<ConsumedRecord>>,
+}
+
+impl Stream for ConsumerStream {
+    type Item = std::io::Result<ConsumedRecord>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use rand::{Rng, thread_rng};
+    use rand::distributions::Alphanumeric;
+
+    use crate::connection::Connection;
+    use crate::consumer::{CheckpointState, CheckpointStore, Checkpointer, ConsumedRecord, Consumer, ConsumerConfig, FileCheckpointStore};
+
+    struct MemoryCheckpointStore(BTreeMap<u32, u64>);
+
+    impl CheckpointStore for MemoryCheckpointStore {
+        fn load(&mut self, _stream: &str) -> std::io::Result<BTreeMap<u32, u64>> {
+            Ok(self.0.clone())
+        }
+
+        fn save(&mut self, _stream: &str, checkpoints: &BTreeMap<u32, u64>) -> std::io::Result<()> {
+            self.0.extend(checkpoints);
+            Ok(())
+        }
+    }
+
+    /// A node serving stream orders, shards 0 and 1, with one record at 5 in shard 0. Reads of
+    /// shard 1 fail while `failing` is set.
+    fn serve_orders(failing: Arc<AtomicBool>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split(' ').nth(1).unwrap().split('?').next().unwrap();
+                let records = |next: u64| format!(r#"{{"next_shard_iterator":{},"records":[],"sequence_numbers":[],"partition_keys":[]}}"#, next);
+                let (status, body) = match path {
+                    "/streams/orders" => (200, r#"{"name":"orders","shards":[
+                        {"shard_id":0,"starting_hash_key":0,"ending_hash_key":100,"replicas":[1],"leader":1,"leader_epoch":0},
+                        {"shard_id":1,"starting_hash_key":101,"ending_hash_key":200,"replicas":[1],"leader":1,"leader_epoch":0}
+                    ]}"#.to_string()),
+                    "/streams/orders/shards/0/get-records/5" => (200, format!(
+                        r#"{{"next_shard_iterator":6,"records":["{}"],"sequence_numbers":[5],"partition_keys":[null]}}"#,
+                        base64::encode(b"meucu_tem_oculos"),
+                    )),
+                    "/streams/orders/shards/0/get-records/6" => (200, records(6)),
+                    "/streams/orders/shards/1/get-records/3" if failing.load(Ordering::SeqCst) => (400, "{}".to_string()),
+                    "/streams/orders/shards/1/get-records/3" => (200, records(3)),
+                    _ => (404, "{}".to_string()),
+                };
+                let _ = write!(stream, "HTTP/1.0 {} X\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body);
+            }
+        });
+        addr
+    }
+
+    fn record(shard_id: u32, next_shard_iterator: u64) -> ConsumedRecord {
+        ConsumedRecord {
+            shard_id,
+            sequence_number: next_shard_iterator - 1,
+            data: b"meucu_tem_oculos".to_vec(),
+            partition_key: None,
+            headers: BTreeMap::new(),
+            next_shard_iterator,
+        }
+    }
+
+    #[test]
+    fn commits_are_saved_together_and_survive_in_the_file() {
+        let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let path = env::temp_dir().join(format!("to_mount-checkpoints-{}.json", rand_string));
+        let checkpointer = Checkpointer {
+            state: Arc::new(Mutex::new(CheckpointState {
+                store: Box::new(FileCheckpointStore { path: path.clone() }),
+                stream: "orders".to_string(),
+                pending: BTreeMap::new(),
+                interval: Duration::from_secs(3600),
+                last_saved: Instant::now(),
+            })),
+        };
+        let mut file = FileCheckpointStore { path: path.clone() };
+
+        checkpointer.commit(&record(0, 5)).unwrap();
+        checkpointer.commit(&record(1, 3)).unwrap();
+        checkpointer.commit(&record(0, 7)).unwrap();
+        assert!(file.load("orders").unwrap().is_empty());
+        checkpointer.flush().unwrap();
+        assert_eq!(file.load("orders").unwrap(), vec![(0, 7), (1, 3)].into_iter().collect());
+
+        checkpointer.commit(&record(1, 4)).unwrap();
+        drop(checkpointer);
+        file.save("payments", &vec![(0, 1)].into_iter().collect()).unwrap();
+        assert_eq!(file.load("orders").unwrap(), vec![(0, 7), (1, 4)].into_iter().collect());
+        assert_eq!(file.load("payments").unwrap(), vec![(0, 1)].into_iter().collect());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_poll_that_fails_on_one_shard_reads_the_others_again() {
+        let failing = Arc::new(AtomicBool::new(true));
+        let addr = serve_orders(failing.clone());
+        let connection = Arc::new(Connection::new(vec![addr], Duration::from_secs(5)).unwrap());
+        let mut config = ConsumerConfig::new("orders");
+        config.retries = 0;
+        let checkpoints = MemoryCheckpointStore(vec![(0, 5), (1, 3)].into_iter().collect());
+        let mut consumer = Consumer::new(connection, config, Box::new(checkpoints)).unwrap();
+
+        assert!(consumer.poll().is_err());
+        failing.store(false, Ordering::SeqCst);
+        let consumed = consumer.poll().unwrap();
+        assert_eq!(consumed, vec![record(0, 6)]);
+        assert!(consumer.poll().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,5 @@
+//! Clients for a rinites cluster, over its HTTP API.
+
+pub mod connection;
+pub mod consumer;
+pub mod producer;
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use rand::Rng;
+
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::shards::shard_controller::{PutRecordsEntry, PutRecordsRequest, PutRecordsResponse};
+
+use crate::connection::{Backoff, Connection, status_of};
+
+/// How a producer batches and retries the records of a stream.
+#[derive(Clone, Debug)]
+pub struct ProducerConfig {
+    pub stream: String,
+    /// a request is sent as soon as it has this many records
+    pub max_batch_records: usize,
+    /// or this many bytes of data, keys and headers
+    pub max_batch_bytes: usize,
+    /// how long a record may wait for others to share its request
+    pub linger_ms: u64,
+    /// how many times a request that failed in a way worth retrying is sent again
+    pub retries: u32,
+    pub backoff: Backoff,
+}
+
+impl ProducerConfig {
+    pub fn new(stream: &str) -> ProducerConfig {
+        ProducerConfig {
+            stream: stream.to_string(),
+            max_batch_records: 500,
+            max_batch_bytes: 1024 * 1024,
+            linger_ms: 20,
+            retries: 10,
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProducerRecord {
+    pub data: Vec<u8>,
+    /// picks the shard; records without one are spread over the shards
+    pub partition_key: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl ProducerRecord {
+    pub fn new(data: Vec<u8>) -> ProducerRecord {
+        ProducerRecord { data, partition_key: None, headers: BTreeMap::new() }
+    }
+
+    pub fn keyed(partition_key: &str, data: Vec<u8>) -> ProducerRecord {
+        ProducerRecord { partition_key: Some(partition_key.to_string()), ..ProducerRecord::new(data) }
+    }
+
+    pub fn with_headers(self, headers: BTreeMap<String, String>) -> ProducerRecord {
+        ProducerRecord { headers, ..self }
+    }
+
+    fn size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(name, value)| name.len() + value.len()).sum();
+        self.data.len() + self.partition_key.as_ref().map_or(0, |k| k.len()) + headers
+    }
+}
+
+/// Where a record ended up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordMetadata {
+    pub shard_id: u32,
+    pub sequence_number: u64,
+}
+
+/// Resolves once the record is acknowledged, or failed for good.
+pub struct RecordFuture(oneshot::Receiver<std::io::Result<RecordMetadata>>);
+
+impl Future for RecordFuture {
+    type Output = std::io::Result<RecordMetadata>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|_| Err(Error::other("the producer stopped before sending the record"))))
+    }
+}
+
+enum Message {
+    Send(ProducerRecord, oneshot::Sender<std::io::Result<RecordMetadata>>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Puts records in the shards of a stream from a thread of its own. Records are routed by
+/// partition key and batched per shard until a batch is full or its first record lingered long
+/// enough. Each shard is put in as an idempotent producer, one request at a time, so retries
+/// neither duplicate nor reorder records. Dropping the producer sends what it still holds.
+pub struct Producer {
+    sender: Option<Mutex<mpsc::Sender<Message>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Producer {
+    /// Looks the stream up, which fails right away if it does not exist, and starts sending.
+    pub fn start(connection: Connection, config: ProducerConfig) -> std::io::Result<Producer> {
+        let stream = connection.describe_stream(&config.stream, config.retries, &config.backoff)?;
+        let (sender, receiver) = mpsc::channel();
+        let mut worker = Worker {
+            connection,
+            stream,
+            batches: Batches::new(config.max_batch_records, config.max_batch_bytes, Duration::from_millis(config.linger_ms)),
+            shards: HashMap::new(),
+            next_keyless: 0,
+            config,
+        };
+        let thread = thread::spawn(move || worker.run(receiver));
+        Ok(Producer { sender: Some(Mutex::new(sender)), thread: Some(thread) })
+    }
+
+    pub fn send(&self, record: ProducerRecord) -> RecordFuture {
+        let (sender, receiver) = oneshot::channel();
+        // a failed send drops `sender`, which fails the future
+        let _ = self.sender.as_ref().unwrap().lock().unwrap().send(Message::Send(record, sender));
+        RecordFuture(receiver)
+    }
+
+    /// Sends the records held right away; resolves once all those sent before are done.
+    pub fn flush(&self) -> impl Future<Output = ()> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.sender.as_ref().unwrap().lock().unwrap().send(Message::Flush(sender));
+        async move {
+            let _ = receiver.await;
+        }
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+type Pending = (ProducerRecord, oneshot::Sender<std::io::Result<RecordMetadata>>);
+
+#[derive(Default)]
+struct Batch {
+    records: Vec<Pending>,
+    bytes: usize,
+    /// when its first record came
+    since: Option<Instant>,
+}
+
+/// The records waiting to be sent, per shard.
+struct Batches {
+    max_records: usize,
+    max_bytes: usize,
+    linger: Duration,
+    shards: BTreeMap<u32, Batch>,
+}
+
+impl Batches {
+    fn new(max_records: usize, max_bytes: usize, linger: Duration) -> Batches {
+        Batches { max_records: max_records.max(1), max_bytes, linger, shards: BTreeMap::new() }
+    }
+
+    /// Adds a record, returning the batches of the shard that are ready: the one it did not fit
+    /// in, and the one it filled.
+    fn add(&mut self, shard_id: u32, pending: Pending, now: Instant) -> Vec<Batch> {
+        let mut ready = Vec::new();
+        let size = pending.0.size();
+        let batch = self.shards.entry(shard_id).or_default();
+        if !batch.records.is_empty() && batch.bytes + size > self.max_bytes {
+            ready.push(std::mem::take(batch));
+        }
+        batch.since.get_or_insert(now);
+        batch.bytes += size;
+        batch.records.push(pending);
+        if batch.records.len() >= self.max_records || batch.bytes >= self.max_bytes {
+            ready.push(std::mem::take(batch));
+        }
+        ready
+    }
+
+    /// when the batch that waited the longest is due
+    fn next_deadline(&self) -> Option<Instant> {
+        self.shards.values().filter_map(|b| b.since).min().map(|since| since + self.linger)
+    }
+
+    /// takes the batches that lingered long enough, or all of them
+    fn take(&mut self, now: Instant, all: bool) -> Vec<(u32, Batch)> {
+        let linger = self.linger;
+        self.shards
+            .iter_mut()
+            .filter(|(_, b)| b.since.is_some_and(|since| all || since + linger <= now))
+            .map(|(shard_id, b)| (*shard_id, std::mem::take(b)))
+            .collect()
+    }
+}
+
+/// The idempotent producer a shard is put in as. It gets a new id whenever a request fails for
+/// good, since that request may or may not have been appended.
+struct ShardProducer {
+    producer_id: u64,
+    next_sequence: u64,
+}
+
+impl ShardProducer {
+    fn new() -> ShardProducer {
+        ShardProducer { producer_id: rand::thread_rng().gen_range(1, 1 << 53), next_sequence: 0 }
+    }
+}
+
+struct Worker {
+    connection: Connection,
+    config: ProducerConfig,
+    stream: StreamMetadata,
+    batches: Batches,
+    shards: HashMap<u32, ShardProducer>,
+    next_keyless: usize,
+}
+
+impl Worker {
+    fn run(&mut self, receiver: mpsc::Receiver<Message>) {
+        loop {
+            let received = match self.batches.next_deadline() {
+                Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let now = Instant::now();
+            match received {
+                Ok(Message::Send(record, sender)) => {
+                    let shard_id = self.route(&record);
+                    for batch in self.batches.add(shard_id, (record, sender), now) {
+                        self.send(shard_id, batch);
+                    }
+                }
+                Ok(Message::Flush(sender)) => {
+                    self.send_all(now, true);
+                    let _ = sender.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => self.send_all(now, false),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.send_all(now, true);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn route(&mut self, record: &ProducerRecord) -> u32 {
+        let shard = match &record.partition_key {
+            Some(partition_key) => self.stream.shard_for_key(partition_key),
+            None => {
+                self.next_keyless = self.next_keyless.wrapping_add(1);
+                let open: Vec<_> = self.stream.shards.iter().filter(|s| !s.closed).collect();
+                open.get(self.next_keyless % open.len().max(1)).copied()
+            }
+        };
+        shard.map_or(0, |s| s.shard_id)
+    }
+
+    fn send_all(&mut self, now: Instant, all: bool) {
+        for (shard_id, batch) in self.batches.take(now, all) {
+            self.send(shard_id, batch);
+        }
+    }
+
+    fn send(&mut self, shard_id: u32, batch: Batch) {
+        match self.put(shard_id, &batch) {
+            Ok(sequence_numbers) => {
+                for ((_, sender), sequence_number) in batch.records.into_iter().zip(sequence_numbers) {
+                    let _ = sender.send(Ok(RecordMetadata { shard_id, sequence_number }));
+                }
+            }
+            Err(e) => {
+                self.shards.remove(&shard_id);
+                // the stream may have changed shape since it was looked up
+                let refreshed = e.kind() == ErrorKind::NotFound && self.refresh().is_ok();
+                if refreshed && status_of(&e) == Some(410) {
+                    // the shard was split or merged, its children take the records
+                    for (record, sender) in batch.records {
+                        let shard_id = self.route(&record);
+                        for batch in self.batches.add(shard_id, (record, sender), Instant::now()) {
+                            self.send(shard_id, batch);
+                        }
+                    }
+                    return;
+                }
+                for (_, sender) in batch.records {
+                    let _ = sender.send(Err(Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
+    }
+
+    fn refresh(&mut self) -> std::io::Result<()> {
+        self.stream = self.connection.describe_stream(&self.config.stream, self.config.retries, &self.config.backoff)?;
+        Ok(())
+    }
+
+    fn put(&mut self, shard_id: u32, batch: &Batch) -> std::io::Result<Vec<u64>> {
+        let shard = self.shards.entry(shard_id).or_insert_with(ShardProducer::new);
+        let records = batch.records
+            .iter()
+            .map(|(record, _)| PutRecordsEntry::Keyed {
+                data: base64::encode(&record.data),
+                partition_key: record.partition_key.clone(),
+                headers: record.headers.clone(),
+            })
+            .collect();
+        let request = PutRecordsRequest {
+            record: None,
+            records,
+            producer_id: Some(shard.producer_id),
+            producer_sequence: Some(shard.next_sequence),
+            transaction_id: None,
+        };
+        let path = format!("/streams/{}/shards/{}/put-records", self.config.stream, shard_id);
+        let response = self.connection.request_with_retries("POST", &path, &serde_json::to_vec(&request)?, self.config.retries, &self.config.backoff)?;
+        let put: PutRecordsResponse = serde_json::from_slice(&response.body)?;
+        if put.sequence_numbers.len() != batch.records.len() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} records were put, {} sequence numbers came back", batch.records.len(), put.sequence_numbers.len())));
+        }
+        shard.next_sequence += batch.records.len() as u64;
+        Ok(put.sequence_numbers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures::channel::oneshot;
+
+    use crate::producer::{Batches, Pending, ProducerRecord};
+
+    fn pending(size: usize) -> Pending {
+        (ProducerRecord::new(vec![0; size]), oneshot::channel().0)
+    }
+
+    #[test]
+    fn batches_are_ready_once_full_or_lingered_long_enough() {
+        let start = Instant::now();
+        let mut batches = Batches::new(3, 100, Duration::from_millis(50));
+        assert!(batches.add(0, pending(10), start).is_empty());
+        assert!(batches.add(1, pending(10), start + Duration::from_millis(10)).is_empty());
+        assert!(batches.add(0, pending(10), start + Duration::from_millis(20)).is_empty());
+        assert_eq!(batches.next_deadline(), Some(start + Duration::from_millis(50)));
+
+        let full = batches.add(0, pending(10), start + Duration::from_millis(30));
+        assert_eq!(full.iter().map(|b| b.records.len()).collect::<Vec<usize>>(), vec![3]);
+        assert_eq!(batches.next_deadline(), Some(start + Duration::from_millis(60)));
+
+        // a record that does not fit sends what came before it, one bigger than a batch goes alone
+        assert!(batches.add(1, pending(80), start + Duration::from_millis(40)).is_empty());
+        let ready = batches.add(1, pending(150), start + Duration::from_millis(45));
+        assert_eq!(ready.iter().map(|b| b.bytes).collect::<Vec<usize>>(), vec![90, 150]);
+
+        assert!(batches.add(2, pending(10), start + Duration::from_millis(70)).is_empty());
+        assert!(batches.take(start + Duration::from_millis(100), false).is_empty());
+        assert_eq!(batches.take(start + Duration::from_millis(120), false).len(), 1);
+        assert!(batches.take(start + Duration::from_millis(120), true).is_empty());
+    }
+}
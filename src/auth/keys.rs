@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use rand::distributions::Alphanumeric;
+use rand::{Rng, thread_rng};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::middleware::KeyStore;
+
+/// the grant pattern of every stream, and what needs admin on it needs admin over all of them
+pub const ALL_STREAMS: &str = "*";
+
+/// What a grant lets a key do with a stream. Admin takes in read and write.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// get records, shard iterators, checkpoints and the description of the stream
+    Read,
+    /// put records
+    Write,
+    /// create, delete, split and merge; on `*`, the cluster and its keys as well
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A key requests can be signed with, besides the root key of the nodes, and what it may do.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// permissions by the streams they are for: a stream name, `*` for every stream, or a prefix
+    /// ending in `*` for the streams starting with it
+    #[serde(default)]
+    pub grants: BTreeMap<String, BTreeSet<Permission>>,
+}
+
+/// A key as the admin API lists it, without its secret.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct KeySummary {
+    pub access_key_id: String,
+    pub grants: BTreeMap<String, BTreeSet<Permission>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ListKeysResponse {
+    pub keys: Vec<KeySummary>,
+}
+
+/// What `POST /admin/keys` takes. The id and the secret left out are generated, and the key
+/// comes back with them, the only time the secret is shown.
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default)]
+pub struct NewKey {
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub grants: BTreeMap<String, BTreeSet<Permission>>,
+}
+
+/// What `PUT /admin/keys/{id}/grants/{stream}` takes.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GrantRequest {
+    pub permissions: BTreeSet<Permission>,
+}
+
+/// A change to the keys, as the admin API makes it.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyChange {
+    Put { key: ApiKey },
+    Delete { access_key_id: String },
+    /// sets what the key may do with the streams `stream` matches, no permissions taking the
+    /// grant away
+    Grant { access_key_id: String, stream: String, permissions: BTreeSet<Permission> },
+}
+
+/// Every key but the root one, by access key id.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ApiKeys {
+    keys: BTreeMap<String, ApiKey>,
+}
+
+/// The keys of a standalone shard server, kept in a file under its mount path.
+#[derive(Debug)]
+pub struct LocalKeys {
+    path: PathBuf,
+    /// the id no other key can have
+    root_access_key_id: String,
+    keys: RwLock<ApiKeys>,
+}
+
+impl ApiKey {
+    /// Whether the key may do `permission` with `stream`. `*` only stands for every stream in
+    /// the grants, so only a grant on `*` lets a key do what needs `permission` on `*`.
+    pub fn allows(&self, stream: &str, permission: Permission) -> bool {
+        self.grants.iter().any(|(pattern, permissions)| {
+            matches(pattern, stream) && (permissions.contains(&permission) || permissions.contains(&Permission::Admin))
+        })
+    }
+
+    pub fn summary(&self) -> KeySummary {
+        KeySummary { access_key_id: self.access_key_id.clone(), grants: self.grants.clone() }
+    }
+}
+
+impl NewKey {
+    pub fn into_key(self) -> ApiKey {
+        let random = |len: usize| thread_rng().sample_iter(&Alphanumeric).take(len).collect::<String>();
+        ApiKey {
+            access_key_id: self.access_key_id.unwrap_or_else(|| format!("RK{}", random(18).to_ascii_uppercase())),
+            secret_access_key: self.secret_access_key.unwrap_or_else(|| random(40)),
+            grants: self.grants,
+        }
+    }
+}
+
+impl ApiKeys {
+    pub fn get(&self, access_key_id: &str) -> Option<&ApiKey> {
+        self.keys.get(access_key_id)
+    }
+
+    pub fn summaries(&self) -> Vec<KeySummary> {
+        self.keys.values().map(ApiKey::summary).collect()
+    }
+
+    /// Refuses a change the admin API answers with an error: a key whose id is taken, the id of
+    /// the root key included, a key with an invalid id, a secret or grant, and changes to keys
+    /// that are not there.
+    pub fn check(&self, change: &KeyChange, root_access_key_id: &str) -> std::io::Result<()> {
+        match change {
+            KeyChange::Put { key } => {
+                let valid_id = key.access_key_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if key.access_key_id.is_empty() || key.access_key_id.len() > 128 || !valid_id {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("invalid access key id '{}'", key.access_key_id)));
+                }
+                if key.access_key_id == root_access_key_id || self.keys.contains_key(&key.access_key_id) {
+                    return Err(Error::new(ErrorKind::AlreadyExists, format!("key {} already exists", key.access_key_id)));
+                }
+                if key.secret_access_key.len() < 16 || key.secret_access_key.chars().any(char::is_whitespace) {
+                    return Err(Error::new(ErrorKind::InvalidInput, "a secret access key has 16 characters or more and no spaces"));
+                }
+                key.grants.keys().try_for_each(|pattern| check_pattern(pattern))
+            }
+            KeyChange::Delete { access_key_id } => self.check_exists(access_key_id),
+            KeyChange::Grant { access_key_id, stream, .. } => {
+                self.check_exists(access_key_id)?;
+                check_pattern(stream)
+            }
+        }
+    }
+
+    /// Makes a change that was checked, leaving out one that no longer fits the keys.
+    pub fn apply(&mut self, change: &KeyChange) {
+        match change {
+            KeyChange::Put { key } => {
+                self.keys.entry(key.access_key_id.clone()).or_insert_with(|| key.clone());
+            }
+            KeyChange::Delete { access_key_id } => {
+                self.keys.remove(access_key_id);
+            }
+            KeyChange::Grant { access_key_id, stream, permissions } => {
+                if let Some(key) = self.keys.get_mut(access_key_id) {
+                    if permissions.is_empty() {
+                        key.grants.remove(stream);
+                    } else {
+                        key.grants.insert(stream.clone(), permissions.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_exists(&self, access_key_id: &str) -> std::io::Result<()> {
+        if !self.keys.contains_key(access_key_id) {
+            return Err(Error::new(ErrorKind::NotFound, format!("no key {}", access_key_id)));
+        }
+        Ok(())
+    }
+}
+
+impl LocalKeys {
+    /// The keys stored at `path`, none when there is no file yet.
+    pub fn open(path: PathBuf, root_access_key_id: String) -> std::io::Result<LocalKeys> {
+        let keys = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid keys file {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => ApiKeys::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(LocalKeys { path, root_access_key_id, keys: RwLock::new(keys) })
+    }
+
+    pub fn summaries(&self) -> Vec<KeySummary> {
+        self.keys.read().unwrap().summaries()
+    }
+
+    pub fn summary(&self, access_key_id: &str) -> std::io::Result<KeySummary> {
+        self.keys
+            .read()
+            .unwrap()
+            .get(access_key_id)
+            .map(ApiKey::summary)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no key {}", access_key_id)))
+    }
+
+    /// Checks and makes `change`, which is synced to the file before it is taken into use.
+    pub fn change(&self, change: &KeyChange) -> std::io::Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        keys.check(change, &self.root_access_key_id)?;
+        let mut changed = keys.clone();
+        changed.apply(change);
+        store(&self.path, &changed)?;
+        *keys = changed;
+        Ok(())
+    }
+}
+
+impl KeyStore for LocalKeys {
+    fn api_key(&self, access_key_id: &str) -> Option<ApiKey> {
+        self.keys.read().unwrap().get(access_key_id).cloned()
+    }
+}
+
+fn matches(pattern: &str, stream: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => stream.starts_with(prefix),
+        None => pattern == stream,
+    }
+}
+
+fn check_pattern(pattern: &str) -> std::io::Result<()> {
+    let name = pattern.strip_suffix('*').unwrap_or(pattern);
+    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if pattern.is_empty() || name.len() > 128 || !valid_name {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid grant '{}', give a stream, `*` or a prefix ending in `*`", pattern)));
+    }
+    Ok(())
+}
+
+fn store(path: &Path, keys: &ApiKeys) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(keys)?)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::io::ErrorKind;
+
+    use crate::auth::keys::{KeyChange, LocalKeys, NewKey, Permission};
+    use crate::auth::middleware::KeyStore;
+    use crate::test_util::with_tmp_dir;
+
+    #[test]
+    fn grants_allow_what_they_name_on_the_streams_they_match() {
+        with_tmp_dir(|dir| {
+            fs::create_dir_all(&dir).unwrap();
+            let keys = LocalKeys::open(dir.join("keys.json"), "root".to_string()).unwrap();
+            let key = NewKey::default().into_key();
+            let id = key.access_key_id.clone();
+            keys.change(&KeyChange::Put { key }).unwrap();
+            let grant = |stream: &str, permissions: &[Permission]| KeyChange::Grant {
+                access_key_id: id.clone(),
+                stream: stream.to_string(),
+                permissions: permissions.iter().cloned().collect::<BTreeSet<_>>(),
+            };
+            keys.change(&grant("meucu_tem_oculos", &[Permission::Read])).unwrap();
+            keys.change(&grant("orders-*", &[Permission::Admin])).unwrap();
+
+            let key = LocalKeys::open(dir.join("keys.json"), "root".to_string()).unwrap().api_key(&id).unwrap();
+            assert!(key.allows("meucu_tem_oculos", Permission::Read));
+            assert!(!key.allows("meucu_tem_oculos", Permission::Write));
+            assert!(key.allows("orders-eu", Permission::Write));
+            assert!(!key.allows("orders", Permission::Read));
+            assert!(!key.allows("*", Permission::Admin));
+
+            keys.change(&grant("*", &[Permission::Admin])).unwrap();
+            assert!(keys.api_key(&id).unwrap().allows("*", Permission::Admin));
+            keys.change(&grant("*", &[])).unwrap();
+            assert_eq!(keys.summary(&id).unwrap().grants.len(), 2);
+
+            let taken = NewKey { access_key_id: Some("root".to_string()), ..NewKey::default() }.into_key();
+            assert_eq!(keys.change(&KeyChange::Put { key: taken }).unwrap_err().kind(), ErrorKind::AlreadyExists);
+            assert_eq!(keys.change(&grant("meucu/tem", &[Permission::Read])).unwrap_err().kind(), ErrorKind::InvalidInput);
+            keys.change(&KeyChange::Delete { access_key_id: id.clone() }).unwrap();
+            assert_eq!(keys.change(&grant("*", &[Permission::Read])).unwrap_err().kind(), ErrorKind::NotFound);
+            assert!(keys.summaries().is_empty());
+        });
+    }
+}
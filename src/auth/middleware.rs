@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorForbidden, ErrorPayloadTooLarge, ErrorUnauthorized};
+use actix_web::{HttpMessage, HttpRequest};
+use actix_web::web::BytesMut;
+use futures::future::{ok, ready, Ready};
+use futures::{stream, StreamExt};
+
+use crate::auth::keys::{ApiKey, Permission};
+use crate::auth::sigv4::{Credentials, Request, unix_now};
+
+/// the largest body of a signed request, which is read whole to check it against its signature
+pub const MAX_SIGNED_BODY: usize = 64 * 1024 * 1024;
+
+/// set to its key id on requests a node let through for an API key and forwards, signed with the
+/// root key, to another node, which takes that key as the one that signed the request
+pub const SIGNED_BY_HEADER: &str = "x-rinites-signed-by";
+
+/// The API key a request that was let through was signed with, `None` for the root key. Routes
+/// find it in the extensions of the request when auth is on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedBy(pub Option<String>);
+
+/// the API key `req` was signed with, `None` for the root key or when auth is off
+pub fn signed_by(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<SignedBy>().and_then(|signed_by| signed_by.0.clone())
+}
+
+/// What a request needs for its key to be let through.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Access {
+    /// nothing, for health checks and metrics, which probes and scrapers fetch unsigned
+    Open,
+    /// a signature by any key
+    Signed,
+    /// the root key the nodes sign their calls to each other with
+    Root,
+    Stream(String, Permission),
+}
+
+/// Where the middleware looks up the keys other than the root key.
+pub trait KeyStore: Send + Sync {
+    fn api_key(&self, access_key_id: &str) -> Option<ApiKey>;
+}
+
+/// Tells what a request needs from its method, path and body.
+pub type AccessFn = fn(&str, &str, &[u8]) -> Access;
+
+/// Middleware letting through only requests signed with AWS Signature Version 4, by the root key
+/// or a key in the store, whose key may do what `access` says the request needs. The others get
+/// a 401 when their signature does not check out and a 403 when their key may not do it. Without
+/// a root key auth is off, and every request is let through.
+pub struct Authenticate {
+    checks: Rc<Checks>,
+}
+
+pub struct AuthenticateService<S> {
+    service: Rc<RefCell<S>>,
+    checks: Rc<Checks>,
+}
+
+struct Checks {
+    root: Option<Credentials>,
+    keys: Arc<dyn KeyStore>,
+    access: AccessFn,
+}
+
+impl Authenticate {
+    pub fn new(root: Option<Credentials>, keys: Arc<dyn KeyStore>, access: AccessFn) -> Authenticate {
+        Authenticate { checks: Rc::new(Checks { root, keys, access }) }
+    }
+}
+
+impl<S, B> Transform<S> for Authenticate
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static, B: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticateService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticateService { service: Rc::new(RefCell::new(service)), checks: self.checks.clone() })
+    }
+}
+
+impl<S, B> Service for AuthenticateService<S>
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static, B: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<ServiceResponse<B>, actix_web::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let checks = self.checks.clone();
+        Box::pin(async move {
+            let root = match &checks.root {
+                Some(root) => root,
+                None => {
+                    let served = service.borrow_mut().call(req);
+                    return served.await;
+                }
+            };
+            // the body is part of what is signed, so it is read here and handed on in one piece
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > MAX_SIGNED_BODY {
+                    return Ok(req.error_response(ErrorPayloadTooLarge("the body is larger than a signed request can be")));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = body.freeze();
+            let checked = checks.check(&req, root, &body);
+            req.set_payload(Payload::Stream(Box::pin(stream::once(ready(Ok(body))))));
+            match checked {
+                Ok(signed_by) => {
+                    req.extensions_mut().insert(signed_by);
+                    let served = service.borrow_mut().call(req);
+                    served.await
+                }
+                Err(e) => Ok(req.error_response(e)),
+            }
+        })
+    }
+}
+
+impl Checks {
+    /// Checks the request and tells which key signed it. `access` is asked about the path as
+    /// actix routes it, decoded but for `/` and `+`, the signature is checked over the path as sent.
+    fn check(&self, req: &ServiceRequest, root: &Credentials, body: &[u8]) -> Result<SignedBy, actix_web::Error> {
+        let access = (self.access)(req.method().as_str(), req.match_info().path(), body);
+        if access == Access::Open {
+            return Ok(SignedBy(None));
+        }
+        let request = Request {
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            headers: req
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            body,
+        };
+        let unauthorized = |e: std::io::Error| ErrorUnauthorized(e.to_string());
+        let authorization = request.authorization().map_err(unauthorized)?;
+        let id = &authorization.access_key_id;
+        let key = match id == &root.access_key_id {
+            true => None,
+            false => Some(self.keys.api_key(id).ok_or_else(|| ErrorUnauthorized(format!("unknown access key id {}", id)))?),
+        };
+        let secret = key.as_ref().map_or(&root.secret_access_key, |key| &key.secret_access_key);
+        request.verify(&authorization, secret, unix_now()).map_err(unauthorized)?;
+
+        match (access, key) {
+            // forwarded by a node, which signed who the request came from as well
+            (_, None) => match authorization.signed_headers.iter().any(|name| name == SIGNED_BY_HEADER) {
+                true => Ok(SignedBy(request.header(SIGNED_BY_HEADER).map(str::to_string))),
+                false => Ok(SignedBy(None)),
+            },
+            (Access::Open, _) | (Access::Signed, _) => Ok(SignedBy(Some(id.clone()))),
+            (Access::Root, Some(_)) => Err(ErrorForbidden(format!("key {} may not call the routes between nodes", id))),
+            (Access::Stream(stream, permission), Some(key)) if !key.allows(&stream, permission) => {
+                Err(ErrorForbidden(format!("key {} has no {} grant on {}", id, permission, stream)))
+            }
+            (Access::Stream(..), Some(_)) => Ok(SignedBy(Some(id.clone()))),
+        }
+    }
+}
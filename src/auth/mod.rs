@@ -0,0 +1,3 @@
+pub mod keys;
+pub mod middleware;
+pub mod sigv4;
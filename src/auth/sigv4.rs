@@ -0,0 +1,377 @@
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// when the request was signed, as `yyyymmddThhmmssZ`
+pub const DATE_HEADER: &str = "x-amz-date";
+
+/// hex sha256 of the body, checked against the body when a request has it
+pub const CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+
+/// how far from the clock of the node a request may have been signed, as AWS allows
+pub const MAX_CLOCK_SKEW_SECS: u64 = 15 * 60;
+
+/// scope requests signed by rinites itself are signed for; requests of others can have any
+const REGION: &str = "local";
+const SERVICE: &str = "rinites";
+
+/// An access key id and the secret requests are signed with.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// The parts of an HTTP request an AWS Signature Version 4 goes over, which is all of it but the
+/// headers left out of `SignedHeaders`.
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// as sent, still percent-encoded
+    pub path: &'a str,
+    pub query: &'a str,
+    /// names in lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: &'a [u8],
+}
+
+/// The `Authorization` header of a signed request.
+#[derive(Debug, PartialEq)]
+pub struct Authorization {
+    pub access_key_id: String,
+    /// `yyyymmdd`, region and service of the credential scope the signing key was derived for
+    pub date: String,
+    pub region: String,
+    pub service: String,
+    pub signed_headers: Vec<String>,
+    /// hex
+    pub signature: String,
+}
+
+impl Request<'_> {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The headers that sign the request with `credentials` at `now`, in seconds since the
+    /// epoch: the host, which `headers` must hold already, the date, the hash of the body and the
+    /// `x-rinites-` headers in `headers`.
+    pub fn sign(&self, credentials: &Credentials, now: u64) -> Vec<(String, String)> {
+        let date = format_amz_date(now);
+        let payload_hash = hex::encode(Sha256::digest(self.body));
+        let mut headers = self.headers.clone();
+        headers.push((DATE_HEADER.to_string(), date.clone()));
+        headers.push((CONTENT_SHA256_HEADER.to_string(), payload_hash.clone()));
+        let mut signed_headers: Vec<String> = headers
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| name == "host" || name.starts_with("x-amz-") || name.starts_with("x-rinites-"))
+            .collect();
+        signed_headers.sort();
+        signed_headers.dedup();
+
+        let request = Request { headers, ..*self };
+        let scope = Scope { date: &date[..8], region: REGION, service: SERVICE };
+        let canonical = request.canonical_request(&signed_headers, &payload_hash);
+        let signature = hex::encode(scope.signature(&credentials.secret_access_key, &date, &canonical));
+        let authorization = format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, credentials.access_key_id, scope, signed_headers.join(";"), signature,
+        );
+        vec![
+            (DATE_HEADER.to_string(), date),
+            (CONTENT_SHA256_HEADER.to_string(), payload_hash),
+            ("authorization".to_string(), authorization),
+        ]
+    }
+
+    pub fn authorization(&self) -> std::io::Result<Authorization> {
+        self.header("authorization").ok_or_else(|| rejected("the request is not signed"))?.parse()
+    }
+
+    /// Checks that the request was signed as `authorization` says with `secret_access_key`, no
+    /// more than `MAX_CLOCK_SKEW_SECS` away from `now`, and that the host, the date and the body
+    /// are what was signed.
+    pub fn verify(&self, authorization: &Authorization, secret_access_key: &str, now: u64) -> std::io::Result<()> {
+        let date = self.header(DATE_HEADER).ok_or_else(|| rejected("the request has no x-amz-date"))?;
+        let signed_at = parse_amz_date(date).ok_or_else(|| rejected(format!("x-amz-date {} is not yyyymmddThhmmssZ", date)))?;
+        if signed_at.max(now) - signed_at.min(now) > MAX_CLOCK_SKEW_SECS {
+            return Err(rejected(format!("the request was signed at {}, too far from now", date)));
+        }
+        if !date.starts_with(&authorization.date) {
+            return Err(rejected("the credential scope is not for the day the request was signed"));
+        }
+        for name in ["host", DATE_HEADER].iter() {
+            if !authorization.signed_headers.iter().any(|signed| signed == name) {
+                return Err(rejected(format!("{} is not signed", name)));
+            }
+        }
+        if let Some(missing) = authorization.signed_headers.iter().find(|name| self.header(name).is_none()) {
+            return Err(rejected(format!("signed header {} is missing", missing)));
+        }
+        let payload_hash = hex::encode(Sha256::digest(self.body));
+        if self.header(CONTENT_SHA256_HEADER).is_some_and(|claimed| !claimed.eq_ignore_ascii_case(&payload_hash)) {
+            return Err(rejected("the body does not match x-amz-content-sha256"));
+        }
+
+        let scope = Scope { date: &authorization.date, region: &authorization.region, service: &authorization.service };
+        let canonical = self.canonical_request(&authorization.signed_headers, &payload_hash);
+        let signature = hex::decode(&authorization.signature).map_err(|_| rejected("the signature is not hex"))?;
+        scope
+            .mac(secret_access_key, date, &canonical)
+            .verify_slice(&signature)
+            .map_err(|_| rejected("the signature does not match, check the secret access key and how the request is signed"))
+    }
+
+    fn canonical_request(&self, signed_headers: &[String], payload_hash: &str) -> String {
+        let headers: String = signed_headers
+            .iter()
+            .map(|name| {
+                let values: Vec<String> = self
+                    .headers
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .map(|(_, value)| value.split_whitespace().collect::<Vec<_>>().join(" "))
+                    .collect();
+                format!("{}:{}\n", name, values.join(","))
+            })
+            .collect();
+        let path = if self.path.is_empty() { "/" } else { self.path };
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.method, uri_encode(path, true), canonical_query(self.query), headers, signed_headers.join(";"), payload_hash,
+        )
+    }
+}
+
+impl FromStr for Authorization {
+    type Err = Error;
+
+    fn from_str(header: &str) -> std::io::Result<Authorization> {
+        let malformed = || rejected(format!("authorization is not `{} Credential=..., SignedHeaders=..., Signature=...`", ALGORITHM));
+        let fields = header.strip_prefix(ALGORITHM).filter(|rest| rest.starts_with(' ')).ok_or_else(malformed)?;
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for field in fields.split(',') {
+            match field.trim().split_once('=') {
+                Some(("Credential", value)) => credential = Some(value),
+                Some(("SignedHeaders", value)) => signed_headers = Some(value),
+                Some(("Signature", value)) => signature = Some(value),
+                _ => {}
+            }
+        }
+        let credential: Vec<&str> = credential.ok_or_else(malformed)?.split('/').collect();
+        match (credential.as_slice(), signed_headers, signature) {
+            ([access_key_id, date, region, service, "aws4_request"], Some(signed_headers), Some(signature)) => Ok(Authorization {
+                access_key_id: access_key_id.to_string(),
+                date: date.to_string(),
+                region: region.to_string(),
+                service: service.to_string(),
+                signed_headers: signed_headers.split(';').map(|name| name.to_ascii_lowercase()).collect(),
+                signature: signature.to_string(),
+            }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// seconds since the epoch, what `sign` and `verify` take as now
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// the credential scope, which the signing key is derived for
+struct Scope<'a> {
+    date: &'a str,
+    region: &'a str,
+    service: &'a str,
+}
+
+impl Scope<'_> {
+    /// the hmac over the string to sign, keyed with the key derived from `secret` for the scope
+    fn mac(&self, secret: &str, date: &str, canonical_request: &str) -> Hmac<Sha256> {
+        let key = [self.date, self.region, self.service, "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", secret).into_bytes(), |key, part| hmac_sha256(&key, part.as_bytes()));
+        let string_to_sign = format!("{}\n{}\n{}\n{}", ALGORITHM, date, self, hex::encode(Sha256::digest(canonical_request.as_bytes())));
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("hmac takes keys of any length");
+        mac.update(string_to_sign.as_bytes());
+        mac
+    }
+
+    fn signature(&self, secret: &str, date: &str, canonical_request: &str) -> Vec<u8> {
+        self.mac(secret, date, canonical_request).finalize().into_bytes().to_vec()
+    }
+}
+
+impl std::fmt::Display for Scope<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}/aws4_request", self.date, self.region, self.service)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn rejected(reason: impl Into<String>) -> Error {
+    Error::new(ErrorKind::PermissionDenied, reason.into())
+}
+
+/// the query decoded, encoded again the one way SigV4 does and sorted by name and value
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (uri_encode(&percent_decode(name), false), uri_encode(&percent_decode(value), false))
+        })
+        .collect();
+    params.sort();
+    params.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&")
+}
+
+/// every byte but the unreserved ones as `%XX`, and `/` as well unless `keep_slash`
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            b'/' if keep_slash => "/".to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match s.get(i + 1..i + 3).filter(|_| bytes[i] == b'%').and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// `secs` since the epoch as `yyyymmddThhmmssZ`
+fn format_amz_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+fn parse_amz_date(date: &str) -> Option<u64> {
+    if date.len() != 16 || !date.is_char_boundary(8) || &date[8..9] != "T" || !date.ends_with('Z') {
+        return None;
+    }
+    let field = |range: Range<usize>| date.get(range).filter(|s| s.bytes().all(|b| b.is_ascii_digit())).and_then(|s| s.parse::<i64>().ok());
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hours, minutes, seconds) = (field(9..11)?, field(11..13)?, field(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds;
+    if secs < 0 { None } else { Some(secs as u64) }
+}
+
+/// days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::sigv4::{format_amz_date, parse_amz_date, Credentials, Request, MAX_CLOCK_SKEW_SECS};
+
+    fn request<'a>(body: &'a [u8], headers: &[(&str, &str)]) -> Request<'a> {
+        Request {
+            method: "POST",
+            path: "/streams/meucu_tem_oculos/shards/0/put-records",
+            query: "",
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body,
+        }
+    }
+
+    #[test]
+    fn requests_of_the_aws_test_suite_verify() {
+        // get-vanilla of the AWS Signature Version 4 test suite
+        let request = Request {
+            method: "GET",
+            path: "/",
+            query: "",
+            headers: vec![
+                ("host".to_string(), "example.amazonaws.com".to_string()),
+                ("x-amz-date".to_string(), "20150830T123600Z".to_string()),
+                (
+                    "authorization".to_string(),
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, \
+                     Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+                        .to_string(),
+                ),
+            ],
+            body: b"",
+        };
+        let now = parse_amz_date("20150830T123600Z").unwrap();
+        let authorization = request.authorization().unwrap();
+        assert_eq!(authorization.access_key_id, "AKIDEXAMPLE");
+        request.verify(&authorization, "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", now).unwrap();
+        assert!(request.verify(&authorization, "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEZ", now).is_err());
+        assert_eq!(format_amz_date(now), "20150830T123600Z");
+    }
+
+    #[test]
+    fn signed_requests_verify_until_anything_signed_is_changed() {
+        let credentials = Credentials { access_key_id: "meucu".to_string(), secret_access_key: "tem_oculos".to_string() };
+        let now = parse_amz_date("20240229T235959Z").unwrap();
+        let unsigned = request(b"[\"meucu\"]", &[("host", "127.0.0.1:8080")]);
+        let mut signed = request(b"[\"meucu\"]", &[("host", "127.0.0.1:8080"), ("content-type", "application/json")]);
+        signed.headers.extend(unsigned.sign(&credentials, now));
+        let authorization = signed.authorization().unwrap();
+        assert_eq!(authorization.signed_headers, ["host", "x-amz-content-sha256", "x-amz-date"]);
+        signed.verify(&authorization, "tem_oculos", now + MAX_CLOCK_SKEW_SECS).unwrap();
+
+        assert!(signed.verify(&authorization, "tem_oculos", now + MAX_CLOCK_SKEW_SECS + 1).is_err());
+        assert!(signed.verify(&authorization, "oculos", now).is_err());
+        let tampered = Request { body: b"[\"oculos\"]", ..request(b"", &[]) };
+        let tampered = Request { headers: signed.headers.clone(), ..tampered };
+        assert!(tampered.verify(&authorization, "tem_oculos", now).is_err());
+        let elsewhere = Request { path: "/streams/meucu_tem_oculos/shards/1/put-records", headers: signed.headers.clone(), ..request(b"[\"meucu\"]", &[]) };
+        assert!(elsewhere.verify(&authorization, "tem_oculos", now).is_err());
+        assert!(unsigned.authorization().is_err());
+    }
+}
@@ -1,10 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime};
 
-use actix_web::client::{Client, ClientResponse};
-use futures;
-use rand::{Rng, thread_rng};
-use rand::distributions::Alphanumeric;
+use actix_web::client::Client;
 
 //use futures_util::future::future::FutureExt;
 #[actix_rt::main]
@@ -12,8 +9,7 @@ async fn main() {
     let data = "{\"record\":\"\
     VFpDS1Bpb1FPdlJwWWgzdzVwZ1RBNG50UXhHT2pRUUlqc0tQM3ZPSUJBdDA5Q285S0dNejkxc1djMzYxNHJWMTJyVnphSjBWa2JQMEpmNjhiUm9RRUlnN0I0SHV5OE1PRlEwZQ\
     OXk3NkVDMXVPbHRYc1dpT1g3NmhlNXNxbXc2Q2RrRzlYWVp1UlZTU000TU9ONUlLOUJsUEVZb1VOSllpYjFGcjU1ZU5kVzJpbDlObGVBeVdwUmpRaFl5Q2NIUUYwMVZWRjlSZg==\"}";
-    dbg!(data);
-    let mut client = Client::default();
+    let client = Client::default();
     let s = data.len();
     let z = 50;
     let n = 1000;
@@ -41,7 +37,7 @@ async fn main() {
                     c.fetch_add(1, Ordering::Relaxed);
                 },
                 Ok(res) => {
-                    dbg!(res);
+                    eprintln!("put failed with status {}", res.status());
                     errors.fetch_add(1, Ordering::Relaxed);
                 },
                 _ => {
@@ -51,8 +47,7 @@ async fn main() {
         }
     }
 
-    dbg!(c.load(Ordering::Relaxed));
-    dbg!(errors.load(Ordering::Relaxed));
+    println!("{} puts succeeded, {} failed", c.load(Ordering::Relaxed), errors.load(Ordering::Relaxed));
     let end = now.elapsed().expect("se fodeu 2").as_millis();
     println!("it took {} ms to send {} bytes per request in {} request, totaling {} bytes and {} bytes/sec", end, s, n, n*s, (n as f64 *s as f64)/(end as f64 / 1000.0));
 
@@ -0,0 +1,215 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use structopt::StructOpt;
+
+use rinites::shards::batch::{BatchHeader, RecordBatch};
+use rinites::shards::encryption::{KeyFile, KeyProvider};
+use rinites::shards::format::{FORMAT_VERSION, LegacyOffsets, migrate_legacy};
+use rinites::shards::inspect::{check_index, rebuild_index, scan_segment, SegmentScan, truncate_at_bad_frame};
+use rinites::shards::shards::{SegmentId, ShardDir};
+
+/// Inspects and repairs the segments of a shard dir. Only run it while no server has the shard
+/// open.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "rinites-segment")]
+enum Command {
+    /// Prints every batch of a segment file, with its records if asked to
+    Dump {
+        #[structopt(parse(from_os_str))]
+        segment: PathBuf,
+
+        /// prints the records of every batch too
+        #[structopt(long)]
+        records: bool,
+
+        /// file with one `<key id> <64 hex digits>` line per key, to print encrypted records
+        #[structopt(long, parse(from_os_str))]
+        keyfile: Option<PathBuf>,
+    },
+    /// Checks every segment of a shard dir and its index, exiting with 1 if anything is wrong
+    Verify {
+        #[structopt(parse(from_os_str))]
+        shard_dir: PathBuf,
+    },
+    /// Writes the index files of a shard dir that are missing or do not match their segment
+    RebuildIndexes {
+        #[structopt(parse(from_os_str))]
+        shard_dir: PathBuf,
+    },
+    /// Cuts a segment file right before its first bad frame, losing everything from there on
+    Truncate {
+        #[structopt(parse(from_os_str))]
+        segment: PathBuf,
+    },
+    /// Rewrites a shard dir of the legacy newline format in the current one
+    Migrate {
+        #[structopt(parse(from_os_str))]
+        shard_dir: PathBuf,
+    },
+    /// Prints the shard iterator a legacy one, a byte offset, became in a migrated shard dir
+    LegacyOffset {
+        #[structopt(parse(from_os_str))]
+        shard_dir: PathBuf,
+        offset: u64,
+    },
+}
+
+fn main() {
+    match run(Command::from_args()) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("rinites-segment: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+/// false when something wrong was found
+fn run(command: Command) -> std::io::Result<bool> {
+    match command {
+        Command::Dump { segment, records, keyfile } => {
+            let keys = keyfile.map(|path| KeyFile::load(&path)).transpose()?;
+            let (shard_dir, base_offset) = segment_of(&segment)?;
+            dump(&shard_dir, base_offset, records, keys.as_ref().map(|k| k as &dyn KeyProvider))
+        }
+        Command::Verify { shard_dir } => {
+            let shard_dir = ShardDir { mount_dir: shard_dir };
+            let mut healthy = true;
+            for base_offset in segments(&shard_dir)? {
+                let scan = scan_segment(&shard_dir, base_offset)?;
+                let mut problems = check_index(&shard_dir, &scan)?;
+                if let Some(bad_frame) = &scan.bad_frame {
+                    problems.insert(0, format!("bad frame at {} of {} bytes: {}", bad_frame.position, scan.len, bad_frame.reason));
+                }
+                let path = shard_dir.path_to_segment(base_offset);
+                match problems.is_empty() {
+                    true => println!("{}: {} batches, ok", path.display(), scan.frames.len()),
+                    false => problems.iter().for_each(|problem| println!("{}: {}", path.display(), problem)),
+                }
+                healthy &= problems.is_empty();
+            }
+            Ok(healthy)
+        }
+        Command::RebuildIndexes { shard_dir } => {
+            let shard_dir = ShardDir { mount_dir: shard_dir };
+            for base_offset in segments(&shard_dir)? {
+                let scan = scan_segment(&shard_dir, base_offset)?;
+                if !check_index(&shard_dir, &scan)?.is_empty() {
+                    rebuild_index(&shard_dir, &scan)?;
+                    println!("{}: rebuilt, {} batches", shard_dir.path_to_index(base_offset).display(), scan.frames.len());
+                }
+            }
+            Ok(true)
+        }
+        Command::Truncate { segment } => {
+            let (shard_dir, base_offset) = segment_of(&segment)?;
+            let scan = scan_segment(&shard_dir, base_offset)?;
+            match &scan.bad_frame {
+                Some(bad_frame) => {
+                    let cut = truncate_at_bad_frame(&shard_dir, &scan)?;
+                    println!("{}: cut {} bytes from {} on, {}", segment.display(), cut, bad_frame.position, bad_frame.reason);
+                }
+                None => println!("{}: every frame is good, nothing to cut", segment.display()),
+            }
+            Ok(true)
+        }
+        Command::Migrate { shard_dir } => {
+            let migration = migrate_legacy(&ShardDir { mount_dir: shard_dir.clone() })?;
+            println!(
+                "{}: {} records in {} segments migrated to format version {}, {} bytes of a torn last record dropped",
+                shard_dir.display(), migration.records, migration.segments, FORMAT_VERSION, migration.torn_bytes,
+            );
+            Ok(true)
+        }
+        Command::LegacyOffset { shard_dir, offset } => {
+            let legacy_offsets = LegacyOffsets::load(&ShardDir { mount_dir: shard_dir.clone() })?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} was not migrated from the legacy format", shard_dir.display())))?;
+            match legacy_offsets.sequence(offset) {
+                Some(sequence) => println!("{}", sequence),
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("{} is past the legacy log end of {}", offset, shard_dir.display()))),
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// the shard dir a segment file is in and its base offset
+fn segment_of(path: &Path) -> std::io::Result<(ShardDir, SegmentId)> {
+    let base_offset = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|name| name.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a segment file", path.display())))?;
+    let mount_dir = path.parent().map_or_else(|| PathBuf::from("."), |dir| dir.to_path_buf());
+    Ok((ShardDir { mount_dir }, base_offset))
+}
+
+fn segments(shard_dir: &ShardDir) -> std::io::Result<Vec<SegmentId>> {
+    let mut base_offsets = shard_dir.list_segments()?;
+    base_offsets.sort_unstable();
+    Ok(base_offsets)
+}
+
+fn dump(shard_dir: &ShardDir, base_offset: SegmentId, records: bool, keys: Option<&dyn KeyProvider>) -> std::io::Result<bool> {
+    let scan: SegmentScan = scan_segment(shard_dir, base_offset)?;
+    let data = if records { fs::read(shard_dir.path_to_segment(base_offset))? } else { vec![] };
+
+    for (position, header) in scan.frames.iter() {
+        println!("{}", describe(*position, header));
+        if !records || header.control {
+            continue;
+        }
+        match RecordBatch::decode_with(&data[*position as usize..], keys) {
+            Ok(batch) => {
+                for (sequence, record) in batch.sequenced() {
+                    let key = record.partition_key.map(|k| format!(" key {}", k)).unwrap_or_default();
+                    let headers = match record.headers.is_empty() {
+                        true => String::new(),
+                        false => format!(" headers {:?}", record.headers),
+                    };
+                    println!("  {}{}{} {}", sequence, key, headers, record.data.escape_ascii());
+                }
+            }
+            Err(e) => println!("  records unreadable: {}", e),
+        }
+    }
+    match &scan.bad_frame {
+        Some(bad_frame) => {
+            println!("bad frame at {} of {} bytes: {}", bad_frame.position, scan.len, bad_frame.reason);
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
+
+/// one line about the batch of the frame at `position`
+fn describe(position: u64, header: &BatchHeader) -> String {
+    let flags: Vec<&str> = [
+        (header.has_producer, "producer"),
+        (header.transactional, "transactional"),
+        (header.control, "control"),
+        (header.encrypted, "encrypted"),
+        (header.has_headers, "headers"),
+    ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect();
+    format!(
+        "batch {}..{} at {}: {} records, {} bytes, timestamp {} ms, crc {:08x}, {}{}",
+        header.base_sequence,
+        header.next_sequence(),
+        position,
+        header.record_count,
+        header.frame_len(),
+        header.timestamp_ms,
+        header.crc,
+        format!("{:?}", header.compression).to_lowercase(),
+        if flags.is_empty() { String::new() } else { format!(", {}", flags.join(" ")) },
+    )
+}
@@ -1,115 +1,586 @@
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use actix_web::{App, get, HttpRequest, HttpResponse, HttpServer, post, Responder, web};
-use actix_web::body::Body;
+use actix_web::{App, delete, get, HttpResponse, HttpServer, post, put, web};
+use actix_web::error::{BlockingError, ErrorBadRequest, InternalError};
 use actix_web::Result;
-use actix_web::web::Json;
-use json::JsonValue;
+use actix_web::web::{Bytes, Json};
 use serde_derive::{Deserialize, Serialize};
 use structopt::StructOpt;
+use tracing::{info, Span};
 
-use rinites::Response;
-use rinites::shards::shard_controller::{GetRecordsResponse, PutRecordsResponse, ShardController};
-use rinites::shards::shards::{assert_recordable, Record, ShardDir, ShardReader, ShardWriter, ShaW};
+use rinites::auth::keys::{ALL_STREAMS, GrantRequest, KeyChange, ListKeysResponse, LocalKeys, NewKey, Permission};
+use rinites::auth::middleware::{Access, Authenticate, KeyStore};
+use rinites::cluster::api;
+use rinites::cluster::metadata::NodeId;
+use rinites::cluster::mirror::{Checkpoints, Mirror, parse_mapping_rules};
+use rinites::cluster::node::{ClusterNode, DEFAULT_FAILURE_TIMEOUT_MS, parse_peers};
+use rinites::cluster::raft::{DEFAULT_ELECTION_TIMEOUT_MS, DEFAULT_HEARTBEAT_MS, RaftConfig};
+use rinites::config::{DEFAULT_SHARD_COUNT, NodeConfig};
+use rinites::health::Readiness;
+use rinites::logging;
+use rinites::logging::LogFormat;
+use rinites::metrics::{Exposition, EXPOSITION_CONTENT_TYPE};
+use rinites::shards::batch::Compression;
+use rinites::shards::compaction::CleanupPolicy;
+use rinites::shards::durability::FsyncPolicy;
+use rinites::shards::replication::{FETCH_MAX_BYTES, FetchQuery, HIGH_WATERMARK_HEADER, HttpLeader, Leader, LEADER_EPOCH_HEADER, spawn_follower};
+use rinites::shards::shard_controller::{AdminShardsResponse, GetRecordsResponse, PutRecordsRequest, PutRecordsResponse, ShardController};
+use rinites::shards::shards::{ShardDir, ShardIteratorType};
 
-/// Rinites
+/// file under the mount path the mirror keeps its progress in
+const MIRROR_CHECKPOINTS: &str = "mirror-checkpoints.json";
+
+/// file under the mount path of a single shard server the keys of the admin API are kept in
+const KEYS_FILE: &str = "auth-keys.json";
+
+/// how long the mirror waits before reading the source again once it has caught up
+const MIRROR_IDLE_MS: u64 = 100;
+
+/// Rinites. Settings come from the flags, then `RINITES_*` environment variables, then the
+/// config file, then their defaults.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 struct Opts {
-    #[structopt(short, long)]
-    mount_path: String,
+    /// TOML file with the settings of the node, see rinites.example.toml
+    #[structopt(long, parse(from_os_str), env = "RINITES_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[structopt(short, long, parse(from_os_str), env = "RINITES_MOUNT_PATH")]
+    mount_path: Option<PathBuf>,
+
+    /// 127.0.0.1 by default; listens on this host and --port only, in place of the listeners
+    /// of the config file
+    #[structopt(short, long, env = "RINITES_HOST")]
+    host: Option<String>,
+
+    #[structopt(short, long, env = "RINITES_PORT")]
+    port: Option<u16>,
+
+    /// size in bytes past which the active segment is rolled [default: 1000000]
+    #[structopt(long, env = "RINITES_MAX_SEGMENT_SIZE")]
+    max_segment_size: Option<u64>,
+
+    /// how many records a get returns at most [default: 10]
+    #[structopt(long, env = "RINITES_MAX_RECORDS_PER_GET")]
+    max_records_per_get: Option<usize>,
+
+    /// none, interval(<ms>), every_n_records(<n>) or always [default: none]
+    #[structopt(long, env = "RINITES_FSYNC_POLICY")]
+    fsync_policy: Option<FsyncPolicy>,
+
+    /// codec for new batches: none, zstd, lz4 or snappy [default: none]
+    #[structopt(long, env = "RINITES_COMPRESSION")]
+    compression: Option<Compression>,
+
+    /// none, or compact to only keep the latest record of each partition key [default: none]
+    #[structopt(long, env = "RINITES_CLEANUP_POLICY")]
+    cleanup_policy: Option<CleanupPolicy>,
+
+    /// how long compaction keeps tombstones, records with a partition key and empty data
+    /// [default: 86400000]
+    #[structopt(long, env = "RINITES_TOMBSTONE_RETENTION_MS")]
+    tombstone_retention_ms: Option<u64>,
+
+    /// how long each shard keeps its records, whole segments of them, after they were put
+    /// [default: 0, forever]
+    #[structopt(long, env = "RINITES_RETENTION_MS")]
+    retention_ms: Option<u64>,
+
+    /// how many bytes of segments each shard keeps before deleting its oldest ones
+    /// [default: 0, no limit]
+    #[structopt(long, env = "RINITES_RETENTION_BYTES")]
+    retention_bytes: Option<u64>,
+
+    /// records each shard takes in per second, puts past it get a 429 [default: 0, no quota]
+    #[structopt(long, env = "RINITES_PUT_RECORDS_PER_SEC")]
+    put_records_per_sec: Option<u64>,
+
+    /// bytes of record data each shard takes in per second [default: 0, no quota]
+    #[structopt(long, env = "RINITES_PUT_BYTES_PER_SEC")]
+    put_bytes_per_sec: Option<u64>,
+
+    /// directory sealed segments are uploaded to, standing in for an object store
+    #[structopt(long, parse(from_os_str), env = "RINITES_REMOTE_DIR")]
+    remote_dir: Option<PathBuf>,
+
+    /// how long sealed segments stay on the mount path once uploaded [default: 3600000]
+    #[structopt(long, env = "RINITES_HOT_RETENTION_MS")]
+    hot_retention_ms: Option<u64>,
+
+    /// how many remote segments are cached on the mount path for readers [default: 16]
+    #[structopt(long, env = "RINITES_REMOTE_CACHE_SEGMENTS")]
+    remote_cache_segments: Option<usize>,
+
+    /// file with one `<key id> <64 hex digits>` line per encryption key
+    #[structopt(long, parse(from_os_str), env = "RINITES_KEYFILE")]
+    keyfile: Option<PathBuf>,
+
+    /// host:port of the leader to follow, this node leads the shard when missing
+    #[structopt(long, env = "RINITES_LEADER")]
+    leader: Option<String>,
+
+    /// name this node fetches from its leader with [default: follower]
+    #[structopt(long, env = "RINITES_REPLICA_ID")]
+    replica_id: Option<String>,
+
+    /// how many replicas, this one included, must have a put before it is acknowledged
+    /// [default: 1]
+    #[structopt(long, env = "RINITES_MIN_INSYNC_REPLICAS")]
+    min_insync_replicas: Option<usize>,
+
+    /// a follower that has not caught up for this long is out of sync [default: 10000]
+    #[structopt(long, env = "RINITES_REPLICA_LAG_MAX_MS")]
+    replica_lag_max_ms: Option<u64>,
+
+    /// how long a put waits for the in-sync replicas before failing [default: 10000]
+    #[structopt(long, env = "RINITES_ACK_TIMEOUT_MS")]
+    ack_timeout_ms: Option<u64>,
+
+    /// id of this node in a cluster, along with --peers
+    #[structopt(long, env = "RINITES_NODE_ID")]
+    node_id: Option<NodeId>,
+
+    /// every node of the cluster as <node id>=<host:port>,..., this one included
+    #[structopt(long, env = "RINITES_PEERS")]
+    peers: Option<String>,
 
-    #[structopt(short, long, default_value = "127.0.0.1")]
-    host: String,
+    /// how long cluster nodes wait for the metadata leader before electing another
+    /// [default: 1000]
+    #[structopt(long, env = "RINITES_ELECTION_TIMEOUT_MS")]
+    election_timeout_ms: Option<u64>,
 
-    #[structopt(short, long)]
-    port: u16,
+    /// [default: 100]
+    #[structopt(long, env = "RINITES_HEARTBEAT_MS")]
+    heartbeat_ms: Option<u64>,
+
+    /// how long a cluster node goes unheard of before the shards it leads get new leaders
+    /// [default: 3000]
+    #[structopt(long, env = "RINITES_FAILURE_TIMEOUT_MS")]
+    failure_timeout_ms: Option<u64>,
+
+    /// shards of streams created without a shard count [default: 1]
+    #[structopt(long, env = "RINITES_DEFAULT_SHARD_COUNT")]
+    default_shard_count: Option<u32>,
+
+    /// host:port of a cluster whose streams this cluster node copies into its own cluster
+    #[structopt(long, env = "RINITES_MIRROR_FROM")]
+    mirror_from: Option<String>,
+
+    /// which streams to mirror and what to call them, as <from>=<to>,... where * matches any
+    /// part of a name, all of them under their own names when missing
+    #[structopt(long, env = "RINITES_MIRROR_STREAMS")]
+    mirror_streams: Option<String>,
+
+    /// a key of the mirrored cluster that may read the mirrored streams, when it has auth on
+    #[structopt(long, env = "RINITES_MIRROR_ACCESS_KEY_ID")]
+    mirror_access_key_id: Option<String>,
+
+    #[structopt(long, env = "RINITES_MIRROR_SECRET_ACCESS_KEY", hide_env_values = true)]
+    mirror_secret_access_key: Option<String>,
+
+    /// id of the root key, which every request must be signed with or with a key it created
+    #[structopt(long, env = "RINITES_ROOT_ACCESS_KEY_ID")]
+    root_access_key_id: Option<String>,
+
+    #[structopt(long, env = "RINITES_ROOT_SECRET_ACCESS_KEY", hide_env_values = true)]
+    root_secret_access_key: Option<String>,
+
+    /// which logs to write, like `info` or `info,rinites::shards=debug`; RUST_LOG when missing
+    #[structopt(long, env = "RINITES_LOG_FILTER")]
+    log_filter: Option<String>,
+
+    /// text, or json for one object per line [default: text]
+    #[structopt(long, env = "RINITES_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
 }
 
-fn get_cli_opts() -> Opts {
-    let mut opts = Opts::from_args();
-    if opts.host == "localhost" {
-        opts.host = "127.0.0.1".to_string()
+/// The config file, if any, with the flags and environment variables given in place of what it
+/// says.
+fn node_config(opts: Opts) -> std::io::Result<NodeConfig> {
+    let mut config = match &opts.config {
+        Some(path) => NodeConfig::load(path)?,
+        None => NodeConfig::default(),
+    };
+
+    let node = &mut config.node;
+    node.mount_path = opts.mount_path.or(node.mount_path.take());
+    match (opts.host, opts.port) {
+        (host, Some(port)) => {
+            let host = match host.as_deref() {
+                None | Some("localhost") => "127.0.0.1".to_string(),
+                Some(host) => host.to_string(),
+            };
+            node.listeners = Some(vec![format!("{}:{}", host, port)]);
+        }
+        (Some(_), None) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--host goes with --port")),
+        (None, None) => {}
     }
-    opts
-}
+    node.keyfile = opts.keyfile.or(node.keyfile.take());
+    node.remote_dir = opts.remote_dir.or(node.remote_dir.take());
+    node.remote_cache_segments = opts.remote_cache_segments.or(node.remote_cache_segments);
+
+    let log = &mut config.log;
+    log.filter = opts.log_filter.or(log.filter.take());
+    log.format = opts.log_format.or(log.format);
 
+    let replication = &mut config.replication;
+    replication.leader = opts.leader.or(replication.leader.take());
+    replication.replica_id = opts.replica_id.or(replication.replica_id.take());
+    replication.min_insync_replicas = opts.min_insync_replicas.or(replication.min_insync_replicas);
+    replication.replica_lag_max_ms = opts.replica_lag_max_ms.or(replication.replica_lag_max_ms);
+    replication.ack_timeout_ms = opts.ack_timeout_ms.or(replication.ack_timeout_ms);
 
+    let cluster = &mut config.cluster;
+    cluster.node_id = opts.node_id.or(cluster.node_id);
+    cluster.peers = opts.peers.or(cluster.peers.take());
+    cluster.election_timeout_ms = opts.election_timeout_ms.or(cluster.election_timeout_ms);
+    cluster.heartbeat_ms = opts.heartbeat_ms.or(cluster.heartbeat_ms);
+    cluster.failure_timeout_ms = opts.failure_timeout_ms.or(cluster.failure_timeout_ms);
+    cluster.default_shard_count = opts.default_shard_count.or(cluster.default_shard_count);
+    cluster.mirror_from = opts.mirror_from.or(cluster.mirror_from.take());
+    cluster.mirror_streams = opts.mirror_streams.or(cluster.mirror_streams.take());
+    cluster.mirror_access_key_id = opts.mirror_access_key_id.or(cluster.mirror_access_key_id.take());
+    cluster.mirror_secret_access_key = opts.mirror_secret_access_key.or(cluster.mirror_secret_access_key.take());
+
+    let auth = &mut config.auth;
+    auth.root_access_key_id = opts.root_access_key_id.or(auth.root_access_key_id.take());
+    auth.root_secret_access_key = opts.root_secret_access_key.or(auth.root_secret_access_key.take());
+
+    let streams = &mut config.streams;
+    streams.max_segment_size = opts.max_segment_size.or(streams.max_segment_size);
+    streams.max_records_per_get = opts.max_records_per_get.or(streams.max_records_per_get);
+    streams.fsync_policy = opts.fsync_policy.or(streams.fsync_policy);
+    streams.compression = opts.compression.or(streams.compression);
+    streams.cleanup_policy = opts.cleanup_policy.or(streams.cleanup_policy);
+    streams.tombstone_retention_ms = opts.tombstone_retention_ms.or(streams.tombstone_retention_ms);
+    streams.retention_ms = opts.retention_ms.or(streams.retention_ms);
+    streams.retention_bytes = opts.retention_bytes.or(streams.retention_bytes);
+    streams.hot_retention_ms = opts.hot_retention_ms.or(streams.hot_retention_ms);
+    streams.put_records_per_sec = opts.put_records_per_sec.or(streams.put_records_per_sec);
+    streams.put_bytes_per_sec = opts.put_bytes_per_sec.or(streams.put_bytes_per_sec);
+    streams.check()?;
+    Ok(config)
+}
 
 #[get("/get-records/{shard_iterator}")]
 async fn get_records(shard_controller: web::Data<ShardController>, shard_iterator: web::Path<u64>) -> Result<Json<GetRecordsResponse>> {
 
-    let result = shard_controller.get_records(shard_iterator.into_inner());
+    let result = shard_controller.get_records(shard_iterator.into_inner())?;
 
     Ok(Json(result))
 }
 
-#[derive(Deserialize, Serialize)]
-struct PutRecordsRequest {
-    record: String,
-}
-
 #[post("/put-records")]
 async fn put_records(shard_controller: web::Data<ShardController>, body: web::Json<PutRecordsRequest>) -> Result<Json<PutRecordsResponse>> {
-    let record = Record::from_string(body.record.clone())?;
-    let result = shard_controller.put_records(record);
+    let body = body.into_inner();
+    if body.transaction_id.is_some() {
+        return Err(ErrorBadRequest("transactions need a cluster to decide them, start with --node-id and --peers"));
+    }
+    let producer = body.producer().map_err(ErrorBadRequest)?;
+    let records = body.into_records()?;
+    if records.is_empty() {
+        return Ok(Json(PutRecordsResponse { sequence_numbers: vec![] }));
+    }
+    // waiting for the replicas blocks, so it must not happen on the worker serving fetches
+    let span = Span::current();
+    let result = web::block(move || span.in_scope(|| shard_controller.put_records_from(producer, records)))
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) if e.kind() == ErrorKind::QuotaExceeded => {
+                let response = api::slow_down(&e.to_string());
+                InternalError::from_response(e, response).into()
+            }
+            BlockingError::Error(e) => actix_web::Error::from(e),
+            BlockingError::Canceled => std::io::Error::other("put records was canceled").into(),
+        })?;
     Ok(Json(result))
 }
 
+/// Raw frames from `offset` on, for followers. `offset` is the follower's log end.
+#[get("/fetch/{offset}")]
+async fn fetch(shard_controller: web::Data<ShardController>, offset: web::Path<u64>, query: web::Query<FetchQuery>) -> Result<HttpResponse> {
+    let max_bytes = query.max_bytes.unwrap_or(FETCH_MAX_BYTES);
+    let fetched = shard_controller.fetch(&query.replica_id, query.leader_epoch, offset.into_inner(), max_bytes)?;
+    Ok(HttpResponse::Ok()
+        .header(LEADER_EPOCH_HEADER, fetched.leader_epoch.to_string())
+        .header(HIGH_WATERMARK_HEADER, fetched.high_watermark.to_string())
+        .content_type("application/octet-stream")
+        .body(fetched.frames))
+}
+
+/// The one shard served has no stream, so its metrics go unlabelled.
+#[get("/metrics")]
+async fn metrics(shard_controller: web::Data<ShardController>) -> HttpResponse {
+    let mut exposition = Exposition::default();
+    shard_controller.metrics().expose(&mut exposition, &[]);
+    HttpResponse::Ok().content_type(EXPOSITION_CONTENT_TYPE).body(exposition.render())
+}
+
+/// The shard was recovered before anything was served, so ready means the mount path takes
+/// writes and, for a follower, that it is in sync with its leader.
+#[get("/ready")]
+async fn ready(shard_controller: web::Data<ShardController>) -> Result<HttpResponse> {
+    let shard_controller = shard_controller.into_inner();
+    let readiness = web::block(move || -> std::io::Result<Readiness> {
+        let out_of_sync = match (shard_controller.replica_status().in_sync, shard_controller.leadership().leader) {
+            (false, Some(leader)) => vec![format!("the shard is not in sync with its leader {}", leader)],
+            _ => vec![],
+        };
+        Ok(Readiness::check(&shard_controller.shard_dir.mount_dir, vec![], out_of_sync))
+    })
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => actix_web::Error::from(e),
+            BlockingError::Canceled => std::io::Error::other("the readiness check was canceled").into(),
+        })?;
+    Ok(api::readiness_response(readiness))
+}
+
+#[get("/admin/shards")]
+async fn admin_shards(shard_controller: web::Data<ShardController>) -> Json<AdminShardsResponse> {
+    Json(AdminShardsResponse { shards: vec![shard_controller.summary()] })
+}
+
 #[derive(Deserialize, Serialize)]
 struct GetShardIteratorRequest {
-    iterator_type: String
+    iterator_type: String,
+    /// for `AtTimestamp`
+    timestamp_ms: Option<u64>,
 }
 
 #[post("/get-shard-iterator")]
-async fn get_shard_iterator(shard_controller: web::Data<ShardController>, body: web::Json<GetShardIteratorRequest>) -> impl Responder {
-    let shard_dir = &shard_controller.shard_dir;
-    let res = {
-        match body.iterator_type.as_str() {
-            "Latest" => {
-                let shard_iterator = shard_controller.latest_log_offset.load(Ordering::Relaxed);
-                Response(format!("shard iterator: {}", shard_iterator))
-            },
-            "Oldest" => {
-                let shard_iterator = shard_dir.get_oldest_segment();
-
-                Response(format!("shard iterator: {}", shard_iterator))
-            }
-            _ => Response(format!("shard iterator type not supported"))
-        }
+async fn get_shard_iterator(shard_controller: web::Data<ShardController>, body: web::Json<GetShardIteratorRequest>) -> Result<HttpResponse> {
+    let iterator_type = match (body.iterator_type.as_str(), body.timestamp_ms) {
+        ("Latest", _) => ShardIteratorType::Latest,
+        ("Oldest", _) => ShardIteratorType::Oldest,
+        ("AtTimestamp", Some(timestamp_ms)) => ShardIteratorType::AtTimestamp { timestamp_ms },
+        ("AtTimestamp", None) => return Ok(HttpResponse::BadRequest().body("AtTimestamp needs a timestamp_ms")),
+        _ => return Ok(HttpResponse::Ok().body("shard iterator type not supported")),
+    };
+    let shard_iterator = shard_controller.get_shard_iterator(iterator_type)?;
+    Ok(HttpResponse::Ok().body(format!("shard iterator: {}", shard_iterator)))
+}
+
+/// Same fields as the Kinesis requests. `KMS` is the only encryption type, with keys coming from
+/// the keyfile instead of a KMS.
+#[derive(Deserialize, Serialize)]
+struct StreamEncryptionRequest {
+    encryption_type: String,
+    key_id: String,
+}
+
+#[post("/start-stream-encryption")]
+async fn start_stream_encryption(shard_controller: web::Data<ShardController>, body: web::Json<StreamEncryptionRequest>) -> Result<HttpResponse> {
+    let body = body.into_inner();
+    if body.encryption_type != "KMS" {
+        return Ok(HttpResponse::BadRequest().body(format!("encryption type {} not supported", body.encryption_type)));
+    }
+    shard_controller.start_stream_encryption(body.key_id)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/stop-stream-encryption")]
+async fn stop_stream_encryption(shard_controller: web::Data<ShardController>, body: web::Json<StreamEncryptionRequest>) -> Result<HttpResponse> {
+    let current = shard_controller.encryption_key_id.read().unwrap().clone();
+    if current.as_deref() != Some(body.key_id.as_str()) {
+        return Ok(HttpResponse::BadRequest().body(format!("stream is not encrypted with key {}", body.key_id)));
+    }
+    shard_controller.stop_stream_encryption()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// What each route needs once auth is on. The one shard served belongs to no stream, so only
+/// grants on `*` count.
+fn access(method: &str, path: &str, _body: &[u8]) -> Access {
+    let all_streams = |permission| Access::Stream(ALL_STREAMS.to_string(), permission);
+    match (method, path) {
+        ("GET", "/health") | ("GET", "/ready") | ("GET", "/metrics") => Access::Open,
+        (_, "/put-records") => all_streams(Permission::Write),
+        (_, "/get-shard-iterator") => all_streams(Permission::Read),
+        (_, path) if path.starts_with("/get-records/") => all_streams(Permission::Read),
+        (_, path) if path.starts_with("/fetch/") => Access::Root,
+        _ => all_streams(Permission::Admin),
+    }
+}
+
+#[get("/admin/keys")]
+async fn list_keys(keys: web::Data<LocalKeys>) -> Json<ListKeysResponse> {
+    Json(ListKeysResponse { keys: keys.summaries() })
+}
+
+/// Creates a key and answers with it, secret included, which is never shown again.
+#[post("/admin/keys")]
+async fn create_key(keys: web::Data<LocalKeys>, body: Bytes) -> Result<HttpResponse> {
+    let request: NewKey = match body.is_empty() {
+        true => NewKey::default(),
+        false => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
     };
-    HttpResponse::Ok().body(res.0)
+    let key = request.into_key();
+    keys.change(&KeyChange::Put { key: key.clone() }).map_err(api::error)?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[get("/admin/keys/{access_key_id}")]
+async fn describe_key(keys: web::Data<LocalKeys>, access_key_id: web::Path<String>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(keys.summary(&access_key_id).map_err(api::error)?))
+}
 
+#[delete("/admin/keys/{access_key_id}")]
+async fn delete_key(keys: web::Data<LocalKeys>, access_key_id: web::Path<String>) -> Result<HttpResponse> {
+    keys.change(&KeyChange::Delete { access_key_id: access_key_id.into_inner() }).map_err(api::error)?;
+    Ok(HttpResponse::Ok().finish())
 }
 
-fn setup_shard_controller(opts: &Opts) -> ShardController {
+#[put("/admin/keys/{access_key_id}/grants/{stream}")]
+async fn grant(keys: web::Data<LocalKeys>, path: web::Path<(String, String)>, body: Json<GrantRequest>) -> Result<HttpResponse> {
+    let (access_key_id, stream) = path.into_inner();
+    keys.change(&KeyChange::Grant { access_key_id: access_key_id.clone(), stream, permissions: body.into_inner().permissions })
+        .map_err(api::error)?;
+    Ok(HttpResponse::Ok().json(keys.summary(&access_key_id).map_err(api::error)?))
+}
+
+#[delete("/admin/keys/{access_key_id}/grants/{stream}")]
+async fn revoke(keys: web::Data<LocalKeys>, path: web::Path<(String, String)>) -> Result<HttpResponse> {
+    let (access_key_id, stream) = path.into_inner();
+    keys.change(&KeyChange::Grant { access_key_id: access_key_id.clone(), stream, permissions: Default::default() })
+        .map_err(api::error)?;
+    Ok(HttpResponse::Ok().json(keys.summary(&access_key_id).map_err(api::error)?))
+}
+
+fn setup_shard_controller(config: &NodeConfig) -> std::io::Result<ShardController> {
     let shard_dir = ShardDir {
-        mount_dir: Path::new(&opts.mount_path).to_path_buf(),
+        mount_dir: config.mount_path()?.to_path_buf(),
     };
     shard_dir.assert_mount_path();
-    let latest_segment = shard_dir.get_latest_segment();
 
-    let latest_segment = Arc::new(AtomicUsize::new(latest_segment as usize));
+    let shard_controller = ShardController::new(shard_dir, config.stream_config())?;
+    shard_controller.spawn_flusher();
+    shard_controller.spawn_compactor();
+    shard_controller.spawn_tierer();
+    shard_controller.spawn_retainer();
+    Ok(shard_controller)
+}
+
+/// The node of a cluster `config` asks for, if any. Its shards live under the mount path.
+fn setup_cluster_node(config: &NodeConfig) -> std::io::Result<Option<web::Data<ClusterNode>>> {
+    let cluster = &config.cluster;
+    let (node_id, peers) = match (cluster.node_id, &cluster.peers) {
+        (Some(node_id), Some(peers)) => (node_id, parse_peers(peers)?),
+        (None, None) => return Ok(None),
+        _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--node-id and --peers go together")),
+    };
+    if !peers.contains_key(&node_id) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("node {} is not one of the peers", node_id)));
+    }
+
+    let raft_config = RaftConfig {
+        node_id,
+        peers,
+        election_timeout_ms: cluster.election_timeout_ms.unwrap_or(DEFAULT_ELECTION_TIMEOUT_MS),
+        heartbeat_ms: cluster.heartbeat_ms.unwrap_or(DEFAULT_HEARTBEAT_MS),
+    };
+    let failure_timeout_ms = cluster.failure_timeout_ms.unwrap_or(DEFAULT_FAILURE_TIMEOUT_MS);
+    let mut node = ClusterNode::open(
+        raft_config,
+        config.mount_path()?.to_path_buf(),
+        config.stream_config(),
+        failure_timeout_ms,
+        config.root_credentials()?,
+    )?;
+    node.default_shard_count = cluster.default_shard_count.unwrap_or(DEFAULT_SHARD_COUNT);
+    let node = web::Data::new(node);
+    node.clone().into_inner().start();
+    Ok(Some(node))
+}
 
-    let write_lock = Mutex::new(());
-    ShardController { shard_dir, latest_log_offset: latest_segment.clone(), write_lock }
+/// Mirrors the streams of `mirror_from` into the cluster of this node, through its own API.
+fn setup_mirror(config: &NodeConfig, addr: &str) -> std::io::Result<()> {
+    let source = match &config.cluster.mirror_from {
+        Some(source) => source.clone(),
+        None => return Ok(()),
+    };
+    if config.cluster.node_id.is_none() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "--mirror-from needs a cluster node"));
+    }
+    let mirror = Mirror {
+        source,
+        destination: addr.to_string(),
+        rules: parse_mapping_rules(config.cluster.mirror_streams.as_deref().unwrap_or(""))?,
+        checkpoints: Checkpoints::open(config.mount_path()?.join(MIRROR_CHECKPOINTS))?,
+        source_credentials: config.mirror_credentials()?,
+        credentials: config.root_credentials()?,
+    };
+    mirror.spawn(MIRROR_IDLE_MS);
+    Ok(())
 }
 
+/// Serves until SIGTERM, SIGINT or SIGQUIT stops the server. SIGTERM lets the requests under way
+/// finish first, the others drop them, and either way the shards are shut down cleanly after,
+/// once nothing else can write to them.
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let opts = get_cli_opts();
-    let addr = format!("{}:{}", opts.host, opts.port);
-    let shard_controller = web::Data::new(setup_shard_controller(&opts));
+    let config = node_config(Opts::from_args())?;
+    logging::init(config.log.filter.as_deref(), config.log.format.unwrap_or(LogFormat::Text))?;
+    let listeners = config.listeners()?.to_vec();
+    let root = config.root_credentials()?;
+    if let Some(root) = &root {
+        info!(root_access_key_id = %root.access_key_id, "auth is on, requests must be signed");
+    }
+    let node = setup_cluster_node(&config)?;
+    setup_mirror(&config, &listeners[0])?;
+    if let Some(node) = node {
+        let served = node.clone();
+        let keys: Arc<dyn KeyStore> = node.clone().into_inner();
+        let mut server = HttpServer::new(move || App::new()
+            .wrap(Authenticate::new(root.clone(), keys.clone(), api::access))
+            .wrap_fn(logging::traced)
+            .app_data(served.clone())
+            .configure(api::configure));
+        for listener in listeners.iter() {
+            server = server.bind(listener)?;
+        }
+        server.start().await?;
+        info!("stopped serving, shutting down the shards");
+        return node.shut_down();
+    }
+
+    let shard_controller = web::Data::new(setup_shard_controller(&config)?);
+    let served = shard_controller.clone();
+    if config.replication.leader.is_some() {
+        let credentials = root.clone();
+        let connect = move |leader: &str| Box::new(HttpLeader::new(leader.to_string()).with_credentials(credentials.clone())) as Box<dyn Leader>;
+        spawn_follower(Arc::downgrade(&shard_controller.clone().into_inner()), connect, 10);
+    }
+    let root_access_key_id = root.as_ref().map(|root| root.access_key_id.clone()).unwrap_or_default();
+    let keys = web::Data::new(LocalKeys::open(config.mount_path()?.join(KEYS_FILE), root_access_key_id)?);
 
-    HttpServer::new(move|| App::new()
-        .app_data(shard_controller.clone())
+    let mut server = HttpServer::new(move|| App::new()
+        .wrap(Authenticate::new(root.clone(), keys.clone().into_inner(), access))
+        .wrap_fn(logging::traced)
+        .app_data(served.clone())
+        .app_data(keys.clone())
         .service(get_records)
         .service(put_records)
-        .service(get_shard_iterator))
-        .bind(addr)?
-        .start()
-        .await
-}
\ No newline at end of file
+        .service(fetch)
+        .service(get_shard_iterator)
+        .service(start_stream_encryption)
+        .service(stop_stream_encryption)
+        .service(metrics)
+        .service(api::health)
+        .service(ready)
+        .service(admin_shards)
+        .service(list_keys)
+        .service(create_key)
+        .service(describe_key)
+        .service(delete_key)
+        .service(grant)
+        .service(revoke));
+    for listener in listeners.iter() {
+        server = server.bind(listener)?;
+    }
+    server.start().await?;
+    info!("stopped serving, shutting down the shard");
+    shard_controller.shut_down()
+}
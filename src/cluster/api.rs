@@ -0,0 +1,593 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{delete, get, HttpRequest, HttpResponse, post, put, web};
+use actix_web::client::Client;
+use actix_web::error::{BlockingError, ErrorBadRequest, ErrorConflict, ErrorForbidden, ErrorInternalServerError, ErrorNotFound, InternalError};
+use actix_web::http::header::{CONTENT_TYPE, HOST, LOCATION, RETRY_AFTER};
+use actix_web::Result;
+use actix_web::web::{Bytes, Json};
+use serde_derive::{Deserialize, Serialize};
+use tracing::Span;
+
+use crate::auth::keys::{ALL_STREAMS, GrantRequest, KeyChange, ListKeysResponse, NewKey, Permission};
+use crate::auth::middleware::{Access, SIGNED_BY_HEADER, signed_by};
+use crate::auth::sigv4::{Credentials, Request, unix_now};
+use crate::cluster::node::{ClusterNode, ClusterStatus, DEFAULT_TRANSACTION_TIMEOUT_MS, Route};
+use crate::cluster::raft::{AppendRequest, AppendResponse, VoteRequest, VoteResponse};
+use crate::cluster::metadata::StreamMetadata;
+use crate::config::StreamOverrides;
+use crate::health::Readiness;
+use crate::logging::REQUEST_ID_HEADER;
+use crate::metrics::EXPOSITION_CONTENT_TYPE;
+use crate::shards::replication::{FETCH_MAX_BYTES, FetchQuery, HIGH_WATERMARK_HEADER, LEADER_EPOCH_HEADER};
+use crate::shards::shard_controller::{AdminShardsResponse, PutRecordsRequest, ShardController};
+use crate::shards::shards::ShardIteratorType;
+use crate::shards::transactions::Isolation;
+
+/// set on requests a node forwards, which are never forwarded again
+pub const PROXIED_HEADER: &str = "x-rinites-proxied";
+
+/// the largest response relayed when proxying
+const PROXY_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// what clients are told to wait, in seconds, before retrying a request nobody could serve
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Routes of a node in cluster mode.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(request_vote)
+        .service(append_entries)
+        .service(cluster_status)
+        .service(metrics)
+        .service(health)
+        .service(ready)
+        .service(admin_shards)
+        .service(create_stream)
+        .service(list_streams)
+        .service(describe_stream)
+        .service(delete_stream)
+        .service(put_records)
+        .service(get_records)
+        .service(get_shard_iterator)
+        .service(fetch)
+        .service(replica_status)
+        .service(begin_transaction)
+        .service(describe_transaction)
+        .service(commit_transaction)
+        .service(abort_transaction)
+        .service(split_shard)
+        .service(merge_shards)
+        .service(get_checkpoints)
+        .service(set_checkpoints)
+        .service(list_keys)
+        .service(create_key)
+        .service(describe_key)
+        .service(delete_key)
+        .service(grant)
+        .service(revoke);
+}
+
+/// What each route needs once auth is on, see `Authenticate`. Routes that act on a stream need
+/// a grant on it; the cluster, its keys and whatever is not listed need admin on every stream.
+/// Any key may begin a transaction, but only the key that began it may end it, which the routes
+/// check themselves.
+pub fn access(method: &str, path: &str, body: &[u8]) -> Access {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let stream = |stream: &str, permission| Access::Stream(stream.to_string(), permission);
+    match (method, segments.as_slice()) {
+        ("GET", ["health"]) | ("GET", ["ready"]) | ("GET", ["metrics"]) => Access::Open,
+        (_, ["raft", ..]) | (_, ["streams", _, "shards", _, "fetch", _]) | (_, ["streams", _, "shards", _, "replica"]) => Access::Root,
+        // a body that does not parse gets its 400 from the route, if the key may create any stream
+        ("POST", ["streams"]) => match serde_json::from_slice::<CreateStreamRequest>(body) {
+            Ok(request) => stream(&request.stream_name, Permission::Admin),
+            Err(_) => stream(ALL_STREAMS, Permission::Admin),
+        },
+        ("GET", ["streams"]) | (_, ["transactions", ..]) => Access::Signed,
+        ("DELETE", ["streams", name]) | (_, ["streams", name, "shards", _, "split"]) | (_, ["streams", name, "shards", _, "merge"]) => {
+            stream(name, Permission::Admin)
+        }
+        (_, ["streams", name, "shards", _, "put-records"]) => stream(name, Permission::Write),
+        (_, ["streams", name, ..]) | (_, ["groups", _, "checkpoints", name]) => stream(name, Permission::Read),
+        _ => stream(ALL_STREAMS, Permission::Admin),
+    }
+}
+
+#[post("/raft/request-vote")]
+async fn request_vote(node: web::Data<ClusterNode>, body: Json<VoteRequest>) -> Result<Json<VoteResponse>> {
+    Ok(Json(node.raft.handle_request_vote(&body)?))
+}
+
+#[post("/raft/append-entries")]
+async fn append_entries(node: web::Data<ClusterNode>, body: Json<AppendRequest>) -> Result<Json<AppendResponse>> {
+    Ok(Json(node.raft.handle_append_entries(&body)?))
+}
+
+#[get("/cluster")]
+async fn cluster_status(node: web::Data<ClusterNode>) -> Json<ClusterStatus> {
+    Json(node.status())
+}
+
+/// The metrics of this node only, every node is scraped on its own.
+#[get("/metrics")]
+async fn metrics(node: web::Data<ClusterNode>) -> HttpResponse {
+    HttpResponse::Ok().content_type(EXPOSITION_CONTENT_TYPE).body(node.metrics().render())
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateStreamRequest {
+    pub stream_name: String,
+    /// defaults to the `default_shard_count` of the cluster config
+    pub shard_count: Option<u32>,
+    /// defaults to three replicas, or one per node in smaller clusters
+    pub replication_factor: Option<usize>,
+    /// settings of the stream that differ from those of the nodes
+    #[serde(default, skip_serializing_if = "StreamOverrides::is_empty")]
+    pub config: StreamOverrides,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ListStreamsResponse {
+    pub stream_names: Vec<String>,
+}
+
+/// The process is up and serving, nothing more.
+#[get("/health")]
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+#[get("/ready")]
+async fn ready(node: web::Data<ClusterNode>) -> Result<HttpResponse> {
+    let node = node.into_inner();
+    let readiness = blocking(move || Ok(node.readiness())).await?;
+    Ok(readiness_response(readiness))
+}
+
+/// a 200 when the node is ready, a 503 listing what it is waiting for otherwise
+pub fn readiness_response(readiness: Readiness) -> HttpResponse {
+    match readiness.is_ready() {
+        true => HttpResponse::Ok().json(readiness),
+        false => HttpResponse::ServiceUnavailable().json(readiness),
+    }
+}
+
+/// The shards hosted on this node with their segments; every node only knows its own.
+#[get("/admin/shards")]
+async fn admin_shards(node: web::Data<ClusterNode>) -> Json<AdminShardsResponse> {
+    Json(AdminShardsResponse { shards: node.shard_summaries() })
+}
+
+#[post("/streams")]
+async fn create_stream(node: web::Data<ClusterNode>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: CreateStreamRequest = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    let replication_factor = request.replication_factor.unwrap_or_else(|| node.peers.len().min(3));
+    let shard_count = request.shard_count.unwrap_or(node.default_shard_count);
+    let node = node.into_inner();
+    let stream: StreamMetadata = blocking(move || {
+        node.create_stream(&request.stream_name, shard_count, replication_factor, request.config)
+    }).await?;
+    Ok(HttpResponse::Ok().json(stream))
+}
+
+#[get("/streams")]
+async fn list_streams(node: web::Data<ClusterNode>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    Ok(HttpResponse::Ok().json(ListStreamsResponse { stream_names: node.list_streams() }))
+}
+
+#[get("/streams/{stream}")]
+async fn describe_stream(node: web::Data<ClusterNode>, stream: web::Path<String>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    Ok(HttpResponse::Ok().json(node.describe_stream(&stream).map_err(error)?))
+}
+
+#[delete("/streams/{stream}")]
+async fn delete_stream(node: web::Data<ClusterNode>, stream: web::Path<String>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    let node = node.into_inner();
+    blocking(move || node.delete_stream(&stream)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/streams/{stream}/shards/{shard_id}/put-records")]
+async fn put_records(node: web::Data<ClusterNode>, path: web::Path<(String, u32)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if node.is_shard_closed(&path.0, path.1) {
+        return Ok(gone(&path.0, path.1));
+    }
+    let shard = match node.route_shard(&path.0, path.1)? {
+        Route::Local(shard) => shard,
+        route => return forward(route, node.credentials.as_ref(), &req, &body).await,
+    };
+    let request: PutRecordsRequest = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    let producer = request.producer().map_err(ErrorBadRequest)?;
+    if let Some(transaction_id) = request.transaction_id {
+        node.assert_ongoing(transaction_id).map_err(error)?;
+    }
+    let records = request.into_records()?;
+    // waiting for the replicas blocks, so it must not happen on the worker serving fetches
+    let span = Span::current();
+    match web::block(move || span.in_scope(|| shard.put_records_from(producer, records))).await {
+        Ok(result) => Ok(HttpResponse::Ok().json(result)),
+        // the shard changed leaders since the request was routed here
+        Err(BlockingError::Error(e)) if e.kind() == ErrorKind::PermissionDenied => {
+            match node.route_shard(&path.0, path.1)? {
+                Route::Remote(addr) => Ok(redirect(&addr, &req, &e.to_string())),
+                _ => Ok(retry_later(&e.to_string())),
+            }
+        }
+        // or it was closed since
+        Err(BlockingError::Error(_)) if node.is_shard_closed(&path.0, path.1) => Ok(gone(&path.0, path.1)),
+        Err(e) => Err(blocking_error(e)),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GetRecordsQuery {
+    #[serde(default)]
+    pub isolation: Isolation,
+}
+
+#[get("/streams/{stream}/shards/{shard_id}/get-records/{shard_iterator}")]
+async fn get_records(node: web::Data<ClusterNode>, path: web::Path<(String, u32, u64)>, query: web::Query<GetRecordsQuery>, req: HttpRequest) -> Result<HttpResponse> {
+    match node.route_shard(&path.0, path.1)? {
+        Route::Local(shard) => Ok(HttpResponse::Ok().json(shard.get_records_with(path.2, query.isolation)?)),
+        route => forward(route, node.credentials.as_ref(), &req, &Bytes::new()).await,
+    }
+}
+
+#[post("/streams/{stream}/shards/{shard_id}/get-shard-iterator")]
+async fn get_shard_iterator(node: web::Data<ClusterNode>, path: web::Path<(String, u32)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    let shard = match node.route_shard(&path.0, path.1)? {
+        Route::Local(shard) => shard,
+        route => return forward(route, node.credentials.as_ref(), &req, &body).await,
+    };
+    let iterator_type: ShardIteratorType = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    let shard_iterator = shard.get_shard_iterator(iterator_type)?;
+    Ok(HttpResponse::Ok().body(format!("shard iterator: {}", shard_iterator)))
+}
+
+/// Only the shard leader is fetched from, so this is never forwarded.
+#[get("/streams/{stream}/shards/{shard_id}/fetch/{offset}")]
+async fn fetch(node: web::Data<ClusterNode>, path: web::Path<(String, u32, u64)>, query: web::Query<FetchQuery>) -> Result<HttpResponse> {
+    let shard: Arc<ShardController> = match node.route_shard(&path.0, path.1)? {
+        Route::Local(shard) => shard,
+        _ => return Ok(HttpResponse::ServiceUnavailable().body(format!("node {} does not lead this shard", node.node_id))),
+    };
+    let max_bytes = query.max_bytes.unwrap_or(FETCH_MAX_BYTES);
+    let fetched = shard.fetch(&query.replica_id, query.leader_epoch, path.2, max_bytes)?;
+    Ok(HttpResponse::Ok()
+        .header(LEADER_EPOCH_HEADER, fetched.leader_epoch.to_string())
+        .header(HIGH_WATERMARK_HEADER, fetched.high_watermark.to_string())
+        .content_type("application/octet-stream")
+        .body(fetched.frames))
+}
+
+/// How far the replica hosted here got, asked by the raft leader when the shard leader is down.
+#[get("/streams/{stream}/shards/{shard_id}/replica")]
+async fn replica_status(node: web::Data<ClusterNode>, path: web::Path<(String, u32)>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(node.replica_status(&path.0, path.1).map_err(error)?))
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct BeginTransactionRequest {
+    /// defaults to a minute
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BeginTransactionResponse {
+    pub transaction_id: u64,
+}
+
+#[post("/transactions")]
+async fn begin_transaction(node: web::Data<ClusterNode>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: BeginTransactionRequest = match body.is_empty() {
+        true => BeginTransactionRequest::default(),
+        false => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
+    };
+    let timeout_ms = request.timeout_ms.unwrap_or(DEFAULT_TRANSACTION_TIMEOUT_MS);
+    let node = node.into_inner();
+    let owner = signed_by(&req);
+    let transaction_id = blocking(move || node.begin_transaction(timeout_ms, owner)).await?;
+    Ok(HttpResponse::Ok().json(BeginTransactionResponse { transaction_id }))
+}
+
+#[get("/transactions/{transaction_id}")]
+async fn describe_transaction(node: web::Data<ClusterNode>, transaction_id: web::Path<u64>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    Ok(HttpResponse::Ok().json(node.transaction(*transaction_id).map_err(error)?))
+}
+
+#[post("/transactions/{transaction_id}/commit")]
+async fn commit_transaction(node: web::Data<ClusterNode>, transaction_id: web::Path<u64>, req: HttpRequest) -> Result<HttpResponse> {
+    end_transaction(node, *transaction_id, true, req).await
+}
+
+#[post("/transactions/{transaction_id}/abort")]
+async fn abort_transaction(node: web::Data<ClusterNode>, transaction_id: web::Path<u64>, req: HttpRequest) -> Result<HttpResponse> {
+    end_transaction(node, *transaction_id, false, req).await
+}
+
+async fn end_transaction(node: web::Data<ClusterNode>, transaction_id: u64, commit: bool, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    if let Some(key) = signed_by(&req) {
+        if node.transaction(transaction_id).map_err(error)?.owner.as_ref() != Some(&key) {
+            return Err(ErrorForbidden(format!("key {} did not begin transaction {}", key, transaction_id)));
+        }
+    }
+    let node = node.into_inner();
+    let transaction = blocking(move || node.end_transaction(transaction_id, commit)).await?;
+    Ok(HttpResponse::Ok().json(transaction))
+}
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct SplitShardRequest {
+    /// where the second new shard starts, the middle of the hash keys of the shard by default
+    pub starting_hash_key: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MergeShardsRequest {
+    pub adjacent_shard_id: u32,
+}
+
+#[post("/streams/{stream}/shards/{shard_id}/split")]
+async fn split_shard(node: web::Data<ClusterNode>, path: web::Path<(String, u32)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: SplitShardRequest = match body.is_empty() {
+        true => SplitShardRequest::default(),
+        false => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
+    };
+    let node = node.into_inner();
+    let stream = blocking(move || node.split_shard(&path.0, path.1, request.starting_hash_key)).await?;
+    Ok(HttpResponse::Ok().json(stream))
+}
+
+#[post("/streams/{stream}/shards/{shard_id}/merge")]
+async fn merge_shards(node: web::Data<ClusterNode>, path: web::Path<(String, u32)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: MergeShardsRequest = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    let node = node.into_inner();
+    let stream = blocking(move || node.merge_shards(&path.0, path.1, request.adjacent_shard_id)).await?;
+    Ok(HttpResponse::Ok().json(stream))
+}
+
+/// The shard iterator a consumer group reads each shard of a stream from next.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Checkpoints {
+    pub checkpoints: BTreeMap<u32, u64>,
+}
+
+#[get("/groups/{group}/checkpoints/{stream}")]
+async fn get_checkpoints(node: web::Data<ClusterNode>, path: web::Path<(String, String)>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    let checkpoints = node.checkpoints(&path.0, &path.1).map_err(error)?;
+    Ok(HttpResponse::Ok().json(Checkpoints { checkpoints }))
+}
+
+/// Moves the checkpoints of the shards given, leaving the others be.
+#[put("/groups/{group}/checkpoints/{stream}")]
+async fn set_checkpoints(node: web::Data<ClusterNode>, path: web::Path<(String, String)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: Checkpoints = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    let node = node.into_inner();
+    blocking(move || node.set_checkpoints(&path.0, &path.1, request.checkpoints)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[get("/admin/keys")]
+async fn list_keys(node: web::Data<ClusterNode>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    Ok(HttpResponse::Ok().json(ListKeysResponse { keys: node.key_summaries() }))
+}
+
+/// Creates a key and answers with it, secret included, which is never shown again.
+#[post("/admin/keys")]
+async fn create_key(node: web::Data<ClusterNode>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: NewKey = match body.is_empty() {
+        true => NewKey::default(),
+        false => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
+    };
+    let key = request.into_key();
+    let created = key.clone();
+    let node = node.into_inner();
+    blocking(move || node.change_keys(KeyChange::Put { key })).await?;
+    Ok(HttpResponse::Ok().json(created))
+}
+
+#[get("/admin/keys/{access_key_id}")]
+async fn describe_key(node: web::Data<ClusterNode>, access_key_id: web::Path<String>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    Ok(HttpResponse::Ok().json(node.key_summary(&access_key_id).map_err(error)?))
+}
+
+#[delete("/admin/keys/{access_key_id}")]
+async fn delete_key(node: web::Data<ClusterNode>, access_key_id: web::Path<String>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    let node = node.into_inner();
+    blocking(move || node.change_keys(KeyChange::Delete { access_key_id: access_key_id.into_inner() })).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Sets what the key may do with the streams `stream` matches, a name, `*` or a prefix ending
+/// in `*`, and answers with the key.
+#[put("/admin/keys/{access_key_id}/grants/{stream}")]
+async fn grant(node: web::Data<ClusterNode>, path: web::Path<(String, String)>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &body).await? {
+        return Ok(forwarded);
+    }
+    let request: GrantRequest = serde_json::from_slice(&body).map_err(ErrorBadRequest)?;
+    change_grant(node, path.into_inner(), request.permissions).await
+}
+
+#[delete("/admin/keys/{access_key_id}/grants/{stream}")]
+async fn revoke(node: web::Data<ClusterNode>, path: web::Path<(String, String)>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(forwarded) = forward_metadata(&node, &req, &Bytes::new()).await? {
+        return Ok(forwarded);
+    }
+    change_grant(node, path.into_inner(), BTreeSet::new()).await
+}
+
+async fn change_grant(node: web::Data<ClusterNode>, (access_key_id, stream): (String, String), permissions: BTreeSet<Permission>) -> Result<HttpResponse> {
+    let node = node.into_inner();
+    let key = blocking(move || {
+        node.change_keys(KeyChange::Grant { access_key_id: access_key_id.clone(), stream, permissions })?;
+        node.key_summary(&access_key_id)
+    }).await?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+/// `None` when this node is the raft leader and serves the request itself.
+async fn forward_metadata(node: &ClusterNode, req: &HttpRequest, body: &Bytes) -> Result<Option<HttpResponse>> {
+    match node.route_metadata() {
+        Route::Local(()) => Ok(None),
+        route => Ok(Some(forward(route, node.credentials.as_ref(), req, body).await?)),
+    }
+}
+
+/// Proxies the request to the node that serves it. While leaders change hands, requests that
+/// were already forwarded once but landed on a node that does not serve them either are
+/// redirected, and those nobody can serve right now get a 503; both can be retried. With auth on,
+/// the request was let through here already and goes on signed with the root key, saying which
+/// key it was let through for.
+async fn forward<T>(route: Route<T>, credentials: Option<&Credentials>, req: &HttpRequest, body: &Bytes) -> Result<HttpResponse> {
+    let addr = match route {
+        Route::Remote(addr) if !req.headers().contains_key(PROXIED_HEADER) => addr,
+        Route::Remote(addr) => return Ok(redirect(&addr, req, "forwarded to a node that does not serve it")),
+        Route::Unavailable(reason) => return Ok(retry_later(&reason)),
+        Route::Local(_) => unreachable!("local requests are not forwarded"),
+    };
+
+    let mut forwarded = Client::default()
+        .request(req.method().clone(), format!("http://{}{}", addr, req.uri()))
+        .header(PROXIED_HEADER, "1")
+        .timeout(Duration::from_secs(30));
+    for name in [CONTENT_TYPE.as_str(), REQUEST_ID_HEADER].iter() {
+        if let Some(value) = req.headers().get(*name) {
+            forwarded = forwarded.header(*name, value.clone());
+        }
+    }
+    if let Some(credentials) = credentials {
+        let mut headers = vec![("host".to_string(), addr.clone())];
+        if let Some(key) = signed_by(req) {
+            forwarded = forwarded.header(SIGNED_BY_HEADER, key.as_str());
+            headers.push((SIGNED_BY_HEADER.to_string(), key));
+        }
+        let request = Request { method: req.method().as_str(), path: req.path(), query: req.query_string(), headers, body };
+        forwarded = forwarded.header(HOST, addr.as_str());
+        for (name, value) in request.sign(credentials, unix_now()) {
+            forwarded = forwarded.header(name.as_str(), value);
+        }
+    }
+    let mut response = match forwarded.send_body(body.clone()).await {
+        Ok(response) => response,
+        Err(e) => return Ok(retry_later(&format!("could not reach {}: {}", addr, e))),
+    };
+
+    let body = response.body().limit(PROXY_BODY_LIMIT).await?;
+    let mut relayed = HttpResponse::build(response.status());
+    for name in [CONTENT_TYPE.as_str(), LOCATION.as_str(), RETRY_AFTER.as_str(), HIGH_WATERMARK_HEADER].iter() {
+        if let Some(value) = response.headers().get(*name) {
+            relayed.header(*name, value.clone());
+        }
+    }
+    Ok(relayed.body(body))
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+    where F: FnOnce() -> std::io::Result<T> + Send + 'static,
+          T: Send + 'static
+{
+    let span = Span::current();
+    web::block(move || span.in_scope(f)).await.map_err(blocking_error)
+}
+
+fn blocking_error(e: BlockingError<std::io::Error>) -> actix_web::Error {
+    match e {
+        BlockingError::Error(e) => error(e),
+        BlockingError::Canceled => ErrorInternalServerError("request was canceled"),
+    }
+}
+
+/// the status an error of a node or its shards answers with
+pub fn error(e: std::io::Error) -> actix_web::Error {
+    match e.kind() {
+        ErrorKind::InvalidInput => ErrorBadRequest(e),
+        ErrorKind::NotFound => ErrorNotFound(e),
+        ErrorKind::AlreadyExists => ErrorConflict(e),
+        // puts that found too few replicas in sync, did not reach them in time or lost their
+        // leader on the way
+        ErrorKind::ResourceBusy | ErrorKind::TimedOut | ErrorKind::Interrupted => {
+            let response = retry_later(&e.to_string());
+            InternalError::from_response(e, response).into()
+        }
+        ErrorKind::QuotaExceeded => {
+            let response = slow_down(&e.to_string());
+            InternalError::from_response(e, response).into()
+        }
+        _ => ErrorInternalServerError(e),
+    }
+}
+
+/// a 503 for requests that can be sent again as they are
+fn retry_later(reason: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .header(RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+        .body(reason.to_string())
+}
+
+/// a 429 for puts over the quota of their shard
+pub fn slow_down(reason: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header(RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+        .body(reason.to_string())
+}
+
+/// a 410 for puts in a shard that was split or merged, which go to its children now
+fn gone(stream: &str, shard_id: u32) -> HttpResponse {
+    HttpResponse::Gone().body(format!("shard {} of {} is closed, put in its children", shard_id, stream))
+}
+
+/// a 307 sending the request to the node at `addr`, which serves it now
+fn redirect(addr: &str, req: &HttpRequest, reason: &str) -> HttpResponse {
+    HttpResponse::TemporaryRedirect()
+        .header(LOCATION, format!("http://{}{}", addr, req.uri()))
+        .header(RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+        .body(format!("{}, send it to {}", reason, addr))
+}
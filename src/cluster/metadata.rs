@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::keys::{ApiKeys, KeyChange};
+use crate::config::StreamOverrides;
+
+pub type NodeId = u64;
+
+/// One shard of a stream: the partition key hashes it takes and the nodes holding it. Puts go to
+/// its leader, the other replicas follow it.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ShardMetadata {
+    pub shard_id: u32,
+    pub starting_hash_key: u64,
+    pub ending_hash_key: u64,
+    pub replicas: Vec<NodeId>,
+    pub leader: NodeId,
+    /// bumped every time the shard gets a new leader
+    pub leader_epoch: u64,
+    /// where in the log each leader epoch after the first started
+    #[serde(default)]
+    pub epoch_starts: Vec<EpochStart>,
+    /// the shards this one was split or merged from, whose records come before its own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parent_shard_ids: Vec<u32>,
+    /// split or merged shards take no more puts, their children do
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub closed: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct EpochStart {
+    pub leader_epoch: u64,
+    /// log end offset of the new leader when it took over
+    pub start_offset: u64,
+}
+
+impl ShardMetadata {
+    /// a new shard on the replicas of `placed_like`, led by its leader
+    fn child(placed_like: &ShardMetadata, parent_shard_ids: &[u32], shard_id: u32, starting_hash_key: u64, ending_hash_key: u64) -> ShardMetadata {
+        ShardMetadata {
+            shard_id,
+            starting_hash_key,
+            ending_hash_key,
+            replicas: placed_like.replicas.clone(),
+            leader: placed_like.leader,
+            leader_epoch: 0,
+            epoch_starts: vec![],
+            parent_shard_ids: parent_shard_ids.to_vec(),
+            closed: false,
+        }
+    }
+
+    /// Where a replica whose log was last written in `leader_epoch` has to cut it before it
+    /// follows the current leader. Anything it has past the start of a later epoch may never
+    /// have made it to the leaders that came after, so it goes. `None` when nothing has to.
+    pub fn truncation_offset(&self, leader_epoch: u64) -> Option<u64> {
+        self.epoch_starts
+            .iter()
+            .filter(|e| e.leader_epoch > leader_epoch)
+            .map(|e| e.start_offset)
+            .min()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct StreamMetadata {
+    pub name: String,
+    pub shards: Vec<ShardMetadata>,
+    /// settings of the stream that differ from those of the nodes
+    #[serde(default, skip_serializing_if = "StreamOverrides::is_empty")]
+    pub config: StreamOverrides,
+}
+
+impl StreamMetadata {
+    pub fn shard(&self, shard_id: u32) -> Option<&ShardMetadata> {
+        self.shards.iter().find(|s| s.shard_id == shard_id)
+    }
+
+    /// the shard records with `partition_key` go to
+    pub fn shard_for_key(&self, partition_key: &str) -> Option<&ShardMetadata> {
+        let hash = hash_key(partition_key);
+        self.shards.iter().find(|s| !s.closed && s.starting_hash_key <= hash && hash <= s.ending_hash_key)
+    }
+
+    /// Closes shard `shard_id` and hands its hash keys to two new shards on the same replicas,
+    /// the second one taking them from `starting_hash_key` on. `false`, changing nothing, when
+    /// the shard is closed or does not hold that key and the one before it.
+    pub fn split(&mut self, shard_id: u32, starting_hash_key: u64) -> bool {
+        let next_shard_id = self.next_shard_id();
+        let parent = match self.shards.iter_mut().find(|s| s.shard_id == shard_id && !s.closed) {
+            Some(parent) if parent.starting_hash_key < starting_hash_key && starting_hash_key <= parent.ending_hash_key => parent,
+            _ => return false,
+        };
+        parent.closed = true;
+        let children = [
+            (next_shard_id, parent.starting_hash_key, starting_hash_key - 1),
+            (next_shard_id + 1, starting_hash_key, parent.ending_hash_key),
+        ];
+        let parent = parent.clone();
+        for (shard_id, starting_hash_key, ending_hash_key) in children.iter() {
+            self.shards.push(ShardMetadata::child(&parent, &[parent.shard_id], *shard_id, *starting_hash_key, *ending_hash_key));
+        }
+        true
+    }
+
+    /// Closes two open shards with adjacent hash keys and hands them to a new shard, placed like
+    /// the first of them. `false`, changing nothing, when they cannot be merged.
+    pub fn merge(&mut self, shard_id: u32, adjacent_shard_id: u32) -> bool {
+        let next_shard_id = self.next_shard_id();
+        let (first, second) = match (self.shard(shard_id), self.shard(adjacent_shard_id)) {
+            (Some(a), Some(b)) if a.ending_hash_key.checked_add(1) == Some(b.starting_hash_key) => (a.clone(), b.clone()),
+            (Some(a), Some(b)) if b.ending_hash_key.checked_add(1) == Some(a.starting_hash_key) => (b.clone(), a.clone()),
+            _ => return false,
+        };
+        if first.closed || second.closed {
+            return false;
+        }
+        for shard in self.shards.iter_mut().filter(|s| s.shard_id == shard_id || s.shard_id == adjacent_shard_id) {
+            shard.closed = true;
+        }
+        let parents = [first.shard_id, second.shard_id];
+        self.shards.push(ShardMetadata::child(&first, &parents, next_shard_id, first.starting_hash_key, second.ending_hash_key));
+        true
+    }
+
+    fn next_shard_id(&self) -> u32 {
+        self.shards.iter().map(|s| s.shard_id + 1).max().unwrap_or(0)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum TransactionState {
+    Ongoing,
+    Committed,
+    Aborted,
+}
+
+/// A transaction puts records in any shards of any streams, which readers see all at once after
+/// it commits, or never if it aborts. The decision is made here; shard leaders then write the
+/// matching marker in each shard the transaction put records in.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TransactionMetadata {
+    pub state: TransactionState,
+    pub started_ms: u64,
+    /// an ongoing transaction is aborted once this long has passed since it started
+    pub timeout_ms: u64,
+    /// when it was committed or aborted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_ms: Option<u64>,
+    /// the API key that began it, the only one besides the root key that may end it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+/// A change to the cluster metadata, replicated through the raft log.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Command {
+    CreateStream {
+        name: String,
+        shards: Vec<ShardMetadata>,
+        #[serde(default, skip_serializing_if = "StreamOverrides::is_empty")]
+        config: StreamOverrides,
+    },
+    DeleteStream { name: String },
+    /// hands the shard over to `leader`, a replica whose log ends at `start_offset`, unless it
+    /// already changed leaders since `leader_epoch`
+    SetShardLeader { stream: String, shard_id: u32, leader: NodeId, leader_epoch: u64, start_offset: u64 },
+    BeginTransaction {
+        transaction_id: u64,
+        started_ms: u64,
+        timeout_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
+    },
+    /// commits or aborts the transaction, unless it already ended
+    EndTransaction { transaction_id: u64, commit: bool, decided_ms: u64 },
+    /// forgets the transactions that ended before `before_ms`
+    ForgetTransactions { before_ms: u64 },
+    SplitShard { stream: String, shard_id: u32, starting_hash_key: u64 },
+    MergeShards { stream: String, shard_id: u32, adjacent_shard_id: u32 },
+    /// moves the checkpoints of consumer group `group` in `stream` to these shard iterators
+    SetCheckpoints { group: String, stream: String, checkpoints: BTreeMap<u32, u64> },
+    /// adds, deletes or changes the grants of an API key
+    ChangeKeys { change: KeyChange },
+}
+
+/// Streams, their shards and where those live. Every node holds a copy, built by applying the
+/// committed raft log in order.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ClusterMetadata {
+    pub streams: BTreeMap<String, StreamMetadata>,
+    #[serde(default)]
+    pub transactions: BTreeMap<u64, TransactionMetadata>,
+    /// where each consumer group got to in each stream: the shard iterator to read every shard
+    /// from next
+    #[serde(default)]
+    pub groups: BTreeMap<String, BTreeMap<String, BTreeMap<u32, u64>>>,
+    /// the API keys besides the root key of the nodes, with their secrets
+    #[serde(default)]
+    pub keys: ApiKeys,
+}
+
+impl ClusterMetadata {
+    /// Applies a committed command. Commands are checked before they are proposed, so one that
+    /// no longer makes sense, like creating a stream twice, lost a race and is ignored.
+    pub fn apply(&mut self, command: &Command) {
+        match command {
+            Command::CreateStream { name, shards, config } => {
+                self.streams.entry(name.clone()).or_insert_with(|| StreamMetadata {
+                    name: name.clone(),
+                    shards: shards.clone(),
+                    config: config.clone(),
+                });
+            }
+            Command::DeleteStream { name } => {
+                self.streams.remove(name);
+                for streams in self.groups.values_mut() {
+                    streams.remove(name);
+                }
+                self.groups.retain(|_, streams| !streams.is_empty());
+            }
+            Command::SetShardLeader { stream, shard_id, leader, leader_epoch, start_offset } => {
+                let shard = self.streams
+                    .get_mut(stream)
+                    .and_then(|s| s.shards.iter_mut().find(|s| s.shard_id == *shard_id));
+                if let Some(shard) = shard {
+                    if shard.leader_epoch == *leader_epoch && shard.replicas.contains(leader) && shard.leader != *leader {
+                        shard.leader = *leader;
+                        shard.leader_epoch += 1;
+                        shard.epoch_starts.push(EpochStart { leader_epoch: shard.leader_epoch, start_offset: *start_offset });
+                    }
+                }
+            }
+            Command::BeginTransaction { transaction_id, started_ms, timeout_ms, owner } => {
+                self.transactions.entry(*transaction_id).or_insert(TransactionMetadata {
+                    state: TransactionState::Ongoing,
+                    started_ms: *started_ms,
+                    timeout_ms: *timeout_ms,
+                    decided_ms: None,
+                    owner: owner.clone(),
+                });
+            }
+            Command::EndTransaction { transaction_id, commit, decided_ms } => {
+                if let Some(transaction) = self.transactions.get_mut(transaction_id) {
+                    if transaction.state == TransactionState::Ongoing {
+                        transaction.state = if *commit { TransactionState::Committed } else { TransactionState::Aborted };
+                        transaction.decided_ms = Some(*decided_ms);
+                    }
+                }
+            }
+            Command::ForgetTransactions { before_ms } => {
+                self.transactions.retain(|_, t| t.decided_ms.is_none_or(|decided_ms| decided_ms >= *before_ms));
+            }
+            Command::SplitShard { stream, shard_id, starting_hash_key } => {
+                if let Some(stream) = self.streams.get_mut(stream) {
+                    stream.split(*shard_id, *starting_hash_key);
+                }
+            }
+            Command::MergeShards { stream, shard_id, adjacent_shard_id } => {
+                if let Some(stream) = self.streams.get_mut(stream) {
+                    stream.merge(*shard_id, *adjacent_shard_id);
+                }
+            }
+            Command::SetCheckpoints { group, stream, checkpoints } => {
+                if self.streams.contains_key(stream) {
+                    let stored = self.groups.entry(group.clone()).or_default().entry(stream.clone()).or_default();
+                    stored.extend(checkpoints.iter().map(|(shard_id, shard_iterator)| (*shard_id, *shard_iterator)));
+                }
+            }
+            Command::ChangeKeys { change } => self.keys.apply(change),
+        }
+    }
+
+    /// how many shards `node_id` leads
+    pub fn led_by(&self, node_id: NodeId) -> usize {
+        self.streams
+            .values()
+            .flat_map(|s| s.shards.iter())
+            .filter(|s| s.leader == node_id)
+            .count()
+    }
+
+    /// Splits the hash key space evenly among `shard_count` shards and places
+    /// `replication_factor` replicas of each on `nodes`, round robin, starting after the shards
+    /// that already exist so that leaders spread over the cluster.
+    pub fn place_shards(&self, shard_count: u32, replication_factor: usize, nodes: &[NodeId]) -> Vec<ShardMetadata> {
+        let existing: usize = self.streams.values().map(|s| s.shards.len()).sum();
+        let width = u64::MAX / shard_count as u64;
+        (0..shard_count)
+            .map(|shard_id| {
+                let replicas: Vec<NodeId> = (0..replication_factor.min(nodes.len()))
+                    .map(|r| nodes[(existing + shard_id as usize + r) % nodes.len()])
+                    .collect();
+                ShardMetadata {
+                    shard_id,
+                    starting_hash_key: shard_id as u64 * width,
+                    ending_hash_key: if shard_id + 1 == shard_count { u64::MAX } else { (shard_id as u64 + 1) * width - 1 },
+                    leader: replicas[0],
+                    replicas,
+                    leader_epoch: 0,
+                    epoch_starts: vec![],
+                    parent_shard_ids: vec![],
+                    closed: false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// 64-bit FNV-1a of a partition key, which picks its shard
+pub fn hash_key(partition_key: &str) -> u64 {
+    partition_key
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::metadata::{ClusterMetadata, Command, hash_key, TransactionState};
+    use crate::config::StreamOverrides;
+
+    #[test]
+    fn shards_cover_the_hash_space_and_spread_over_nodes() {
+        let mut metadata = ClusterMetadata::default();
+        let shards = metadata.place_shards(3, 2, &[1, 2, 3]);
+        metadata.apply(&Command::CreateStream { name: "orders".to_string(), shards, config: StreamOverrides::default() });
+        let stream = &metadata.streams["orders"];
+
+        assert_eq!(stream.shards[0].starting_hash_key, 0);
+        assert_eq!(stream.shards[2].ending_hash_key, u64::MAX);
+        for pair in stream.shards.windows(2) {
+            assert_eq!(pair[0].ending_hash_key + 1, pair[1].starting_hash_key);
+        }
+        assert_eq!(stream.shards.iter().map(|s| s.replicas.clone()).collect::<Vec<_>>(), vec![vec![1, 2], vec![2, 3], vec![3, 1]]);
+        assert!(stream.shard_for_key("meucu_tem_oculos").is_some());
+
+        // the next stream starts where this one stopped
+        assert_eq!(metadata.place_shards(1, 2, &[1, 2, 3])[0].replicas, vec![1, 2]);
+    }
+
+    #[test]
+    fn commands_that_lost_a_race_are_ignored() {
+        let mut metadata = ClusterMetadata::default();
+        let shards = metadata.place_shards(1, 2, &[1, 2]);
+        metadata.apply(&Command::CreateStream { name: "orders".to_string(), shards: shards.clone(), config: StreamOverrides::default() });
+        metadata.apply(&Command::CreateStream { name: "orders".to_string(), shards: vec![], config: StreamOverrides::default() });
+        let set_leader = |leader, leader_epoch, start_offset| Command::SetShardLeader {
+            stream: "orders".to_string(),
+            shard_id: 0,
+            leader,
+            leader_epoch,
+            start_offset,
+        };
+        metadata.apply(&set_leader(3, 0, 10));
+        assert_eq!(metadata.streams["orders"].shards, shards);
+
+        metadata.apply(&set_leader(2, 0, 10));
+        metadata.apply(&set_leader(1, 0, 12));
+        metadata.apply(&set_leader(1, 1, 8));
+        let shard = &metadata.streams["orders"].shards[0];
+        assert_eq!(shard.leader, 1);
+        assert_eq!(shard.leader_epoch, 2);
+        // a replica last written in epoch 0 may hold what neither later leader got
+        assert_eq!(shard.truncation_offset(0), Some(8));
+        assert_eq!(shard.truncation_offset(1), Some(8));
+        assert_eq!(shard.truncation_offset(2), None);
+        metadata.apply(&Command::DeleteStream { name: "orders".to_string() });
+        assert!(metadata.streams.is_empty());
+    }
+
+    #[test]
+    fn transactions_end_once_and_are_forgotten_after_they_end() {
+        let mut metadata = ClusterMetadata::default();
+        let begin = |transaction_id| Command::BeginTransaction { transaction_id, started_ms: 100, timeout_ms: 50, owner: None };
+        let end = |transaction_id, commit, decided_ms| Command::EndTransaction { transaction_id, commit, decided_ms };
+        metadata.apply(&begin(1));
+        metadata.apply(&begin(2));
+        metadata.apply(&begin(3));
+        metadata.apply(&end(1, true, 120));
+        metadata.apply(&end(1, false, 130));
+        metadata.apply(&end(2, false, 200));
+        metadata.apply(&end(4, true, 200));
+        assert_eq!(metadata.transactions[&1].state, TransactionState::Committed);
+        assert_eq!(metadata.transactions[&1].decided_ms, Some(120));
+        assert_eq!(metadata.transactions[&2].state, TransactionState::Aborted);
+        assert!(!metadata.transactions.contains_key(&4));
+
+        metadata.apply(&Command::ForgetTransactions { before_ms: 150 });
+        assert_eq!(metadata.transactions.keys().copied().collect::<Vec<u64>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn splits_and_merges_close_their_parents_and_keep_the_hash_space_covered() {
+        let mut metadata = ClusterMetadata::default();
+        let shards = metadata.place_shards(2, 2, &[1, 2, 3]);
+        let split_at = shards[0].ending_hash_key / 2;
+        metadata.apply(&Command::CreateStream { name: "orders".to_string(), shards, config: StreamOverrides::default() });
+        let stream = metadata.streams.get_mut("orders").unwrap();
+
+        assert!(!stream.split(0, 0));
+        assert!(stream.split(0, split_at));
+        assert!(!stream.split(0, split_at));
+        assert!(stream.merge(3, 1));
+        assert!(!stream.merge(2, 1));
+        let open: Vec<(u32, u64, u64, Vec<u32>)> = stream.shards
+            .iter()
+            .filter(|s| !s.closed)
+            .map(|s| (s.shard_id, s.starting_hash_key, s.ending_hash_key, s.parent_shard_ids.clone()))
+            .collect();
+        assert_eq!(open, vec![(2, 0, split_at - 1, vec![0]), (4, split_at, u64::MAX, vec![3, 1])]);
+        assert_eq!(stream.shard(4).unwrap().replicas, stream.shard(0).unwrap().replicas);
+        assert_eq!(stream.shard_for_key("meucu_tem_oculos").unwrap().shard_id, if hash_key("meucu_tem_oculos") < split_at { 2 } else { 4 });
+
+        let checkpoints = |shard_iterator| Command::SetCheckpoints {
+            group: "billing".to_string(),
+            stream: "orders".to_string(),
+            checkpoints: vec![(2, shard_iterator)].into_iter().collect(),
+        };
+        metadata.apply(&checkpoints(7));
+        metadata.apply(&checkpoints(9));
+        assert_eq!(metadata.groups["billing"]["orders"][&2], 9);
+        metadata.apply(&Command::DeleteStream { name: "orders".to_string() });
+        assert!(metadata.groups.is_empty());
+    }
+}
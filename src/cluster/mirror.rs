@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use tracing::warn;
+
+use crate::auth::sigv4::Credentials;
+use crate::cluster::api::{CreateStreamRequest, ListStreamsResponse};
+use crate::cluster::metadata::StreamMetadata;
+use crate::config::StreamOverrides;
+use crate::http;
+use crate::shards::shard_controller::{GetRecordsResponse, PutRecordsEntry, PutRecordsRequest};
+
+/// how long the mirror waits for either cluster to answer a request
+const MIRROR_REQUEST_TIMEOUT_MS: u64 = 15 * 1000;
+
+/// Renames a mirrored stream. `from` may hold one `*`, matching any part of the source stream
+/// name, which takes the place of the `*` in `to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappingRule {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for MappingRule {
+    type Err = String;
+
+    /// `<from>=<to>`, or just `<from>` to keep the names it matches
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s.split_once('=').unwrap_or((s, s));
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() || from.matches('*').count() > 1 || to.matches('*').count() > from.matches('*').count() {
+            return Err(format!("mapping rule '{}' is not <from>=<to> with at most one * on each side", s));
+        }
+        Ok(MappingRule { from: from.to_string(), to: to.to_string() })
+    }
+}
+
+impl MappingRule {
+    /// what `stream` is called on the destination, `None` when the rule does not match it
+    pub fn apply(&self, stream: &str) -> Option<String> {
+        match self.from.split_once('*') {
+            None => Some(self.to.clone()).filter(|_| stream == self.from),
+            Some((prefix, suffix)) => {
+                let matched = stream.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some(self.to.replacen('*', matched, 1))
+            }
+        }
+    }
+}
+
+/// Parses comma separated mapping rules.
+pub fn parse_mapping_rules(rules: &str) -> std::io::Result<Vec<MappingRule>> {
+    rules
+        .split(',')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(|rule| rule.parse().map_err(|e| Error::new(ErrorKind::InvalidInput, e)))
+        .collect()
+}
+
+/// The destination name of `stream`, after the first rule that matches it. Without rules every
+/// stream is mirrored under its own name, with rules the streams none of them match are not.
+pub fn map_stream(rules: &[MappingRule], stream: &str) -> Option<String> {
+    if rules.is_empty() {
+        return Some(stream.to_string());
+    }
+    rules.iter().find_map(|rule| rule.apply(stream))
+}
+
+/// Where mirroring got to: for every `<stream>/<shard id>` of the source, the shard iterator to
+/// read from next. Stored as JSON, replaced as a whole on every change.
+pub struct Checkpoints {
+    pub path: PathBuf,
+    pub positions: BTreeMap<String, u64>,
+}
+
+impl Checkpoints {
+    /// the checkpoints stored at `path`, none when the file does not exist yet
+    pub fn open(path: PathBuf) -> std::io::Result<Checkpoints> {
+        let positions = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Checkpoints { path, positions })
+    }
+
+    pub fn get(&self, stream: &str, shard_id: u32) -> Option<u64> {
+        self.positions.get(&format!("{}/{}", stream, shard_id)).copied()
+    }
+
+    pub fn set(&mut self, stream: &str, shard_id: u32, shard_iterator: u64) -> std::io::Result<()> {
+        self.positions.insert(format!("{}/{}", stream, shard_id), shard_iterator);
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&self.positions)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+/// Copies the streams of a source cluster into a destination cluster, both reached over the
+/// cluster API at `host:port`. Records keep their partition keys and headers, keyless records
+/// stay on the shard with the same id. A shard is checkpointed once what was read from it is put,
+/// so records put right before a crash can be mirrored twice, but none are skipped.
+pub struct Mirror {
+    pub source: String,
+    pub destination: String,
+    pub rules: Vec<MappingRule>,
+    pub checkpoints: Checkpoints,
+    /// a key of the source cluster that may read the mirrored streams, when it has auth on
+    pub source_credentials: Option<Credentials>,
+    /// the root key of the destination cluster, when it has auth on
+    pub credentials: Option<Credentials>,
+}
+
+impl Mirror {
+    /// Mirrors until the process exits, polling again after `idle_ms` once caught up.
+    pub fn spawn(mut self, idle_ms: u64) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            match self.poll() {
+                Ok(0) => thread::sleep(Duration::from_millis(idle_ms)),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(source = %self.source, destination = %self.destination, error = %e, "could not mirror");
+                    thread::sleep(Duration::from_millis(idle_ms));
+                }
+            }
+        })
+    }
+
+    /// Copies one read of every shard of the mirrored streams and returns how many records that
+    /// was. Destination streams are created as needed, with as many shards as their source has
+    /// open.
+    pub fn poll(&mut self) -> std::io::Result<usize> {
+        let listed: ListStreamsResponse = self.request_json(&self.source, "GET", "/streams", b"")?;
+        let mut mirrored = 0;
+        for stream in listed.stream_names {
+            let destination_stream = match map_stream(&self.rules, &stream) {
+                Some(destination_stream) => destination_stream,
+                None => continue,
+            };
+            let source: StreamMetadata = self.request_json(&self.source, "GET", &format!("/streams/{}", stream), b"")?;
+            let open_shards = source.shards.iter().filter(|s| !s.closed).count() as u32;
+            let destination = self.destination_stream(&destination_stream, open_shards.max(1), &source.config)?;
+            for shard in source.shards.iter() {
+                mirrored += self.mirror_shard(&stream, shard.shard_id, &destination)?;
+            }
+        }
+        Ok(mirrored)
+    }
+
+    /// the stream records are mirrored into, created like the source stream when missing
+    fn destination_stream(&self, stream: &str, shard_count: u32, config: &StreamOverrides) -> std::io::Result<StreamMetadata> {
+        let described = self.request(&self.destination, "GET", &format!("/streams/{}", stream), b"")?;
+        if described.is_success() {
+            return serde_json::from_slice(&described.body).map_err(Error::from);
+        }
+        if described.status != 404 {
+            return Err(failed("GET", &format!("/streams/{}", stream), &described));
+        }
+        let create = CreateStreamRequest {
+            stream_name: stream.to_string(),
+            shard_count: Some(shard_count),
+            replication_factor: None,
+            config: config.clone(),
+        };
+        self.request_json(&self.destination, "POST", "/streams", &serde_json::to_vec(&create)?)
+    }
+
+    fn mirror_shard(&mut self, stream: &str, shard_id: u32, destination: &StreamMetadata) -> std::io::Result<usize> {
+        let shard_iterator = match self.checkpoints.get(stream, shard_id) {
+            Some(shard_iterator) => shard_iterator,
+            None => {
+                let path = format!("/streams/{}/shards/{}/get-shard-iterator", stream, shard_id);
+                let response = self.request(&self.source, "POST", &path, br#"{"iterator_type":"Oldest"}"#)?;
+                if !response.is_success() {
+                    return Err(failed("POST", &path, &response));
+                }
+                parse_shard_iterator(&response.body)?
+            }
+        };
+        let path = format!("/streams/{}/shards/{}/get-records/{}", stream, shard_id, shard_iterator);
+        let read: GetRecordsResponse = self.request_json(&self.source, "GET", &path, b"")?;
+
+        let mut shards: BTreeMap<u32, Vec<PutRecordsEntry>> = BTreeMap::new();
+        let headers = read.headers.into_iter().chain(std::iter::repeat_with(BTreeMap::new));
+        let record_count = read.records.len();
+        for ((data, partition_key), headers) in read.records.into_iter().zip(read.partition_keys).zip(headers) {
+            let shard = match &partition_key {
+                Some(partition_key) => destination.shard_for_key(partition_key),
+                None => destination.shard(shard_id).filter(|s| !s.closed).or_else(|| destination.shards.iter().find(|s| !s.closed)),
+            };
+            let shard = shard.ok_or_else(|| Error::other(format!("stream {} has no shards", destination.name)))?;
+            shards.entry(shard.shard_id).or_default().push(PutRecordsEntry::Keyed { data, partition_key, headers });
+        }
+        for (destination_shard, records) in shards {
+            let put = PutRecordsRequest { record: None, records, producer_id: None, producer_sequence: None, transaction_id: None };
+            let path = format!("/streams/{}/shards/{}/put-records", destination.name, destination_shard);
+            let response = self.request(&self.destination, "POST", &path, &serde_json::to_vec(&put)?)?;
+            if !response.is_success() {
+                return Err(failed("POST", &path, &response));
+            }
+        }
+
+        if read.next_shard_iterator != shard_iterator || self.checkpoints.get(stream, shard_id).is_none() {
+            self.checkpoints.set(stream, shard_id, read.next_shard_iterator)?;
+        }
+        Ok(record_count)
+    }
+
+    fn request(&self, addr: &str, method: &str, path: &str, body: &[u8]) -> std::io::Result<http::HttpResponse> {
+        let credentials = if addr == self.source { &self.source_credentials } else { &self.credentials };
+        http::signed_request(addr, method, path, body, Duration::from_millis(MIRROR_REQUEST_TIMEOUT_MS), credentials.as_ref())
+    }
+
+    fn request_json<T: DeserializeOwned>(&self, addr: &str, method: &str, path: &str, body: &[u8]) -> std::io::Result<T> {
+        let response = self.request(addr, method, path, body)?;
+        if !response.is_success() {
+            return Err(failed(method, path, &response));
+        }
+        serde_json::from_slice(&response.body).map_err(Error::from)
+    }
+}
+
+/// the number in a `shard iterator: <n>` answer
+pub fn parse_shard_iterator(body: &[u8]) -> std::io::Result<u64> {
+    String::from_utf8_lossy(body)
+        .trim()
+        .strip_prefix("shard iterator: ")
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a shard iterator"))
+}
+
+fn failed(method: &str, path: &str, response: &http::HttpResponse) -> Error {
+    Error::other(format!("{} {} answered {}: {}", method, path, response.status, String::from_utf8_lossy(&response.body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::mirror::{map_stream, MappingRule, parse_mapping_rules};
+
+    #[test]
+    fn mapping_rules_rename_the_streams_they_match_and_drop_the_others() {
+        let rules = parse_mapping_rules("orders-*=dr-orders-*, payments=staging-payments,audit,*-eu=*").unwrap();
+
+        assert_eq!(map_stream(&rules, "orders-2020"), Some("dr-orders-2020".to_string()));
+        assert_eq!(map_stream(&rules, "orders-"), Some("dr-orders-".to_string()));
+        assert_eq!(map_stream(&rules, "payments"), Some("staging-payments".to_string()));
+        assert_eq!(map_stream(&rules, "audit"), Some("audit".to_string()));
+        assert_eq!(map_stream(&rules, "clicks-eu"), Some("clicks".to_string()));
+        assert_eq!(map_stream(&rules, "payments-eu"), Some("payments".to_string()));
+        assert_eq!(map_stream(&rules, "clicks"), None);
+        assert_eq!(map_stream(&[], "clicks"), Some("clicks".to_string()));
+
+        assert!("a*b*=c".parse::<MappingRule>().is_err());
+        assert!("orders=dr-*".parse::<MappingRule>().is_err());
+        assert!("=orders".parse::<MappingRule>().is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod api;
+pub mod metadata;
+pub mod mirror;
+pub mod node;
+pub mod raft;
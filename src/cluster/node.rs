@@ -0,0 +1,700 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::auth::keys::{ApiKey, KeyChange, KeySummary};
+use crate::auth::middleware::KeyStore;
+use crate::auth::sigv4::Credentials;
+use crate::cluster::metadata::{ClusterMetadata, Command, NodeId, ShardMetadata, StreamMetadata, TransactionMetadata, TransactionState};
+use crate::cluster::raft::{HttpTransport, RaftConfig, RaftNode, RaftStatus};
+use crate::config::{DEFAULT_SHARD_COUNT, StreamOverrides};
+use crate::health::Readiness;
+use crate::http;
+use crate::metrics::Exposition;
+use crate::shards::replication::{HttpLeader, Leader, ReplicaStatus, spawn_follower};
+use crate::shards::shard_controller::{ShardController, ShardSummary, StreamConfig};
+use crate::shards::shards::{now_ms, ShardDir};
+use crate::shards::transactions::Marker;
+
+/// how long metadata changes wait to be committed
+const PROPOSE_TIMEOUT_MS: u64 = 10 * 1000;
+
+/// how often the shards hosted here are matched against the metadata
+const RECONCILE_INTERVAL_MS: u64 = 100;
+
+/// how long the metadata leader goes without hearing from a node before moving the leadership of
+/// its shards to other replicas
+pub const DEFAULT_FAILURE_TIMEOUT_MS: u64 = 3 * 1000;
+
+/// how long asking a replica how far it got may take when picking a new leader
+const REPLICA_STATUS_TIMEOUT_MS: u64 = 1000;
+
+/// how long a transaction may stay open unless its client asks for something else
+pub const DEFAULT_TRANSACTION_TIMEOUT_MS: u64 = 60 * 1000;
+
+pub const MAX_TRANSACTION_TIMEOUT_MS: u64 = 15 * 60 * 1000;
+
+/// how long the outcome of a transaction is remembered after it ended; shard leaders have this
+/// long to write its markers
+const TRANSACTION_RETENTION_MS: u64 = 60 * 60 * 1000;
+
+/// Where a request has to be served.
+pub enum Route<T> {
+    Local(T),
+    /// `host:port` of the node that serves it
+    Remote(String),
+    /// nobody can serve it right now, the reason says why; retrying later can work
+    Unavailable(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ClusterStatus {
+    pub raft: RaftStatus,
+    pub nodes: BTreeMap<NodeId, String>,
+    /// `<stream>/<shard id>` of the shards hosted here
+    pub shards: Vec<String>,
+}
+
+/// A node of a cluster. Stream metadata and shard placement live in the raft group of all nodes;
+/// every node opens the shards placed on it, leading or following them as the metadata says, and
+/// sends requests for other shards to their leaders. The raft leader also watches for nodes that
+/// went down and gives the shards they led new leaders.
+pub struct ClusterNode {
+    pub node_id: NodeId,
+    pub peers: BTreeMap<NodeId, String>,
+    pub raft: Arc<RaftNode>,
+    pub data_dir: PathBuf,
+    /// settings of the shards opened here, before the overrides of their stream; replication is
+    /// filled in per shard
+    pub stream_config: StreamConfig,
+    pub failure_timeout_ms: u64,
+    /// shards of streams created without a shard count
+    pub default_shard_count: u32,
+    /// the root key, which calls to the other nodes are signed with when auth is on
+    pub credentials: Option<Credentials>,
+    shards: RwLock<HashMap<(String, u32), Arc<ShardController>>>,
+    /// held for each round of the reconcile thread, so a shutdown waits for the round under way
+    reconciling: Mutex<()>,
+    shut_down: AtomicBool,
+}
+
+impl ClusterNode {
+    pub fn open(
+        raft_config: RaftConfig,
+        data_dir: PathBuf,
+        stream_config: StreamConfig,
+        failure_timeout_ms: u64,
+        credentials: Option<Credentials>,
+    ) -> std::io::Result<ClusterNode> {
+        let transport = HttpTransport {
+            peers: raft_config.peers.clone(),
+            timeout: Duration::from_millis(raft_config.heartbeat_ms.max(100) * 5),
+            credentials: credentials.clone(),
+        };
+        let raft = RaftNode::open(raft_config.clone(), &data_dir.join("raft"), Arc::new(transport))?;
+        Ok(ClusterNode {
+            node_id: raft_config.node_id,
+            peers: raft_config.peers,
+            raft,
+            data_dir,
+            stream_config,
+            failure_timeout_ms,
+            default_shard_count: DEFAULT_SHARD_COUNT,
+            credentials,
+            shards: RwLock::new(HashMap::new()),
+            reconciling: Mutex::new(()),
+            shut_down: AtomicBool::new(false),
+        })
+    }
+
+    /// Starts raft and the thread opening, closing and failing over shards as the metadata
+    /// changes, until the node is shut down.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        self.raft.start();
+        let node = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(node) = node.upgrade() {
+                {
+                    let _round = node.reconciling.lock().unwrap();
+                    if node.shut_down.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let Err(e) = node.reconcile() {
+                        warn!(error = %e, "could not open the shards placed here");
+                    }
+                    if let Err(e) = node.fail_over() {
+                        warn!(error = %e, "could not fail over the shards of dead nodes");
+                    }
+                    if let Err(e) = node.expire_transactions() {
+                        warn!(error = %e, "could not expire transactions");
+                    }
+                    node.end_transactions();
+                }
+                drop(node);
+                thread::sleep(Duration::from_millis(RECONCILE_INTERVAL_MS));
+            }
+        })
+    }
+
+    /// Stops opening, failing over and ending anything once the round under way is done, then
+    /// shuts down every shard hosted here, see `ShardController::shut_down`. Raft runs on until
+    /// the process exits, and the other nodes fail the shards over once it stops answering.
+    pub fn shut_down(&self) -> std::io::Result<()> {
+        let _round = self.reconciling.lock().unwrap();
+        self.shut_down.store(true, Ordering::SeqCst);
+        let mut result = Ok(());
+        for ((stream, shard_id), shard_controller) in self.shards.read().unwrap().iter() {
+            if let Err(e) = shard_controller.shut_down() {
+                warn!(%stream, shard_id, error = %e, "could not shut the shard down cleanly");
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let mut shards: Vec<String> = self.shards
+            .read()
+            .unwrap()
+            .keys()
+            .map(|(stream, shard_id)| format!("{}/{}", stream, shard_id))
+            .collect();
+        shards.sort();
+        ClusterStatus { raft: self.raft.status(), nodes: self.peers.clone(), shards }
+    }
+
+    /// Metadata changes are made by the raft leader.
+    pub fn route_metadata(&self) -> Route<()> {
+        match self.raft.leader() {
+            Some(leader) if leader == self.node_id => Route::Local(()),
+            Some(leader) => Route::Remote(self.peers[&leader].clone()),
+            None => Route::Unavailable("the cluster has no leader yet".to_string()),
+        }
+    }
+
+    /// Shard requests are served by the shard leader. A stream this node does not know about
+    /// yet may just have been created, so its requests go to the raft leader, which knows.
+    pub fn route_shard(&self, stream: &str, shard_id: u32) -> std::io::Result<Route<Arc<ShardController>>> {
+        let metadata = self.raft.metadata();
+        let stream_metadata = match metadata.streams.get(stream) {
+            Some(stream_metadata) => stream_metadata,
+            None => return match self.route_metadata() {
+                Route::Remote(addr) => Ok(Route::Remote(addr)),
+                _ => Err(Error::new(ErrorKind::NotFound, format!("stream {} not found", stream))),
+            },
+        };
+        let shard = stream_metadata
+            .shard(shard_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("stream {} has no shard {}", stream, shard_id)))?;
+
+        if shard.leader != self.node_id {
+            return Ok(Route::Remote(self.peers[&shard.leader].clone()));
+        }
+        Ok(match self.shards.read().unwrap().get(&(stream.to_string(), shard_id)) {
+            Some(shard_controller) => Route::Local(shard_controller.clone()),
+            None => Route::Unavailable(format!("shard {} of {} is being opened", shard_id, stream)),
+        })
+    }
+
+    /// Places `shard_count` shards with `replication_factor` replicas each on the cluster, to be
+    /// opened with the settings of `config` in place of the nodes' own. Only on the raft leader.
+    pub fn create_stream(&self, name: &str, shard_count: u32, replication_factor: usize, config: StreamOverrides) -> std::io::Result<StreamMetadata> {
+        let invalid = |reason: String| Err(Error::new(ErrorKind::InvalidInput, reason));
+        check_name("stream", name)?;
+        config.check()?;
+        if shard_count == 0 {
+            return invalid("a stream needs at least one shard".to_string());
+        }
+        if replication_factor == 0 || replication_factor > self.peers.len() {
+            return invalid(format!("replication factor must be between 1 and {}", self.peers.len()));
+        }
+
+        let metadata = self.raft.metadata();
+        if metadata.streams.contains_key(name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("stream {} already exists", name)));
+        }
+        let nodes: Vec<NodeId> = self.peers.keys().copied().collect();
+        let shards = metadata.place_shards(shard_count, replication_factor, &nodes);
+        self.propose(Command::CreateStream { name: name.to_string(), shards, config })?;
+        self.describe_stream(name)
+    }
+
+    pub fn delete_stream(&self, name: &str) -> std::io::Result<()> {
+        self.describe_stream(name)?;
+        self.propose(Command::DeleteStream { name: name.to_string() })
+    }
+
+    pub fn describe_stream(&self, name: &str) -> std::io::Result<StreamMetadata> {
+        self.raft
+            .metadata()
+            .streams
+            .remove(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("stream {} not found", name)))
+    }
+
+    /// Splits an open shard in two, at `starting_hash_key` or else in the middle of its hash
+    /// keys. Only on the raft leader.
+    pub fn split_shard(&self, stream: &str, shard_id: u32, starting_hash_key: Option<u64>) -> std::io::Result<StreamMetadata> {
+        let mut stream_metadata = self.describe_stream(stream)?;
+        let shard = stream_metadata
+            .shard(shard_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("stream {} has no shard {}", stream, shard_id)))?;
+        let starting_hash_key = starting_hash_key
+            .unwrap_or_else(|| shard.starting_hash_key + (shard.ending_hash_key - shard.starting_hash_key) / 2 + 1);
+        if !stream_metadata.split(shard_id, starting_hash_key) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("shard {} of {} is closed or cannot be split at {}", shard_id, stream, starting_hash_key)));
+        }
+        self.propose(Command::SplitShard { stream: stream.to_string(), shard_id, starting_hash_key })?;
+        self.describe_stream(stream)
+    }
+
+    /// Merges two open shards with adjacent hash keys. Only on the raft leader.
+    pub fn merge_shards(&self, stream: &str, shard_id: u32, adjacent_shard_id: u32) -> std::io::Result<StreamMetadata> {
+        if !self.describe_stream(stream)?.merge(shard_id, adjacent_shard_id) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("shards {} and {} of {} are not open and adjacent", shard_id, adjacent_shard_id, stream)));
+        }
+        self.propose(Command::MergeShards { stream: stream.to_string(), shard_id, adjacent_shard_id })?;
+        self.describe_stream(stream)
+    }
+
+    /// whether the metadata has the shard closed
+    pub fn is_shard_closed(&self, stream: &str, shard_id: u32) -> bool {
+        self.raft
+            .metadata()
+            .streams
+            .get(stream)
+            .and_then(|s| s.shard(shard_id).map(|shard| shard.closed))
+            .unwrap_or(false)
+    }
+
+    /// where consumer group `group` got to in `stream`, by shard id
+    pub fn checkpoints(&self, group: &str, stream: &str) -> std::io::Result<BTreeMap<u32, u64>> {
+        self.describe_stream(stream)?;
+        let mut metadata = self.raft.metadata();
+        Ok(metadata.groups.get_mut(group).and_then(|streams| streams.remove(stream)).unwrap_or_default())
+    }
+
+    /// Only on the raft leader.
+    pub fn set_checkpoints(&self, group: &str, stream: &str, checkpoints: BTreeMap<u32, u64>) -> std::io::Result<()> {
+        check_name("group", group)?;
+        self.describe_stream(stream)?;
+        self.propose(Command::SetCheckpoints { group: group.to_string(), stream: stream.to_string(), checkpoints })
+    }
+
+    /// Checks `change` against the keys and the root key, then makes it. Only on the raft leader.
+    pub fn change_keys(&self, change: KeyChange) -> std::io::Result<()> {
+        let root_access_key_id = self.credentials.as_ref().map_or("", |root| root.access_key_id.as_str());
+        self.raft.read_metadata(|metadata| metadata.keys.check(&change, root_access_key_id))?;
+        self.propose(Command::ChangeKeys { change })
+    }
+
+    pub fn key_summaries(&self) -> Vec<KeySummary> {
+        self.raft.read_metadata(|metadata| metadata.keys.summaries())
+    }
+
+    pub fn key_summary(&self, access_key_id: &str) -> std::io::Result<KeySummary> {
+        self.raft
+            .read_metadata(|metadata| metadata.keys.get(access_key_id).map(ApiKey::summary))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no key {}", access_key_id)))
+    }
+
+    pub fn list_streams(&self) -> Vec<String> {
+        self.raft.metadata().streams.into_keys().collect()
+    }
+
+    /// how far the replica of a shard hosted here got
+    pub fn replica_status(&self, stream: &str, shard_id: u32) -> std::io::Result<ReplicaStatus> {
+        self.shards
+            .read()
+            .unwrap()
+            .get(&(stream.to_string(), shard_id))
+            .map(|shard_controller| shard_controller.replica_status())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("node {} has no replica of shard {} of {}", self.node_id, shard_id, stream)))
+    }
+
+    /// Starts a transaction that is aborted unless committed within `timeout_ms`, for the API key
+    /// `owner` when it is not begun with the root key. Only on the raft leader.
+    pub fn begin_transaction(&self, timeout_ms: u64, owner: Option<String>) -> std::io::Result<u64> {
+        if timeout_ms == 0 || timeout_ms > MAX_TRANSACTION_TIMEOUT_MS {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("transaction timeout must be between 1 and {} ms", MAX_TRANSACTION_TIMEOUT_MS)));
+        }
+        // ids stay below 2^53 so that they survive JSON parsers that only have doubles
+        let transaction_id = rand::thread_rng().gen_range(1, 1 << 53);
+        self.propose(Command::BeginTransaction { transaction_id, started_ms: now_ms(), timeout_ms, owner })?;
+        Ok(transaction_id)
+    }
+
+    /// Commits or aborts a transaction. Asking again for what was already decided is fine,
+    /// asking to commit one that was aborted, after timing out say, is not. Only on the raft
+    /// leader.
+    pub fn end_transaction(&self, transaction_id: u64, commit: bool) -> std::io::Result<TransactionMetadata> {
+        if self.transaction(transaction_id)?.state == TransactionState::Ongoing {
+            self.propose(Command::EndTransaction { transaction_id, commit, decided_ms: now_ms() })?;
+        }
+        let transaction = self.transaction(transaction_id)?;
+        let wanted = if commit { TransactionState::Committed } else { TransactionState::Aborted };
+        if transaction.state != wanted {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("transaction {} was {:?}", transaction_id, transaction.state)));
+        }
+        Ok(transaction)
+    }
+
+    pub fn transaction(&self, transaction_id: u64) -> std::io::Result<TransactionMetadata> {
+        self.raft
+            .metadata()
+            .transactions
+            .remove(&transaction_id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("transaction {} not found", transaction_id)))
+    }
+
+    /// Checks that records can be put in `transaction_id`. One this node has not heard of may
+    /// just have begun, so that is worth retrying.
+    pub fn assert_ongoing(&self, transaction_id: u64) -> std::io::Result<()> {
+        match self.transaction(transaction_id) {
+            Ok(transaction) if transaction.state == TransactionState::Ongoing => Ok(()),
+            Ok(transaction) => Err(Error::new(ErrorKind::InvalidInput, format!("transaction {} was {:?}", transaction_id, transaction.state))),
+            Err(_) => Err(Error::new(ErrorKind::ResourceBusy, format!("node {} does not know transaction {} yet", self.node_id, transaction_id))),
+        }
+    }
+
+    fn propose(&self, command: Command) -> std::io::Result<()> {
+        self.raft.propose(command, Duration::from_millis(PROPOSE_TIMEOUT_MS))
+    }
+
+    /// Opens the shards the metadata places here, leading or following them as it says, and
+    /// closes, deleting them, those it no longer does.
+    fn reconcile(&self) -> std::io::Result<()> {
+        let metadata = self.raft.metadata();
+        let mut placed = HashSet::new();
+        for stream in metadata.streams.values() {
+            for shard in stream.shards.iter().filter(|s| s.replicas.contains(&self.node_id)) {
+                let key = (stream.name.clone(), shard.shard_id);
+                placed.insert(key.clone());
+                let opened = self.shards.read().unwrap().get(&key).cloned();
+                let shard_controller = match opened {
+                    Some(shard_controller) => {
+                        self.assign(&shard_controller, &stream.name, shard)?;
+                        shard_controller
+                    }
+                    None => {
+                        let shard_controller = self.open_shard(stream, shard)?;
+                        self.assign(&shard_controller, &stream.name, shard)?;
+                        self.shards.write().unwrap().insert(key, shard_controller.clone());
+                        shard_controller
+                    }
+                };
+                if shard.closed && !shard_controller.is_closed() {
+                    shard_controller.close();
+                    info!(stream = %stream.name, shard_id = shard.shard_id, "closed shard to puts");
+                }
+            }
+        }
+
+        let removed: Vec<(String, u32)> = self.shards
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| !placed.contains(*key))
+            .cloned()
+            .collect();
+        for (stream, shard_id) in removed {
+            self.shards.write().unwrap().remove(&(stream.clone(), shard_id));
+            fs::remove_dir_all(self.shard_dir(&stream, shard_id).mount_dir)?;
+            // the stream dir goes with its last shard
+            let _ = fs::remove_dir(self.data_dir.join("streams").join(&stream));
+            info!(%stream, shard_id, "closed shard");
+        }
+        Ok(())
+    }
+
+    fn open_shard(&self, stream_metadata: &StreamMetadata, shard: &ShardMetadata) -> std::io::Result<Arc<ShardController>> {
+        let stream = stream_metadata.name.as_str();
+        let shard_dir = self.shard_dir(stream, shard.shard_id);
+        fs::create_dir_all(&shard_dir.mount_dir)?;
+        shard_dir.assert_mount_path();
+
+        // who leads is up to `assign`
+        let mut config = stream_metadata.config.apply(&self.stream_config);
+        config.replication.leader = None;
+        config.replication.replica_id = self.node_id.to_string();
+        config.replication.min_insync_replicas = config.replication.min_insync_replicas.min(shard.replicas.len());
+        if let Some(tiering) = config.tiering.as_mut() {
+            tiering.remote_dir = tiering.remote_dir.join(stream).join(shard.shard_id.to_string());
+        }
+
+        let shard_controller = Arc::new(ShardController::new(shard_dir, config)?);
+        shard_controller.spawn_flusher();
+        shard_controller.spawn_compactor();
+        shard_controller.spawn_tierer();
+        shard_controller.spawn_retainer();
+        let path = shard_path(stream, shard.shard_id);
+        let credentials = self.credentials.clone();
+        spawn_follower(
+            Arc::downgrade(&shard_controller),
+            move |leader: &str| {
+                let leader = HttpLeader::mounted_at(leader.to_string(), path.clone()).with_credentials(credentials.clone());
+                Box::new(leader) as Box<dyn Leader>
+            },
+            10,
+        );
+        info!(stream, shard_id = shard.shard_id, "opened shard");
+        Ok(shard_controller)
+    }
+
+    /// Has the replica hosted here lead or follow `shard` as the metadata says, unless it
+    /// already does. One that falls behind a change of leaders drops what it has past the start
+    /// of the epochs it missed.
+    fn assign(&self, shard_controller: &ShardController, stream: &str, shard: &ShardMetadata) -> std::io::Result<()> {
+        let leader = Some(shard.leader)
+            .filter(|leader| *leader != self.node_id)
+            .map(|leader| self.peers[&leader].clone());
+        let leadership = shard_controller.leadership();
+        if leadership.leader_epoch == shard.leader_epoch && leadership.leader == leader {
+            return Ok(());
+        }
+        let truncate_to = shard.truncation_offset(leadership.leader_epoch);
+        shard_controller.set_leader(leader, shard.leader_epoch, truncate_to)?;
+        info!(stream, shard_id = shard.shard_id, leader = shard.leader, leader_epoch = shard.leader_epoch, "shard leader changed");
+        Ok(())
+    }
+
+    /// On the raft leader, moves the leadership of the shards led by nodes it has not heard from
+    /// for `failure_timeout_ms` to the live in-sync replica that got furthest. A shard with no
+    /// such replica stays without a leader until one comes back.
+    fn fail_over(&self) -> std::io::Result<()> {
+        let dead: HashSet<NodeId> = self.raft
+            .unreachable_peers(Duration::from_millis(self.failure_timeout_ms))
+            .into_iter()
+            .collect();
+        if dead.is_empty() {
+            return Ok(());
+        }
+
+        let metadata = self.raft.metadata();
+        for stream in metadata.streams.values() {
+            for shard in stream.shards.iter().filter(|s| dead.contains(&s.leader)) {
+                let candidate = shard.replicas
+                    .iter()
+                    .filter(|replica| !dead.contains(replica))
+                    .filter_map(|replica| Some((*replica, self.query_replica(&stream.name, shard.shard_id, *replica).ok()?)))
+                    .filter(|(_, status)| status.in_sync && status.leader_epoch == shard.leader_epoch)
+                    .max_by_key(|(replica, status)| (status.log_end_offset, Reverse(*replica)));
+                if let Some((leader, status)) = candidate {
+                    warn!(
+                        stream = %stream.name, shard_id = shard.shard_id, dead_leader = shard.leader, leader, start_offset = status.log_end_offset,
+                        "shard leader is down, failing over",
+                    );
+                    self.propose(Command::SetShardLeader {
+                        stream: stream.name.clone(),
+                        shard_id: shard.shard_id,
+                        leader,
+                        leader_epoch: shard.leader_epoch,
+                        start_offset: status.log_end_offset,
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// On the raft leader, aborts the transactions that outlived their timeout and forgets those
+    /// that ended long enough ago.
+    fn expire_transactions(&self) -> std::io::Result<()> {
+        if !self.raft.is_leader() {
+            return Ok(());
+        }
+        let now = now_ms();
+        let metadata = self.raft.metadata();
+        for (transaction_id, transaction) in metadata.transactions.iter() {
+            if transaction.state == TransactionState::Ongoing && transaction.started_ms + transaction.timeout_ms <= now {
+                info!(transaction_id, timeout_ms = transaction.timeout_ms, "transaction timed out, aborting it");
+                self.propose(Command::EndTransaction { transaction_id: *transaction_id, commit: false, decided_ms: now })?;
+            }
+        }
+        let before_ms = now.saturating_sub(TRANSACTION_RETENTION_MS);
+        if metadata.transactions.values().any(|t| t.decided_ms.is_some_and(|decided_ms| decided_ms < before_ms)) {
+            self.propose(Command::ForgetTransactions { before_ms })?;
+        }
+        Ok(())
+    }
+
+    /// Writes the markers of the transactions that ended in the shards led here. Transactions
+    /// that are not in the metadata any more ended so long ago that they must have been aborted
+    /// by their timeout before anyone could commit them.
+    fn end_transactions(&self) {
+        let metadata = self.raft.metadata();
+        let shards: Vec<((String, u32), Arc<ShardController>)> = self.shards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, shard_controller)| (key.clone(), shard_controller.clone()))
+            .collect();
+        for ((stream, shard_id), shard_controller) in shards {
+            if !self.leads(&metadata, &stream, shard_id) {
+                continue;
+            }
+            for transaction_id in shard_controller.open_transactions() {
+                let marker = match metadata.transactions.get(&transaction_id).map(|t| t.state) {
+                    Some(TransactionState::Ongoing) => continue,
+                    Some(TransactionState::Committed) => Marker::Commit,
+                    Some(TransactionState::Aborted) | None => Marker::Abort,
+                };
+                if let Err(e) = shard_controller.end_transaction(transaction_id, marker) {
+                    warn!(transaction_id, %stream, shard_id, error = %e, "could not end transaction");
+                }
+            }
+        }
+    }
+
+    /// Whether this node is ready to serve: every shard the metadata places here is open, the
+    /// mount path takes writes, the metadata is applied as far as it is committed and the shards
+    /// followed here are in sync with their leaders. Probes the disk, so it blocks.
+    pub fn readiness(&self) -> Readiness {
+        let raft = self.raft.status();
+        let mut out_of_sync = Vec::new();
+        if raft.leader.is_none() {
+            out_of_sync.push("the cluster has no metadata leader".to_string());
+        } else if raft.last_applied < raft.commit_index {
+            out_of_sync.push(format!("the metadata is applied up to {} of {}", raft.last_applied, raft.commit_index));
+        }
+
+        let metadata = self.raft.metadata();
+        let shards = self.shards.read().unwrap();
+        let mut unrecovered = Vec::new();
+        for stream in metadata.streams.values() {
+            for shard in stream.shards.iter().filter(|s| s.replicas.contains(&self.node_id)) {
+                match shards.get(&(stream.name.clone(), shard.shard_id)) {
+                    None => unrecovered.push(format!("{}/{} is not open yet", stream.name, shard.shard_id)),
+                    Some(shard_controller) if !shard_controller.replica_status().in_sync => {
+                        out_of_sync.push(format!("{}/{} is not in sync with its leader", stream.name, shard.shard_id));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        drop(shards);
+        Readiness::check(&self.data_dir, unrecovered, out_of_sync)
+    }
+
+    /// the shards hosted here and their segments, by stream and shard id
+    pub fn shard_summaries(&self) -> Vec<ShardSummary> {
+        let mut summaries: Vec<ShardSummary> = self.shards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|((stream, shard_id), shard_controller)| ShardSummary {
+                stream: Some(stream.clone()),
+                shard_id: Some(*shard_id),
+                ..shard_controller.summary()
+            })
+            .collect();
+        summaries.sort_by(|a, b| (&a.stream, a.shard_id).cmp(&(&b.stream, b.shard_id)));
+        summaries
+    }
+
+    /// Metrics of the replicas hosted here, and how far behind each consumer group is on the
+    /// shards led here. Only leaders report lag so that each shard has it once.
+    pub fn metrics(&self) -> Exposition {
+        let metadata = self.raft.metadata();
+        let mut shards: Vec<((String, u32), Arc<ShardController>)> = self.shards
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, shard_controller)| (key.clone(), shard_controller.clone()))
+            .collect();
+        shards.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = Exposition::default();
+        for ((stream, shard_id), shard_controller) in shards {
+            let metrics = shard_controller.metrics();
+            let shard = shard_id.to_string();
+            metrics.expose(&mut out, &[("stream", &stream), ("shard", &shard)]);
+            if !self.leads(&metadata, &stream, shard_id) {
+                continue;
+            }
+            for (group, streams) in metadata.groups.iter() {
+                if let Some(checkpoint) = streams.get(&stream).and_then(|checkpoints| checkpoints.get(&shard_id)) {
+                    out.gauge(
+                        "rinites_consumer_group_lag",
+                        "Records a consumer group has yet to read, from its checkpoint to the high-watermark.",
+                        &[("group", group), ("stream", &stream), ("shard", &shard)],
+                        metrics.replication.high_watermark.saturating_sub(*checkpoint),
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    fn leads(&self, metadata: &ClusterMetadata, stream: &str, shard_id: u32) -> bool {
+        metadata.streams
+            .get(stream)
+            .and_then(|s| s.shard(shard_id))
+            .is_some_and(|shard| shard.leader == self.node_id)
+    }
+
+    fn query_replica(&self, stream: &str, shard_id: u32, node_id: NodeId) -> std::io::Result<ReplicaStatus> {
+        if node_id == self.node_id {
+            return self.replica_status(stream, shard_id);
+        }
+        let path = format!("{}/replica", shard_path(stream, shard_id));
+        let timeout = Duration::from_millis(REPLICA_STATUS_TIMEOUT_MS);
+        let response = http::signed_request(&self.peers[&node_id], "GET", &path, &[], timeout, self.credentials.as_ref())?;
+        if !response.is_success() {
+            return Err(Error::other(format!("node {} answered {} for {}", node_id, response.status, path)));
+        }
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    fn shard_dir(&self, stream: &str, shard_id: u32) -> ShardDir {
+        ShardDir { mount_dir: self.data_dir.join("streams").join(stream).join(shard_id.to_string()) }
+    }
+}
+
+impl KeyStore for ClusterNode {
+    fn api_key(&self, access_key_id: &str) -> Option<ApiKey> {
+        self.raft.read_metadata(|metadata| metadata.keys.get(access_key_id).cloned())
+    }
+}
+
+/// Stream and group names: up to 128 letters, digits, `_`, `-` and `.`, not starting with a `.`.
+fn check_name(what: &str, name: &str) -> std::io::Result<()> {
+    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if name.is_empty() || name.len() > 128 || !valid_name || name.starts_with('.') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid {} name '{}'", what, name)));
+    }
+    Ok(())
+}
+
+/// where the routes of a shard are mounted
+pub fn shard_path(stream: &str, shard_id: u32) -> String {
+    format!("/streams/{}/shards/{}", stream, shard_id)
+}
+
+/// Parses `<node id>=<host:port>,...`, the nodes of a cluster.
+pub fn parse_peers(peers: &str) -> std::io::Result<BTreeMap<NodeId, String>> {
+    peers
+        .split(',')
+        .map(|peer| {
+            let (node_id, addr) = peer
+                .split_once('=')
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("peer '{}' is not <node id>=<host:port>", peer)))?;
+            let node_id = node_id
+                .trim()
+                .parse()
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid node id in '{}'", peer)))?;
+            Ok((node_id, addr.trim().to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,689 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::auth::sigv4::Credentials;
+use crate::cluster::metadata::{ClusterMetadata, Command, NodeId};
+use crate::http;
+
+pub const DEFAULT_ELECTION_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_HEARTBEAT_MS: u64 = 100;
+
+/// the most entries sent to a follower in one append
+const MAX_ENTRIES_PER_APPEND: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaftConfig {
+    pub node_id: NodeId,
+    /// every node of the cluster, this one included, by id
+    pub peers: BTreeMap<NodeId, String>,
+    /// a follower that has not heard from a leader for this long, plus up to as much again at
+    /// random, starts an election
+    pub election_timeout_ms: u64,
+    pub heartbeat_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    /// `None` is the entry every leader starts its term with, so that it can commit the entries
+    /// of earlier terms
+    pub command: Option<Command>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate_id: NodeId,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader_id: NodeId,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    /// the last entry known to match the leader's log, or where the leader should look for one
+    pub match_index: u64,
+}
+
+/// How raft nodes reach each other.
+pub trait RaftTransport: Send + Sync {
+    fn request_vote(&self, peer: NodeId, request: &VoteRequest) -> std::io::Result<VoteResponse>;
+    fn append_entries(&self, peer: NodeId, request: &AppendRequest) -> std::io::Result<AppendResponse>;
+}
+
+/// Sends raft messages as JSON to the `/raft` routes of the other nodes.
+pub struct HttpTransport {
+    pub peers: BTreeMap<NodeId, String>,
+    pub timeout: Duration,
+    /// the root key, when the nodes have auth on
+    pub credentials: Option<Credentials>,
+}
+
+impl HttpTransport {
+    fn post<Req: serde::Serialize, Res: serde::de::DeserializeOwned>(&self, peer: NodeId, path: &str, request: &Req) -> std::io::Result<Res> {
+        let addr = self.peers
+            .get(&peer)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("unknown node {}", peer)))?;
+        let body = serde_json::to_vec(request)?;
+        let response = http::signed_request(addr, "POST", path, &body, self.timeout, self.credentials.as_ref())?;
+        if !response.is_success() {
+            return Err(Error::other(format!("{} on node {} failed with {}", path, peer, response.status)));
+        }
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+}
+
+impl RaftTransport for HttpTransport {
+    fn request_vote(&self, peer: NodeId, request: &VoteRequest) -> std::io::Result<VoteResponse> {
+        self.post(peer, "/raft/request-vote", request)
+    }
+
+    fn append_entries(&self, peer: NodeId, request: &AppendRequest) -> std::io::Result<AppendResponse> {
+        self.post(peer, "/raft/append-entries", request)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// what has to be on disk before answering any message
+#[derive(Deserialize, Serialize, Default)]
+struct PersistentState {
+    current_term: u64,
+    voted_for: Option<NodeId>,
+    log: Vec<LogEntry>,
+}
+
+impl PersistentState {
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    /// term of the entry at `index`, 0 before the first one
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.log.get(i as usize - 1).map(|e| e.term).unwrap_or(0),
+        }
+    }
+}
+
+struct RaftState {
+    persistent: PersistentState,
+    role: Role,
+    leader_id: Option<NodeId>,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    votes: HashSet<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    /// peers with an append on the way, which are not sent another one meanwhile
+    in_flight: HashSet<NodeId>,
+    /// when the leader last heard back from each peer
+    last_contact: HashMap<NodeId, Instant>,
+    metadata: ClusterMetadata,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RaftStatus {
+    pub node_id: NodeId,
+    pub term: u64,
+    pub role: Role,
+    pub leader: Option<NodeId>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+}
+
+/// One member of the raft group keeping the cluster metadata. The log is kept whole in
+/// `state.json`, it only grows with metadata changes.
+pub struct RaftNode {
+    pub config: RaftConfig,
+    path: PathBuf,
+    transport: Arc<dyn RaftTransport>,
+    state: Mutex<RaftState>,
+    applied: Condvar,
+}
+
+impl RaftNode {
+    pub fn open(config: RaftConfig, dir: &Path, transport: Arc<dyn RaftTransport>) -> std::io::Result<Arc<RaftNode>> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("state.json");
+        let persistent = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => PersistentState::default(),
+            Err(e) => return Err(e),
+        };
+
+        let node = RaftNode {
+            path,
+            transport,
+            state: Mutex::new(RaftState {
+                persistent,
+                role: Role::Follower,
+                leader_id: None,
+                election_deadline: Instant::now(),
+                last_heartbeat: Instant::now(),
+                votes: HashSet::new(),
+                commit_index: 0,
+                last_applied: 0,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                in_flight: HashSet::new(),
+                last_contact: HashMap::new(),
+                metadata: ClusterMetadata::default(),
+            }),
+            applied: Condvar::new(),
+            config,
+        };
+        node.reset_election_deadline(&mut node.state.lock().unwrap());
+        Ok(Arc::new(node))
+    }
+
+    /// Starts the thread that holds elections and sends heartbeats, until the node is dropped.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let node = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(node) = node.upgrade() {
+                node.tick();
+                drop(node);
+                thread::sleep(Duration::from_millis(10));
+            }
+        })
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let state = self.state.lock().unwrap();
+        RaftStatus {
+            node_id: self.config.node_id,
+            term: state.persistent.current_term,
+            role: state.role,
+            leader: state.leader_id,
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+        }
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.state.lock().unwrap().leader_id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state.lock().unwrap().role == Role::Leader
+    }
+
+    /// Peers the leader has not heard back from for `timeout`, which are taken for dead. Only the
+    /// leader knows, other nodes get none.
+    pub fn unreachable_peers(&self, timeout: Duration) -> Vec<NodeId> {
+        let state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return vec![];
+        }
+        state.last_contact
+            .iter()
+            .filter(|(_, contact)| contact.elapsed() >= timeout)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// the metadata as of the last applied entry
+    pub fn metadata(&self) -> ClusterMetadata {
+        self.state.lock().unwrap().metadata.clone()
+    }
+
+    /// `read` of the metadata in place, for lookups made on every request
+    pub fn read_metadata<T>(&self, read: impl FnOnce(&ClusterMetadata) -> T) -> T {
+        read(&self.state.lock().unwrap().metadata)
+    }
+
+    /// Appends `command` to the log and waits until it was committed and applied here. Only the
+    /// leader takes proposals.
+    pub fn propose(self: &Arc<Self>, command: Command, timeout: Duration) -> std::io::Result<()> {
+        let (index, term) = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(Error::other(format!("node {} is not the leader", self.config.node_id)));
+            }
+            let term = state.persistent.current_term;
+            state.persistent.log.push(LogEntry { term, command: Some(command) });
+            self.persist(&state)?;
+            // a leader without peers is a majority on its own
+            self.advance_commit(&mut state);
+            (state.persistent.last_index(), term)
+        };
+        self.broadcast();
+
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.last_applied < index {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, format!("entry {} was not committed in time", index)));
+            }
+            state = self.applied.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        if state.persistent.term_at(index) != term {
+            return Err(Error::other(format!("entry {} was replaced by a newer leader", index)));
+        }
+        Ok(())
+    }
+
+    pub fn handle_request_vote(&self, request: &VoteRequest) -> std::io::Result<VoteResponse> {
+        let mut state = self.state.lock().unwrap();
+        if request.term > state.persistent.current_term {
+            self.step_down(&mut state, request.term)?;
+        }
+
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.persistent.term_at(state.persistent.last_index()), state.persistent.last_index());
+        let vote_granted = request.term == state.persistent.current_term
+            && state.persistent.voted_for.is_none_or(|v| v == request.candidate_id)
+            && up_to_date;
+        if vote_granted {
+            state.persistent.voted_for = Some(request.candidate_id);
+            self.persist(&state)?;
+            self.reset_election_deadline(&mut state);
+        }
+        Ok(VoteResponse { term: state.persistent.current_term, vote_granted })
+    }
+
+    pub fn handle_append_entries(&self, request: &AppendRequest) -> std::io::Result<AppendResponse> {
+        let mut state = self.state.lock().unwrap();
+        let current_term = state.persistent.current_term;
+        if request.term < current_term {
+            return Ok(AppendResponse { term: current_term, success: false, match_index: state.persistent.last_index() });
+        }
+        if request.term > current_term || state.role != Role::Follower {
+            self.step_down(&mut state, request.term)?;
+        }
+        state.leader_id = Some(request.leader_id);
+        self.reset_election_deadline(&mut state);
+
+        let last_index = state.persistent.last_index();
+        if request.prev_log_index > last_index {
+            return Ok(AppendResponse { term: request.term, success: false, match_index: last_index });
+        }
+        if state.persistent.term_at(request.prev_log_index) != request.prev_log_term {
+            return Ok(AppendResponse { term: request.term, success: false, match_index: request.prev_log_index - 1 });
+        }
+
+        let mut changed = false;
+        for (i, entry) in request.entries.iter().enumerate() {
+            let index = request.prev_log_index + 1 + i as u64;
+            if index <= state.persistent.last_index() {
+                if state.persistent.term_at(index) == entry.term {
+                    continue;
+                }
+                // a conflicting entry was never committed, so it and everything after it go
+                state.persistent.log.truncate(index as usize - 1);
+            }
+            state.persistent.log.push(entry.clone());
+            changed = true;
+        }
+        if changed {
+            self.persist(&state)?;
+        }
+
+        let match_index = request.prev_log_index + request.entries.len() as u64;
+        if request.leader_commit > state.commit_index {
+            state.commit_index = request.leader_commit.min(match_index).max(state.commit_index);
+            self.apply(&mut state);
+        }
+        Ok(AppendResponse { term: request.term, success: true, match_index })
+    }
+
+    fn tick(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        match state.role {
+            Role::Leader => {
+                if state.last_heartbeat.elapsed() >= Duration::from_millis(self.config.heartbeat_ms) {
+                    drop(state);
+                    self.broadcast();
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if Instant::now() >= state.election_deadline {
+                    if let Err(e) = self.start_election(&mut state) {
+                        warn!(error = %e, "could not start an election");
+                    }
+                }
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut MutexGuard<RaftState>) -> std::io::Result<()> {
+        state.persistent.current_term += 1;
+        state.persistent.voted_for = Some(self.config.node_id);
+        self.persist(state)?;
+        state.role = Role::Candidate;
+        state.leader_id = None;
+        state.votes = [self.config.node_id].iter().copied().collect();
+        self.reset_election_deadline(state);
+        if state.votes.len() >= self.majority() {
+            self.become_leader(state)?;
+            return Ok(());
+        }
+
+        let request = VoteRequest {
+            term: state.persistent.current_term,
+            candidate_id: self.config.node_id,
+            last_log_index: state.persistent.last_index(),
+            last_log_term: state.persistent.term_at(state.persistent.last_index()),
+        };
+        for peer in self.other_peers() {
+            let node = self.clone();
+            let request = request.clone();
+            thread::spawn(move || {
+                if let Ok(response) = node.transport.request_vote(peer, &request) {
+                    if let Err(e) = node.handle_vote_response(peer, request.term, response) {
+                        warn!(peer, error = %e, "could not count a vote");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_vote_response(self: &Arc<Self>, peer: NodeId, term: u64, response: VoteResponse) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if response.term > state.persistent.current_term {
+            return self.step_down(&mut state, response.term);
+        }
+        if state.role != Role::Candidate || state.persistent.current_term != term || !response.vote_granted {
+            return Ok(());
+        }
+        state.votes.insert(peer);
+        if state.votes.len() >= self.majority() {
+            self.become_leader(&mut state)?;
+            drop(state);
+            self.broadcast();
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, state: &mut RaftState) -> std::io::Result<()> {
+        info!(node_id = self.config.node_id, term = state.persistent.current_term, "leading the metadata");
+        state.role = Role::Leader;
+        state.leader_id = Some(self.config.node_id);
+        let term = state.persistent.current_term;
+        state.persistent.log.push(LogEntry { term, command: None });
+        self.persist(state)?;
+
+        let next_index = state.persistent.last_index();
+        state.next_index = self.other_peers().map(|p| (p, next_index)).collect();
+        state.match_index = self.other_peers().map(|p| (p, 0)).collect();
+        state.in_flight.clear();
+        // nobody is taken for dead before getting a chance to answer the new leader
+        state.last_contact = self.other_peers().map(|p| (p, Instant::now())).collect();
+        self.advance_commit(state);
+        Ok(())
+    }
+
+    /// sends every follower the entries it is missing, or a heartbeat
+    fn broadcast(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return;
+        }
+        state.last_heartbeat = Instant::now();
+        for peer in self.other_peers() {
+            if state.in_flight.contains(&peer) {
+                continue;
+            }
+            let next_index = state.next_index[&peer];
+            let prev_log_index = next_index - 1;
+            let entries: Vec<LogEntry> = state.persistent.log
+                .iter()
+                .skip(prev_log_index as usize)
+                .take(MAX_ENTRIES_PER_APPEND)
+                .cloned()
+                .collect();
+            let request = AppendRequest {
+                term: state.persistent.current_term,
+                leader_id: self.config.node_id,
+                prev_log_index,
+                prev_log_term: state.persistent.term_at(prev_log_index),
+                entries,
+                leader_commit: state.commit_index,
+            };
+            state.in_flight.insert(peer);
+
+            let node = self.clone();
+            thread::spawn(move || {
+                let response = node.transport.append_entries(peer, &request);
+                let more = node.handle_append_response(peer, request.term, response);
+                if more {
+                    node.broadcast();
+                }
+            });
+        }
+    }
+
+    /// Returns whether the follower should be sent more entries right away.
+    fn handle_append_response(&self, peer: NodeId, term: u64, response: std::io::Result<AppendResponse>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(&peer);
+        let response = match response {
+            Ok(response) => response,
+            Err(_) => return false,
+        };
+        state.last_contact.insert(peer, Instant::now());
+        if response.term > state.persistent.current_term {
+            if let Err(e) = self.step_down(&mut state, response.term) {
+                warn!(error = %e, "could not step down");
+            }
+            return false;
+        }
+        if state.role != Role::Leader || state.persistent.current_term != term {
+            return false;
+        }
+
+        if response.success {
+            let match_index = state.match_index[&peer].max(response.match_index);
+            state.match_index.insert(peer, match_index);
+            state.next_index.insert(peer, match_index + 1);
+            self.advance_commit(&mut state);
+        } else {
+            let next_index = (state.next_index[&peer] - 1).min(response.match_index + 1).max(1);
+            state.next_index.insert(peer, next_index);
+        }
+        state.next_index[&peer] <= state.persistent.last_index()
+    }
+
+    /// commits the newest entry of this term a majority has
+    fn advance_commit(&self, state: &mut RaftState) {
+        let term = state.persistent.current_term;
+        for index in (state.commit_index + 1..=state.persistent.last_index()).rev() {
+            if state.persistent.term_at(index) != term {
+                break;
+            }
+            let holders = 1 + state.match_index.values().filter(|m| **m >= index).count();
+            if holders >= self.majority() {
+                state.commit_index = index;
+                self.apply(state);
+                break;
+            }
+        }
+    }
+
+    fn apply(&self, state: &mut RaftState) {
+        while state.last_applied < state.commit_index {
+            state.last_applied += 1;
+            if let Some(command) = state.persistent.log[state.last_applied as usize - 1].command.clone() {
+                state.metadata.apply(&command);
+            }
+        }
+        self.applied.notify_all();
+    }
+
+    fn step_down(&self, state: &mut RaftState, term: u64) -> std::io::Result<()> {
+        if term > state.persistent.current_term {
+            state.persistent.current_term = term;
+            state.persistent.voted_for = None;
+            state.leader_id = None;
+            self.persist(state)?;
+        }
+        state.role = Role::Follower;
+        self.reset_election_deadline(state);
+        Ok(())
+    }
+
+    fn reset_election_deadline(&self, state: &mut RaftState) {
+        let timeout = self.config.election_timeout_ms;
+        let jitter = rand::thread_rng().gen_range(0, timeout.max(1));
+        state.election_deadline = Instant::now() + Duration::from_millis(timeout + jitter);
+    }
+
+    fn majority(&self) -> usize {
+        self.config.peers.len() / 2 + 1
+    }
+
+    fn other_peers(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.config.peers.keys().copied().filter(move |p| *p != self.config.node_id)
+    }
+
+    /// writes the term, vote and log to a new file that replaces the old one
+    fn persist(&self, state: &RaftState) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&state.persistent)?)?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::io::{Error, ErrorKind};
+    use std::sync::{Arc, Mutex, RwLock, Weak};
+    use std::time::Duration;
+
+    use crate::cluster::metadata::{Command, NodeId};
+    use crate::cluster::raft::{AppendRequest, AppendResponse, RaftConfig, RaftNode, RaftTransport, VoteRequest, VoteResponse};
+    use crate::config::StreamOverrides;
+    use crate::test_util::{eventually, with_tmp_dir};
+
+    /// nodes of the same process, some of which can be cut off
+    #[derive(Default)]
+    struct LocalTransport {
+        nodes: RwLock<HashMap<NodeId, Weak<RaftNode>>>,
+        down: Mutex<HashSet<NodeId>>,
+    }
+
+    impl LocalTransport {
+        fn node(&self, from: NodeId, to: NodeId) -> std::io::Result<Arc<RaftNode>> {
+            let down = self.down.lock().unwrap();
+            if down.contains(&from) || down.contains(&to) {
+                return Err(Error::new(ErrorKind::ConnectionRefused, "node is down"));
+            }
+            self.nodes.read().unwrap()[&to].upgrade().ok_or_else(|| Error::new(ErrorKind::ConnectionRefused, "node is gone"))
+        }
+    }
+
+    impl RaftTransport for LocalTransport {
+        fn request_vote(&self, peer: NodeId, request: &VoteRequest) -> std::io::Result<VoteResponse> {
+            self.node(request.candidate_id, peer)?.handle_request_vote(request)
+        }
+
+        fn append_entries(&self, peer: NodeId, request: &AppendRequest) -> std::io::Result<AppendResponse> {
+            self.node(request.leader_id, peer)?.handle_append_entries(request)
+        }
+    }
+
+    fn create(name: &str) -> Command {
+        Command::CreateStream { name: name.to_string(), shards: vec![], config: StreamOverrides::default() }
+    }
+
+    #[test]
+    fn raft_elects_a_leader_and_replaces_it_when_cut_off() {
+        with_tmp_dir(|dir| {
+            let transport = Arc::new(LocalTransport::default());
+            let peers: BTreeMap<NodeId, String> = (1..=3).map(|id| (id, format!("node-{}", id))).collect();
+            let nodes: Vec<Arc<RaftNode>> = (1..=3)
+                .map(|node_id| {
+                    let config = RaftConfig { node_id, peers: peers.clone(), election_timeout_ms: 150, heartbeat_ms: 30 };
+                    RaftNode::open(config, &dir.join(node_id.to_string()), transport.clone()).unwrap()
+                })
+                .collect();
+            for node in nodes.iter() {
+                transport.nodes.write().unwrap().insert(node.config.node_id, Arc::downgrade(node));
+                node.start();
+            }
+
+            let leader = eventually("a leader", || nodes.iter().find(|n| n.is_leader()).cloned());
+            leader.propose(create("orders"), Duration::from_secs(5)).unwrap();
+            eventually("every node to apply it", || {
+                Some(()).filter(|_| nodes.iter().all(|n| n.metadata().streams.contains_key("orders")))
+            });
+
+            // the cut off leader cannot commit, the others elect a new one
+            transport.down.lock().unwrap().insert(leader.config.node_id);
+            assert!(leader.propose(create("lost"), Duration::from_millis(300)).is_err());
+            let new_leader = eventually("a new leader", || {
+                nodes.iter().find(|n| n.is_leader() && n.config.node_id != leader.config.node_id).cloned()
+            });
+            new_leader.propose(create("payments"), Duration::from_secs(5)).unwrap();
+            eventually("the new leader to miss the old one", || {
+                Some(()).filter(|_| new_leader.unreachable_peers(Duration::from_millis(200)) == vec![leader.config.node_id])
+            });
+
+            // back in the cluster, the old leader drops its uncommitted entry
+            transport.down.lock().unwrap().clear();
+            eventually("the old leader to catch up", || {
+                Some(()).filter(|_| leader.metadata().streams.contains_key("payments"))
+            });
+            eventually("the new leader to hear from it", || {
+                Some(()).filter(|_| new_leader.unreachable_peers(Duration::from_millis(200)).is_empty())
+            });
+            for node in nodes.iter() {
+                let streams: Vec<String> = node.metadata().streams.keys().cloned().collect();
+                assert_eq!(streams, vec!["orders".to_string(), "payments".to_string()]);
+            }
+
+            // and everything survives a restart
+            drop(nodes);
+            thread::sleep(Duration::from_millis(100));
+            let config = RaftConfig { node_id: 1, peers: [(1, "node-1".to_string())].iter().cloned().collect(), election_timeout_ms: 150, heartbeat_ms: 30 };
+            let alone = RaftNode::open(config, &dir.join("1"), Arc::new(LocalTransport::default())).unwrap();
+            alone.start();
+            eventually("the restarted node to replay its log", || {
+                Some(()).filter(|_| alone.metadata().streams.len() == 2)
+            });
+        });
+    }
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::auth::sigv4::Credentials;
+use crate::cluster::metadata::NodeId;
+use crate::logging::LogFormat;
+use crate::shards::batch::Compression;
+use crate::shards::compaction::CleanupPolicy;
+use crate::shards::durability::FsyncPolicy;
+use crate::shards::replication::{DEFAULT_ACK_TIMEOUT_MS, DEFAULT_REPLICA_LAG_MAX_MS, ReplicationConfig};
+use crate::shards::shard_controller::StreamConfig;
+use crate::shards::tiering::{DEFAULT_CACHE_SEGMENTS, DEFAULT_HOT_RETENTION_MS, TieringConfig};
+
+/// how many shards a stream created without saying gets, unless the config says
+pub const DEFAULT_SHARD_COUNT: u32 = 1;
+
+/// name a follower fetches from its leader with, unless the config says
+pub const DEFAULT_REPLICA_ID: &str = "follower";
+
+/// Settings of a node as its config file has them, every one of them optional. Flags and
+/// `RINITES_*` environment variables take precedence over the file, and whatever is set in none
+/// of them gets its default.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub node: NodeSection,
+    pub log: LogSection,
+    pub replication: ReplicationSection,
+    pub cluster: ClusterSection,
+    pub auth: AuthSection,
+    /// settings of every stream, which a stream can override when it is created
+    pub streams: StreamOverrides,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSection {
+    pub mount_path: Option<PathBuf>,
+    /// `host:port` addresses the API is served on
+    pub listeners: Option<Vec<String>>,
+    /// file with one `<key id> <64 hex digits>` line per encryption key
+    pub keyfile: Option<PathBuf>,
+    /// directory sealed segments are uploaded to, standing in for an object store
+    pub remote_dir: Option<PathBuf>,
+    /// how many remote segments are cached on the mount path for readers
+    pub remote_cache_segments: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    pub filter: Option<String>,
+    #[serde(deserialize_with = "parsed::deserialize")]
+    pub format: Option<LogFormat>,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationSection {
+    /// `host:port` of the leader a single shard server follows
+    pub leader: Option<String>,
+    pub replica_id: Option<String>,
+    pub min_insync_replicas: Option<usize>,
+    pub replica_lag_max_ms: Option<u64>,
+    pub ack_timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterSection {
+    pub node_id: Option<NodeId>,
+    /// every node of the cluster as `<node id>=<host:port>,...`, this one included
+    pub peers: Option<String>,
+    pub election_timeout_ms: Option<u64>,
+    pub heartbeat_ms: Option<u64>,
+    pub failure_timeout_ms: Option<u64>,
+    /// shards of streams created without a shard count
+    pub default_shard_count: Option<u32>,
+    pub mirror_from: Option<String>,
+    pub mirror_streams: Option<String>,
+    /// a key of the mirrored cluster that may read its streams, when it has auth on
+    pub mirror_access_key_id: Option<String>,
+    pub mirror_secret_access_key: Option<String>,
+}
+
+/// The root key: requests have to be signed once it is set, the nodes sign their calls to each
+/// other with it, and it may do anything, creating the other keys through the admin API first.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub root_access_key_id: Option<String>,
+    pub root_secret_access_key: Option<String>,
+}
+
+/// Settings a stream can have of its own. Left out, they are those of the node the shard is
+/// opened on. The same keys make up the `[streams]` section of the config file.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_segment_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_records_per_get: Option<usize>,
+    #[serde(with = "parsed", skip_serializing_if = "Option::is_none")]
+    pub fsync_policy: Option<FsyncPolicy>,
+    #[serde(with = "parsed", skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(with = "parsed", skip_serializing_if = "Option::is_none")]
+    pub cleanup_policy: Option<CleanupPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tombstone_retention_ms: Option<u64>,
+    /// how long and how many bytes each shard keeps, 0 for no limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention_bytes: Option<u64>,
+    /// only for nodes with tiered storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hot_retention_ms: Option<u64>,
+    /// quota of each shard, 0 for none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub put_records_per_sec: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub put_bytes_per_sec: Option<u64>,
+}
+
+impl NodeConfig {
+    /// Reads the config file at `path`. Settings it does not know are refused rather than
+    /// ignored, so a misspelt one is noticed.
+    pub fn load(path: &Path) -> std::io::Result<NodeConfig> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("could not read config file {}: {}", path.display(), e)))?;
+        let config: NodeConfig = toml::from_str(&text)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid config file {}: {}", path.display(), e)))?;
+        config.streams.check()?;
+        Ok(config)
+    }
+
+    pub fn mount_path(&self) -> std::io::Result<&Path> {
+        self.node
+            .mount_path
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no mount path, set --mount-path or mount_path in [node]"))
+    }
+
+    pub fn listeners(&self) -> std::io::Result<&[String]> {
+        match self.node.listeners.as_deref() {
+            Some(listeners) if !listeners.is_empty() => Ok(listeners),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "nothing to listen on, set --port or listeners in [node]")),
+        }
+    }
+
+    /// The root key, `None` when auth is off.
+    pub fn root_credentials(&self) -> std::io::Result<Option<Credentials>> {
+        credentials(&self.auth.root_access_key_id, &self.auth.root_secret_access_key, "root_access_key_id and root_secret_access_key in [auth]")
+    }
+
+    /// The key the mirror reads the streams of `mirror_from` with, `None` when that cluster has
+    /// auth off.
+    pub fn mirror_credentials(&self) -> std::io::Result<Option<Credentials>> {
+        let cluster = &self.cluster;
+        credentials(&cluster.mirror_access_key_id, &cluster.mirror_secret_access_key, "mirror_access_key_id and mirror_secret_access_key in [cluster]")
+    }
+
+    /// What the shards opened here start out with, before the overrides of their stream.
+    pub fn stream_config(&self) -> StreamConfig {
+        let replication = &self.replication;
+        let node_config = StreamConfig {
+            tiering: self.node.remote_dir.clone().map(|remote_dir| TieringConfig {
+                remote_dir,
+                hot_retention_ms: DEFAULT_HOT_RETENTION_MS,
+                cache_segments: self.node.remote_cache_segments.unwrap_or(DEFAULT_CACHE_SEGMENTS),
+            }),
+            keyfile: self.node.keyfile.clone(),
+            replication: ReplicationConfig {
+                leader: replication.leader.clone(),
+                replica_id: replication.replica_id.clone().unwrap_or_else(|| DEFAULT_REPLICA_ID.to_string()),
+                min_insync_replicas: replication.min_insync_replicas.unwrap_or(1),
+                replica_lag_max_ms: replication.replica_lag_max_ms.unwrap_or(DEFAULT_REPLICA_LAG_MAX_MS),
+                ack_timeout_ms: replication.ack_timeout_ms.unwrap_or(DEFAULT_ACK_TIMEOUT_MS),
+            },
+            ..StreamConfig::default()
+        };
+        self.streams.apply(&node_config)
+    }
+}
+
+fn credentials(access_key_id: &Option<String>, secret_access_key: &Option<String>, settings: &str) -> std::io::Result<Option<Credentials>> {
+    match (access_key_id, secret_access_key) {
+        (Some(access_key_id), Some(secret_access_key)) => Ok(Some(Credentials {
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.clone(),
+        })),
+        (None, None) => Ok(None),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("{} go together", settings))),
+    }
+}
+
+impl StreamOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == StreamOverrides::default()
+    }
+
+    /// refuses settings no shard could run with
+    pub fn check(&self) -> std::io::Result<()> {
+        if self.max_segment_size == Some(0) || self.max_records_per_get == Some(0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "max_segment_size and max_records_per_get must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    /// `config` with the settings given here in place of its own
+    pub fn apply(&self, config: &StreamConfig) -> StreamConfig {
+        let mut config = config.clone();
+        config.max_segment_size = self.max_segment_size.unwrap_or(config.max_segment_size);
+        config.max_records_per_get = self.max_records_per_get.unwrap_or(config.max_records_per_get);
+        config.fsync_policy = self.fsync_policy.unwrap_or(config.fsync_policy);
+        config.compression = self.compression.unwrap_or(config.compression);
+        config.cleanup_policy = self.cleanup_policy.unwrap_or(config.cleanup_policy);
+        config.tombstone_retention_ms = self.tombstone_retention_ms.unwrap_or(config.tombstone_retention_ms);
+        if let Some(retention_ms) = self.retention_ms {
+            config.retention.ms = Some(retention_ms).filter(|ms| *ms > 0);
+        }
+        if let Some(retention_bytes) = self.retention_bytes {
+            config.retention.bytes = Some(retention_bytes).filter(|bytes| *bytes > 0);
+        }
+        if let (Some(hot_retention_ms), Some(tiering)) = (self.hot_retention_ms, config.tiering.as_mut()) {
+            tiering.hot_retention_ms = hot_retention_ms;
+        }
+        if let Some(records_per_sec) = self.put_records_per_sec {
+            config.quota.records_per_sec = Some(records_per_sec).filter(|n| *n > 0);
+        }
+        if let Some(bytes_per_sec) = self.put_bytes_per_sec {
+            config.quota.bytes_per_sec = Some(bytes_per_sec).filter(|n| *n > 0);
+        }
+        config
+    }
+}
+
+/// Settings like the fsync policy are written the way their flags take them, `interval(100)`
+/// say, in the config file and in stream metadata alike.
+mod parsed {
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+        where T: FromStr, T::Err: Display, D: Deserializer<'de>
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.parse().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{NodeConfig, StreamOverrides};
+    use crate::shards::batch::Compression;
+    use crate::shards::durability::FsyncPolicy;
+    use crate::shards::quota::Quota;
+    use crate::shards::retention::Retention;
+    use crate::shards::shard_controller::StreamConfig;
+
+    #[test]
+    fn config_files_set_what_they_have_and_streams_override_it() {
+        let config: NodeConfig = toml::from_str(r#"
+            [node]
+            mount_path = "/var/lib/rinites"
+            listeners = ["0.0.0.0:8080"]
+
+            [streams]
+            max_segment_size = 4096
+            fsync_policy = "interval(100)"
+            put_records_per_sec = 1000
+            retention_ms = 3600000
+        "#).unwrap();
+        assert_eq!(config.listeners().unwrap(), ["0.0.0.0:8080"]);
+        let node_config = config.stream_config();
+        assert_eq!(node_config.max_segment_size, 4096);
+        assert_eq!(node_config.fsync_policy, FsyncPolicy::Interval(100));
+        assert_eq!(node_config.replication.min_insync_replicas, 1);
+        assert_eq!(node_config.max_records_per_get, StreamConfig::default().max_records_per_get);
+
+        let overrides = r#"{"compression":"zstd","retention_bytes":1048576,"put_records_per_sec":0}"#;
+        let overrides: StreamOverrides = serde_json::from_str(overrides).unwrap();
+        assert_eq!(serde_json::to_string(&overrides).unwrap(), r#"{"compression":"zstd","retention_bytes":1048576,"put_records_per_sec":0}"#);
+        let stream_config = overrides.apply(&node_config);
+        assert_eq!(stream_config.compression, Compression::Zstd);
+        assert_eq!(stream_config.quota, Quota::default());
+        assert_eq!(stream_config.retention, Retention { ms: Some(3600000), bytes: Some(1048576) });
+        assert_eq!(stream_config.fsync_policy, FsyncPolicy::Interval(100));
+
+        assert_eq!(config.root_credentials().unwrap(), None);
+        let half_a_key: NodeConfig = toml::from_str("[auth]\nroot_access_key_id = \"meucu\"").unwrap();
+        assert!(half_a_key.root_credentials().is_err());
+
+        assert!(toml::from_str::<NodeConfig>("[node]\nmount_pth = \"/tmp\"").is_err());
+        assert!(toml::from_str::<NodeConfig>("[streams]\nfsync_policy = \"sometimes\"").is_err());
+        assert!(StreamOverrides { max_segment_size: Some(0), ..StreamOverrides::default() }.check().is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// files written, synced and removed again under the mount path to see that the disk takes
+/// writes, numbered so that probes running at once do not share one
+const PROBE_FILE: &str = "ready-probe";
+
+static PROBES: AtomicU64 = AtomicU64::new(0);
+
+/// What `/ready` answers, with a 200 when every check passed and a 503 otherwise. `/health` only
+/// tells the process is up, so an orchestrator restarts a node when that fails and stops sending
+/// it requests while it is not ready.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Readiness {
+    /// every shard hosted here is open, its segments and indexes recovered
+    pub recovered: bool,
+    pub disk_writable: bool,
+    /// the node follows the metadata leader and the shards it follows are in sync with theirs
+    pub in_sync: bool,
+    /// what failed, empty when the node is ready
+    pub problems: Vec<String>,
+}
+
+impl Readiness {
+    /// Probes the disk under `mount_path` and adds the result to what the caller found wrong
+    /// with the recovery and the sync of the node.
+    pub fn check(mount_path: &Path, unrecovered: Vec<String>, out_of_sync: Vec<String>) -> Readiness {
+        let disk = probe_disk(mount_path).err().map(|e| format!("{} does not take writes: {}", mount_path.display(), e));
+        Readiness {
+            recovered: unrecovered.is_empty(),
+            disk_writable: disk.is_none(),
+            in_sync: out_of_sync.is_empty(),
+            problems: unrecovered.into_iter().chain(disk).chain(out_of_sync).collect(),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+fn probe_disk(mount_path: &Path) -> std::io::Result<()> {
+    let path = mount_path.join(format!("{}-{}", PROBE_FILE, PROBES.fetch_add(1, Ordering::Relaxed)));
+    let mut file = File::create(&path)?;
+    file.write_all(b"ready")?;
+    file.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+
+    use crate::health::Readiness;
+
+    #[test]
+    fn readiness_lists_every_check_that_failed() {
+        let ready = Readiness::check(&env::temp_dir(), vec![], vec![]);
+        assert!(ready.is_ready());
+        assert!(ready.recovered && ready.disk_writable && ready.in_sync);
+
+        let not_ready = Readiness::check(Path::new("/meucu_tem_oculos/missing"), vec![], vec!["orders/0 lags behind".to_string()]);
+        assert!(!not_ready.is_ready());
+        assert!(not_ready.recovered);
+        assert!(!not_ready.disk_writable && !not_ready.in_sync);
+        assert_eq!(not_ready.problems.len(), 2);
+    }
+}
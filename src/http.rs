@@ -0,0 +1,90 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::auth::sigv4::{Credentials, Request, unix_now};
+
+/// What came back from a `request`.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// A blocking HTTP/1.0 request to `addr`, one connection per request, for the background
+/// threads talking to other nodes.
+pub fn request(addr: &str, method: &str, path: &str, body: &[u8], timeout: Duration) -> std::io::Result<HttpResponse> {
+    signed_request(addr, method, path, body, timeout, None)
+}
+
+/// A `request` signed with `credentials`, for nodes that have auth on.
+pub fn signed_request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    timeout: Duration,
+    credentials: Option<&Credentials>,
+) -> std::io::Result<HttpResponse> {
+    let socket_addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} does not resolve", addr)))?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let signature: String = match credentials {
+        Some(credentials) => {
+            let (path, query) = path.split_once('?').unwrap_or((path, ""));
+            let request = Request { method, path, query, headers: vec![("host".to_string(), addr.to_string())], body };
+            request
+                .sign(credentials, unix_now())
+                .iter()
+                .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                .collect()
+        }
+        None => String::new(),
+    };
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}\r\n",
+        method, path, addr, body.len(), signature,
+    )?;
+    stream.write_all(body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("{} {} on {}: {}", method, path, addr, reason));
+    let head_len = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("truncated response"))?;
+    let head = String::from_utf8_lossy(&response[..head_len]).to_string();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(HttpResponse { status, headers, body: response[head_len + 4..].to_vec() })
+}
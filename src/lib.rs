@@ -1,4 +1,13 @@
+pub mod auth;
+pub mod cluster;
+pub mod config;
+pub mod health;
+pub mod http;
+pub mod logging;
+pub mod metrics;
 pub mod shards;
+#[cfg(test)]
+mod test_util;
 
 #[derive(Debug)]
 pub struct Response(pub String);
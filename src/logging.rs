@@ -0,0 +1,108 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use rand::Rng;
+use tracing::{info, Instrument};
+use tracing_subscriber::EnvFilter;
+
+/// header with the id a request is logged under, kept when the client or the node forwarding it
+/// sends one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// How log lines are written: for people to read, or one JSON object per line, with the spans
+/// they were logged in, for log pipelines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+/// Logs to stderr what `filter` lets through. It takes directives like
+/// `info,rinites::shards=debug`; without one they come from `RUST_LOG`, and without that either
+/// everything from `info` up is logged.
+pub fn init(filter: Option<&str>, format: LogFormat) -> std::io::Result<()> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid log filter '{}': {}", filter, e)))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    let initialized = match format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+    initialized.map_err(|e| Error::other(format!("could not set up logging: {}", e)))
+}
+
+/// Middleware serving every request in a `request` span with its request id, which the response
+/// gets back in `REQUEST_ID_HEADER`. Work the request hands to other threads has to carry
+/// `Span::current()` along to stay in it.
+pub fn traced<S, B>(mut req: ServiceRequest, service: &mut S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+{
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    // so that the node a request is forwarded to logs it under the same id
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        req.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    let span = tracing::info_span!("request", request_id = %request_id, method = %req.method(), path = %req.path());
+    let start = Instant::now();
+    let served = span.in_scope(|| service.call(req));
+
+    async move {
+        let mut response = served.await?;
+        info!(status = response.status().as_u16(), elapsed_ms = start.elapsed().as_millis() as u64, "served");
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        Ok(response)
+    }.instrument(span)
+}
+
+/// Ids from clients end up in every log line of their request, so only short plain ones are
+/// taken.
+fn is_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::{is_request_id, LogFormat, new_request_id};
+
+    #[test]
+    fn request_ids_from_clients_are_kept_only_when_plain() {
+        assert!(is_request_id("3f2a-req_7"));
+        assert!(is_request_id(&new_request_id()));
+        assert!(!is_request_id(""));
+        assert!(!is_request_id("a\nforged log line"));
+        assert!(!is_request_id(&"a".repeat(65)));
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
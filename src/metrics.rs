@@ -0,0 +1,171 @@
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// what `/metrics` answers with
+pub const EXPOSITION_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// Latencies counted in the buckets of `LATENCY_BUCKETS`, updated without locking.
+#[derive(Default, Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+/// A histogram as of some moment, with the number of latencies up to each bound like Prometheus
+/// counts them.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut below = 0;
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                below += count.load(Ordering::Relaxed);
+                (*bound, below)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            // read last so that it is never below the buckets
+            count: self.count.load(Ordering::Relaxed).max(below),
+            sum_seconds: self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+}
+
+/// Metrics in the Prometheus text format. Samples are grouped by metric whatever order they are
+/// added in, since the format wants all of a metric together.
+#[derive(Default)]
+pub struct Exposition {
+    families: Vec<Family>,
+}
+
+struct Family {
+    name: String,
+    help: String,
+    kind: &'static str,
+    samples: String,
+}
+
+impl Exposition {
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: impl Display) {
+        let family = self.family(name, help, "counter");
+        sample(&mut family.samples, name, labels, None, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], value: impl Display) {
+        let family = self.family(name, help, "gauge");
+        sample(&mut family.samples, name, labels, None, value);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, labels: &[(&str, &str)], histogram: &HistogramSnapshot) {
+        let family = self.family(name, help, "histogram");
+        let bucket = format!("{}_bucket", name);
+        for (bound, count) in histogram.buckets.iter() {
+            sample(&mut family.samples, &bucket, labels, Some(&bound.to_string()), count);
+        }
+        sample(&mut family.samples, &bucket, labels, Some("+Inf"), histogram.count);
+        sample(&mut family.samples, &format!("{}_sum", name), labels, None, histogram.sum_seconds);
+        sample(&mut family.samples, &format!("{}_count", name), labels, None, histogram.count);
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for family in self.families.iter() {
+            let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, family.kind);
+            text.push_str(&family.samples);
+        }
+        text
+    }
+
+    fn family(&mut self, name: &str, help: &str, kind: &'static str) -> &mut Family {
+        let position = match self.families.iter().position(|family| family.name == name) {
+            Some(position) => position,
+            None => {
+                self.families.push(Family { name: name.to_string(), help: help.to_string(), kind, samples: String::new() });
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[position]
+    }
+}
+
+/// one `name{labels} value` line, with `le` added for histogram buckets
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], le: Option<&str>, value: impl Display) {
+    let mut pairs: Vec<String> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, escape(value))).collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    let labels = if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) };
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::metrics::{Exposition, Histogram};
+
+    #[test]
+    fn samples_are_grouped_by_metric_and_histograms_count_up_to_each_bound() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(2));
+        histogram.observe(Duration::from_secs(7));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(&snapshot.buckets[..5], &[(0.0001, 0), (0.00025, 0), (0.0005, 1), (0.001, 1), (0.0025, 2)]);
+        assert_eq!(snapshot.buckets.last(), Some(&(5.0, 2)));
+
+        let mut exposition = Exposition::default();
+        exposition.counter("rinites_records_in_total", "records put", &[("stream", "orders"), ("shard", "0")], 3);
+        exposition.gauge("rinites_active_iterators", "iterators", &[], 1);
+        exposition.counter("rinites_records_in_total", "records put", &[("stream", "a\"b"), ("shard", "1")], 4);
+        exposition.histogram("rinites_put_latency_seconds", "put latency", &[("shard", "0")], &snapshot);
+        let text = exposition.render();
+
+        assert!(text.starts_with(concat!(
+            "# HELP rinites_records_in_total records put\n",
+            "# TYPE rinites_records_in_total counter\n",
+            "rinites_records_in_total{stream=\"orders\",shard=\"0\"} 3\n",
+            "rinites_records_in_total{stream=\"a\\\"b\",shard=\"1\"} 4\n",
+            "# HELP rinites_active_iterators iterators\n",
+            "# TYPE rinites_active_iterators gauge\n",
+            "rinites_active_iterators 1\n",
+        )));
+        assert!(text.contains("rinites_put_latency_seconds_bucket{shard=\"0\",le=\"0.0025\"} 2\n"));
+        assert!(text.contains("rinites_put_latency_seconds_bucket{shard=\"0\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("rinites_put_latency_seconds_sum{shard=\"0\"} 7.0023\n"));
+        assert!(text.ends_with("rinites_put_latency_seconds_count{shard=\"0\"} 3\n"));
+    }
+}
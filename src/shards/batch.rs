@@ -0,0 +1,593 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use crate::shards::encryption::{BatchKey, KeyProvider, open, seal, sealed_key_id};
+use crate::shards::producers::ProducerBatch;
+use crate::shards::shards::Record;
+use crate::shards::transactions::Marker;
+
+/// Frame layout, all integers big endian:
+///
+/// ```text
+/// magic u8 | base_sequence u64 | record_count u32 | span u32 | timestamp_ms u64
+///   | attributes u8 | payload_len u32 | crc32 u32 | payload
+/// ```
+///
+/// `span` is how many sequence numbers the batch covers. It only differs from `record_count`
+/// once compaction removed records from the batch. The low bits of `attributes` are the
+/// compression codec, `ENCRYPTED` is set for encrypted batches, `HAS_HEADERS` for batches with
+/// record headers and `HAS_PRODUCER` for batches of an idempotent producer. Batches of a
+/// transaction also have `TRANSACTIONAL`, and the commit and abort markers ending it `CONTROL`.
+/// The crc covers every header byte before it and the payload as stored.
+///
+/// With `HAS_PRODUCER`, the stored payload starts with `producer_id u64 | producer_sequence u64`,
+/// outside of compression and encryption so the dedup window can be rebuilt from the headers.
+/// The records follow. A transaction is its own producer, with its transaction id as producer
+/// id. A marker is a batch of one empty record, never shown to readers, with the transaction id
+/// as producer id and 1 as producer sequence for a commit, 0 for an abort.
+///
+/// Once decrypted and decompressed, the payload is the records one after the other, each as
+/// `offset_delta u32 | key_len u32 | key | len u32 | data`, where a `key_len` of 0 means the
+/// record has no partition key. With `HAS_HEADERS`, every record goes on with `header_count u32`
+/// and that many `name_len u32 | name | value_len u32 | value`. An encrypted payload is `key_id_len u8 | key_id | nonce |
+/// ciphertext`, AES-256-GCM over the compressed records with the header up to `attributes` as
+/// associated data.
+pub const BATCH_MAGIC: u8 = 2;
+pub const BATCH_HEADER_SIZE: usize = 34;
+/// header bytes authenticated along with an encrypted payload
+const BATCH_AAD_SIZE: usize = 26;
+const CONTROL: u8 = 0x08;
+const ENCRYPTED: u8 = 0x10;
+const HAS_HEADERS: u8 = 0x20;
+const HAS_PRODUCER: u8 = 0x40;
+const TRANSACTIONAL: u8 = 0x80;
+const PRODUCER_SIZE: usize = 16;
+const CODEC_MASK: u8 = 0x07;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+    Snappy,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            "snappy" => Ok(Compression::Snappy),
+            other => Err(format!("unknown compression codec '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+            Compression::Snappy => "snappy",
+        })
+    }
+}
+
+impl Compression {
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+            Compression::Snappy => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> std::io::Result<Compression> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            3 => Ok(Compression::Snappy),
+            _ => Err(corrupt(format!("unknown compression id {}", id))),
+        }
+    }
+
+    fn compress(&self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Zstd => zstd::bulk::compress(&data, 0),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(&data)),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(&data)
+                .map_err(Error::other),
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::stream::decode_all(data),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data).map_err(|e| corrupt(e.to_string())),
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(data)
+                .map_err(|e| corrupt(e.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchHeader {
+    pub base_sequence: u64,
+    pub record_count: u32,
+    pub span: u32,
+    pub timestamp_ms: u64,
+    pub compression: Compression,
+    pub encrypted: bool,
+    /// whether the records carry headers, batches without any leave them out
+    pub has_headers: bool,
+    /// whether the payload starts with the producer of the batch
+    pub has_producer: bool,
+    /// whether the batch belongs to a transaction
+    pub transactional: bool,
+    /// whether the batch is a transaction marker
+    pub control: bool,
+    pub payload_len: u32,
+    pub crc: u32,
+}
+
+impl BatchHeader {
+    pub fn parse(data: &[u8]) -> std::io::Result<BatchHeader> {
+        if data.len() < BATCH_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated batch header"));
+        }
+        if data[0] != BATCH_MAGIC {
+            return Err(corrupt(format!("bad batch magic {}", data[0])));
+        }
+
+        Ok(BatchHeader {
+            base_sequence: u64::from_be_bytes(data[1..9].try_into().unwrap()),
+            record_count: u32::from_be_bytes(data[9..13].try_into().unwrap()),
+            span: u32::from_be_bytes(data[13..17].try_into().unwrap()),
+            timestamp_ms: u64::from_be_bytes(data[17..25].try_into().unwrap()),
+            compression: Compression::from_id(data[25] & CODEC_MASK)?,
+            encrypted: data[25] & ENCRYPTED != 0,
+            has_headers: data[25] & HAS_HEADERS != 0,
+            has_producer: data[25] & HAS_PRODUCER != 0,
+            transactional: data[25] & TRANSACTIONAL != 0,
+            control: data[25] & CONTROL != 0,
+            payload_len: u32::from_be_bytes(data[26..30].try_into().unwrap()),
+            crc: u32::from_be_bytes(data[30..34].try_into().unwrap()),
+        })
+    }
+
+    /// size of the whole frame, header included
+    pub fn frame_len(&self) -> u64 {
+        (BATCH_HEADER_SIZE + self.payload_len as usize) as u64
+    }
+
+    /// sequence number right after the ones covered by the batch
+    pub fn next_sequence(&self) -> u64 {
+        self.base_sequence + self.span as u64
+    }
+
+    /// where the records, compressed and maybe encrypted, start in the frame
+    pub fn records_start(&self) -> usize {
+        BATCH_HEADER_SIZE + if self.has_producer { PRODUCER_SIZE } else { 0 }
+    }
+
+    /// The producer of the frame `data` starts with, read from the first `records_start` bytes.
+    pub fn producer(&self, data: &[u8]) -> std::io::Result<Option<ProducerBatch>> {
+        if !self.has_producer {
+            return Ok(None);
+        }
+        if data.len() < self.records_start() || (self.payload_len as usize) < PRODUCER_SIZE {
+            return Err(corrupt(format!("truncated producer in batch {}", self.base_sequence)));
+        }
+        let prefix = &data[BATCH_HEADER_SIZE..];
+        Ok(Some(ProducerBatch {
+            producer_id: u64::from_be_bytes(prefix[..8].try_into().unwrap()),
+            producer_sequence: u64::from_be_bytes(prefix[8..16].try_into().unwrap()),
+            transactional: self.transactional,
+        }))
+    }
+
+    fn write_without_crc(&self, out: &mut Vec<u8>) {
+        out.push(BATCH_MAGIC);
+        out.extend_from_slice(&self.base_sequence.to_be_bytes());
+        out.extend_from_slice(&self.record_count.to_be_bytes());
+        out.extend_from_slice(&self.span.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_ms.to_be_bytes());
+        let mut attributes = self.compression.id();
+        if self.encrypted {
+            attributes |= ENCRYPTED;
+        }
+        if self.has_headers {
+            attributes |= HAS_HEADERS;
+        }
+        if self.has_producer {
+            attributes |= HAS_PRODUCER;
+        }
+        if self.transactional {
+            attributes |= TRANSACTIONAL;
+        }
+        if self.control {
+            attributes |= CONTROL;
+        }
+        out.push(attributes);
+        out.extend_from_slice(&self.payload_len.to_be_bytes());
+    }
+}
+
+/// A record of a batch, with sequence number `base_sequence + offset_delta`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchRecord {
+    pub offset_delta: u32,
+    pub record: Record,
+}
+
+/// Records stored together in one frame, covering the sequence numbers
+/// `[base_sequence, base_sequence + span)`. Fresh batches have no gaps; compaction drops records
+/// but keeps the sequence numbers of the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordBatch {
+    pub base_sequence: u64,
+    pub span: u32,
+    pub timestamp_ms: u64,
+    pub records: Vec<BatchRecord>,
+    /// the idempotent producer or transaction that put the batch, if any
+    pub producer: Option<ProducerBatch>,
+    /// whether this is a transaction marker
+    pub control: bool,
+}
+
+impl RecordBatch {
+    /// A batch giving `records` consecutive sequence numbers from `base_sequence` on.
+    pub fn new(base_sequence: u64, timestamp_ms: u64, records: Vec<Record>) -> RecordBatch {
+        RecordBatch {
+            base_sequence,
+            span: records.len() as u32,
+            timestamp_ms,
+            records: records
+                .into_iter()
+                .enumerate()
+                .map(|(i, record)| BatchRecord { offset_delta: i as u32, record })
+                .collect(),
+            producer: None,
+            control: false,
+        }
+    }
+
+    pub fn with_producer(self, producer: Option<ProducerBatch>) -> RecordBatch {
+        RecordBatch { producer, ..self }
+    }
+
+    /// The marker ending transaction `transaction_id` at `base_sequence`.
+    pub fn marker(base_sequence: u64, timestamp_ms: u64, transaction_id: u64, marker: Marker) -> RecordBatch {
+        let producer = ProducerBatch { producer_id: transaction_id, producer_sequence: marker.id(), transactional: true };
+        RecordBatch { control: true, ..RecordBatch::new(base_sequence, timestamp_ms, vec![Record::new(vec![])]).with_producer(Some(producer)) }
+    }
+
+    pub fn next_sequence(&self) -> u64 {
+        self.base_sequence + self.span as u64
+    }
+
+    /// the records with their sequence numbers
+    pub fn sequenced(self) -> impl Iterator<Item = (u64, Record)> {
+        let base_sequence = self.base_sequence;
+        self.records.into_iter().map(move |r| (base_sequence + r.offset_delta as u64, r.record))
+    }
+
+    pub fn encode(&self, compression: Compression) -> std::io::Result<Vec<u8>> {
+        self.encode_with(compression, None)
+    }
+
+    /// Encodes the batch, encrypting it with `key` when there is one.
+    pub fn encode_with(&self, compression: Compression, key: Option<&BatchKey>) -> std::io::Result<Vec<u8>> {
+        let has_headers = self.records.iter().any(|r| !r.record.headers.is_empty());
+        let mut payload = Vec::new();
+        for BatchRecord { offset_delta, record } in self.records.iter() {
+            payload.extend_from_slice(&offset_delta.to_be_bytes());
+            put_bytes(&mut payload, record.partition_key.as_deref().unwrap_or("").as_bytes());
+            put_bytes(&mut payload, &record.data);
+            if has_headers {
+                payload.extend_from_slice(&(record.headers.len() as u32).to_be_bytes());
+                for (name, value) in record.headers.iter() {
+                    put_bytes(&mut payload, name.as_bytes());
+                    put_bytes(&mut payload, value.as_bytes());
+                }
+            }
+        }
+        let payload = compression.compress(payload)?;
+
+        let mut header = BatchHeader {
+            base_sequence: self.base_sequence,
+            record_count: self.records.len() as u32,
+            span: self.span,
+            timestamp_ms: self.timestamp_ms,
+            compression,
+            encrypted: key.is_some(),
+            has_headers,
+            has_producer: self.producer.is_some(),
+            transactional: self.producer.is_some_and(|p| p.transactional),
+            control: self.control,
+            payload_len: 0,
+            crc: 0,
+        };
+        let mut frame = Vec::with_capacity(BATCH_HEADER_SIZE + payload.len());
+        header.write_without_crc(&mut frame);
+        let mut payload = match key {
+            Some(key) => seal(key, &frame[..BATCH_AAD_SIZE], &payload)?,
+            None => payload,
+        };
+        if let Some(producer) = &self.producer {
+            let mut prefixed = Vec::with_capacity(PRODUCER_SIZE + payload.len());
+            prefixed.extend_from_slice(&producer.producer_id.to_be_bytes());
+            prefixed.extend_from_slice(&producer.producer_sequence.to_be_bytes());
+            prefixed.extend_from_slice(&payload);
+            payload = prefixed;
+        }
+        header.payload_len = payload.len() as u32;
+        frame.clear();
+        header.write_without_crc(&mut frame);
+        let crc = checksum(&frame, &payload);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Decodes the frame at the start of `data`, checking its crc. Encrypted batches need
+    /// `decode_with`.
+    pub fn decode(data: &[u8]) -> std::io::Result<RecordBatch> {
+        RecordBatch::decode_with(data, None)
+    }
+
+    /// Decodes the frame at the start of `data`, decrypting it with a key of `keys` if it is
+    /// encrypted.
+    pub fn decode_with(data: &[u8], keys: Option<&dyn KeyProvider>) -> std::io::Result<RecordBatch> {
+        let header = RecordBatch::verify(data)?;
+        let producer = header.producer(data)?;
+        let payload = &data[header.records_start()..header.frame_len() as usize];
+        let decrypted;
+        let payload = match (header.encrypted, keys) {
+            (false, _) => payload,
+            (true, Some(keys)) => {
+                decrypted = open(keys, &data[..BATCH_AAD_SIZE], payload)?;
+                &decrypted[..]
+            }
+            (true, None) => return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("batch {} is encrypted with key '{}' and there is no key provider", header.base_sequence, sealed_key_id(payload)?),
+            )),
+        };
+
+        let payload = header.compression.decompress(payload)?;
+        let truncated = || corrupt(format!("truncated record in batch {}", header.base_sequence));
+        let utf8 = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|e| corrupt(e.to_string()));
+        let mut records = Vec::with_capacity(header.record_count as usize);
+        let mut rest = &payload[..];
+        for _ in 0..header.record_count {
+            let offset_delta = take_u32(&mut rest).ok_or_else(truncated)?;
+            let key_len = take_u32(&mut rest).ok_or_else(truncated)? as usize;
+            let key = take(&mut rest, key_len).ok_or_else(truncated)?;
+            let len = take_u32(&mut rest).ok_or_else(truncated)? as usize;
+            let data = take(&mut rest, len).ok_or_else(truncated)?;
+            let mut headers = BTreeMap::new();
+            if header.has_headers {
+                for _ in 0..take_u32(&mut rest).ok_or_else(truncated)? {
+                    let name_len = take_u32(&mut rest).ok_or_else(truncated)? as usize;
+                    let name = utf8(take(&mut rest, name_len).ok_or_else(truncated)?)?;
+                    let value_len = take_u32(&mut rest).ok_or_else(truncated)? as usize;
+                    headers.insert(name, utf8(take(&mut rest, value_len).ok_or_else(truncated)?)?);
+                }
+            }
+
+            let in_order = records.last().is_none_or(|r: &BatchRecord| r.offset_delta < offset_delta);
+            if offset_delta >= header.span || !in_order {
+                return Err(corrupt(format!("bad offset delta {} in batch {}", offset_delta, header.base_sequence)));
+            }
+            let partition_key = match key_len {
+                0 => None,
+                _ => Some(utf8(key)?),
+            };
+            records.push(BatchRecord {
+                offset_delta,
+                record: Record { partition_key, data: data.to_vec(), headers },
+            });
+        }
+
+        Ok(RecordBatch {
+            base_sequence: header.base_sequence,
+            span: header.span,
+            timestamp_ms: header.timestamp_ms,
+            records,
+            producer,
+            control: header.control,
+        })
+    }
+
+    /// Checks that a whole frame with a matching crc is at the start of `data`, without
+    /// decrypting or decompressing it.
+    pub fn verify(data: &[u8]) -> std::io::Result<BatchHeader> {
+        let header = BatchHeader::parse(data)?;
+        let frame_len = header.frame_len() as usize;
+        if data.len() < frame_len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated batch payload"));
+        }
+        if checksum(&data[..BATCH_HEADER_SIZE - 4], &data[BATCH_HEADER_SIZE..frame_len]) != header.crc {
+            return Err(corrupt(format!("crc mismatch in batch {}", header.base_sequence)));
+        }
+        Ok(header)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(taken)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Option<u32> {
+    take(rest, 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn corrupt(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::batch::{BATCH_HEADER_SIZE, BatchHeader, Compression, RecordBatch};
+    use crate::shards::encryption::{BatchKey, KeyFile, KeyProvider};
+    use crate::shards::producers::ProducerBatch;
+    use crate::shards::shards::Record;
+    use crate::shards::transactions::Marker;
+
+    fn batch() -> RecordBatch {
+        let json = r#"{"entity":"order","id":42,"status":"shipped","items":["a","b","c"]}"#;
+        let records = (0..50)
+            .map(|i| match i % 2 {
+                0 => Record::new(format!("{}{}", json, i).into_bytes()),
+                _ => Record::keyed(format!("order-{}", i % 7), format!("{}{}", json, i).into_bytes()),
+            })
+            .collect();
+        RecordBatch::new(7, 1577836800000, records)
+    }
+
+    #[test]
+    fn record_batch_roundtrips_with_every_codec() {
+        let uncompressed = batch().encode(Compression::None).unwrap().len();
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4, Compression::Snappy].iter() {
+            let frame = batch().encode(*compression).unwrap();
+            let header = BatchHeader::parse(&frame).unwrap();
+
+            assert_eq!(header.compression, *compression);
+            assert_eq!(header.frame_len(), frame.len() as u64);
+            assert_eq!(header.next_sequence(), 57);
+            assert_eq!(RecordBatch::decode(&frame).unwrap(), batch());
+            if *compression != Compression::None {
+                assert!(frame.len() < uncompressed / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn record_batch_detects_corruption() {
+        let mut frame = batch().encode(Compression::Lz4).unwrap();
+        frame[BATCH_HEADER_SIZE + 3] ^= 0xff;
+        assert!(RecordBatch::decode(&frame).is_err());
+
+        let frame = batch().encode(Compression::None).unwrap();
+        assert!(RecordBatch::decode(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn record_batch_keeps_sequence_numbers_of_a_compacted_batch() {
+        let mut compacted = batch();
+        compacted.records.retain(|r| r.offset_delta % 3 == 1);
+        let frame = compacted.encode(Compression::Snappy).unwrap();
+        let header = BatchHeader::parse(&frame).unwrap();
+
+        assert_eq!(header.record_count, 17);
+        assert_eq!(header.next_sequence(), 57);
+        let decoded = RecordBatch::decode(&frame).unwrap();
+        assert_eq!(decoded, compacted);
+        let sequences: Vec<u64> = decoded.sequenced().map(|(sequence, _)| sequence).take(3).collect();
+        assert_eq!(sequences, vec![8, 11, 14]);
+    }
+
+    #[test]
+    fn record_batch_encrypts_the_payload() {
+        let keys = KeyFile::parse("k1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+        let key = BatchKey { key_id: "k1".to_string(), key: keys.key("k1").unwrap() };
+
+        let frame = batch().encode_with(Compression::Zstd, Some(&key)).unwrap();
+        let header = BatchHeader::parse(&frame).unwrap();
+
+        assert!(header.encrypted);
+        assert_eq!(header.compression, Compression::Zstd);
+        assert!(!frame.windows(5).any(|w| w == b"order"));
+        assert_eq!(RecordBatch::decode_with(&frame, Some(&keys)).unwrap(), batch());
+        assert!(RecordBatch::decode(&frame).is_err());
+        assert_eq!(RecordBatch::verify(&frame).unwrap(), header);
+    }
+
+    #[test]
+    fn record_batch_keeps_its_producer_readable_without_the_key() {
+        let keys = KeyFile::parse("k1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+        let key = BatchKey { key_id: "k1".to_string(), key: keys.key("k1").unwrap() };
+        let producer = ProducerBatch { producer_id: 42, producer_sequence: 1000, transactional: false };
+        let idempotent = batch().with_producer(Some(producer));
+
+        let frame = idempotent.encode_with(Compression::Lz4, Some(&key)).unwrap();
+        let header = BatchHeader::parse(&frame).unwrap();
+
+        assert!(header.has_producer);
+        assert_eq!(header.producer(&frame).unwrap(), Some(producer));
+        assert_eq!(RecordBatch::decode_with(&frame, Some(&keys)).unwrap(), idempotent);
+        let plain = BatchHeader::parse(&batch().encode(Compression::None).unwrap()).unwrap();
+        assert_eq!(plain.records_start(), BATCH_HEADER_SIZE);
+    }
+
+    #[test]
+    fn transaction_markers_are_control_batches_of_their_transaction() {
+        let frame = RecordBatch::marker(90, 1577836800000, 42, Marker::Commit).encode(Compression::None).unwrap();
+        let header = BatchHeader::parse(&frame).unwrap();
+
+        assert!(header.control && header.transactional);
+        assert_eq!(header.next_sequence(), 91);
+        let producer = header.producer(&frame).unwrap().unwrap();
+        assert_eq!(producer.producer_id, 42);
+        assert_eq!(Marker::from_id(producer.producer_sequence), Some(Marker::Commit));
+        assert!(RecordBatch::decode(&frame).unwrap().control);
+    }
+
+    #[test]
+    fn record_batch_carries_headers_only_when_a_record_has_some() {
+        let frame = batch().encode(Compression::None).unwrap();
+        assert!(!BatchHeader::parse(&frame).unwrap().has_headers);
+
+        let mut with_headers = batch();
+        let headers = vec![("source".to_string(), "eu-west".to_string()), ("trace".to_string(), "".to_string())];
+        with_headers.records[3].record.headers = headers.into_iter().collect();
+        let frame = with_headers.encode(Compression::Zstd).unwrap();
+
+        assert!(BatchHeader::parse(&frame).unwrap().has_headers);
+        let decoded = RecordBatch::decode(&frame).unwrap();
+        assert_eq!(decoded, with_headers);
+        assert!(decoded.records[4].record.headers.is_empty());
+    }
+
+    #[test]
+    fn compression_parses_codec_names() {
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd));
+        assert_eq!("lz4".parse(), Ok(Compression::Lz4));
+        assert_eq!("snappy".parse(), Ok(Compression::Snappy));
+        assert_eq!("none".parse(), Ok(Compression::None));
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
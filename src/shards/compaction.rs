@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::{info, Span, warn};
+
+use crate::shards::batch::{BatchHeader, RecordBatch};
+use crate::shards::encryption::{BatchKey, sealed_key_id};
+use crate::shards::index::{IndexEntry, SegmentIndex};
+use crate::shards::segments::{Segment, SegmentManager, SegmentState};
+use crate::shards::shards::now_ms;
+use crate::shards::shutdown::StopSignal;
+
+/// how long a tombstone is kept once it is the latest record of its key, so that slow consumers
+/// still get to see the delete
+pub const DEFAULT_TOMBSTONE_RETENTION_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// What happens to old records of a stream.
+///
+/// With `Compact`, a background compactor rewrites the sealed segments keeping only the latest
+/// record of each partition key. Records without a key are never compacted away.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CleanupPolicy {
+    #[default]
+    None,
+    Compact,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(CleanupPolicy::None),
+            "compact" => Ok(CleanupPolicy::Compact),
+            other => Err(format!("unknown cleanup policy '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for CleanupPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CleanupPolicy::None => "none",
+            CleanupPolicy::Compact => "compact",
+        })
+    }
+}
+
+/// One compaction pass over the sealed segments of `segments`, returning how many records were
+/// removed.
+///
+/// A record is kept when it has no partition key, or when it is the latest record of its key in
+/// the sealed segments and not a tombstone older than `tombstone_retention_ms`. Kept records keep
+/// their sequence numbers and batches keep their timestamp and codec. Segments are only rewritten
+/// when something is removed from them, and the catalog is only locked to swap them in.
+pub fn compact(segments: &RwLock<SegmentManager>, tombstone_retention_ms: u64) -> std::io::Result<usize> {
+    let sealed: Vec<Segment> = segments
+        .read()
+        .unwrap()
+        .segments()
+        .filter(|s| s.state == SegmentState::Sealed)
+        .cloned()
+        .collect();
+
+    let mut latest: HashMap<String, u64> = HashMap::new();
+    for segment in sealed.iter() {
+        for_each_batch(segments, segment, |batch, _, _| {
+            for (sequence, record) in batch.sequenced() {
+                if let Some(key) = record.partition_key {
+                    latest.insert(key, sequence);
+                }
+            }
+        })?;
+    }
+
+    let now = now_ms();
+    let mut removed = 0;
+    for segment in sealed.iter() {
+        let mut compacted = Vec::new();
+        let mut segment_removed = 0;
+        for_each_batch(segments, segment, |mut batch, header, key| {
+            let before = batch.records.len();
+            let base_sequence = batch.base_sequence;
+            let expired = batch.timestamp_ms.saturating_add(tombstone_retention_ms) <= now;
+            batch.records.retain(|r| match &r.record.partition_key {
+                None => true,
+                Some(key) => {
+                    latest.get(key) == Some(&(base_sequence + r.offset_delta as u64))
+                        && !(r.record.is_tombstone() && expired)
+                }
+            });
+            segment_removed += before - batch.records.len();
+            compacted.push((batch, header, key));
+        })?;
+        if segment_removed == 0 {
+            continue;
+        }
+
+        let (index, size) = write_compacted(segments, segment, compacted)?;
+        if segments.write().unwrap().replace_compacted(segment.base_offset, index, size)? {
+            removed += segment_removed;
+        }
+    }
+    Ok(removed)
+}
+
+/// Decodes the batches of a sealed segment in order, along with the key of the encrypted ones.
+/// A segment deleted since the catalog was read has no batches left.
+fn for_each_batch<F>(segments: &RwLock<SegmentManager>, segment: &Segment, mut f: F) -> std::io::Result<()>
+    where F: FnMut(RecordBatch, BatchHeader, Option<BatchKey>)
+{
+    let (map, keys) = {
+        let segments = segments.read().unwrap();
+        match segments.mmap(segment) {
+            Ok(map) => (map, segments.keys.clone()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    };
+
+    let end = (segment.size as usize).min(map.len());
+    let mut position = 0;
+    while position < end {
+        let frame = &map[position..end];
+        let header = BatchHeader::parse(frame)?;
+        let batch = RecordBatch::decode_with(frame, keys.as_deref())?;
+        let key = match (header.encrypted, &keys) {
+            (true, Some(keys)) => {
+                let key_id = sealed_key_id(&frame[header.records_start()..])?;
+                Some(BatchKey { key_id: key_id.to_string(), key: keys.key(key_id)? })
+            }
+            _ => None,
+        };
+        position += header.frame_len() as usize;
+        f(batch, header, key);
+    }
+    Ok(())
+}
+
+/// Writes the batches that still have records to the compacted copy of `segment`, returning its
+/// index and size.
+fn write_compacted(
+    segments: &RwLock<SegmentManager>,
+    segment: &Segment,
+    batches: Vec<(RecordBatch, BatchHeader, Option<BatchKey>)>,
+) -> std::io::Result<(SegmentIndex, u64)> {
+    let path = segments.read().unwrap().shard_dir.path_to_compacted(segment.base_offset);
+    let file = File::create(&path)?;
+    let mut writer = BufWriter::new(&file);
+
+    let mut index = SegmentIndex::default();
+    let mut position = 0;
+    for (batch, header, key) in batches.into_iter().filter(|(b, _, _)| !b.records.is_empty()) {
+        let frame = batch.encode_with(header.compression, key.as_ref())?;
+        writer.write_all(&frame)?;
+        index.push(IndexEntry { sequence: batch.base_sequence, position });
+        position += frame.len() as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_data()?;
+    Ok((index, position))
+}
+
+/// Background thread of compacted streams: every `interval_ms` it runs a compaction pass.
+pub fn spawn_compactor(
+    segments: Arc<RwLock<SegmentManager>>,
+    tombstone_retention_ms: u64,
+    interval_ms: u64,
+    stop: Arc<StopSignal>,
+) -> JoinHandle<()> {
+    // stops with the shard, when nobody else holds its segments, or when it shuts down
+    let span = Span::current();
+    thread::spawn(move || while Arc::strong_count(&segments) > 1 && stop.sleep(Duration::from_millis(interval_ms)) {
+
+        let _entered = span.enter();
+        match compact(&segments, tombstone_retention_ms) {
+            Ok(0) => {}
+            Ok(removed) => info!(removed, "compaction removed records"),
+            Err(e) => warn!(error = %e, "compaction failed"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::shards::batch::Compression;
+    use crate::shards::compaction::{CleanupPolicy, compact};
+    use crate::shards::durability::{FsyncPolicy, FsyncStats};
+    use crate::shards::segments::SegmentManager;
+    use crate::shards::shards::{Record, ShardDir, ShardReader, ShardWriter, ShaW};
+    use crate::test_util::with_tmp_dir;
+
+    fn shard_writer(shard_dir: &ShardDir) -> ShardWriter {
+        ShardWriter {
+            segments: Arc::new(RwLock::new(SegmentManager::open(shard_dir.clone(), 200).unwrap())),
+            compression: Compression::Lz4,
+            encryption: None,
+            fsync_policy: FsyncPolicy::None,
+            unsynced_records: Arc::new(AtomicUsize::new(0)),
+            fsync_stats: Arc::new(FsyncStats::default()),
+            producers: Arc::default(),
+            transactions: Arc::default(),
+        }
+    }
+
+    fn read_all(segments: &Arc<RwLock<SegmentManager>>, shard_dir: &ShardDir) -> Vec<(u64, Record)> {
+        let mut reader = ShardReader {
+            segments: segments.clone(),
+            position: 0,
+            chunk_size: 4,
+            shard_dir: shard_dir.clone(),
+            aborted: vec![],
+        };
+        let mut read = Vec::new();
+        loop {
+            let records = reader.read_sequenced().unwrap();
+            if records.is_empty() {
+                return read;
+            }
+            read.extend(records);
+        }
+    }
+
+    fn keyed(key: &str, data: &str) -> Record {
+        Record::keyed(key.to_string(), data.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn compact_keeps_the_latest_record_of_each_key_with_its_sequence_number() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let mut writer = shard_writer(&shard_dir);
+            for i in 0..60 {
+                let key = format!("order-{}", i % 5);
+                writer.write(vec![keyed(&key, &format!("meucu_tem_oculos_{}", i))]).unwrap();
+            }
+            writer.write(vec![Record::new(b"no key".to_vec()), keyed("order-0", "")]).unwrap();
+            writer.write(vec![keyed("order-1", "meucu_tem_oculos_62")]).unwrap();
+            let segments = writer.segments.clone();
+            let active = segments.read().unwrap().active().base_offset;
+            assert!(segments.read().unwrap().len() > 5);
+
+            let removed = compact(&segments, 60 * 1000).unwrap();
+            let read = read_all(&segments, &shard_dir);
+            let sealed: Vec<&(u64, Record)> = read.iter().filter(|(sequence, _)| *sequence < active).collect();
+
+            assert!(removed > 40);
+            let keys: Vec<&String> = sealed.iter().filter_map(|(_, r)| r.partition_key.as_ref()).collect();
+            let mut unique_keys = keys.clone();
+            unique_keys.sort();
+            unique_keys.dedup();
+            assert_eq!(keys.len(), unique_keys.len());
+            for (sequence, record) in read.iter().filter(|(sequence, _)| *sequence < 60) {
+                assert_eq!(record.data, format!("meucu_tem_oculos_{}", sequence).into_bytes());
+            }
+            assert_eq!(read.last().unwrap(), &(62, keyed("order-1", "meucu_tem_oculos_62")));
+            assert_eq!(segments.read().unwrap().end_offset(), 63);
+
+            // compacting again removes nothing, and what is left survives a reopen
+            assert_eq!(compact(&segments, 60 * 1000).unwrap(), 0);
+            let reopened = Arc::new(RwLock::new(SegmentManager::open(shard_dir.clone(), 200).unwrap()));
+            assert_eq!(read_all(&reopened, &shard_dir), read);
+        })
+    }
+
+    #[test]
+    fn compact_removes_tombstones_after_the_grace_period() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let mut writer = shard_writer(&shard_dir);
+            writer.write(vec![keyed("a", "meucu_tem_oculos"), keyed("b", "meucu_tem_oculos")]).unwrap();
+            writer.write(vec![keyed("a", "")]).unwrap();
+            while writer.segments.read().unwrap().len() < 3 {
+                writer.write(vec![Record::new(b"filler".to_vec())]).unwrap();
+            }
+            let segments = writer.segments.clone();
+
+            compact(&segments, 60 * 1000).unwrap();
+            let keyed_records: Vec<(u64, Record)> = read_all(&segments, &shard_dir)
+                .into_iter()
+                .filter(|(_, r)| r.partition_key.is_some())
+                .collect();
+            assert_eq!(keyed_records, vec![(1, keyed("b", "meucu_tem_oculos")), (2, keyed("a", ""))]);
+
+            compact(&segments, 0).unwrap();
+            let keyed_records: Vec<(u64, Record)> = read_all(&segments, &shard_dir)
+                .into_iter()
+                .filter(|(_, r)| r.partition_key.is_some())
+                .collect();
+            assert_eq!(keyed_records, vec![(1, keyed("b", "meucu_tem_oculos"))]);
+        })
+    }
+
+    #[test]
+    fn cleanup_policy_parses_names() {
+        assert_eq!("compact".parse(), Ok(CleanupPolicy::Compact));
+        assert_eq!("none".parse(), Ok(CleanupPolicy::None));
+        assert!("delete".parse::<CleanupPolicy>().is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::{Span, warn};
+
+use crate::metrics::{Histogram, HistogramSnapshot};
+use crate::shards::segments::SegmentManager;
+
+/// When the shard writer calls `sync_data` on the active segment.
+///
+/// A put is only acknowledged after the policy is satisfied, so `Always` never loses an acked
+/// record, `EveryNRecords(n)` can lose up to n - 1, `Interval(ms)` up to the last `ms`
+/// milliseconds of writes and `None` whatever the OS had not flushed yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FsyncPolicy {
+    #[default]
+    None,
+    Interval(u64),
+    EveryNRecords(u64),
+    Always,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// accepts `none`, `always`, `interval(<ms>)` and `every_n_records(<n>)`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let argument = |prefix: &str| -> Option<Result<u64, String>> {
+            let inner = s.strip_prefix(prefix)?.strip_prefix('(')?.strip_suffix(')')?;
+            Some(match inner.trim().parse::<u64>() {
+                Ok(0) => Err(format!("{} must be greater than 0", prefix)),
+                Ok(n) => Ok(n),
+                Err(e) => Err(format!("invalid {} argument '{}': {}", prefix, inner, e)),
+            })
+        };
+
+        match s {
+            "none" => Ok(FsyncPolicy::None),
+            "always" => Ok(FsyncPolicy::Always),
+            _ => {
+                if let Some(ms) = argument("interval") {
+                    return ms.map(FsyncPolicy::Interval);
+                }
+                if let Some(n) = argument("every_n_records") {
+                    return n.map(FsyncPolicy::EveryNRecords);
+                }
+                Err(format!("unknown fsync policy '{}'", s))
+            }
+        }
+    }
+}
+
+/// written the way `from_str` reads it
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsyncPolicy::None => write!(f, "none"),
+            FsyncPolicy::Interval(ms) => write!(f, "interval({})", ms),
+            FsyncPolicy::EveryNRecords(n) => write!(f, "every_n_records({})", n),
+            FsyncPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+impl FsyncPolicy {
+    /// whether a write that left `unsynced_records` records unsynced must be synced before it
+    /// is acknowledged
+    pub fn must_sync(&self, unsynced_records: u64) -> bool {
+        match *self {
+            FsyncPolicy::None | FsyncPolicy::Interval(_) => false,
+            FsyncPolicy::EveryNRecords(n) => unsynced_records >= n,
+            FsyncPolicy::Always => true,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct FsyncStats {
+    latency: Histogram,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct FsyncMetrics {
+    pub fsyncs: u64,
+    pub latency: HistogramSnapshot,
+}
+
+impl FsyncStats {
+    pub fn record(&self, elapsed: Duration) {
+        self.latency.observe(elapsed);
+    }
+
+    pub fn metrics(&self) -> FsyncMetrics {
+        let latency = self.latency.snapshot();
+        FsyncMetrics { fsyncs: latency.count, latency }
+    }
+}
+
+pub fn timed_sync(file: &File, stats: &FsyncStats) -> std::io::Result<()> {
+    let start = Instant::now();
+    file.sync_data()?;
+    stats.record(start.elapsed());
+    Ok(())
+}
+
+/// Background thread backing `FsyncPolicy::Interval`: every `interval_ms` it syncs the active
+/// segment if anything was written to it since the last sync.
+pub fn spawn_interval_flusher(
+    segments: Arc<RwLock<SegmentManager>>,
+    unsynced_records: Arc<AtomicUsize>,
+    stats: Arc<FsyncStats>,
+    interval_ms: u64,
+) -> JoinHandle<()> {
+    // the shard was dropped once this thread holds the last reference to its segments
+    let span = Span::current();
+    thread::spawn(move || while Arc::strong_count(&segments) > 1 {
+        thread::sleep(Duration::from_millis(interval_ms));
+        let _entered = span.enter();
+
+        let pending = unsynced_records.swap(0, Ordering::AcqRel);
+        if pending == 0 {
+            continue;
+        }
+
+        let path = {
+            let segments = segments.read().unwrap();
+            segments.path_to(segments.active())
+        };
+        let synced = File::open(&path).and_then(|f| timed_sync(&f, &stats));
+        if let Err(e) = synced {
+            warn!(path = %path.display(), error = %e, "interval fsync failed");
+            unsynced_records.fetch_add(pending, Ordering::AcqRel);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::durability::FsyncPolicy;
+
+    #[test]
+    fn fsync_policy_parses_all_modes() {
+        assert_eq!("none".parse(), Ok(FsyncPolicy::None));
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("interval(250)".parse(), Ok(FsyncPolicy::Interval(250)));
+        assert_eq!("every_n_records(10)".parse(), Ok(FsyncPolicy::EveryNRecords(10)));
+        assert!("every_n_records(0)".parse::<FsyncPolicy>().is_err());
+        assert!("interval".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn fsync_policy_must_sync() {
+        assert!(!FsyncPolicy::None.must_sync(100));
+        assert!(!FsyncPolicy::Interval(10).must_sync(100));
+        assert!(!FsyncPolicy::EveryNRecords(3).must_sync(2));
+        assert!(FsyncPolicy::EveryNRecords(3).must_sync(3));
+        assert!(FsyncPolicy::Always.must_sync(1));
+    }
+}
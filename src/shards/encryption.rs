@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use rand::RngCore;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// Hands out the AES-256 keys batches are encrypted with, by key id. Key ids are stored in every
+/// encrypted batch, so a provider has to keep serving retired keys for as long as batches written
+/// with them are around.
+pub trait KeyProvider: Send + Sync {
+    fn key(&self, key_id: &str) -> std::io::Result<[u8; KEY_SIZE]>;
+}
+
+/// Keys read from a local file with one `<key id> <64 hex digits>` line per key. Blank lines and
+/// lines starting with `#` are skipped.
+#[derive(Default)]
+pub struct KeyFile {
+    keys: HashMap<String, [u8; KEY_SIZE]>,
+}
+
+impl KeyFile {
+    pub fn load(path: &Path) -> std::io::Result<KeyFile> {
+        KeyFile::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> std::io::Result<KeyFile> {
+        let mut keys = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("keyfile line {}: {}", number + 1, reason));
+
+            let mut fields = line.split_whitespace();
+            let (key_id, key) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(key_id), Some(key), None) => (key_id, key),
+                _ => return Err(invalid("expected a key id and a key")),
+            };
+            if key_id.len() > u8::MAX as usize {
+                return Err(invalid("key id is too long"));
+            }
+            let key: [u8; KEY_SIZE] = hex::decode(key)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| invalid("key must be 64 hex digits"))?;
+            keys.insert(key_id.to_string(), key);
+        }
+        Ok(KeyFile { keys })
+    }
+}
+
+impl KeyProvider for KeyFile {
+    fn key(&self, key_id: &str) -> std::io::Result<[u8; KEY_SIZE]> {
+        self.keys
+            .get(key_id)
+            .copied()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("unknown encryption key '{}'", key_id)))
+    }
+}
+
+/// The key new batches of a stream are encrypted with.
+#[derive(Clone)]
+pub struct BatchKey {
+    pub key_id: String,
+    pub key: [u8; KEY_SIZE],
+}
+
+/// Encrypts a batch payload, authenticating `aad` along with it, into
+/// `key_id_len u8 | key_id | nonce | ciphertext`.
+pub fn seal(key: &BatchKey, aad: &[u8], plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key.key))
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| Error::other("batch encryption failed"))?;
+
+    let mut sealed = Vec::with_capacity(1 + key.key_id.len() + NONCE_SIZE + ciphertext.len());
+    sealed.push(key.key_id.len() as u8);
+    sealed.extend_from_slice(key.key_id.as_bytes());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// the key id a sealed payload was encrypted with
+pub fn sealed_key_id(sealed: &[u8]) -> std::io::Result<&str> {
+    let len = *sealed.first().ok_or_else(|| corrupt("empty encrypted payload"))? as usize;
+    let key_id = sealed.get(1..1 + len).ok_or_else(|| corrupt("truncated key id"))?;
+    std::str::from_utf8(key_id).map_err(|_| corrupt("key id is not utf-8"))
+}
+
+/// Decrypts what `seal` produced, with the key it names.
+pub fn open(keys: &dyn KeyProvider, aad: &[u8], sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let key_id = sealed_key_id(sealed)?;
+    let key = keys.key(key_id)?;
+    let rest = &sealed[1 + key_id.len()..];
+    if rest.len() < NONCE_SIZE {
+        return Err(corrupt("truncated nonce"));
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| corrupt(&format!("batch does not decrypt with key '{}'", key_id)))
+}
+
+/// Where the key id of an encrypted stream is kept, so that encryption survives restarts.
+pub fn load_stream_key_id(path: &Path) -> std::io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(key_id) => Ok(Some(key_id.trim().to_string()).filter(|k| !k.is_empty())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn store_stream_key_id(path: &Path, key_id: Option<&str>) -> std::io::Result<()> {
+    match key_id {
+        Some(key_id) => fs::write(path, key_id),
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::encryption::{BatchKey, KeyFile, KeyProvider, open, seal};
+
+    const KEYS: &str = "
+        # rotated on 2020-01-01
+        old 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+        new 1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100
+    ";
+
+    #[test]
+    fn seal_and_open_with_the_key_named_in_the_payload() {
+        let keys = KeyFile::parse(KEYS).unwrap();
+        let key = BatchKey { key_id: "old".to_string(), key: keys.key("old").unwrap() };
+
+        let sealed = seal(&key, b"header", b"meucu_tem_oculos").unwrap();
+
+        assert!(!sealed.windows(16).any(|w| w == b"meucu_tem_oculos"));
+        assert_eq!(open(&keys, b"header", &sealed).unwrap(), b"meucu_tem_oculos".to_vec());
+        assert!(open(&keys, b"other header", &sealed).is_err());
+        assert!(open(&KeyFile::default(), b"header", &sealed).is_err());
+    }
+
+    #[test]
+    fn key_file_rejects_malformed_lines() {
+        assert!(KeyFile::parse("k1 0011").is_err());
+        assert!(KeyFile::parse("k1").is_err());
+        assert!(KeyFile::parse("k1 zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").is_err());
+        assert!(KeyFile::parse(KEYS).unwrap().key("missing").is_err());
+    }
+}
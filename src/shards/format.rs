@@ -0,0 +1,309 @@
+use std::convert::TryInto;
+use std::fs;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::shards::batch::{BATCH_MAGIC, BatchHeader, Compression, RecordBatch};
+use crate::shards::index::{IndexEntry, SegmentIndex};
+use crate::shards::shards::{Record, SegmentId, ShardDir};
+
+/// Version of the layout of a shard dir this build reads and writes: segments of framed batches
+/// named by the sequence number they start at, with index sidecars. Version 0, which has no
+/// version file, is the legacy one of a base64 record per line with segments named by byte
+/// offset.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// how many legacy records go in each batch of a migrated segment
+const MIGRATION_BATCH_RECORDS: usize = 500;
+
+/// Makes sure the shard dir is in `FORMAT_VERSION`, before anything reads it. A dir without a
+/// version file is either new, or from before versioning and already framed, and is stamped with
+/// the current version; legacy segments and versions from newer builds are refused.
+pub fn check_format(shard_dir: &ShardDir) -> std::io::Result<()> {
+    let path = shard_dir.path_to_format_version();
+    match fs::read_to_string(&path) {
+        Ok(version) => {
+            let version: u32 = version.trim().parse().map_err(|_| Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not hold a format version: {:?}", path.display(), version),
+            ))?;
+            if version != FORMAT_VERSION {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is in format version {}, this build only reads version {}", shard_dir.mount_dir.display(), version, FORMAT_VERSION),
+                ));
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            if holds_legacy_segments(shard_dir)? {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} holds segments of the legacy newline format, migrate it first with `rinites-segment migrate {}`",
+                        shard_dir.mount_dir.display(), shard_dir.mount_dir.display(),
+                    ),
+                ));
+            }
+            write_format_version(shard_dir)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// whether the first segment that is not empty starts with something else than a batch
+fn holds_legacy_segments(shard_dir: &ShardDir) -> std::io::Result<bool> {
+    let mut base_offsets = shard_dir.list_segments()?;
+    base_offsets.sort_unstable();
+    for base_offset in base_offsets {
+        if let Some(first) = first_byte(&shard_dir.path_to_segment(base_offset))? {
+            return Ok(first != BATCH_MAGIC);
+        }
+    }
+    Ok(false)
+}
+
+fn first_byte(path: &Path) -> std::io::Result<Option<u8>> {
+    let mut byte = [0; 1];
+    match File::open(path)?.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn write_format_version(shard_dir: &ShardDir) -> std::io::Result<()> {
+    let path = shard_dir.path_to_format_version();
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(format!("{}\n", FORMAT_VERSION).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// What migrating a shard dir did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Migration {
+    pub segments: usize,
+    pub records: u64,
+    /// bytes of a last line without a newline, left by a crash mid-append and dropped
+    pub torn_bytes: u64,
+}
+
+/// Rewrites the legacy segments of a shard dir as framed batches, keeping the segments apart
+/// and every record in order. Sequence numbers start right past the legacy log end, the byte
+/// offset after the last record, so that no legacy shard iterator is a sequence number too. The
+/// old segments are kept under `legacy/`, and where each record was, the byte offset legacy
+/// shard iterators point at, is written to the `legacy-offsets` file for `LegacyOffsets`.
+/// Batches get the time their segment was last written to as timestamp.
+///
+/// Only for dirs no server has open. The version file is written last, and a migration that was
+/// interrupted is started over.
+pub fn migrate_legacy(shard_dir: &ShardDir) -> std::io::Result<Migration> {
+    if shard_dir.path_to_format_version().exists() {
+        check_format(shard_dir)?;
+        return Ok(Migration::default());
+    }
+    let legacy_dir = shard_dir.mount_dir.join("legacy");
+    let migrating = ShardDir { mount_dir: shard_dir.mount_dir.join("migrating") };
+    match fs::remove_dir_all(&migrating.mount_dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::create_dir_all(&legacy_dir)?;
+    fs::create_dir(&migrating.mount_dir)?;
+
+    // segments already moved away by an interrupted migration, then those still in place;
+    // framed ones in place are what it had moved in, and are redone
+    let legacy = ShardDir { mount_dir: legacy_dir.clone() };
+    let mut sources: Vec<(SegmentId, ShardDir)> = legacy.list_segments()?.into_iter().map(|b| (b, legacy.clone())).collect();
+    for base_offset in shard_dir.list_segments()? {
+        let path = shard_dir.path_to_segment(base_offset);
+        match first_byte(&path)? {
+            Some(BATCH_MAGIC) => {
+                fs::remove_file(&path)?;
+                let _ = fs::remove_file(shard_dir.path_to_index(base_offset));
+            }
+            _ if sources.iter().any(|(b, _)| *b == base_offset) => fs::remove_file(&path)?,
+            _ => sources.push((base_offset, shard_dir.clone())),
+        }
+    }
+    sources.sort_unstable_by_key(|(base_offset, _)| *base_offset);
+    let legacy_end = match sources.last() {
+        Some((base_offset, dir)) => base_offset + complete_len(&fs::read(dir.path_to_segment(*base_offset))?) as u64,
+        None => 0,
+    };
+    let first_sequence = legacy_end + 1;
+
+    let mut migration = Migration::default();
+    let mut offsets = Vec::new();
+    for (base_offset, dir) in sources.iter() {
+        let path = dir.path_to_segment(*base_offset);
+        let data = fs::read(&path)?;
+        let timestamp_ms = fs::metadata(&path)?.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let complete = complete_len(&data);
+        migration.torn_bytes += (data.len() - complete) as u64;
+
+        let mut records = Vec::new();
+        let mut position = 0;
+        let lines = match complete {
+            0 => None,
+            _ => Some(data[..complete - 1].split(|b| *b == b'\n')),
+        };
+        for line in lines.into_iter().flatten() {
+            let invalid = |reason: String| Error::new(ErrorKind::InvalidData, format!("{} at {}: {}", path.display(), position, reason));
+            let line = std::str::from_utf8(line).map_err(|e| invalid(e.to_string()))?;
+            let record = base64::decode(line).map_err(|e| invalid(e.to_string()))?;
+            offsets.extend_from_slice(&(base_offset + position as u64).to_be_bytes());
+            offsets.extend_from_slice(&(first_sequence + migration.records + records.len() as u64).to_be_bytes());
+            records.push(Record::new(record));
+            position += line.len() + 1;
+        }
+        if records.is_empty() {
+            continue;
+        }
+        let count = records.len() as u64;
+        write_segment(&migrating, first_sequence + migration.records, timestamp_ms, records)?;
+        migration.records += count;
+        migration.segments += 1;
+    }
+    // the legacy log end, where caught up legacy iterators point, is the migrated log end
+    offsets.extend_from_slice(&legacy_end.to_be_bytes());
+    offsets.extend_from_slice(&(first_sequence + migration.records).to_be_bytes());
+    if migration.records == 0 {
+        File::create(migrating.path_to_segment(first_sequence))?;
+    }
+    let mut file = File::create(migrating.mount_dir.join(LEGACY_OFFSETS))?;
+    file.write_all(&offsets)?;
+    file.sync_all()?;
+
+    for (base_offset, dir) in sources.iter() {
+        if dir.mount_dir != legacy_dir {
+            fs::rename(dir.path_to_segment(*base_offset), legacy.path_to_segment(*base_offset))?;
+        }
+    }
+    for entry in fs::read_dir(&migrating.mount_dir)? {
+        let entry = entry?;
+        fs::rename(entry.path(), shard_dir.mount_dir.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&migrating.mount_dir)?;
+    write_format_version(shard_dir)?;
+    Ok(migration)
+}
+
+/// file of a migrated shard dir mapping legacy shard iterators to sequence numbers
+const LEGACY_OFFSETS: &str = "legacy-offsets";
+
+/// how many bytes of a legacy segment are whole lines, a torn last one left out
+fn complete_len(data: &[u8]) -> usize {
+    data.iter().rposition(|b| *b == b'\n').map_or(0, |last| last + 1)
+}
+
+/// writes `records` as segment `base_sequence` of `shard_dir`, with its index
+fn write_segment(shard_dir: &ShardDir, base_sequence: u64, timestamp_ms: u64, records: Vec<Record>) -> std::io::Result<()> {
+    let mut data = Vec::new();
+    let mut index = SegmentIndex::default();
+    let mut sequence = base_sequence;
+    for chunk in records.chunks(MIGRATION_BATCH_RECORDS) {
+        let frame = RecordBatch::new(sequence, timestamp_ms, chunk.to_vec()).encode(Compression::None)?;
+        index.push(IndexEntry { sequence, position: data.len() as u64 });
+        sequence = BatchHeader::parse(&frame)?.next_sequence();
+        data.extend_from_slice(&frame);
+    }
+    let mut file = File::create(shard_dir.path_to_segment(base_sequence))?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    index.store(&shard_dir.path_to_index(base_sequence))
+}
+
+/// Where the records of a migrated shard dir were in the legacy format, for the shard iterators
+/// of that format, which were byte offsets. They are all up to the legacy log end, below every
+/// sequence number of the migrated dir.
+#[derive(Debug)]
+pub struct LegacyOffsets {
+    /// legacy offset and sequence number of every record, then of the log end
+    pairs: Vec<(u64, u64)>,
+}
+
+impl LegacyOffsets {
+    /// The `legacy-offsets` file of a migrated shard dir, `None` for dirs that were not migrated
+    /// or whose file was removed.
+    pub fn load(shard_dir: &ShardDir) -> std::io::Result<Option<LegacyOffsets>> {
+        let bytes = match fs::read(shard_dir.mount_dir.join(LEGACY_OFFSETS)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let pairs = bytes
+            .chunks_exact(16)
+            .map(|pair| (u64::from_be_bytes(pair[..8].try_into().unwrap()), u64::from_be_bytes(pair[8..].try_into().unwrap())))
+            .collect();
+        Ok(Some(LegacyOffsets { pairs }))
+    }
+
+    /// The sequence number legacy shard iterator `offset` stands for: the one of the first record
+    /// at or after it. `None` past the legacy log end, where `offset` is a sequence number already.
+    pub fn sequence(&self, offset: u64) -> Option<u64> {
+        let following = self.pairs.partition_point(|(legacy_offset, _)| *legacy_offset < offset);
+        self.pairs.get(following).map(|(_, sequence)| *sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::shards::format::{check_format, LegacyOffsets, migrate_legacy, Migration};
+    use crate::shards::segments::SegmentManager;
+    use crate::shards::shards::{ShardDir, ShardReader};
+    use crate::test_util::with_tmp_dir;
+
+    #[test]
+    fn legacy_segments_are_refused_until_migrated_and_keep_their_offsets_mappable() {
+        with_tmp_dir(|mount_dir| {
+            fs::create_dir(&mount_dir).unwrap();
+            let shard_dir = ShardDir { mount_dir };
+            let line = |data: &str| format!("{}\n", base64::encode(data.as_bytes()));
+            let first = line("meucu_tem_oculos_0") + &line("meucu_tem_oculos_1");
+            fs::write(shard_dir.path_to_segment(0), &first).unwrap();
+            // and a torn line at the end of the active segment
+            fs::write(shard_dir.path_to_segment(first.len() as u64), line("meucu_tem_oculos_2") + "bWV1").unwrap();
+            let legacy_end = (first.len() + line("meucu_tem_oculos_2").len()) as u64;
+
+            let refused = SegmentManager::open(shard_dir.clone(), 1024).err().unwrap();
+            assert!(refused.to_string().contains("rinites-segment migrate"));
+
+            let migration = migrate_legacy(&shard_dir).unwrap();
+            assert_eq!(migration, Migration { segments: 2, records: 3, torn_bytes: 4 });
+            assert_eq!(migrate_legacy(&shard_dir).unwrap(), Migration::default());
+
+            // sequence numbers start past every legacy shard iterator
+            let segments = SegmentManager::open(shard_dir.clone(), 1024).unwrap();
+            assert_eq!(segments.oldest_offset(), legacy_end + 1);
+            assert_eq!(segments.end_offset(), legacy_end + 4);
+            let mut reader = ShardReader {
+                segments: std::sync::Arc::new(std::sync::RwLock::new(segments)),
+                position: 0,
+                chunk_size: 10,
+                shard_dir: shard_dir.clone(),
+                aborted: vec![],
+            };
+            let read: Vec<Vec<u8>> = reader.read().unwrap().into_iter().map(|r| r.data).collect();
+            assert_eq!(read, vec![b"meucu_tem_oculos_0".to_vec(), b"meucu_tem_oculos_1".to_vec(), b"meucu_tem_oculos_2".to_vec()]);
+
+            let second_line = line("meucu_tem_oculos_0").len() as u64;
+            let legacy_offsets = LegacyOffsets::load(&shard_dir).unwrap().unwrap();
+            assert_eq!(legacy_offsets.sequence(0), Some(legacy_end + 1));
+            assert_eq!(legacy_offsets.sequence(second_line), Some(legacy_end + 2));
+            assert_eq!(legacy_offsets.sequence(first.len() as u64), Some(legacy_end + 3));
+            assert_eq!(legacy_offsets.sequence(legacy_end), Some(legacy_end + 4));
+            assert_eq!(legacy_offsets.sequence(legacy_end + 1), None);
+            assert!(shard_dir.mount_dir.join("legacy").join("00000000").exists());
+
+            fs::write(shard_dir.path_to_format_version(), "2\n").unwrap();
+            let refused = check_format(&shard_dir).err().unwrap();
+            assert!(refused.to_string().contains("format version 2"));
+        });
+    }
+}
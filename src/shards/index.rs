@@ -0,0 +1,139 @@
+use std::convert::TryInto;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+pub const INDEX_ENTRY_SIZE: usize = 16;
+
+/// Where a batch starts: the sequence number of its first record and the position of its frame
+/// in the segment file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub sequence: u64,
+    pub position: u64,
+}
+
+impl IndexEntry {
+    fn to_bytes(self) -> [u8; INDEX_ENTRY_SIZE] {
+        let mut bytes = [0; INDEX_ENTRY_SIZE];
+        bytes[..8].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.position.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> IndexEntry {
+        IndexEntry {
+            sequence: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            position: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// One entry per batch of a segment, in the order they were written. It lives in memory and in a
+/// sidecar file next to the segment, which can always be rebuilt from the segment itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SegmentIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl SegmentIndex {
+    /// Reads the index file at `path`. A missing file is an empty index and a trailing partial
+    /// entry, left by a crash mid-append, is ignored.
+    pub fn load(path: &Path) -> std::io::Result<SegmentIndex> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let entries = bytes
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(IndexEntry::from_bytes)
+            .collect();
+        Ok(SegmentIndex { entries })
+    }
+
+    /// Replaces the index file at `path` with this index.
+    pub fn store(&self, path: &Path) -> std::io::Result<()> {
+        let bytes: Vec<u8> = self.entries.iter().flat_map(|e| e.to_bytes().to_vec()).collect();
+        fs::write(path, bytes)
+    }
+
+    /// Adds `entry` to the index and appends it to the index file at `path`.
+    pub fn append(&mut self, path: &Path, entry: IndexEntry) -> std::io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&entry.to_bytes())?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub fn push(&mut self, entry: IndexEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    pub fn last(&self) -> Option<&IndexEntry> {
+        self.entries.last()
+    }
+
+    /// The batch that holds `sequence`: the last one starting at or before it, or the first batch
+    /// when `sequence` comes before all of them.
+    pub fn lookup(&self, sequence: u64) -> Option<IndexEntry> {
+        let following = self.entries.partition_point(|e| e.sequence <= sequence);
+        self.entries.get(following.saturating_sub(1)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use rand::{Rng, thread_rng};
+    use rand::distributions::Alphanumeric;
+
+    use crate::shards::index::{IndexEntry, SegmentIndex};
+
+    #[test]
+    fn segment_index_looks_up_the_batch_holding_a_sequence() {
+        let mut index = SegmentIndex::default();
+        index.push(IndexEntry { sequence: 10, position: 0 });
+        index.push(IndexEntry { sequence: 15, position: 100 });
+        index.push(IndexEntry { sequence: 30, position: 250 });
+
+        assert_eq!(index.lookup(3), Some(IndexEntry { sequence: 10, position: 0 }));
+        assert_eq!(index.lookup(14), Some(IndexEntry { sequence: 10, position: 0 }));
+        assert_eq!(index.lookup(15), Some(IndexEntry { sequence: 15, position: 100 }));
+        assert_eq!(index.lookup(29), Some(IndexEntry { sequence: 15, position: 100 }));
+        assert_eq!(index.lookup(1000), Some(IndexEntry { sequence: 30, position: 250 }));
+        assert_eq!(SegmentIndex::default().lookup(0), None);
+    }
+
+    #[test]
+    fn segment_index_survives_a_reload_and_a_torn_append() {
+        let rand_string: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .collect();
+        let path = env::temp_dir().join(format!("to_mount-test-{}.index", rand_string));
+
+        let mut index = SegmentIndex::default();
+        index.append(&path, IndexEntry { sequence: 0, position: 0 }).unwrap();
+        index.append(&path, IndexEntry { sequence: 4, position: 90 }).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        fs::write(&path, bytes).unwrap();
+
+        let loaded = SegmentIndex::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, index);
+        assert_eq!(SegmentIndex::load(&path).unwrap(), SegmentIndex::default());
+    }
+}
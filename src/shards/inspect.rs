@@ -0,0 +1,184 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+
+use crate::shards::batch::{BatchHeader, RecordBatch};
+use crate::shards::index::{INDEX_ENTRY_SIZE, IndexEntry, SegmentIndex};
+use crate::shards::shards::{SegmentId, ShardDir};
+use crate::shards::shutdown::discard_clean_shutdown;
+
+/// The first frame of a segment that cannot be read, and why. Frames are only found by walking
+/// the ones before them, so nothing after it can be read either.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BadFrame {
+    pub position: u64,
+    pub reason: String,
+}
+
+/// What walking the frames of a segment file found: every good frame with its position, up to
+/// the first bad one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentScan {
+    pub base_offset: SegmentId,
+    pub len: u64,
+    pub frames: Vec<(u64, BatchHeader)>,
+    pub bad_frame: Option<BadFrame>,
+}
+
+impl SegmentScan {
+    /// the index the good frames should have
+    pub fn index(&self) -> SegmentIndex {
+        let mut index = SegmentIndex::default();
+        for (position, header) in self.frames.iter() {
+            index.push(IndexEntry { sequence: header.base_sequence, position: *position });
+        }
+        index
+    }
+}
+
+/// Walks the frames of segment `base_offset`, checking that each one is whole, matches its
+/// checksum and starts past the sequence numbers of the one before it. The whole file is read, so
+/// this is for tools working on a shard dir while the server is down.
+pub fn scan_segment(shard_dir: &ShardDir, base_offset: SegmentId) -> std::io::Result<SegmentScan> {
+    let data = fs::read(shard_dir.path_to_segment(base_offset))?;
+    let mut frames = Vec::new();
+    let mut position = 0;
+    let mut next_sequence = base_offset;
+
+    let bad_frame = loop {
+        if position == data.len() {
+            break None;
+        }
+        let reason = match RecordBatch::verify(&data[position..]) {
+            Ok(header) if header.base_sequence < next_sequence => {
+                format!("batch {} starts before {}, where the batch before it ends", header.base_sequence, next_sequence)
+            }
+            Ok(header) => {
+                next_sequence = header.next_sequence();
+                let frame_len = header.frame_len() as usize;
+                frames.push((position as u64, header));
+                position += frame_len;
+                continue;
+            }
+            Err(e) => e.to_string(),
+        };
+        break Some(BadFrame { position: position as u64, reason });
+    };
+
+    Ok(SegmentScan { base_offset, len: data.len() as u64, frames, bad_frame })
+}
+
+/// How the index file of segment `scan.base_offset` differs from the good frames `scan` found in
+/// it, nothing when it matches them.
+pub fn check_index(shard_dir: &ShardDir, scan: &SegmentScan) -> std::io::Result<Vec<String>> {
+    let path = shard_dir.path_to_index(scan.base_offset);
+    let len = match fs::metadata(&path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec!["the index file is missing".to_string()]),
+        Err(e) => return Err(e),
+    };
+
+    let mut problems = Vec::new();
+    if len % INDEX_ENTRY_SIZE as u64 != 0 {
+        problems.push(format!("the index file ends with a partial entry of {} bytes", len % INDEX_ENTRY_SIZE as u64));
+    }
+    let index = SegmentIndex::load(&path)?;
+    let expected = scan.index();
+    let mismatch = index.entries().iter().zip(expected.entries()).position(|(found, expected)| found != expected);
+    if let Some(i) = mismatch {
+        let (found, expected) = (index.entries()[i], expected.entries()[i]);
+        problems.push(format!(
+            "entry {} is batch {} at {}, the segment has batch {} at {}",
+            i, found.sequence, found.position, expected.sequence, expected.position,
+        ));
+    }
+    if index.entries().len() != expected.entries().len() {
+        problems.push(format!(
+            "the index has {} entries for the {} good batches of the segment",
+            index.entries().len(), expected.entries().len(),
+        ));
+    }
+    Ok(problems)
+}
+
+/// Replaces the index file of segment `scan.base_offset` with one of the good frames `scan`
+/// found.
+pub fn rebuild_index(shard_dir: &ShardDir, scan: &SegmentScan) -> std::io::Result<()> {
+    scan.index().store(&shard_dir.path_to_index(scan.base_offset))
+}
+
+/// Cuts segment `scan.base_offset` right before its first bad frame and rebuilds its index.
+/// Whatever the segment held from that frame on is lost, and so is any clean-shutdown marker,
+/// which no longer matches the segments. Returns how many bytes were cut, 0 when there is no
+/// bad frame.
+pub fn truncate_at_bad_frame(shard_dir: &ShardDir, scan: &SegmentScan) -> std::io::Result<u64> {
+    let bad_frame = match &scan.bad_frame {
+        Some(bad_frame) => bad_frame,
+        None => return Ok(0),
+    };
+    let file = OpenOptions::new().write(true).open(shard_dir.path_to_segment(scan.base_offset))?;
+    file.set_len(bad_frame.position)?;
+    file.sync_all()?;
+    rebuild_index(shard_dir, scan)?;
+    discard_clean_shutdown(&shard_dir.path_to_clean_shutdown())?;
+    Ok(scan.len - bad_frame.position)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use crate::shards::batch::{Compression, RecordBatch};
+    use crate::shards::inspect::{check_index, rebuild_index, scan_segment, truncate_at_bad_frame};
+    use crate::shards::segments::SegmentManager;
+    use crate::shards::shards::{Record, ShardDir};
+    use crate::test_util::with_tmp_dir;
+
+    #[test]
+    fn bad_frames_are_found_reported_against_the_index_and_cut_off() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            let frames: Vec<Vec<u8>> = (0..3)
+                .map(|i| {
+                    let records = vec![Record::new(b"meucu_tem_oculos".to_vec()), Record::new(b"meucu_tem_oculos".to_vec())];
+                    RecordBatch::new(i * 2, 0, records).encode(Compression::None).unwrap()
+                })
+                .collect();
+            let mut segment = OpenOptions::new().create(true).append(true).open(shard_dir.path_to_segment(0)).unwrap();
+            for frame in frames.iter() {
+                segment.write_all(frame).unwrap();
+            }
+            drop(segment);
+
+            let scan = scan_segment(&shard_dir, 0).unwrap();
+            assert_eq!(scan.frames.len(), 3);
+            assert_eq!(scan.bad_frame, None);
+            assert_eq!(check_index(&shard_dir, &scan).unwrap(), vec!["the index file is missing".to_string()]);
+            rebuild_index(&shard_dir, &scan).unwrap();
+            assert!(check_index(&shard_dir, &scan).unwrap().is_empty());
+
+            // flips a byte of the payload of the second batch
+            let mut data = fs::read(shard_dir.path_to_segment(0)).unwrap();
+            let second = frames[0].len();
+            data[second + frames[1].len() - 1] ^= 0xff;
+            fs::write(shard_dir.path_to_segment(0), &data).unwrap();
+
+            let scan = scan_segment(&shard_dir, 0).unwrap();
+            assert_eq!(scan.frames.len(), 1);
+            let bad_frame = scan.bad_frame.clone().unwrap();
+            assert_eq!(bad_frame.position, second as u64);
+            assert_eq!(bad_frame.reason, "crc mismatch in batch 2");
+            assert_eq!(check_index(&shard_dir, &scan).unwrap(), vec!["the index has 3 entries for the 1 good batches of the segment".to_string()]);
+
+            assert_eq!(truncate_at_bad_frame(&shard_dir, &scan).unwrap(), (frames[1].len() + frames[2].len()) as u64);
+            let scan = scan_segment(&shard_dir, 0).unwrap();
+            assert_eq!((scan.frames.len(), scan.bad_frame.clone()), (1, None));
+            assert!(check_index(&shard_dir, &scan).unwrap().is_empty());
+            assert_eq!(truncate_at_bad_frame(&shard_dir, &scan).unwrap(), 0);
+            assert_eq!(SegmentManager::open(shard_dir, 1024).unwrap().end_offset(), 2);
+        });
+    }
+}
@@ -1,2 +1,19 @@
+pub mod batch;
+pub mod compaction;
+pub mod durability;
+pub mod encryption;
+pub mod format;
+pub mod index;
+pub mod inspect;
+pub mod producers;
+pub mod quota;
+pub mod replication;
+pub mod retention;
+pub mod segments;
 pub mod shard_controller;
-pub mod shards;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod shards;
+pub mod shutdown;
+pub mod stats;
+pub mod tiering;
+pub mod transactions;
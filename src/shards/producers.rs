@@ -0,0 +1,217 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::shards::batch::{BATCH_HEADER_SIZE, BatchHeader};
+use crate::shards::segments::{SegmentManager, SegmentState};
+
+/// how many of its latest batches are remembered for each producer
+pub const PRODUCER_WINDOW_BATCHES: usize = 5;
+
+/// how many producers a shard remembers; the one that put the longest ago is forgotten first
+pub const MAX_PRODUCERS: usize = 1024;
+
+/// An idempotent producer numbers the records it puts in a shard `0, 1, 2, ...`, and a batch
+/// carries the number of its first record. A batch that is put again with the same
+/// `producer_sequence`, after a timeout say, is recognised and not appended twice.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ProducerBatch {
+    /// picked by the producer, unique among the producers of a shard; the transaction id for
+    /// transactional batches, which have ids of their own
+    pub producer_id: u64,
+    pub producer_sequence: u64,
+    #[serde(default)]
+    pub transactional: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+struct AppendedBatch {
+    producer_sequence: u64,
+    record_count: u32,
+    base_sequence: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Dedup {
+    Append,
+    /// the batch is already in the log, from this sequence number on
+    Duplicate(u64),
+}
+
+/// Calls `f` with the header and producer of every batch of the local segments that has one, in
+/// order, only reading the start of each frame. Remote segments are left out.
+pub fn scan_producer_batches<F>(segments: &SegmentManager, mut f: F) -> std::io::Result<()>
+    where F: FnMut(&BatchHeader, ProducerBatch)
+{
+    for segment in segments.segments().filter(|s| s.state != SegmentState::Remote) {
+        let mut reader = BufReader::new(File::open(segments.path_to(segment))?);
+        let mut position = 0;
+        while position < segment.size {
+            let mut frame = vec![0; BATCH_HEADER_SIZE];
+            reader.read_exact(&mut frame)?;
+            let header = BatchHeader::parse(&frame)?;
+            frame.resize(header.records_start(), 0);
+            reader.read_exact(&mut frame[BATCH_HEADER_SIZE..])?;
+            if let Some(producer) = header.producer(&frame)? {
+                f(&header, producer);
+            }
+            position += header.frame_len();
+            reader.seek(SeekFrom::Start(position))?;
+        }
+    }
+    Ok(())
+}
+
+/// The latest batches of the producers of a shard. It is rebuilt from the batch headers when the
+/// shard is opened, or taken from the clean-shutdown marker, so it survives restarts, and
+/// followers keep theirs as they append what they fetch, so it survives failovers too.
+/// Transactions are kept apart from producers with the same id.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ProducerWindow {
+    #[serde(with = "pairs")]
+    producers: HashMap<(u64, bool), VecDeque<AppendedBatch>>,
+}
+
+impl ProducerWindow {
+    pub fn load(segments: &SegmentManager) -> std::io::Result<ProducerWindow> {
+        let mut window = ProducerWindow::default();
+        scan_producer_batches(segments, |header, producer| {
+            if !header.control {
+                window.appended(producer, header.span, header.base_sequence);
+            }
+        })?;
+        Ok(window)
+    }
+
+    /// Whether a batch of `record_count` records from `producer` is new. Producers have to put
+    /// their batches in order, so one that skips sequence numbers is turned away, and so is one
+    /// repeating a batch too old to be remembered.
+    pub fn check(&self, producer: &ProducerBatch, record_count: u32) -> std::io::Result<Dedup> {
+        let batches = match self.producers.get(&(producer.producer_id, producer.transactional)) {
+            Some(batches) => batches,
+            None => return Ok(Dedup::Append),
+        };
+        if let Some(batch) = batches.iter().find(|b| b.producer_sequence == producer.producer_sequence) {
+            if batch.record_count != record_count {
+                return Err(Error::new(ErrorKind::InvalidInput, format!(
+                    "producer {} put {} records from sequence {} before, not {}",
+                    producer.producer_id, batch.record_count, batch.producer_sequence, record_count,
+                )));
+            }
+            return Ok(Dedup::Duplicate(batch.base_sequence));
+        }
+
+        let last = batches.back().unwrap();
+        let expected = last.producer_sequence + last.record_count as u64;
+        if producer.producer_sequence == expected {
+            return Ok(Dedup::Append);
+        }
+        let reason = match producer.producer_sequence < expected {
+            true => "is older than the batches remembered",
+            false => "skips records",
+        };
+        Err(Error::new(ErrorKind::InvalidInput, format!(
+            "producer {} sequence {} {}, the next one is {}",
+            producer.producer_id, producer.producer_sequence, reason, expected,
+        )))
+    }
+
+    /// Remembers a batch of `producer` that was appended at `base_sequence`.
+    pub fn appended(&mut self, producer: ProducerBatch, record_count: u32, base_sequence: u64) {
+        let key = (producer.producer_id, producer.transactional);
+        if !self.producers.contains_key(&key) && self.producers.len() >= MAX_PRODUCERS {
+            let idlest = self.producers
+                .iter()
+                .min_by_key(|(_, batches)| batches.back().map(|b| b.base_sequence))
+                .map(|(key, _)| *key);
+            if let Some(idlest) = idlest {
+                self.producers.remove(&idlest);
+            }
+        }
+
+        let batches = self.producers.entry(key).or_default();
+        batches.push_back(AppendedBatch { producer_sequence: producer.producer_sequence, record_count, base_sequence });
+        if batches.len() > PRODUCER_WINDOW_BATCHES {
+            batches.pop_front();
+        }
+    }
+
+    /// forgets the batches from sequence number `offset` on, which were dropped from the log
+    pub fn truncate(&mut self, offset: u64) {
+        for batches in self.producers.values_mut() {
+            batches.retain(|b| b.base_sequence < offset);
+        }
+        self.producers.retain(|_, batches| !batches.is_empty());
+    }
+}
+
+/// JSON only has string keys, so the producers are written as a list of key and batches pairs.
+mod pairs {
+    use std::collections::{HashMap, VecDeque};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::shards::producers::AppendedBatch;
+
+    type Producers = HashMap<(u64, bool), VecDeque<AppendedBatch>>;
+
+    pub fn serialize<S: Serializer>(producers: &Producers, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(producers.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Producers, D::Error> {
+        Ok(Vec::<((u64, bool), VecDeque<AppendedBatch>)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::producers::{Dedup, MAX_PRODUCERS, PRODUCER_WINDOW_BATCHES, ProducerBatch, ProducerWindow};
+
+    fn producer(producer_id: u64, producer_sequence: u64) -> ProducerBatch {
+        ProducerBatch { producer_id, producer_sequence, transactional: false }
+    }
+
+    #[test]
+    fn producer_window_spots_duplicates_and_out_of_order_batches() {
+        let mut window = ProducerWindow::default();
+        assert_eq!(window.check(&producer(1, 100), 3).unwrap(), Dedup::Append);
+        window.appended(producer(1, 100), 3, 40);
+        window.appended(producer(1, 103), 2, 50);
+
+        assert_eq!(window.check(&producer(1, 100), 3).unwrap(), Dedup::Duplicate(40));
+        assert_eq!(window.check(&producer(1, 103), 2).unwrap(), Dedup::Duplicate(50));
+        assert_eq!(window.check(&producer(1, 105), 1).unwrap(), Dedup::Append);
+        assert!(window.check(&producer(1, 103), 1).is_err());
+        assert!(window.check(&producer(1, 106), 1).is_err());
+        assert!(window.check(&producer(1, 101), 1).is_err());
+        assert_eq!(window.check(&producer(2, 7), 1).unwrap(), Dedup::Append);
+        let transaction = ProducerBatch { transactional: true, ..producer(1, 0) };
+        assert_eq!(window.check(&transaction, 1).unwrap(), Dedup::Append);
+
+        for i in 0..PRODUCER_WINDOW_BATCHES as u64 {
+            window.appended(producer(1, 105 + i), 1, 60 + i);
+        }
+        assert!(window.check(&producer(1, 103), 2).is_err());
+        assert_eq!(window.check(&producer(1, 105), 1).unwrap(), Dedup::Duplicate(60));
+
+        window.truncate(62);
+        assert_eq!(window.check(&producer(1, 107), 1).unwrap(), Dedup::Append);
+        window.truncate(0);
+        assert_eq!(window.check(&producer(1, 0), 1).unwrap(), Dedup::Append);
+    }
+
+    #[test]
+    fn producer_window_forgets_the_idlest_producer_once_full() {
+        let mut window = ProducerWindow::default();
+        for producer_id in 0..MAX_PRODUCERS as u64 + 1 {
+            window.appended(producer(producer_id, 0), 1, producer_id);
+        }
+
+        assert_eq!(window.check(&producer(0, 0), 1).unwrap(), Dedup::Append);
+        assert_eq!(window.check(&producer(1, 0), 1).unwrap(), Dedup::Duplicate(1));
+        assert_eq!(window.check(&producer(MAX_PRODUCERS as u64, 0), 1).unwrap(), Dedup::Duplicate(MAX_PRODUCERS as u64));
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How much a shard takes in per second; `None` leaves it unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quota {
+    pub records_per_sec: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// The quota of a shard as token buckets, refilled at the quota's rate and holding at most one
+/// second of it, so bursts up to a second's worth are let through.
+#[derive(Debug)]
+pub struct PutQuota {
+    quota: Quota,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    records: f64,
+    bytes: f64,
+    refilled: Instant,
+}
+
+impl PutQuota {
+    pub fn new(quota: Quota) -> PutQuota {
+        PutQuota {
+            quota,
+            buckets: Mutex::new(Buckets {
+                records: quota.records_per_sec.unwrap_or(0) as f64,
+                bytes: quota.bytes_per_sec.unwrap_or(0) as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a put of `records` records holding `bytes` bytes out of the buckets, `false` when
+    /// it goes over the quota. A put larger than a second of the quota is let through once the
+    /// buckets are full, and leaves them owing what it took past that.
+    pub fn admit(&self, records: u64, bytes: u64) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        let now = Instant::now();
+        let elapsed = now.duration_since(buckets.refilled).as_secs_f64();
+        buckets.refilled = now;
+
+        let mut limits: Vec<(f64, f64, &mut f64)> = vec![
+            (self.quota.records_per_sec, records, &mut buckets.records),
+            (self.quota.bytes_per_sec, bytes, &mut buckets.bytes),
+        ]
+            .into_iter()
+            .filter_map(|(per_sec, cost, tokens)| per_sec.map(|per_sec| (per_sec as f64, cost as f64, tokens)))
+            .collect();
+        for (per_sec, _, tokens) in limits.iter_mut() {
+            **tokens = (**tokens + elapsed * *per_sec).min(*per_sec);
+        }
+        let admitted = limits.iter().all(|(per_sec, cost, tokens)| **tokens >= cost.min(*per_sec));
+        if admitted {
+            for (_, cost, tokens) in limits {
+                *tokens -= cost;
+            }
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::shards::quota::{PutQuota, Quota};
+
+    #[test]
+    fn puts_over_the_quota_are_turned_away_until_the_buckets_refill() {
+        let quota = PutQuota::new(Quota { records_per_sec: Some(10), bytes_per_sec: Some(100) });
+        assert!(quota.admit(6, 16));
+        assert!(quota.admit(4, 16));
+        assert!(!quota.admit(1, 16));
+
+        quota.buckets.lock().unwrap().refilled -= Duration::from_millis(500);
+        assert!(!quota.admit(6, 16));
+        assert!(quota.admit(5, 16));
+        // a burst larger than a second of the quota waits for full buckets, then runs them dry
+        quota.buckets.lock().unwrap().refilled -= Duration::from_secs(10);
+        assert!(quota.admit(3, 250));
+        quota.buckets.lock().unwrap().refilled -= Duration::from_secs(1);
+        assert!(!quota.admit(1, 1));
+
+        let unlimited = PutQuota::new(Quota::default());
+        assert!(unlimited.admit(u64::MAX, u64::MAX));
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Condvar, Mutex, Weak};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::auth::sigv4::Credentials;
+use crate::http;
+use crate::shards::shard_controller::ShardController;
+use crate::shards::shards::now_ms;
+
+pub const DEFAULT_REPLICA_LAG_MAX_MS: u64 = 10 * 1000;
+pub const DEFAULT_ACK_TIMEOUT_MS: u64 = 10 * 1000;
+
+/// the most a follower asks for in one fetch
+pub const FETCH_MAX_BYTES: u64 = 1024 * 1024;
+
+/// header of fetch responses carrying the leader's high-watermark
+pub const HIGH_WATERMARK_HEADER: &str = "x-high-watermark";
+
+/// header of fetch responses carrying the epoch of the leader that answered
+pub const LEADER_EPOCH_HEADER: &str = "x-leader-epoch";
+
+/// How a shard is replicated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationConfig {
+    /// `host:port` of the leader this node follows, `None` when it is the leader
+    pub leader: Option<String>,
+    /// name this node fetches from its leader with
+    pub replica_id: String,
+    /// how many replicas, the leader included, must have a batch before a put is acknowledged
+    pub min_insync_replicas: usize,
+    /// a follower that has not caught up with the leader for this long is out of sync
+    pub replica_lag_max_ms: u64,
+    /// how long a put waits for the in-sync replicas before failing
+    pub ack_timeout_ms: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            leader: None,
+            replica_id: "leader".to_string(),
+            min_insync_replicas: 1,
+            replica_lag_max_ms: DEFAULT_REPLICA_LAG_MAX_MS,
+            ack_timeout_ms: DEFAULT_ACK_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Who leads a shard. The epoch goes up with every new leader, so followers and leaders can tell
+/// whether they still agree on it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leadership {
+    /// `host:port` of the leader, `None` when this replica leads
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+}
+
+/// What a leader sends back to a fetch: the frames following the fetch offset, as they are
+/// stored, its high-watermark and its epoch.
+#[derive(Debug, PartialEq)]
+pub struct FetchResponse {
+    pub leader_epoch: u64,
+    pub high_watermark: u64,
+    pub frames: Vec<u8>,
+}
+
+/// Query of the fetch route: who fetches, from which leader epoch, and how much it takes at most.
+#[derive(Deserialize, Serialize)]
+pub struct FetchQuery {
+    pub replica_id: String,
+    #[serde(default)]
+    pub leader_epoch: u64,
+    pub max_bytes: Option<u64>,
+}
+
+/// How far a replica got, for picking the one to take over from a dead leader.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReplicaStatus {
+    pub leader_epoch: u64,
+    pub log_end_offset: u64,
+    /// whether it had caught up with its leader the last time it heard from it; leaders always are
+    pub in_sync: bool,
+}
+
+/// The leader of a shard as seen from a follower.
+pub trait Leader: Send {
+    fn fetch(&self, replica_id: &str, leader_epoch: u64, offset: u64, max_bytes: u64) -> std::io::Result<FetchResponse>;
+}
+
+/// Fetches from a leader over its HTTP API.
+pub struct HttpLeader {
+    pub addr: String,
+    /// where the shard's routes are mounted on the leader
+    pub path: String,
+    pub timeout: Duration,
+    /// the root key, when the leader has auth on
+    pub credentials: Option<Credentials>,
+}
+
+impl HttpLeader {
+    pub fn new(addr: String) -> HttpLeader {
+        HttpLeader::mounted_at(addr, String::new())
+    }
+
+    pub fn mounted_at(addr: String, path: String) -> HttpLeader {
+        HttpLeader { addr, path, timeout: Duration::from_secs(10), credentials: None }
+    }
+
+    pub fn with_credentials(self, credentials: Option<Credentials>) -> HttpLeader {
+        HttpLeader { credentials, ..self }
+    }
+}
+
+impl Leader for HttpLeader {
+    fn fetch(&self, replica_id: &str, leader_epoch: u64, offset: u64, max_bytes: u64) -> std::io::Result<FetchResponse> {
+        let path = format!(
+            "{}/fetch/{}?replica_id={}&leader_epoch={}&max_bytes={}",
+            self.path, offset, replica_id, leader_epoch, max_bytes,
+        );
+        let response = http::signed_request(&self.addr, "GET", &path, &[], self.timeout, self.credentials.as_ref())?;
+        if !response.is_success() {
+            return Err(Error::other(format!(
+                "fetch from {} failed with {}: {}", self.addr, response.status, String::from_utf8_lossy(&response.body),
+            )));
+        }
+        let number = |name: &str| response
+            .header(name)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("fetch from {}: missing {}", self.addr, name)));
+        Ok(FetchResponse {
+            leader_epoch: number(LEADER_EPOCH_HEADER)?,
+            high_watermark: number(HIGH_WATERMARK_HEADER)?,
+            frames: response.body,
+        })
+    }
+}
+
+/// A follower as its leader knows it, from its fetches.
+#[derive(Clone, Debug, PartialEq)]
+struct Follower {
+    /// sequence number it fetched from last, everything before it is on its disk
+    log_end_offset: u64,
+    /// last time it fetched from the leader's log end
+    caught_up_ms: u64,
+}
+
+struct ReplicaState {
+    leader_epoch: u64,
+    log_end_offset: u64,
+    high_watermark: u64,
+    followers: HashMap<String, Follower>,
+    /// on a follower, the last time it fetched from its leader and the last time that fetch
+    /// found it at the leader's log end
+    fetched_ms: u64,
+    caught_up_ms: Option<u64>,
+}
+
+/// Keeps track of how far the replicas of a shard got. The high-watermark is the sequence number
+/// up to which `min_insync_replicas` in-sync replicas hold the log: batches below it are
+/// acknowledged and visible to readers.
+///
+/// On a follower it is just the leader's high-watermark, as of the last fetch. Either way it is
+/// reset when the shard changes leaders.
+pub struct ReplicaTracker {
+    pub min_insync_replicas: usize,
+    pub replica_lag_max_ms: u64,
+    state: Mutex<ReplicaState>,
+    advanced: Condvar,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct FollowerMetrics {
+    pub replica_id: String,
+    pub log_end_offset: u64,
+    pub in_sync: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ReplicationMetrics {
+    pub log_end_offset: u64,
+    pub high_watermark: u64,
+    /// the leader included
+    pub in_sync_replicas: usize,
+    pub followers: Vec<FollowerMetrics>,
+}
+
+impl ReplicaTracker {
+    /// Starts with everything already on disk below the high-watermark.
+    pub fn new(min_insync_replicas: usize, replica_lag_max_ms: u64, leader_epoch: u64, log_end_offset: u64) -> ReplicaTracker {
+        ReplicaTracker {
+            min_insync_replicas: min_insync_replicas.max(1),
+            replica_lag_max_ms,
+            state: Mutex::new(ReplicaState {
+                leader_epoch,
+                log_end_offset,
+                high_watermark: log_end_offset,
+                followers: HashMap::new(),
+                fetched_ms: 0,
+                caught_up_ms: None,
+            }),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Starts over for leader epoch `leader_epoch` with the log ending at `log_end_offset`. The
+    /// followers of the last epoch are forgotten and puts still waiting for them fail.
+    pub fn reset(&self, leader_epoch: u64, log_end_offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.leader_epoch = leader_epoch;
+        state.log_end_offset = log_end_offset;
+        state.high_watermark = state.high_watermark.min(log_end_offset);
+        state.followers.clear();
+        state.caught_up_ms = None;
+        self.advanced.notify_all();
+    }
+
+    pub fn high_watermark(&self) -> u64 {
+        self.state.lock().unwrap().high_watermark
+    }
+
+    /// the replicas in sync with the leader, the leader included
+    pub fn in_sync_replicas(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        1 + state.followers.values().filter(|f| self.is_in_sync(f, now_ms())).count()
+    }
+
+    /// The leader appended up to `log_end_offset`.
+    pub fn appended(&self, log_end_offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.log_end_offset = state.log_end_offset.max(log_end_offset);
+        self.advance(&mut state);
+    }
+
+    /// Follower `replica_id` fetched from `offset`, so it holds everything before it.
+    pub fn fetched(&self, replica_id: &str, offset: u64) {
+        let now = now_ms();
+        let mut state = self.state.lock().unwrap();
+        let caught_up = offset >= state.log_end_offset;
+        let log_end_offset = offset.min(state.log_end_offset);
+        let follower = state.followers
+            .entry(replica_id.to_string())
+            .or_insert(Follower { log_end_offset, caught_up_ms: now });
+        follower.log_end_offset = log_end_offset;
+        if caught_up {
+            follower.caught_up_ms = now;
+        }
+        self.advance(&mut state);
+    }
+
+    /// A follower learnt the leader's high-watermark and has its log up to `log_end_offset`,
+    /// which is the leader's log end too when it `caught_up`.
+    pub fn followed(&self, high_watermark: u64, log_end_offset: u64, caught_up: bool) {
+        let now = now_ms();
+        let mut state = self.state.lock().unwrap();
+        state.log_end_offset = log_end_offset;
+        state.high_watermark = state.high_watermark.max(high_watermark.min(log_end_offset));
+        state.fetched_ms = now;
+        if caught_up {
+            state.caught_up_ms = Some(now);
+        }
+        self.advanced.notify_all();
+    }
+
+    /// Whether this follower was in sync as its leader last saw it: as of its last fetch, it had
+    /// been at the leader's log end within `replica_lag_max_ms`. Still true once the leader is
+    /// gone, which is when it matters.
+    pub fn follower_in_sync(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.caught_up_ms.is_some_and(|caught_up| state.fetched_ms.saturating_sub(caught_up) <= self.replica_lag_max_ms)
+    }
+
+    /// Waits until the high-watermark reaches `offset`, failing after `timeout` or as soon as the
+    /// shard moves past leader epoch `leader_epoch`.
+    pub fn wait_for(&self, offset: u64, leader_epoch: u64, timeout: Duration) -> std::io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.high_watermark < offset {
+            if state.leader_epoch != leader_epoch {
+                return Err(Error::new(ErrorKind::Interrupted, format!(
+                    "the shard changed leaders before sequence number {} reached {} in-sync replicas, retry with the new leader",
+                    offset.saturating_sub(1), self.min_insync_replicas,
+                )));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut, format!(
+                    "sequence number {} was written but did not reach {} in-sync replicas",
+                    offset.saturating_sub(1), self.min_insync_replicas,
+                )));
+            }
+            // followers dropping out of sync do not wake anybody up, so check back regularly
+            let wait = (deadline - now).min(Duration::from_millis(self.replica_lag_max_ms.max(1)));
+            state = self.advanced.wait_timeout(state, wait).unwrap().0;
+            self.advance(&mut state);
+        }
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> ReplicationMetrics {
+        let now = now_ms();
+        let state = self.state.lock().unwrap();
+        let mut followers: Vec<FollowerMetrics> = state.followers
+            .iter()
+            .map(|(replica_id, f)| FollowerMetrics {
+                replica_id: replica_id.clone(),
+                log_end_offset: f.log_end_offset,
+                in_sync: self.is_in_sync(f, now),
+            })
+            .collect();
+        followers.sort_by(|a, b| a.replica_id.cmp(&b.replica_id));
+        ReplicationMetrics {
+            log_end_offset: state.log_end_offset,
+            high_watermark: state.high_watermark,
+            in_sync_replicas: 1 + followers.iter().filter(|f| f.in_sync).count(),
+            followers,
+        }
+    }
+
+    fn is_in_sync(&self, follower: &Follower, now: u64) -> bool {
+        now.saturating_sub(follower.caught_up_ms) <= self.replica_lag_max_ms
+    }
+
+    /// moves the high-watermark up to what `min_insync_replicas` in-sync replicas have
+    fn advance(&self, state: &mut ReplicaState) {
+        let now = now_ms();
+        let mut log_end_offsets: Vec<u64> = state.followers
+            .values()
+            .filter(|f| self.is_in_sync(f, now))
+            .map(|f| f.log_end_offset)
+            .collect();
+        log_end_offsets.push(state.log_end_offset);
+        log_end_offsets.sort_unstable_by(|a, b| b.cmp(a));
+
+        if let Some(&held) = log_end_offsets.get(self.min_insync_replicas - 1) {
+            if held > state.high_watermark {
+                state.high_watermark = held;
+                self.advanced.notify_all();
+            }
+        }
+    }
+}
+
+/// The leader epoch a replica last led or followed in, 0 before it ever changed leaders.
+pub fn load_leader_epoch(path: &Path) -> std::io::Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(leader_epoch) => leader_epoch
+            .trim()
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{}: not a leader epoch", path.to_string_lossy()))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn store_leader_epoch(path: &Path, leader_epoch: u64) -> std::io::Result<()> {
+    fs::write(path, leader_epoch.to_string())
+}
+
+/// Tails the leader of the shard of `shard_controller`, reached through `connect`, appending what
+/// it fetches to the shard, until the shard is dropped or shut down. It idles while the shard is led here.
+/// Fetches that come back empty are retried after `idle_ms`.
+pub fn spawn_follower<C>(shard_controller: Weak<ShardController>, connect: C, idle_ms: u64) -> JoinHandle<()>
+    where C: Fn(&str) -> Box<dyn Leader> + Send + 'static
+{
+    thread::spawn(move || {
+        let span = match shard_controller.upgrade() {
+            Some(shard_controller) => shard_controller.span.clone(),
+            None => return,
+        };
+        let _entered = span.enter();
+        while let Some(shard_controller) = shard_controller.upgrade() {
+            if shard_controller.is_shut_down() {
+                return;
+            }
+            let leadership = shard_controller.leadership();
+            let leader = match &leadership.leader {
+                Some(leader) => connect(leader),
+                None => {
+                    drop(shard_controller);
+                    thread::sleep(Duration::from_millis(idle_ms));
+                    continue;
+                }
+            };
+            let offset = shard_controller.segments.read().unwrap().end_offset();
+            let replica_id = &shard_controller.config.replication.replica_id;
+            let fetched = leader
+                .fetch(replica_id, leadership.leader_epoch, offset, FETCH_MAX_BYTES)
+                .and_then(|fetched| {
+                    let empty = fetched.frames.is_empty();
+                    shard_controller.replicate(fetched).map(|_| empty)
+                });
+            drop(shard_controller);
+            match fetched {
+                Ok(false) => {}
+                Ok(true) => thread::sleep(Duration::from_millis(idle_ms)),
+                Err(e) => {
+                    warn!(offset, error = %e, "could not replicate");
+                    thread::sleep(Duration::from_millis(idle_ms.max(1000)));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::shards::replication::ReplicaTracker;
+
+    #[test]
+    fn high_watermark_follows_the_slowest_of_the_required_replicas() {
+        let tracker = ReplicaTracker::new(3, 10 * 1000, 0, 0);
+        tracker.appended(10);
+        assert_eq!(tracker.high_watermark(), 0);
+
+        tracker.fetched("f1", 10);
+        tracker.fetched("f2", 4);
+        assert_eq!(tracker.high_watermark(), 4);
+        assert_eq!(tracker.in_sync_replicas(), 3);
+        assert!(tracker.wait_for(10, 0, Duration::from_millis(10)).is_err());
+
+        tracker.fetched("f2", 10);
+        assert_eq!(tracker.high_watermark(), 10);
+        tracker.wait_for(10, 0, Duration::from_millis(10)).unwrap();
+    }
+
+    #[test]
+    fn followers_that_stop_fetching_drop_out_of_sync() {
+        let tracker = ReplicaTracker::new(2, 50, 0, 0);
+        tracker.fetched("f1", 0);
+        tracker.appended(5);
+        assert_eq!(tracker.in_sync_replicas(), 2);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(tracker.in_sync_replicas(), 1);
+        tracker.fetched("f1", 5);
+        assert_eq!(tracker.in_sync_replicas(), 2);
+        assert_eq!(tracker.high_watermark(), 5);
+    }
+
+    #[test]
+    fn a_new_leader_epoch_fails_the_puts_waiting_for_the_last_one() {
+        let tracker = ReplicaTracker::new(2, 10 * 1000, 0, 0);
+        tracker.fetched("f1", 0);
+        tracker.appended(5);
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| tracker.wait_for(5, 0, Duration::from_secs(10)));
+            thread::sleep(Duration::from_millis(50));
+            tracker.reset(1, 3);
+            assert_eq!(waiting.join().unwrap().unwrap_err().kind(), ErrorKind::Interrupted);
+        });
+        assert_eq!(tracker.in_sync_replicas(), 1);
+
+        assert!(!tracker.follower_in_sync());
+        tracker.followed(3, 3, true);
+        tracker.followed(4, 4, false);
+        assert!(tracker.follower_in_sync());
+        assert_eq!(tracker.high_watermark(), 4);
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tracing::{info, Span, warn};
+
+use crate::shards::segments::{SegmentManager, SegmentState};
+use crate::shards::shards::now_ms;
+use crate::shards::shutdown::StopSignal;
+
+/// How much of a shard is kept; `None` keeps it all. Records are deleted a whole segment at a
+/// time, oldest first, and the active segment is always kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// how long a sealed segment is kept after its last record was put
+    pub ms: Option<u64>,
+    /// how many bytes of segments the shard holds, remote ones included
+    pub bytes: Option<u64>,
+}
+
+impl Retention {
+    pub fn keeps_everything(&self) -> bool {
+        self.ms.is_none() && self.bytes.is_none()
+    }
+}
+
+/// One retention pass: deletes the oldest segments for as long as the shard holds more than
+/// `retention.bytes` or their last record is older than `retention.ms` at `now_ms`. Returns how
+/// many segments were deleted.
+pub fn retain(segments: &RwLock<SegmentManager>, retention: Retention, now_ms: u64) -> std::io::Result<usize> {
+    let expired_before = {
+        let segments = segments.read().unwrap();
+        let mut kept_bytes: u64 = segments.segments().map(|s| s.size).sum();
+        let mut expired_before = None;
+        for segment in segments.segments().filter(|s| s.state != SegmentState::Active) {
+            let too_big = retention.bytes.is_some_and(|bytes| kept_bytes > bytes);
+            let too_old = match (too_big, retention.ms) {
+                (false, Some(ms)) => match segment.last_put_ms {
+                    Some(put_ms) => put_ms.saturating_add(ms) <= now_ms,
+                    // only an empty segment has no timestamp, unless it is a remote one whose
+                    // timestamp was lost, which is left to the size limit
+                    None => segments.index(segment).is_none_or(|index| index.entries().is_empty()),
+                },
+                _ => false,
+            };
+            if !too_big && !too_old {
+                break;
+            }
+            kept_bytes -= segment.size;
+            expired_before = Some(segment.end_offset());
+        }
+        expired_before
+    };
+    match expired_before {
+        Some(offset) => Ok(segments.write().unwrap().delete_before(offset)?.len()),
+        None => Ok(0),
+    }
+}
+
+/// Background thread of streams with a retention: every `interval_ms` it runs a retention pass,
+/// until the shard is dropped or `stop` is raised.
+pub fn spawn_retainer(segments: Arc<RwLock<SegmentManager>>, retention: Retention, interval_ms: u64, stop: Arc<StopSignal>) -> JoinHandle<()> {
+    let span = Span::current();
+    thread::spawn(move || while Arc::strong_count(&segments) > 1 && stop.sleep(Duration::from_millis(interval_ms)) {
+
+        let _entered = span.enter();
+        match retain(&segments, retention, now_ms()) {
+            Ok(0) => {}
+            Ok(deleted) => info!(deleted, "deleted expired segments"),
+            Err(e) => warn!(error = %e, "retention failed"),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::sync::RwLock;
+
+    use crate::shards::batch::{BatchHeader, Compression, RecordBatch};
+    use crate::shards::retention::{retain, Retention};
+    use crate::shards::segments::SegmentManager;
+    use crate::shards::shards::{Record, ShardDir};
+    use crate::test_util::with_tmp_dir;
+
+    /// appends a batch put at `timestamp_ms` and rolls, leaving it alone in a sealed segment
+    fn append_sealed(manager: &mut SegmentManager, timestamp_ms: u64) {
+        let records = vec![Record::new(b"meucu_tem_oculos".to_vec())];
+        let frame = RecordBatch::new(manager.end_offset(), timestamp_ms, records).encode(Compression::None).unwrap();
+        let position = manager.active().size;
+        OpenOptions::new().append(true).open(manager.path_to(manager.active())).unwrap().write_all(&frame).unwrap();
+        manager.append_batch(position, &BatchHeader::parse(&frame).unwrap()).unwrap();
+        manager.roll().unwrap();
+    }
+
+    #[test]
+    fn retain_deletes_the_oldest_segments_past_the_retention_and_keeps_the_active_one() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            let mut manager = SegmentManager::open(shard_dir, 1000).unwrap();
+            for timestamp_ms in &[1000, 2000, 9000, 3000] {
+                append_sealed(&mut manager, *timestamp_ms);
+            }
+            let segment_size = manager.segments().next().unwrap().size;
+            let segments = RwLock::new(manager);
+
+            assert_eq!(retain(&segments, Retention::default(), 10_000).unwrap(), 0);
+            // the segment put at 3000 is past it too, but comes after one that is not
+            let by_time = Retention { ms: Some(7000), bytes: None };
+            assert_eq!(retain(&segments, by_time, 10_000).unwrap(), 2);
+            assert_eq!(segments.read().unwrap().oldest_offset(), 2);
+
+            let by_size = Retention { ms: None, bytes: Some(segment_size) };
+            assert_eq!(retain(&segments, by_size, 10_000).unwrap(), 1);
+            assert_eq!(segments.read().unwrap().oldest_offset(), 3);
+            assert_eq!(retain(&segments, Retention { ms: Some(0), bytes: Some(0) }, 10_000).unwrap(), 1);
+            assert_eq!(segments.read().unwrap().len(), 1);
+            assert_eq!(segments.read().unwrap().end_offset(), 4);
+        })
+    }
+}
@@ -0,0 +1,862 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::shards::batch::{BATCH_HEADER_SIZE, BatchHeader, RecordBatch};
+use crate::shards::encryption::KeyProvider;
+use crate::shards::format::check_format;
+use crate::shards::index::{IndexEntry, SegmentIndex};
+use crate::shards::shards::{SegmentId, ShardDir};
+use crate::shards::tiering::TieredStorage;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentState {
+    /// the one segment records are appended to
+    Active,
+    /// rolled away from, never written again
+    Sealed,
+    /// sealed and only left in the object store of tiered storage
+    Remote,
+}
+
+/// A segment file holds the batches of the records with sequence numbers in
+/// `[base_offset, next_offset)`, `size` bytes in total. Its file name is its base offset, which
+/// is the log end offset at the moment it was created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub base_offset: SegmentId,
+    pub next_offset: u64,
+    pub size: u64,
+    pub state: SegmentState,
+    /// the timestamp of its last batch, `None` while it has none or for a remote segment whose
+    /// timestamp did not make it to the object store
+    pub last_put_ms: Option<u64>,
+}
+
+impl Segment {
+    pub fn end_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    pub fn contains(&self, offset: u64) -> bool {
+        self.base_offset <= offset && offset < self.next_offset
+    }
+}
+
+/// In-memory catalog of a shard's segments keyed by base offset, along with their batch indexes.
+/// The shard dir is only listed once, in `open`, and the object store once, in `attach_tiered`;
+/// afterwards the catalog is kept up to date by `append_batch`, `roll`, `offload`,
+/// `delete_before` and `truncate`. The last segment is always the active one and every other one is sealed or
+/// remote.
+pub struct SegmentManager {
+    pub shard_dir: ShardDir,
+    pub max_segment_size: u64,
+    pub tiered: Option<Arc<TieredStorage>>,
+    /// keys of encrypted batches
+    pub keys: Option<Arc<dyn KeyProvider>>,
+    segments: BTreeMap<SegmentId, Segment>,
+    indexes: HashMap<SegmentId, SegmentIndex>,
+    mapped: Mutex<HashMap<SegmentId, Arc<Mmap>>>,
+    /// sealed segments that already are in the object store
+    uploaded: HashSet<SegmentId>,
+}
+
+impl SegmentManager {
+    /// Loads the segments found in the shard dir, creating the first one if there is none, and
+    /// recovers their indexes. Fails for dirs in another format version.
+    pub fn open(shard_dir: ShardDir, max_segment_size: u64) -> std::io::Result<SegmentManager> {
+        SegmentManager::open_with(shard_dir, max_segment_size, false)
+    }
+
+    /// Like `open`, for a shard that was shut down cleanly, whose indexes were synced with their
+    /// segments: they are taken as they are instead of being recovered, which only reads the last
+    /// batch header of each segment. A segment whose index does not end with its last batch is
+    /// recovered all the same.
+    pub fn open_after_clean_shutdown(shard_dir: ShardDir, max_segment_size: u64) -> std::io::Result<SegmentManager> {
+        SegmentManager::open_with(shard_dir, max_segment_size, true)
+    }
+
+    fn open_with(shard_dir: ShardDir, max_segment_size: u64, trust_indexes: bool) -> std::io::Result<SegmentManager> {
+        check_format(&shard_dir)?;
+        let mut base_offsets = shard_dir.list_segments()?;
+        if base_offsets.is_empty() {
+            shard_dir.create_first_segment();
+            base_offsets.push(0);
+        }
+        base_offsets.sort_unstable();
+
+        let mut segments: BTreeMap<SegmentId, Segment> = BTreeMap::new();
+        let mut indexes = HashMap::new();
+        let mut previous_end = 0;
+        for base_offset in base_offsets {
+            if previous_end > base_offset {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("segment {} overlaps the segment before it", base_offset),
+                ));
+            }
+            let trusted = match trust_indexes {
+                true => trusted_index(&shard_dir, base_offset)?,
+                false => None,
+            };
+            let (segment, index) = match trusted {
+                Some(trusted) => trusted,
+                None => recover_index(&shard_dir, base_offset)?,
+            };
+            previous_end = segment.next_offset;
+            segments.insert(base_offset, segment);
+            indexes.insert(base_offset, index);
+            match fs::remove_file(shard_dir.path_to_compacted(base_offset)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        let mut manager = SegmentManager {
+            shard_dir,
+            max_segment_size,
+            tiered: None,
+            keys: None,
+            segments,
+            indexes,
+            mapped: Mutex::new(HashMap::new()),
+            uploaded: HashSet::new(),
+        };
+        manager.active_mut().state = SegmentState::Active;
+        manager.link_next_offsets();
+        Ok(manager)
+    }
+
+    /// Adds the segments of the object store that are no longer on the mount path to the
+    /// catalog, as remote segments, and reads remote ones from `tiered` from now on.
+    pub fn attach_tiered(&mut self, tiered: Arc<TieredStorage>) -> std::io::Result<()> {
+        let oldest_local = self.oldest_offset();
+        for (segment, index) in tiered.remote_segments()? {
+            let base_offset = segment.base_offset;
+            if self.segments.contains_key(&base_offset) {
+                self.uploaded.insert(base_offset);
+                continue;
+            }
+            if base_offset > oldest_local {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("remote segment {} is missing from the mount path", base_offset),
+                ));
+            }
+            self.segments.insert(base_offset, segment);
+            self.indexes.insert(base_offset, index);
+            self.uploaded.insert(base_offset);
+        }
+        self.link_next_offsets();
+        self.tiered = Some(tiered);
+        Ok(())
+    }
+
+    /// Every segment but the active one covers the sequence numbers up to the segment rolled to
+    /// after it, even when compaction dropped its last batches or it is remote and was never
+    /// scanned.
+    fn link_next_offsets(&mut self) {
+        let base_offsets: Vec<SegmentId> = self.segments.keys().copied().collect();
+        for pair in base_offsets.windows(2) {
+            if let Some(segment) = self.segments.get_mut(&pair[0]) {
+                segment.next_offset = pair[1];
+            }
+        }
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn active(&self) -> &Segment {
+        self.segments.values().next_back().expect("there is always an active segment")
+    }
+
+    fn active_mut(&mut self) -> &mut Segment {
+        self.segments.values_mut().next_back().expect("there is always an active segment")
+    }
+
+    pub fn path_to(&self, segment: &Segment) -> PathBuf {
+        self.shard_dir.path_to_segment(segment.base_offset)
+    }
+
+    pub fn oldest_offset(&self) -> u64 {
+        *self.segments.keys().next().expect("there is always an active segment")
+    }
+
+    /// sequence number the next appended record will get
+    pub fn end_offset(&self) -> u64 {
+        self.active().end_offset()
+    }
+
+    /// The segment holding `offset`, or the first one after it when `offset` falls in a gap
+    /// between segments or before the oldest one. `None` once `offset` reaches the log end.
+    pub fn find(&self, offset: u64) -> Option<&Segment> {
+        let holding = self.segments
+            .range(..=offset)
+            .next_back()
+            .map(|(_, s)| s)
+            .filter(|s| s.contains(offset));
+
+        holding.or_else(|| {
+            self.segments
+                .range(offset..)
+                .map(|(_, s)| s)
+                .find(|s| s.end_offset() > offset)
+        })
+    }
+
+    /// Like `find`, along with the batch reading `offset` has to start from.
+    pub fn locate(&self, offset: u64) -> Option<(Segment, IndexEntry)> {
+        let segment = self.find(offset)?;
+        let entry = self.indexes.get(&segment.base_offset)?.lookup(offset)?;
+        Some((segment.clone(), entry))
+    }
+
+    pub fn index(&self, segment: &Segment) -> Option<&SegmentIndex> {
+        self.indexes.get(&segment.base_offset)
+    }
+
+    pub fn is_uploaded(&self, base_offset: SegmentId) -> bool {
+        self.uploaded.contains(&base_offset)
+    }
+
+    /// Records that `segment` is in the object store, unless it changed since it was read from
+    /// the catalog for the upload.
+    pub fn mark_uploaded(&mut self, segment: &Segment) {
+        if self.segments.get(&segment.base_offset) == Some(segment) {
+            self.uploaded.insert(segment.base_offset);
+        }
+    }
+
+    /// Read-only map of a sealed segment. It is created by the first reader that asks for it and
+    /// then shared by every reader until the segment is deleted or offloaded.
+    pub fn mmap(&self, segment: &Segment) -> std::io::Result<Arc<Mmap>> {
+        if segment.state != SegmentState::Sealed {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("segment {} is not a local sealed segment", segment.base_offset),
+            ));
+        }
+
+        let mut mapped = self.mapped.lock().unwrap();
+        if let Some(map) = mapped.get(&segment.base_offset) {
+            return Ok(map.clone());
+        }
+
+        let file = File::open(self.path_to(segment))?;
+        // sealed segments are never written to again, they are only unlinked or replaced by a
+        // compacted copy, which leaves existing maps valid
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        mapped.insert(segment.base_offset, map.clone());
+        Ok(map)
+    }
+
+    /// Accounts a batch of `header.record_count` records that was just appended to the active
+    /// segment at `position`, indexing it.
+    pub fn append_batch(&mut self, position: u64, header: &BatchHeader) -> std::io::Result<()> {
+        let base_offset = self.active().base_offset;
+        let entry = IndexEntry { sequence: header.base_sequence, position };
+        self.indexes
+            .entry(base_offset)
+            .or_default()
+            .append(&self.shard_dir.path_to_index(base_offset), entry)?;
+
+        let active = self.active_mut();
+        active.size = position + header.frame_len();
+        active.next_offset = header.next_sequence();
+        active.last_put_ms = Some(header.timestamp_ms);
+        Ok(())
+    }
+
+    /// Syncs every local segment along with its index file. Index entries are appended without
+    /// ever being synced, and so are sealed segments under the `none` fsync policy.
+    pub fn sync_local(&self) -> std::io::Result<()> {
+        for segment in self.segments().filter(|s| s.state != SegmentState::Remote) {
+            File::open(self.path_to(segment))?.sync_all()?;
+            match File::open(self.shard_dir.path_to_index(segment.base_offset)) {
+                Ok(index) => index.sync_all()?,
+                // nothing was appended to the segment yet
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn should_roll(&self) -> bool {
+        self.active().size > self.max_segment_size
+    }
+
+    /// Seals the active segment and starts a new, empty one at the log end offset.
+    pub fn roll(&mut self) -> std::io::Result<&Segment> {
+        let base_offset = self.end_offset();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.shard_dir.path_to_segment(base_offset))?;
+
+        self.active_mut().state = SegmentState::Sealed;
+        self.segments.insert(base_offset, Segment {
+            base_offset,
+            next_offset: base_offset,
+            size: 0,
+            state: SegmentState::Active,
+            last_put_ms: None,
+        });
+        self.indexes.insert(base_offset, SegmentIndex::default());
+        Ok(self.active())
+    }
+
+    /// Swaps sealed segment `base_offset` for its compacted copy, already written to
+    /// `path_to_compacted` and holding the batches of `index`, `size` bytes in total. An empty
+    /// copy deletes the segment instead. Returns false, dropping the copy, if the segment was
+    /// deleted in the meantime.
+    pub fn replace_compacted(&mut self, base_offset: SegmentId, index: SegmentIndex, size: u64) -> std::io::Result<bool> {
+        let compacted_path = self.shard_dir.path_to_compacted(base_offset);
+        let sealed = self.segments.get(&base_offset).map(|s| s.state) == Some(SegmentState::Sealed);
+        if !sealed {
+            fs::remove_file(compacted_path)?;
+            return Ok(false);
+        }
+        if index.entries().is_empty() {
+            fs::remove_file(compacted_path)?;
+            self.remove(base_offset)?;
+            return Ok(true);
+        }
+
+        // without its index file the segment is re-indexed on open, so a crash at any point
+        // leaves either the old or the compacted segment with a matching index
+        let index_path = self.shard_dir.path_to_index(base_offset);
+        match fs::remove_file(&index_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::rename(compacted_path, self.shard_dir.path_to_segment(base_offset))?;
+        index.store(&index_path)?;
+
+        self.mapped.lock().unwrap().remove(&base_offset);
+        // the copy in the object store is the uncompacted one
+        self.uploaded.remove(&base_offset);
+        self.indexes.insert(base_offset, index);
+        if let Some(segment) = self.segments.get_mut(&base_offset) {
+            segment.size = size;
+        }
+        Ok(true)
+    }
+
+    /// Deletes the sealed and remote segments that only hold offsets below `offset`, returning
+    /// their base offsets. The active segment is never deleted. Segments in the object store are
+    /// deleted from it too, or they would come back as remote segments on the next open.
+    pub fn delete_before(&mut self, offset: u64) -> std::io::Result<Vec<SegmentId>> {
+        let expired: Vec<SegmentId> = self.segments
+            .range(..offset)
+            .map(|(_, s)| s)
+            .filter(|s| s.state != SegmentState::Active && s.end_offset() <= offset)
+            .map(|s| s.base_offset)
+            .collect();
+
+        for base_offset in expired.iter() {
+            if let (true, Some(tiered)) = (self.uploaded.contains(base_offset), &self.tiered) {
+                tiered.delete(*base_offset)?;
+            }
+            self.remove(*base_offset)?;
+        }
+        Ok(expired)
+    }
+
+    /// Drops every batch from `offset` on, leaving `offset` as the log end; it has to be where a
+    /// batch starts. Segments past it are deleted and the one holding it becomes the active
+    /// segment again. That one is cut short through a copy, like a compacted one, so that maps
+    /// readers already have stay valid.
+    pub fn truncate(&mut self, offset: u64) -> std::io::Result<()> {
+        if offset >= self.end_offset() {
+            return Ok(());
+        }
+        let kept = self.segments.range(..=offset).next_back().map(|(_, s)| s.clone());
+        let dropped: Vec<SegmentId> = self.segments.range(offset..).map(|(b, _)| *b).filter(|b| *b > offset).collect();
+        if kept.iter().chain(dropped.iter().filter_map(|b| self.segments.get(b))).any(|s| s.state == SegmentState::Remote) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot truncate at {}, the segments holding it were offloaded", offset),
+            ));
+        }
+
+        let mut kept = match kept {
+            Some(kept) => kept,
+            None => Segment { base_offset: offset, next_offset: offset, size: 0, state: SegmentState::Active, last_put_ms: None },
+        };
+        let mut index = SegmentIndex::default();
+        let mut cut = 0;
+        if let Some(old_index) = self.indexes.get(&kept.base_offset) {
+            for entry in old_index.entries().iter().take_while(|e| e.sequence < offset) {
+                index.push(*entry);
+            }
+            cut = old_index.entries().get(index.entries().len()).map(|e| e.position).unwrap_or(kept.size);
+        }
+
+        let path = self.shard_dir.path_to_segment(kept.base_offset);
+        let mut last_put_ms = None;
+        if let Some(last) = index.last() {
+            let mut file = File::open(&path)?;
+            let header = read_frame(&mut file, last.position, cut)?.and_then(|frame| BatchHeader::parse(&frame).ok());
+            if header.as_ref().is_none_or(|header| header.next_sequence() > offset) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not where a batch starts", offset)));
+            }
+            last_put_ms = header.map(|header| header.timestamp_ms);
+        }
+
+        for base_offset in dropped {
+            self.remove(base_offset)?;
+        }
+        // the index goes first, as in replace_compacted, so a crash re-indexes whatever is left
+        let index_path = self.shard_dir.path_to_index(kept.base_offset);
+        match fs::remove_file(&index_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let copy_path = self.shard_dir.path_to_compacted(kept.base_offset);
+        {
+            let mut copy = File::create(&copy_path)?;
+            if cut > 0 {
+                std::io::copy(&mut File::open(&path)?.take(cut), &mut copy)?;
+            }
+            copy.sync_all()?;
+        }
+        fs::rename(copy_path, &path)?;
+        index.store(&index_path)?;
+
+        self.mapped.lock().unwrap().remove(&kept.base_offset);
+        self.uploaded.remove(&kept.base_offset);
+        self.indexes.insert(kept.base_offset, index);
+        kept.size = cut;
+        kept.next_offset = offset;
+        kept.state = SegmentState::Active;
+        kept.last_put_ms = last_put_ms;
+        self.segments.insert(kept.base_offset, kept);
+        Ok(())
+    }
+
+    /// Deletes sealed segment `base_offset` from the mount path, keeping it in the catalog as a
+    /// remote segment. Only segments that are in the object store can be offloaded; returns
+    /// false for any other one.
+    pub fn offload(&mut self, base_offset: SegmentId) -> std::io::Result<bool> {
+        let sealed = self.segments.get(&base_offset).map(|s| s.state) == Some(SegmentState::Sealed);
+        if !sealed || !self.uploaded.contains(&base_offset) {
+            return Ok(false);
+        }
+
+        if let Some(segment) = self.segments.get_mut(&base_offset) {
+            segment.state = SegmentState::Remote;
+        }
+        self.mapped.lock().unwrap().remove(&base_offset);
+        self.remove_files(base_offset)?;
+        Ok(true)
+    }
+
+    fn remove(&mut self, base_offset: SegmentId) -> std::io::Result<()> {
+        let segment = self.segments.remove(&base_offset);
+        self.indexes.remove(&base_offset);
+        self.uploaded.remove(&base_offset);
+        self.mapped.lock().unwrap().remove(&base_offset);
+        match segment {
+            Some(segment) if segment.state == SegmentState::Remote => Ok(()),
+            _ => self.remove_files(base_offset),
+        }
+    }
+
+    fn remove_files(&self, base_offset: SegmentId) -> std::io::Result<()> {
+        fs::remove_file(self.shard_dir.path_to_segment(base_offset))?;
+        match fs::remove_file(self.shard_dir.path_to_index(base_offset)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Brings the index of a segment up to date with its data, returning it with the segment, as a
+/// sealed one. The index is only trusted up to the last batch it points at that really is in the
+/// segment; batches after it are checked and re-indexed from the data, and a torn batch left at
+/// the end by a crash mid-append is truncated away. Any other damage is an error.
+fn recover_index(shard_dir: &ShardDir, base_offset: SegmentId) -> std::io::Result<(Segment, SegmentIndex)> {
+    let path = shard_dir.path_to_segment(base_offset);
+    let index_path = shard_dir.path_to_index(base_offset);
+    let in_segment = |e: Error| Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e));
+    let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut index = SegmentIndex::load(&index_path)?;
+    let mut position = 0;
+    let mut next_offset = base_offset;
+    let mut last_put_ms = None;
+    if let Some(last) = index.last().copied() {
+        let header = read_frame(&mut file, last.position, file_len)
+            .ok()
+            .flatten()
+            .and_then(|frame| BatchHeader::parse(&frame).ok())
+            .filter(|header| header.base_sequence == last.sequence);
+        match header {
+            Some(header) => {
+                position = last.position + header.frame_len();
+                next_offset = header.next_sequence();
+                last_put_ms = Some(header.timestamp_ms);
+            }
+            None => index = SegmentIndex::default(),
+        }
+    }
+
+    let mut rebuilt = false;
+    while position < file_len {
+        let frame = match read_frame(&mut file, position, file_len).map_err(in_segment)? {
+            Some(frame) => frame,
+            None => {
+                warn!(position, file_len, path = %path.display(), "truncating a torn batch");
+                file.set_len(position)?;
+                break;
+            }
+        };
+        let header = RecordBatch::verify(&frame).map_err(in_segment)?;
+
+        index.push(IndexEntry { sequence: header.base_sequence, position });
+        position += header.frame_len();
+        next_offset = header.next_sequence();
+        last_put_ms = Some(header.timestamp_ms);
+        rebuilt = true;
+    }
+
+    if rebuilt || index.entries().is_empty() {
+        index.store(&index_path)?;
+    }
+    let segment = Segment { base_offset, next_offset, size: position, state: SegmentState::Sealed, last_put_ms };
+    Ok((segment, index))
+}
+
+/// The index of a segment along with the segment, as a sealed one, provided the last batch the
+/// index points at ends the segment; `None` when it does not and the index has to be recovered.
+fn trusted_index(shard_dir: &ShardDir, base_offset: SegmentId) -> std::io::Result<Option<(Segment, SegmentIndex)>> {
+    let mut file = File::open(shard_dir.path_to_segment(base_offset))?;
+    let file_len = file.metadata()?.len();
+    let index = SegmentIndex::load(&shard_dir.path_to_index(base_offset))?;
+    let last = match index.last() {
+        Some(last) => *last,
+        None if file_len == 0 => {
+            let segment = Segment { base_offset, next_offset: base_offset, size: 0, state: SegmentState::Sealed, last_put_ms: None };
+            return Ok(Some((segment, index)));
+        }
+        None => return Ok(None),
+    };
+    if last.position + BATCH_HEADER_SIZE as u64 > file_len {
+        return Ok(None);
+    }
+    let mut header = vec![0; BATCH_HEADER_SIZE];
+    file.seek(SeekFrom::Start(last.position))?;
+    file.read_exact(&mut header)?;
+    let header = match BatchHeader::parse(&header) {
+        Ok(header) if header.base_sequence == last.sequence && last.position + header.frame_len() == file_len => header,
+        _ => return Ok(None),
+    };
+    let segment = Segment {
+        base_offset,
+        next_offset: header.next_sequence(),
+        size: file_len,
+        state: SegmentState::Sealed,
+        last_put_ms: Some(header.timestamp_ms),
+    };
+    Ok(Some((segment, index)))
+}
+
+/// the whole frame starting at `position`, or `None` if it does not fit in the file
+fn read_frame(file: &mut File, position: u64, file_len: u64) -> std::io::Result<Option<Vec<u8>>> {
+    if position + BATCH_HEADER_SIZE as u64 > file_len {
+        return Ok(None);
+    }
+    let mut frame = vec![0; BATCH_HEADER_SIZE];
+    file.seek(SeekFrom::Start(position))?;
+    file.read_exact(&mut frame)?;
+
+    let header = BatchHeader::parse(&frame)?;
+    if position + header.frame_len() > file_len {
+        return Ok(None);
+    }
+    frame.resize(header.frame_len() as usize, 0);
+    file.read_exact(&mut frame[BATCH_HEADER_SIZE..])?;
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use crate::shards::batch::{BatchHeader, Compression, RecordBatch};
+    use crate::shards::index::{IndexEntry, INDEX_ENTRY_SIZE};
+    use crate::shards::segments::{Segment, SegmentManager, SegmentState};
+    use crate::shards::shards::{Record, ShardDir};
+    use crate::test_util::with_tmp_dir;
+
+    fn frame(base_sequence: u64, records: usize) -> Vec<u8> {
+        let records = (0..records).map(|i| Record::new(vec![b'x'; i + 1])).collect();
+        RecordBatch::new(base_sequence, 0, records).encode(Compression::None).unwrap()
+    }
+
+    /// writes a batch of `records` records at the end of the active segment, like the shard writer
+    fn append(manager: &mut SegmentManager, records: usize) {
+        let frame = frame(manager.end_offset(), records);
+        let path = manager.path_to(manager.active());
+        let position = manager.active().size;
+        OpenOptions::new().append(true).open(path).unwrap().write_all(&frame).unwrap();
+        manager.append_batch(position, &BatchHeader::parse(&frame).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn segment_manager_starts_with_an_empty_active_segment() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let manager = SegmentManager::open(shard_dir, 10).unwrap();
+
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), vec![
+                Segment { base_offset: 0, next_offset: 0, size: 0, state: SegmentState::Active, last_put_ms: None },
+            ]);
+            assert_eq!(manager.end_offset(), 0);
+            assert_eq!(manager.find(0), None);
+        })
+    }
+
+    #[test]
+    fn segment_manager_rolls_at_the_log_end_offset() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let frame_len = frame(0, 2).len() as u64;
+            let mut manager = SegmentManager::open(shard_dir.clone(), frame_len + 1).unwrap();
+            append(&mut manager, 2);
+            assert!(!manager.should_roll());
+            append(&mut manager, 2);
+            assert!(manager.should_roll());
+            manager.roll().unwrap();
+            append(&mut manager, 1);
+
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), vec![
+                Segment { base_offset: 0, next_offset: 4, size: 2 * frame_len, state: SegmentState::Sealed, last_put_ms: Some(0) },
+                Segment { base_offset: 4, next_offset: 5, size: frame(4, 1).len() as u64, state: SegmentState::Active, last_put_ms: Some(0) },
+            ]);
+            assert!(shard_dir.path_to_segment(4).exists());
+            assert_eq!(manager.find(3).unwrap().base_offset, 0);
+            assert_eq!(manager.find(4).unwrap().base_offset, 4);
+            assert_eq!(manager.find(5), None);
+            assert_eq!(manager.locate(3).unwrap().1, IndexEntry { sequence: 2, position: frame_len });
+        })
+    }
+
+    #[test]
+    fn segment_manager_reloads_sizes_and_states_from_disk() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&frame(0, 5)).unwrap();
+            File::create(shard_dir.path_to_segment(5)).unwrap().write_all(&frame(5, 3)).unwrap();
+
+            let manager = SegmentManager::open(shard_dir.clone(), 10).unwrap();
+
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), vec![
+                Segment { base_offset: 0, next_offset: 5, size: frame(0, 5).len() as u64, state: SegmentState::Sealed, last_put_ms: Some(0) },
+                Segment { base_offset: 5, next_offset: 8, size: frame(5, 3).len() as u64, state: SegmentState::Active, last_put_ms: Some(0) },
+            ]);
+            assert_eq!(manager.end_offset(), 8);
+            assert!(shard_dir.path_to_index(0).exists());
+        })
+    }
+
+    #[test]
+    fn segment_manager_trusts_indexes_ending_their_segments_after_a_clean_shutdown() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            let mut manager = SegmentManager::open(shard_dir.clone(), 1000).unwrap();
+            append(&mut manager, 2);
+            append(&mut manager, 3);
+            manager.roll().unwrap();
+            append(&mut manager, 1);
+            manager.sync_local().unwrap();
+            let expected = manager.segments().cloned().collect::<Vec<Segment>>();
+            drop(manager);
+
+            let manager = SegmentManager::open_after_clean_shutdown(shard_dir.clone(), 1000).unwrap();
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), expected);
+            assert_eq!(manager.locate(3).unwrap().1, IndexEntry { sequence: 2, position: frame(0, 2).len() as u64 });
+            drop(manager);
+
+            // an index missing its last entry no longer ends its segment and is recovered
+            let index_path = shard_dir.path_to_index(0);
+            let index = std::fs::read(&index_path).unwrap();
+            std::fs::write(&index_path, &index[..index.len() - INDEX_ENTRY_SIZE]).unwrap();
+
+            let manager = SegmentManager::open_after_clean_shutdown(shard_dir.clone(), 1000).unwrap();
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), expected);
+            assert_eq!(std::fs::read(&index_path).unwrap(), index);
+        })
+    }
+
+    #[test]
+    fn segment_manager_truncates_a_torn_batch_and_refuses_a_corrupt_one() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            let whole = frame(0, 3);
+            let torn = frame(3, 2);
+            let mut data = whole.clone();
+            data.extend_from_slice(&torn[..torn.len() - 1]);
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&data).unwrap();
+
+            let manager = SegmentManager::open(shard_dir.clone(), 1000).unwrap();
+            assert_eq!(manager.end_offset(), 3);
+            assert_eq!(std::fs::read(shard_dir.path_to_segment(0)).unwrap(), whole);
+
+            let mut data = whole.clone();
+            data.extend_from_slice(&torn);
+            let last = data.len() - 1;
+            data[last] ^= 0xff;
+            data.extend_from_slice(&frame(5, 1));
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&data).unwrap();
+
+            assert!(SegmentManager::open(shard_dir, 1000).is_err());
+        })
+    }
+
+    #[test]
+    fn segment_manager_refuses_overlapping_segments() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&frame(0, 5)).unwrap();
+            File::create(shard_dir.path_to_segment(3)).unwrap().write_all(&frame(3, 2)).unwrap();
+
+            assert!(SegmentManager::open(shard_dir, 10).is_err());
+        })
+    }
+
+    #[test]
+    fn segment_manager_ignores_files_that_are_not_segments() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir: mount_dir.clone() };
+            shard_dir.assert_mount_path();
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&frame(0, 5)).unwrap();
+            File::create(mount_dir.join(".DS_Store")).unwrap();
+
+            let manager = SegmentManager::open(shard_dir, 10).unwrap();
+
+            assert_eq!(manager.len(), 1);
+            assert_eq!(manager.end_offset(), 5);
+        })
+    }
+
+    #[test]
+    fn segment_manager_find_uses_the_catalog_after_deletes() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let mut manager = SegmentManager::open(shard_dir.clone(), 10).unwrap();
+            for _ in 0..4 {
+                append(&mut manager, 11);
+                manager.roll().unwrap();
+            }
+            append(&mut manager, 4);
+
+            assert_eq!(manager.delete_before(30).unwrap(), vec![0, 11]);
+            assert!(!shard_dir.path_to_segment(11).exists());
+            assert!(!shard_dir.path_to_index(11).exists());
+            assert!(shard_dir.path_to_segment(22).exists());
+
+            assert_eq!(manager.oldest_offset(), 22);
+            assert_eq!(manager.find(5).unwrap().base_offset, 22);
+            assert_eq!(manager.find(32).unwrap().base_offset, 22);
+            assert_eq!(manager.find(33).unwrap().base_offset, 33);
+            assert_eq!(manager.find(47).unwrap().base_offset, 44);
+            assert_eq!(manager.find(48), None);
+
+            assert_eq!(manager.delete_before(1000).unwrap(), vec![22, 33]);
+            assert_eq!(manager.oldest_offset(), 44);
+        })
+    }
+
+    #[test]
+    fn segment_manager_shares_one_map_per_sealed_segment() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+            File::create(shard_dir.path_to_segment(0)).unwrap().write_all(&frame(0, 5)).unwrap();
+            File::create(shard_dir.path_to_segment(5)).unwrap().write_all(&frame(5, 3)).unwrap();
+
+            let mut manager = SegmentManager::open(shard_dir, 10).unwrap();
+            let sealed = manager.find(0).unwrap().clone();
+            let active = manager.active().clone();
+
+            let first = manager.mmap(&sealed).unwrap();
+            let second = manager.mmap(&sealed).unwrap();
+            assert!(Arc::ptr_eq(&first, &second));
+            assert_eq!(&first[..], &frame(0, 5)[..]);
+            assert!(manager.mmap(&active).is_err());
+
+            manager.delete_before(5).unwrap();
+            assert_eq!(&first[..], &frame(0, 5)[..]);
+            assert!(manager.mmap(&sealed).is_err());
+        })
+    }
+
+    #[test]
+    fn segment_manager_truncates_back_into_a_sealed_segment() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir };
+            shard_dir.assert_mount_path();
+
+            let frame_len = frame(0, 2).len() as u64;
+            let mut manager = SegmentManager::open(shard_dir.clone(), 2 * frame_len).unwrap();
+            for _ in 0..5 {
+                append(&mut manager, 2);
+                if manager.should_roll() {
+                    manager.roll().unwrap();
+                }
+            }
+            assert_eq!(manager.len(), 2);
+            let sealed = manager.find(0).unwrap().clone();
+            let map = manager.mmap(&sealed).unwrap();
+
+            assert!(manager.truncate(3).is_err());
+            manager.truncate(4).unwrap();
+            assert_eq!(manager.segments().cloned().collect::<Vec<Segment>>(), vec![
+                Segment { base_offset: 0, next_offset: 4, size: 2 * frame_len, state: SegmentState::Active, last_put_ms: Some(0) },
+            ]);
+            assert!(!shard_dir.path_to_segment(6).exists());
+            assert_eq!(map.len() as u64, 3 * frame_len);
+            append(&mut manager, 1);
+            assert_eq!(manager.end_offset(), 5);
+
+            // what is left survives a restart
+            drop(manager);
+            let manager = SegmentManager::open(shard_dir, 2 * frame_len).unwrap();
+            assert_eq!(manager.end_offset(), 5);
+            assert_eq!(manager.locate(4).unwrap().1, IndexEntry { sequence: 4, position: 2 * frame_len });
+        })
+    }
+}
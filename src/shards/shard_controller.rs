@@ -1,116 +1,783 @@
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
-
-use actix_web::{App, get, HttpRequest, HttpResponse, HttpServer, post, Responder, web};
-use actix_web::body::Body;
-use json::JsonValue;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
 use serde_derive::{Deserialize, Serialize};
-use structopt::StructOpt;
+use tracing::{info, instrument, Span, warn};
+
+use crate::shards::batch::{BatchHeader, Compression};
+use crate::shards::compaction::{CleanupPolicy, DEFAULT_TOMBSTONE_RETENTION_MS, spawn_compactor};
+use crate::shards::durability::{FsyncPolicy, FsyncStats, spawn_interval_flusher};
+use crate::shards::encryption::{BatchKey, KeyFile, KeyProvider, load_stream_key_id, store_stream_key_id};
+use crate::shards::format::LegacyOffsets;
+use crate::shards::producers::{ProducerBatch, ProducerWindow};
+use crate::shards::quota::{PutQuota, Quota};
+use crate::shards::replication::{FetchResponse, Leadership, load_leader_epoch, ReplicaStatus, ReplicaTracker, ReplicationConfig, store_leader_epoch};
+use crate::shards::retention::{Retention, spawn_retainer};
+use crate::shards::segments::{SegmentManager, SegmentState};
+use crate::shards::shards::{Record, ShardDir, ShardIteratorType, ShardReader, ShardWriter, ShaW};
+use crate::shards::shutdown::{CleanShutdown, StopSignal, store_clean_shutdown, take_clean_shutdown};
+use crate::shards::stats::{ShardMetrics, ShardStats};
+use crate::shards::tiering::{LocalDirStore, spawn_tierer, TieredStorage, TieringConfig};
+use crate::shards::transactions::{Isolation, Marker, ShardTransactions};
+
+/// how often the compactor of a compacted stream runs
+const COMPACTION_INTERVAL_MS: u64 = 30 * 1000;
+
+/// how often sealed segments of a tiered stream are uploaded and offloaded
+const TIERING_INTERVAL_MS: u64 = 10 * 1000;
+
+/// how often the segments of a stream with a retention are checked for expired ones
+const RETENTION_INTERVAL_MS: u64 = 60 * 1000;
 
-use crate::Response;
-use crate::shards::shards::{assert_recordable, Record, ShardDir, ShardIteratorType, ShardReader, ShardWriter, ShaW};
+/// how large the active segment grows before it is rolled
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 1000 * 1000;
+
+/// how many records a get returns at most
+pub const DEFAULT_MAX_RECORDS_PER_GET: usize = 10;
+
+/// Settings of the stream a shard belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+    /// size in bytes past which the active segment is rolled
+    pub max_segment_size: u64,
+    pub max_records_per_get: usize,
+    pub fsync_policy: FsyncPolicy,
+    /// codec new batches are written with; readers take it from each batch header
+    pub compression: Compression,
+    pub cleanup_policy: CleanupPolicy,
+    /// how long compaction keeps a tombstone after it was written
+    pub tombstone_retention_ms: u64,
+    /// how old and how large the shard gets before its oldest segments are deleted
+    pub retention: Retention,
+    /// where sealed segments go once they leave the mount path, `None` keeps them all local
+    pub tiering: Option<TieringConfig>,
+    /// keys encrypted batches can use, see `start_stream_encryption`
+    pub keyfile: Option<PathBuf>,
+    pub replication: ReplicationConfig,
+    /// how much each shard takes in, puts past it are turned away
+    pub quota: Quota,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            max_records_per_get: DEFAULT_MAX_RECORDS_PER_GET,
+            fsync_policy: FsyncPolicy::default(),
+            compression: Compression::default(),
+            cleanup_policy: CleanupPolicy::default(),
+            tombstone_retention_ms: DEFAULT_TOMBSTONE_RETENTION_MS,
+            retention: Retention::default(),
+            tiering: None,
+            keyfile: None,
+            replication: ReplicationConfig::default(),
+            quota: Quota::default(),
+        }
+    }
+}
 
 pub struct ShardController {
     pub shard_dir: ShardDir,
-    pub latest_log_offset: Arc<AtomicUsize>,
+    pub config: StreamConfig,
+    pub segments: Arc<RwLock<SegmentManager>>,
     pub write_lock: Mutex<()>,
+    /// key new batches are encrypted with, `None` while encryption is stopped
+    pub encryption_key_id: RwLock<Option<String>>,
+    pub unsynced_records: Arc<AtomicUsize>,
+    pub fsync_stats: Arc<FsyncStats>,
+    pub stats: ShardStats,
+    /// how far the replicas got; reads stop at its high-watermark
+    pub replicas: ReplicaTracker,
+    pub producers: Arc<Mutex<ProducerWindow>>,
+    pub transactions: Arc<Mutex<ShardTransactions>>,
+    quota: PutQuota,
+    /// where the records were before the dir was migrated from the legacy format, while its
+    /// `legacy-offsets` file is kept
+    legacy_offsets: Option<LegacyOffsets>,
+    /// set once the shard was split or merged, after which it takes no more puts
+    closed: AtomicBool,
+    /// set once the shard was shut down, after which nothing is written to it
+    shut_down: AtomicBool,
+    /// raised on shutdown, for the background threads changing the segments to stop
+    stop: Arc<StopSignal>,
+    /// those threads, joined on shutdown
+    background: Mutex<Vec<JoinHandle<()>>>,
+    /// starts out as `config.replication.leader` in the stored leader epoch, see `set_leader`
+    leadership: RwLock<Leadership>,
+    /// what the background threads of the shard log in
+    pub span: Span,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetRecordsResponse {
     pub next_shard_iterator: u64,
     pub records: Vec<String>,
+    /// sequence number and partition key of each record; compacted streams have gaps in the
+    /// sequence numbers
+    pub sequence_numbers: Vec<u64>,
+    pub partition_keys: Vec<Option<String>>,
+    /// headers of each record, empty for records put without any
+    #[serde(default)]
+    pub headers: Vec<BTreeMap<String, String>>,
+    /// the shard is closed and there is nothing left to read past `next_shard_iterator`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shard_end: bool,
+}
+
+/// A record to put: either just its base64 data or the data along with a partition key and
+/// headers, both optional.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PutRecordsEntry {
+    Data(String),
+    Keyed {
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        partition_key: Option<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+    },
+}
+
+impl PutRecordsEntry {
+    fn into_record(self) -> Result<Record, failure::Error> {
+        match self {
+            PutRecordsEntry::Data(data) => Record::from_string(data),
+            PutRecordsEntry::Keyed { data, partition_key, headers } => {
+                let data = Record::from_string(data)?.data;
+                let record = match partition_key {
+                    Some(partition_key) => Record::keyed(partition_key, data),
+                    None => Record::new(data),
+                };
+                Ok(record.with_headers(headers))
+            }
+        }
+    }
+}
+
+/// `record` puts a single record, `records` a batch that is stored and compressed together.
+/// Idempotent producers also give their id and the producer sequence of the first record, and so
+/// do puts in a transaction, with the transaction id instead of a producer id.
+#[derive(Deserialize, Serialize)]
+pub struct PutRecordsRequest {
+    pub record: Option<PutRecordsEntry>,
+    #[serde(default)]
+    pub records: Vec<PutRecordsEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer_sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<u64>,
+}
+
+impl PutRecordsRequest {
+    pub fn into_records(self) -> Result<Vec<Record>, failure::Error> {
+        self.record.into_iter().chain(self.records).map(PutRecordsEntry::into_record).collect()
+    }
+
+    pub fn producer(&self) -> Result<Option<ProducerBatch>, failure::Error> {
+        match (self.transaction_id, self.producer_id, self.producer_sequence) {
+            (None, None, None) => Ok(None),
+            (None, Some(producer_id), Some(producer_sequence)) => Ok(Some(ProducerBatch { producer_id, producer_sequence, transactional: false })),
+            (Some(transaction_id), None, Some(producer_sequence)) => Ok(Some(ProducerBatch { producer_id: transaction_id, producer_sequence, transactional: true })),
+            (Some(_), Some(_), _) => Err(failure::format_err!("a transaction is its own producer, leave producer_id out")),
+            (Some(_), None, None) => Err(failure::format_err!("puts in a transaction need a producer_sequence")),
+            _ => Err(failure::format_err!("producer_id and producer_sequence go together")),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct PutRecordsResponse {
+    pub sequence_numbers: Vec<u64>,
+}
 
+/// What `/admin/shards` shows of a shard: where it lives, who leads it and its segment catalog.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ShardSummary {
+    /// the stream and shard id, for shards of a cluster
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard_id: Option<u32>,
+    pub dir: String,
+    /// the node followed, `None` while the shard is led here
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+    pub log_end_offset: u64,
+    pub high_watermark: u64,
+    pub closed: bool,
+    pub segments: Vec<SegmentSummary>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AdminShardsResponse {
+    pub shards: Vec<ShardSummary>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SegmentSummary {
+    pub base_offset: u64,
+    pub end_offset: u64,
+    /// bytes, on the mount path or in the object store for remote segments
+    pub size: u64,
+    pub state: SegmentState,
 }
 
 
 impl ShardController {
-    pub fn get_records(&self, shard_iterator: u64) -> GetRecordsResponse {
-        let shard_dir = self.shard_dir.clone();
-        let (shard_id, offset) = shard_dir.find_belonging_segment(shard_iterator);
+    pub fn new(shard_dir: ShardDir, config: StreamConfig) -> std::io::Result<ShardController> {
+        let span = tracing::info_span!("shard", dir = %shard_dir);
+        // a shard shut down cleanly is opened from its marker and its synced indexes, unless the
+        // log ends elsewhere than the marker says, when it is recovered and scanned after all
+        let marker = take_clean_shutdown(&shard_dir.path_to_clean_shutdown())?;
+        let mut segments = span.in_scope(|| match &marker {
+            Some(_) => SegmentManager::open_after_clean_shutdown(shard_dir.clone(), config.max_segment_size),
+            None => SegmentManager::open(shard_dir.clone(), config.max_segment_size),
+        })?;
+        let marker = match marker {
+            Some(marker) if marker.log_end_offset == segments.end_offset() => {
+                span.in_scope(|| info!(log_end_offset = marker.log_end_offset, "shut down cleanly last time, not scanning the segments"));
+                Some(marker)
+            }
+            Some(marker) => {
+                span.in_scope(|| warn!(
+                    marker = marker.log_end_offset,
+                    log_end_offset = segments.end_offset(),
+                    "the clean-shutdown marker does not match the log, scanning the segments",
+                ));
+                drop(segments);
+                segments = span.in_scope(|| SegmentManager::open(shard_dir.clone(), config.max_segment_size))?;
+                None
+            }
+            None => None,
+        };
+        if let Some(tiering) = &config.tiering {
+            let tiered = TieredStorage::new(
+                Box::new(LocalDirStore::new(tiering.remote_dir.clone())?),
+                shard_dir.mount_dir.join("remote-cache"),
+                tiering.hot_retention_ms,
+                tiering.cache_segments,
+            )?;
+            segments.attach_tiered(Arc::new(tiered))?;
+        }
+        if let Some(keyfile) = &config.keyfile {
+            segments.keys = Some(Arc::new(KeyFile::load(keyfile)?) as Arc<dyn KeyProvider>);
+        }
+        let encryption_key_id = load_stream_key_id(&shard_dir.path_to_encryption())?;
+        if let Some(key_id) = &encryption_key_id {
+            stream_key(&segments.keys, key_id)?;
+        }
 
-        let mut reader: ShardReader = ShardReader {
-            segment_id: shard_id,
-            offset,
-            chunk_size: 10,
+        let legacy_offsets = LegacyOffsets::load(&shard_dir)?;
+        let leader_epoch = load_leader_epoch(&shard_dir.path_to_leader_epoch())?;
+        let replicas = ReplicaTracker::new(
+            config.replication.min_insync_replicas,
+            config.replication.replica_lag_max_ms,
+            leader_epoch,
+            segments.end_offset(),
+        );
+        let leadership = Leadership { leader: config.replication.leader.clone(), leader_epoch };
+        let (producers, transactions) = match marker {
+            Some(marker) => (marker.producers, marker.transactions),
+            None => (ProducerWindow::load(&segments)?, ShardTransactions::load(&segments)?),
+        };
+
+        Ok(ShardController {
             shard_dir,
-            latest_log_offset: self.latest_log_offset.load(Ordering::Relaxed),
+            quota: PutQuota::new(config.quota),
+            legacy_offsets,
+            config,
+            segments: Arc::new(RwLock::new(segments)),
+            write_lock: Mutex::new(()),
+            encryption_key_id: RwLock::new(encryption_key_id),
+            unsynced_records: Arc::new(AtomicUsize::new(0)),
+            fsync_stats: Arc::new(FsyncStats::default()),
+            stats: ShardStats::default(),
+            replicas,
+            producers: Arc::new(Mutex::new(producers)),
+            transactions: Arc::new(Mutex::new(transactions)),
+            closed: AtomicBool::new(false),
+            shut_down: AtomicBool::new(false),
+            stop: Arc::new(StopSignal::default()),
+            background: Mutex::new(Vec::new()),
+            leadership: RwLock::new(leadership),
+            span,
+        })
+    }
+
+    /// starts the background flusher when the fsync policy needs one
+    pub fn spawn_flusher(&self) -> Option<JoinHandle<()>> {
+        let _entered = self.span.enter();
+        match self.config.fsync_policy {
+            FsyncPolicy::Interval(interval_ms) => Some(spawn_interval_flusher(
+                self.segments.clone(),
+                self.unsynced_records.clone(),
+                self.fsync_stats.clone(),
+                interval_ms,
+            )),
+            _ => None,
+        }
+    }
+
+    /// starts the background compactor of compacted streams, which stops on shutdown
+    pub fn spawn_compactor(&self) {
+        let _entered = self.span.enter();
+        match self.config.cleanup_policy {
+            CleanupPolicy::Compact => self.background.lock().unwrap().push(spawn_compactor(
+                self.segments.clone(),
+                self.config.tombstone_retention_ms,
+                COMPACTION_INTERVAL_MS,
+                self.stop.clone(),
+            )),
+            CleanupPolicy::None => {}
+        }
+    }
+
+    /// starts uploading and offloading sealed segments when the stream is tiered, until shutdown
+    pub fn spawn_tierer(&self) {
+        let tiered = match self.segments.read().unwrap().tiered.clone() {
+            Some(tiered) => tiered,
+            None => return,
         };
-        let records: Vec<Record> = reader.read().unwrap();
-        println!("read {} records", records.len());
+        let _entered = self.span.enter();
+        let tierer = spawn_tierer(self.segments.clone(), tiered, TIERING_INTERVAL_MS, self.stop.clone());
+        self.background.lock().unwrap().push(tierer);
+    }
+
+    /// starts deleting expired segments when the stream has a retention, until shutdown
+    pub fn spawn_retainer(&self) {
+        if self.config.retention.keeps_everything() {
+            return;
+        }
+        let _entered = self.span.enter();
+        let retainer = spawn_retainer(self.segments.clone(), self.config.retention, RETENTION_INTERVAL_MS, self.stop.clone());
+        self.background.lock().unwrap().push(retainer);
+    }
 
-        let records = records.iter().map(|r| r.as_string()).collect();
+    pub fn get_shard_iterator(&self, iterator_type: ShardIteratorType) -> std::io::Result<u64> {
+        let shard_iterator = self.shard_iterator(iterator_type)?;
+        self.stats.iterators.handed_out(shard_iterator);
+        Ok(shard_iterator)
+    }
+
+    fn shard_iterator(&self, iterator_type: ShardIteratorType) -> std::io::Result<u64> {
+        let segments = self.segments.read().unwrap();
+        match iterator_type {
+            ShardIteratorType::Latest => Ok(segments.end_offset()),
+            ShardIteratorType::Oldest => Ok(segments.oldest_offset()),
+            ShardIteratorType::AtTimestamp { timestamp_ms } => {
+                let oldest = segments.oldest_offset();
+                drop(segments);
+                self.shard_iterator_at(oldest, timestamp_ms)
+            }
+        }
+    }
 
-        GetRecordsResponse {
-            next_shard_iterator: reader.segment_id + reader.offset,
-            records
+    /// The first batch from `oldest` on appended at or after `timestamp_ms`. There is no time
+    /// index, so batch headers are scanned in order.
+    fn shard_iterator_at(&self, oldest: u64, timestamp_ms: u64) -> std::io::Result<u64> {
+        let mut reader = ShardReader {
+            segments: self.segments.clone(),
+            position: oldest,
+            chunk_size: 0,
+            shard_dir: self.shard_dir.clone(),
+            aborted: vec![],
+        };
+        loop {
+            let frames = reader.read_frames(1024 * 1024)?;
+            if frames.is_empty() {
+                return Ok(reader.position);
+            }
+            let mut position = 0;
+            while position < frames.len() {
+                let header = BatchHeader::parse(&frames[position..])?;
+                if header.timestamp_ms >= timestamp_ms {
+                    return Ok(header.base_sequence);
+                }
+                position += header.frame_len() as usize;
+            }
         }
     }
 
-    pub fn put_records(&self, records: Record) -> PutRecordsResponse {
-        let _ = self.write_lock.lock();
+    pub fn get_records(&self, shard_iterator: u64) -> std::io::Result<GetRecordsResponse> {
+        self.get_records_with(shard_iterator, Isolation::ReadUncommitted)
+    }
 
-        let latest_segment = self.shard_dir.get_latest_segment();
-        let latest_shard_offset = self.shard_dir.get_end_offset(latest_segment);
-        let mut shard_writer = ShardWriter {
-            latest_segment: self.latest_log_offset.load(Ordering::Relaxed) as u64,
+    #[instrument(level = "debug", name = "get_records", skip(self), fields(shard = %self.shard_dir))]
+    pub fn get_records_with(&self, shard_iterator: u64, isolation: Isolation) -> std::io::Result<GetRecordsResponse> {
+        let start = Instant::now();
+        // shard iterators handed out before a migration from the legacy format are byte offsets,
+        // all below the sequence numbers; the answer holds a sequence number to go on from
+        let shard_iterator = self.legacy_offsets
+            .as_ref()
+            .and_then(|legacy_offsets| legacy_offsets.sequence(shard_iterator))
+            .unwrap_or(shard_iterator);
+        // records past the high-watermark are not acknowledged yet and could still be lost
+        let high_watermark = self.replicas.high_watermark();
+        let (end, aborted) = match isolation {
+            Isolation::ReadUncommitted => (high_watermark, vec![]),
+            Isolation::ReadCommitted => {
+                let transactions = self.transactions.lock().unwrap();
+                (transactions.last_stable_offset(high_watermark), transactions.aborted_from(shard_iterator))
+            }
+        };
+        let mut reader: ShardReader = ShardReader {
+            segments: self.segments.clone(),
+            position: shard_iterator,
+            chunk_size: self.config.max_records_per_get,
             shard_dir: self.shard_dir.clone(),
-            offset: latest_shard_offset,
-            max_segment_size: 1000000,
+            aborted,
         };
+        let mut records = reader.read_sequenced()?;
+        records.retain(|(sequence, _)| *sequence < end);
+        let next_shard_iterator = reader.position.min(end.max(shard_iterator));
+        let shard_end = self.is_closed() && next_shard_iterator >= self.segments.read().unwrap().end_offset();
+        let bytes = records.iter().map(|(_, r)| r.data.len() as u64).sum();
+        self.stats.got(records.len() as u64, bytes, start.elapsed());
+        self.stats.iterators.read(shard_iterator, next_shard_iterator);
 
+        Ok(GetRecordsResponse {
+            next_shard_iterator,
+            shard_end,
+            records: records.iter().map(|(_, r)| r.as_string()).collect(),
+            sequence_numbers: records.iter().map(|(sequence, _)| *sequence).collect(),
+            partition_keys: records.iter().map(|(_, r)| r.partition_key.clone()).collect(),
+            headers: records.into_iter().map(|(_, r)| r.headers).collect(),
+        })
+    }
 
+    /// Appends the records as one batch and returns once it is as durable as the fsync policy
+    /// demands and `min_insync_replicas` replicas have it, so an `Ok` is the acknowledgement.
+    /// A put that times out waiting for the replicas stays in the log and can still show up.
+    pub fn put_records(&self, records: Vec<Record>) -> std::io::Result<PutRecordsResponse> {
+        self.put_records_from(None, records)
+    }
 
-        shard_writer.write(records);
-        self.latest_log_offset.store(shard_writer.latest_segment as usize, Ordering::Relaxed);
+    /// Same as `put_records`, for an idempotent producer when there is one: a batch it already
+    /// put gets its original sequence numbers back instead of being appended again.
+    #[instrument(level = "debug", name = "put_records", skip_all, fields(shard = %self.shard_dir, records = records.len(), producer = ?producer))]
+    pub fn put_records_from(&self, producer: Option<ProducerBatch>, records: Vec<Record>) -> std::io::Result<PutRecordsResponse> {
+        let start = Instant::now();
+        let (record_count, bytes) = (records.len() as u64, records.iter().map(|r| r.data.len() as u64).sum());
+        let result = self.append_and_wait(producer, records);
+        match &result {
+            Ok(_) => self.stats.put(record_count, bytes, start.elapsed()),
+            Err(e) if e.kind() == ErrorKind::ResourceBusy || e.kind() == ErrorKind::QuotaExceeded => {
+                self.stats.throttled();
+                warn!(error = %e, "put throttled");
+            }
+            Err(e) => warn!(error = %e, "put failed"),
+        }
+        result
+    }
 
-        PutRecordsResponse {}
+    fn append_and_wait(&self, producer: Option<ProducerBatch>, records: Vec<Record>) -> std::io::Result<PutRecordsResponse> {
+        let record_count = records.len() as u64;
+        let bytes = records.iter().map(|r| r.data.len() as u64).sum();
+        let (leader_epoch, first_sequence_number) = {
+            let _guard = self.write_lock.lock().unwrap();
+            let leader_epoch = self.assert_leader()?;
+            if self.is_closed() {
+                return Err(Error::new(ErrorKind::InvalidInput, "the shard is closed, its children take the puts"));
+            }
+            if !self.quota.admit(record_count, bytes) {
+                return Err(Error::new(ErrorKind::QuotaExceeded, "the put quota of the shard is used up, slow down"));
+            }
+            let in_sync_replicas = self.replicas.in_sync_replicas();
+            if in_sync_replicas < self.replicas.min_insync_replicas {
+                return Err(Error::new(ErrorKind::ResourceBusy, format!(
+                    "only {} of the {} required replicas are in sync",
+                    in_sync_replicas, self.replicas.min_insync_replicas,
+                )));
+            }
+            let mut shard_writer = self.shard_writer()?;
+            let first_sequence_number = match producer {
+                Some(producer) => shard_writer.write_idempotent(records, producer)?,
+                None => shard_writer.write(records)?,
+            };
+            (leader_epoch, first_sequence_number)
+        };
+        // the write lock is not held while waiting, so later puts can join the same fetches
+        let end = first_sequence_number + record_count;
+        self.replicas.appended(end);
+        self.replicas.wait_for(end, leader_epoch, Duration::from_millis(self.config.replication.ack_timeout_ms))?;
+
+        Ok(PutRecordsResponse {
+            sequence_numbers: (first_sequence_number..end).collect(),
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{env, panic, thread, time};
-    use std::fs::{create_dir, File};
-    use std::io::BufReader;
-    use std::io::prelude::*;
-    use std::path::PathBuf;
-    use std::sync::{Arc, Mutex};
-    use std::sync::atomic::{AtomicUsize, Ordering};
+    /// Frames from `offset` on for follower `replica_id`, which has everything before `offset`.
+    /// Followers that are not following the current leader epoch are turned away.
+    pub fn fetch(&self, replica_id: &str, leader_epoch: u64, offset: u64, max_bytes: u64) -> std::io::Result<FetchResponse> {
+        let current_epoch = self.assert_leader()?;
+        if leader_epoch != current_epoch {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("replica {} follows leader epoch {}, this leader is in epoch {}", replica_id, leader_epoch, current_epoch),
+            ));
+        }
+        let end_offset = self.segments.read().unwrap().end_offset();
+        if offset > end_offset {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("replica {} fetches from {}, past the log end {}", replica_id, offset, end_offset),
+            ));
+        }
+        self.replicas.fetched(replica_id, offset);
+
+        let mut reader = ShardReader {
+            segments: self.segments.clone(),
+            position: offset,
+            chunk_size: usize::MAX,
+            shard_dir: self.shard_dir.clone(),
+            aborted: vec![],
+        };
+        let frames = reader.read_frames(max_bytes)?;
+        Ok(FetchResponse { leader_epoch: current_epoch, high_watermark: self.replicas.high_watermark(), frames })
+    }
+
+    /// Appends what a follower fetched from its leader, returning the new log end. Fetches
+    /// answered in another leader epoch than the one followed now are dropped.
+    pub fn replicate(&self, fetched: FetchResponse) -> std::io::Result<u64> {
+        let _guard = self.write_lock.lock().unwrap();
+        let leadership = self.leadership();
+        if leadership.leader.is_none() || leadership.leader_epoch != fetched.leader_epoch {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "fetched from leader epoch {} while in epoch {}", fetched.leader_epoch, leadership.leader_epoch,
+            )));
+        }
+        let mut shard_writer = self.shard_writer()?;
+        let end_offset = shard_writer.append_frames(&fetched.frames)?;
+        self.replicas.followed(fetched.high_watermark, end_offset, fetched.frames.is_empty());
+        Ok(end_offset)
+    }
 
-    use rand::{Rng, thread_rng};
-    use rand::distributions::Alphanumeric;
+    /// Writes the marker ending transaction `transaction_id`, if it has records here that are
+    /// not ended yet, and waits for the in-sync replicas like a put does.
+    pub fn end_transaction(&self, transaction_id: u64, marker: Marker) -> std::io::Result<()> {
+        let (leader_epoch, end) = {
+            let _guard = self.write_lock.lock().unwrap();
+            let leader_epoch = self.assert_leader()?;
+            if !self.transactions.lock().unwrap().open.contains_key(&transaction_id) {
+                return Ok(());
+            }
+            let mut shard_writer = self.shard_writer()?;
+            (leader_epoch, shard_writer.write_marker(transaction_id, marker)? + 1)
+        };
+        self.replicas.appended(end);
+        self.replicas.wait_for(end, leader_epoch, Duration::from_millis(self.config.replication.ack_timeout_ms))
+    }
 
-    use crate::shards::shard_controller::{GetRecordsResponse, PutRecordsResponse, ShardController};
-    use crate::shards::shards::{Record, ShardDir, ShardReader, ShardWriter, ShaW};
+    /// Stops taking puts, once the ones being appended are. Transaction markers are still
+    /// written.
+    pub fn close(&self) {
+        let _guard = self.write_lock.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+    }
 
-    fn with_tmp_dir<T>(test: T) -> ()
-        where T: FnOnce(PathBuf) -> () + panic::UnwindSafe
-    {
-        let rand_string: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .collect();
-        let mount_dir = env::temp_dir().join(format!("to_mount-test-{}", rand_string));
-        std::fs::remove_dir_all(&mount_dir);
-        wait_a_bit();
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
 
-        let result = panic::catch_unwind(|| {
-            test(mount_dir.clone())
-        });
+    /// Shuts the shard down cleanly: once the writes under way are done it takes no more, stops
+    /// the compactor, tierer and retainer, syncs its local segments and their indexes, and
+    /// leaves a clean-shutdown marker with its producers and transactions, sparing the next open
+    /// the recovery of every index and the scan of every segment for them. Reads keep working
+    /// until the process exits.
+    pub fn shut_down(&self) -> std::io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let _entered = self.span.enter();
+        self.stop.raise();
+        let background: Vec<JoinHandle<()>> = self.background.lock().unwrap().drain(..).collect();
+        for thread in background {
+            if thread.join().is_err() {
+                warn!("a background thread of the shard panicked");
+            }
+        }
+        let segments = self.segments.read().unwrap();
+        segments.sync_local()?;
+        self.unsynced_records.store(0, Ordering::SeqCst);
+        let marker = CleanShutdown {
+            log_end_offset: segments.end_offset(),
+            producers: self.producers.lock().unwrap().clone(),
+            transactions: self.transactions.lock().unwrap().clone(),
+        };
+        store_clean_shutdown(&self.shard_dir.path_to_clean_shutdown(), &marker)?;
+        info!(log_end_offset = marker.log_end_offset, "shut down cleanly");
+        Ok(())
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// writes that come in during or after the shutdown are turned away, to be retried elsewhere
+    fn assert_running(&self) -> std::io::Result<()> {
+        match self.is_shut_down() {
+            true => Err(Error::new(ErrorKind::Interrupted, "the shard is shutting down")),
+            false => Ok(()),
+        }
+    }
+
+    /// the transactions with records here and no marker yet
+    pub fn open_transactions(&self) -> Vec<u64> {
+        self.transactions.lock().unwrap().open.keys().copied().collect()
+    }
+
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.read().unwrap().clone()
+    }
+
+    /// Makes this replica follow `leader`, or lead when `None`, from leader epoch `leader_epoch`
+    /// on. A follower first drops its log from `truncate_to` on, the tail the new leader may
+    /// never have had. Puts still waiting for the replicas of the old epoch fail.
+    pub fn set_leader(&self, leader: Option<String>, leader_epoch: u64, truncate_to: Option<u64>) -> std::io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.assert_running()?;
+        let end_offset = self.segments.read().unwrap().end_offset();
+        if let (Some(_), Some(offset)) = (&leader, truncate_to.filter(|offset| *offset < end_offset)) {
+            let _entered = self.span.enter();
+            info!(from = offset, to = end_offset, "dropping sequence numbers that did not make it to the new leader");
+            self.segments.write().unwrap().truncate(offset)?;
+            self.producers.lock().unwrap().truncate(offset);
+            // markers may have gone with the tail, reopening their transactions
+            *self.transactions.lock().unwrap() = ShardTransactions::load(&self.segments.read().unwrap())?;
+        }
+        store_leader_epoch(&self.shard_dir.path_to_leader_epoch(), leader_epoch)?;
+
+        let log_end_offset = self.segments.read().unwrap().end_offset();
+        self.replicas.reset(leader_epoch, log_end_offset);
+        if leader.is_none() {
+            self.replicas.appended(log_end_offset);
+        }
+        *self.leadership.write().unwrap() = Leadership { leader, leader_epoch };
+        Ok(())
+    }
+
+    pub fn replica_status(&self) -> ReplicaStatus {
+        let leadership = self.leadership();
+        ReplicaStatus {
+            leader_epoch: leadership.leader_epoch,
+            log_end_offset: self.segments.read().unwrap().end_offset(),
+            in_sync: leadership.leader.is_none() || self.replicas.follower_in_sync(),
+        }
+    }
+
+    /// the leader epoch writes go into
+    fn assert_leader(&self) -> std::io::Result<u64> {
+        let leadership = self.leadership.read().unwrap();
+        match &leadership.leader {
+            Some(leader) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("this shard follows {}, send writes to it", leader),
+            )),
+            None => Ok(leadership.leader_epoch),
+        }
+    }
+
+    fn shard_writer(&self) -> std::io::Result<ShardWriter> {
+        self.assert_running()?;
+        let encryption = match self.encryption_key_id.read().unwrap().as_deref() {
+            Some(key_id) => Some(stream_key(&self.segments.read().unwrap().keys, key_id)?),
+            None => None,
+        };
+        Ok(ShardWriter {
+            segments: self.segments.clone(),
+            compression: self.config.compression,
+            encryption,
+            fsync_policy: self.config.fsync_policy,
+            unsynced_records: self.unsynced_records.clone(),
+            fsync_stats: self.fsync_stats.clone(),
+            producers: self.producers.clone(),
+            transactions: self.transactions.clone(),
+        })
+    }
+
+    /// Encrypts the batches put from now on with key `key_id`, which must be known to the key
+    /// provider. Batches already written stay as they are.
+    pub fn start_stream_encryption(&self, key_id: String) -> std::io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.assert_running()?;
+        stream_key(&self.segments.read().unwrap().keys, &key_id)?;
+        store_stream_key_id(&self.shard_dir.path_to_encryption(), Some(&key_id))?;
+        *self.encryption_key_id.write().unwrap() = Some(key_id);
+        Ok(())
+    }
+
+    /// Stops encrypting new batches. Encrypted batches can still be read as long as the key
+    /// provider has their keys.
+    pub fn stop_stream_encryption(&self) -> std::io::Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        self.assert_running()?;
+        store_stream_key_id(&self.shard_dir.path_to_encryption(), None)?;
+        *self.encryption_key_id.write().unwrap() = None;
+        Ok(())
+    }
 
-        std::fs::remove_dir_all(mount_dir);
+    pub fn summary(&self) -> ShardSummary {
+        let leadership = self.leadership();
+        let segments = self.segments.read().unwrap();
+        ShardSummary {
+            stream: None,
+            shard_id: None,
+            dir: self.shard_dir.to_string(),
+            leader: leadership.leader,
+            leader_epoch: leadership.leader_epoch,
+            log_end_offset: segments.end_offset(),
+            high_watermark: self.replicas.high_watermark(),
+            closed: self.is_closed(),
+            segments: segments
+                .segments()
+                .map(|s| SegmentSummary { base_offset: s.base_offset, end_offset: s.end_offset(), size: s.size, state: s.state })
+                .collect(),
+        }
+    }
 
-        wait_a_bit();
-        assert!(result.is_ok())
+    pub fn metrics(&self) -> ShardMetrics {
+        self.stats.metrics(&self.segments.read().unwrap(), self.fsync_stats.metrics(), self.replicas.metrics())
     }
+}
+
+fn stream_key(keys: &Option<Arc<dyn KeyProvider>>, key_id: &str) -> std::io::Result<BatchKey> {
+    let keys = keys.as_ref().ok_or_else(|| Error::new(
+        ErrorKind::InvalidInput,
+        format!("stream is encrypted with key '{}' but no keyfile was given", key_id),
+    ))?;
+    Ok(BatchKey { key_id: key_id.to_string(), key: keys.key(key_id)? })
+}
 
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time};
+    use std::collections::BTreeMap;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+
+    use crate::shards::durability::FsyncPolicy;
+    use crate::shards::batch::Compression;
+    use crate::shards::compaction::{CleanupPolicy, compact};
+    use crate::shards::format::migrate_legacy;
+    use crate::shards::producers::ProducerBatch;
+    use crate::shards::quota::Quota;
+    use crate::shards::replication::{FetchResponse, Leader, ReplicationConfig};
+    use crate::shards::retention::Retention;
+    use crate::shards::segments::SegmentState;
+    use crate::shards::shard_controller::{GetRecordsResponse, PutRecordsResponse, ShardController, StreamConfig};
+    use crate::shards::shards::{now_ms, Record, ShardDir, ShardIteratorType};
+    use crate::shards::shutdown::CleanShutdown;
+    use crate::shards::transactions::{Isolation, Marker};
+    use crate::test_util::with_tmp_dir;
 
     fn wait_a_bit() {
         let ten_millis = time::Duration::from_millis(10);
@@ -123,17 +790,11 @@ mod tests {
 
             let shard_dir = ShardDir {mount_dir};
             shard_dir.assert_mount_path();
-            let latest_segment = Arc::new(AtomicUsize::new(shard_dir.get_latest_segment() as usize));
-            let write_lock = Mutex::new(());
 
-            let shac = ShardController {
-                shard_dir,
-                latest_log_offset: latest_segment,
-                write_lock
-            };
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
 
-            let result = shac.get_records(0);
-            let expected = GetRecordsResponse { next_shard_iterator: 0, records: vec![] };
+            let result = shac.get_records(0).unwrap();
+            let expected = GetRecordsResponse { next_shard_iterator: 0, records: vec![], sequence_numbers: vec![], partition_keys: vec![], headers: vec![], shard_end: false };
             assert_eq!(result, expected);
         });
     }
@@ -144,29 +805,602 @@ mod tests {
 
             let shard_dir = ShardDir {mount_dir};
             shard_dir.assert_mount_path();
-            let latest_segment = Arc::new(AtomicUsize::new(shard_dir.get_latest_segment() as usize));
-            let write_lock = Mutex::new(());
 
-            let shac = ShardController {
-                shard_dir,
-                latest_log_offset: latest_segment,
-                write_lock
-            };
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
 
             let string_data_1 = base64::encode("meucu_tem_oculos_1".as_bytes());
-            let record_1 = Record(string_data_1.clone().into_bytes());
+            let record_1 = Record::new(string_data_1.clone().into_bytes());
 
 
 
-            let result = shac.put_records(record_1.clone());
-            let expected = PutRecordsResponse {};
+            let result = shac.put_records(vec![record_1.clone()]).unwrap();
+            let expected = PutRecordsResponse { sequence_numbers: vec![0] };
             assert_eq!(result, expected);
 
-            let result = shac.get_records(0);
-            let data_len = (string_data_1.as_bytes().len() + 1) as u64;
-            let expected = GetRecordsResponse { next_shard_iterator: data_len, records: vec![record_1.as_string()]};
+            let result = shac.get_records(0).unwrap();
+            let expected = GetRecordsResponse {
+                next_shard_iterator: 1,
+                records: vec![record_1.as_string()],
+                sequence_numbers: vec![0],
+                partition_keys: vec![None],
+                headers: vec![BTreeMap::new()],
+                shard_end: false,
+            };
             assert_eq!(result, expected);
         });
     }
-}
 
+    #[test]
+    fn closed_shard_refuses_puts_and_reports_shard_end() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            let record = Record::new(b"meucu_tem_oculos".to_vec());
+            shac.put_records(vec![record.clone()]).unwrap();
+            assert!(!shac.get_records(0).unwrap().shard_end);
+
+            shac.close();
+            assert!(shac.put_records(vec![record.clone()]).is_err());
+            let result = shac.get_records(0).unwrap();
+            assert_eq!(result.records, vec![record.as_string()]);
+            assert!(result.shard_end);
+        });
+    }
+
+    #[test]
+    fn put_records_is_acknowledged_after_fsync_when_always() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let shac = ShardController::new(shard_dir, StreamConfig { fsync_policy: FsyncPolicy::Always, ..StreamConfig::default() }).unwrap();
+
+            let record = Record::new(base64::encode("meucu_tem_oculos_1".as_bytes()).into_bytes());
+            shac.put_records(vec![record.clone()]).unwrap();
+            shac.put_records(vec![record]).unwrap();
+
+            assert_eq!(shac.metrics().fsync.fsyncs, 2);
+        });
+    }
+
+    #[test]
+    fn metrics_count_what_went_in_and_out_and_who_reads() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let mut shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            let record = || Record::new(b"meucu_tem_oculos".to_vec());
+            shac.put_records(vec![record(), record()]).unwrap();
+            shac.put_records(vec![record()]).unwrap();
+
+            let oldest = shac.get_shard_iterator(ShardIteratorType::Oldest).unwrap();
+            let next = shac.get_records(oldest).unwrap().next_shard_iterator;
+            shac.get_records(next).unwrap();
+            shac.get_shard_iterator(ShardIteratorType::Latest).unwrap();
+
+            let metrics = shac.metrics();
+            assert_eq!((metrics.records_in, metrics.bytes_in), (3, 48));
+            assert_eq!((metrics.records_out, metrics.bytes_out), (3, 48));
+            assert_eq!((metrics.put_latency.count, metrics.get_latency.count), (2, 2));
+            // the consumer that caught up and the one about to start at the end, both at 3
+            assert_eq!(metrics.active_iterators, 1);
+            assert_eq!((metrics.local_segments, metrics.remote_segments, metrics.disk_bytes), (1, 0, shac.segments.read().unwrap().active().size));
+            assert_eq!(metrics.throttled_puts, 0);
+
+            shac.replicas.min_insync_replicas = 2;
+            assert!(shac.put_records(vec![record()]).is_err());
+            assert_eq!(shac.metrics().throttled_puts, 1);
+        });
+    }
+
+    #[test]
+    fn puts_over_the_quota_are_throttled_and_segments_roll_at_the_configured_size() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let config = StreamConfig {
+                max_segment_size: 100,
+                max_records_per_get: 2,
+                quota: Quota { records_per_sec: Some(3), bytes_per_sec: None },
+                ..StreamConfig::default()
+            };
+            let shac = ShardController::new(shard_dir, config).unwrap();
+            let record = || Record::new(b"meucu_tem_oculos".to_vec());
+            shac.put_records(vec![record(), record()]).unwrap();
+            shac.put_records(vec![record()]).unwrap();
+            let refused = shac.put_records(vec![record()]).unwrap_err();
+            assert_eq!(refused.kind(), ErrorKind::QuotaExceeded);
+            assert_eq!(shac.metrics().throttled_puts, 1);
+
+            assert_eq!(shac.get_records(0).unwrap().records.len(), 2);
+            let summary = shac.summary();
+            assert_eq!((summary.log_end_offset, summary.high_watermark, summary.leader), (3, 3, None));
+            let segments: Vec<(u64, u64, SegmentState)> = summary.segments.iter().map(|s| (s.base_offset, s.end_offset, s.state)).collect();
+            assert_eq!(segments, vec![(0, 3, SegmentState::Sealed), (3, 3, SegmentState::Active)]);
+            assert!(summary.segments[0].size > 100);
+        });
+    }
+
+    #[test]
+    fn get_records_follows_next_shard_iterator_to_the_end() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            let written: Vec<String> = (0..25)
+                .map(|i| base64::encode(format!("meucu_tem_oculos_{}", i).as_bytes()))
+                .collect();
+            for data in written.iter() {
+                shac.put_records(vec![Record::new(data.clone().into_bytes())]).unwrap();
+            }
+
+            let mut read = Vec::new();
+            let mut shard_iterator = shac.get_shard_iterator(ShardIteratorType::Oldest).unwrap();
+            for expected_len in [10, 10, 5, 0].iter() {
+                let result = shac.get_records(shard_iterator).unwrap();
+                assert_eq!(result.records.len(), *expected_len);
+                shard_iterator = result.next_shard_iterator;
+                read.extend(result.records);
+            }
+
+            let written: Vec<String> = written.into_iter().map(|data| Record::new(data.into_bytes()).as_string()).collect();
+            assert_eq!(read, written);
+            assert_eq!(shard_iterator, shac.get_shard_iterator(ShardIteratorType::Latest).unwrap());
+        });
+    }
+
+    #[test]
+    fn shard_iterators_at_a_timestamp_start_at_the_first_batch_appended_since() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            // so that the batches are spread over segments
+            shac.segments.write().unwrap().max_segment_size = 64;
+            let record = Record::new(base64::encode("meucu_tem_oculos".as_bytes()).into_bytes());
+            shac.put_records(vec![record.clone(), record.clone()]).unwrap();
+            wait_a_bit();
+            let since = now_ms();
+            wait_a_bit();
+            shac.put_records(vec![record.clone()]).unwrap();
+            shac.put_records(vec![record]).unwrap();
+
+            let at = |timestamp_ms| shac.get_shard_iterator(ShardIteratorType::AtTimestamp { timestamp_ms }).unwrap();
+            assert_eq!(at(0), 0);
+            assert_eq!(at(since), 2);
+            assert_eq!(at(now_ms() + 1000), 4);
+        });
+    }
+
+    #[test]
+    fn get_records_returns_individual_records_of_compressed_batches() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let shac = ShardController::new(shard_dir, StreamConfig { compression: Compression::Zstd, ..StreamConfig::default() }).unwrap();
+            let batch: Vec<Record> = (0..15)
+                .map(|i| Record::new(format!("{{\"entity\":\"order\",\"id\":{}}}", i).into_bytes()))
+                .collect();
+
+            let result = shac.put_records(batch[..7].to_vec()).unwrap();
+            assert_eq!(result.sequence_numbers, (0..7).collect::<Vec<u64>>());
+            let result = shac.put_records(batch[7..].to_vec()).unwrap();
+            assert_eq!(result.sequence_numbers, (7..15).collect::<Vec<u64>>());
+
+            let expected: Vec<String> = batch.iter().map(|r| r.as_string()).collect();
+            let result = shac.get_records(3).unwrap();
+            assert_eq!(result.next_shard_iterator, 13);
+            assert_eq!(result.records, expected[3..13].to_vec());
+            assert_eq!(result.sequence_numbers, (3..13).collect::<Vec<u64>>());
+            let result = shac.get_records(13).unwrap();
+            assert_eq!(result.next_shard_iterator, 15);
+            assert_eq!(result.records, expected[13..].to_vec());
+        });
+    }
+
+    #[test]
+    fn get_records_skips_what_compaction_removed() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+
+            let config = StreamConfig { cleanup_policy: CleanupPolicy::Compact, ..StreamConfig::default() };
+            let shac = ShardController::new(shard_dir, config).unwrap();
+            shac.segments.write().unwrap().max_segment_size = 100;
+            for i in 0..40 {
+                let record = Record::keyed(format!("user-{}", i % 4), format!("meucu_tem_oculos_{}", i).into_bytes());
+                shac.put_records(vec![record]).unwrap();
+            }
+            let active = shac.segments.read().unwrap().active().base_offset;
+            compact(&shac.segments, shac.config.tombstone_retention_ms).unwrap();
+
+            let mut sequence_numbers = Vec::new();
+            let mut shard_iterator = shac.get_shard_iterator(ShardIteratorType::Oldest).unwrap();
+            loop {
+                let result = shac.get_records(shard_iterator).unwrap();
+                if result.records.is_empty() {
+                    break;
+                }
+                assert!(result.partition_keys.iter().all(|k| k.is_some()));
+                sequence_numbers.extend(result.sequence_numbers);
+                shard_iterator = result.next_shard_iterator;
+            }
+
+            let sealed = sequence_numbers.iter().filter(|s| **s < active).count();
+            assert!(sealed <= 4);
+            assert_eq!(sequence_numbers[sealed..].to_vec(), (active..40).collect::<Vec<u64>>());
+            assert_eq!(shard_iterator, 40);
+        });
+    }
+
+    #[test]
+    fn idempotent_puts_are_appended_once_even_across_restarts() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+            let records = |i: usize| (i..i + 2).map(|i| Record::new(format!("meucu_tem_oculos_{}", i).into_bytes())).collect::<Vec<Record>>();
+            let producer = |producer_sequence: u64| Some(ProducerBatch { producer_id: 7, producer_sequence, transactional: false });
+
+            let shac = ShardController::new(shard_dir.clone(), StreamConfig::default()).unwrap();
+            shac.put_records(records(0)).unwrap();
+            assert_eq!(shac.put_records_from(producer(0), records(2)).unwrap().sequence_numbers, vec![2, 3]);
+            assert_eq!(shac.put_records_from(producer(0), records(2)).unwrap().sequence_numbers, vec![2, 3]);
+            assert_eq!(shac.put_records_from(producer(2), records(4)).unwrap().sequence_numbers, vec![4, 5]);
+            assert!(shac.put_records_from(producer(5), records(6)).is_err());
+            drop(shac);
+
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            assert_eq!(shac.put_records_from(producer(0), records(2)).unwrap().sequence_numbers, vec![2, 3]);
+            assert_eq!(shac.put_records_from(producer(4), records(6)).unwrap().sequence_numbers, vec![6, 7]);
+            assert_eq!(shac.get_records(0).unwrap().sequence_numbers, (0..8).collect::<Vec<u64>>());
+        });
+    }
+
+    #[test]
+    fn a_shard_shut_down_cleanly_is_reopened_from_its_marker_instead_of_a_scan() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+            let record = |i: u64| vec![Record::new(format!("meucu_tem_oculos_{}", i).into_bytes())];
+            let producer = |producer_sequence: u64| Some(ProducerBatch { producer_id: 7, producer_sequence, transactional: false });
+            let path = shard_dir.path_to_clean_shutdown();
+            let tamper = |log_end_offset: u64| {
+                let mut marker: CleanShutdown = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+                marker.log_end_offset = log_end_offset;
+                marker.transactions.open.insert(9, 1);
+                fs::write(&path, serde_json::to_vec(&marker).unwrap()).unwrap();
+            };
+
+            let shac = ShardController::new(shard_dir.clone(), StreamConfig::default()).unwrap();
+            shac.put_records_from(producer(0), record(0)).unwrap();
+            shac.put_records_from(Some(ProducerBatch { producer_id: 8, producer_sequence: 0, transactional: true }), record(1)).unwrap();
+            shac.shut_down().unwrap();
+            assert_eq!(shac.put_records(record(2)).unwrap_err().kind(), ErrorKind::Interrupted);
+            assert_eq!(shac.get_records(0).unwrap().sequence_numbers, vec![0, 1]);
+            drop(shac);
+
+            // the marker holds what the scan would have found, so only a tampered one tells them apart
+            tamper(2);
+            let shac = ShardController::new(shard_dir.clone(), StreamConfig::default()).unwrap();
+            assert!(!path.exists());
+            assert_eq!(shac.open_transactions(), vec![8, 9]);
+            assert_eq!(shac.put_records_from(producer(0), record(0)).unwrap().sequence_numbers, vec![0]);
+            shac.put_records(record(2)).unwrap();
+            shac.shut_down().unwrap();
+            drop(shac);
+
+            // one left at another log end is not trusted
+            tamper(2);
+            let shac = ShardController::new(shard_dir.clone(), StreamConfig::default()).unwrap();
+            assert_eq!(shac.open_transactions(), vec![8]);
+            drop(shac);
+
+            // no marker at all, as after a crash
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            assert_eq!(shac.open_transactions(), vec![8]);
+            assert_eq!(shac.put_records_from(producer(1), record(3)).unwrap().sequence_numbers, vec![3]);
+        });
+    }
+
+    #[test]
+    fn shut_down_stops_the_threads_changing_segments_before_leaving_its_marker() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+            let config = StreamConfig {
+                cleanup_policy: CleanupPolicy::Compact,
+                retention: Retention { ms: Some(60_000), bytes: None },
+                ..StreamConfig::default()
+            };
+            let shac = ShardController::new(shard_dir.clone(), config).unwrap();
+            shac.spawn_compactor();
+            shac.spawn_retainer();
+            assert_eq!(shac.background.lock().unwrap().len(), 2);
+
+            // they sleep for a minute between passes, but are woken up to stop
+            let started = time::Instant::now();
+            shac.shut_down().unwrap();
+            assert!(started.elapsed() < time::Duration::from_secs(10));
+            assert!(shac.background.lock().unwrap().is_empty());
+            assert!(shard_dir.path_to_clean_shutdown().exists());
+        });
+    }
+
+    #[test]
+    fn read_committed_waits_for_open_transactions_and_skips_aborted_ones() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir};
+            shard_dir.assert_mount_path();
+            let record = |i: u64| vec![Record::new(format!("meucu_tem_oculos_{}", i).into_bytes())];
+            let transaction = |transaction_id: u64, producer_sequence: u64| Some(ProducerBatch { producer_id: transaction_id, producer_sequence, transactional: true });
+            let read = |shac: &ShardController, isolation: Isolation| {
+                let mut sequence_numbers = Vec::new();
+                let mut shard_iterator = 0;
+                loop {
+                    let result = shac.get_records_with(shard_iterator, isolation).unwrap();
+                    if result.next_shard_iterator == shard_iterator {
+                        return (sequence_numbers, shard_iterator);
+                    }
+                    sequence_numbers.extend(result.sequence_numbers);
+                    shard_iterator = result.next_shard_iterator;
+                }
+            };
+
+            let shac = ShardController::new(shard_dir.clone(), StreamConfig::default()).unwrap();
+            shac.put_records(record(0)).unwrap();
+            shac.put_records_from(transaction(7, 0), record(1)).unwrap();
+            shac.put_records_from(transaction(8, 0), record(2)).unwrap();
+            shac.put_records(record(3)).unwrap();
+            shac.put_records_from(transaction(7, 1), record(4)).unwrap();
+            assert_eq!(read(&shac, Isolation::ReadCommitted), (vec![0], 1));
+            assert_eq!(read(&shac, Isolation::ReadUncommitted).0, vec![0, 1, 2, 3, 4]);
+
+            shac.end_transaction(7, Marker::Abort).unwrap();
+            shac.end_transaction(7, Marker::Abort).unwrap();
+            assert_eq!(read(&shac, Isolation::ReadCommitted), (vec![0], 2));
+            drop(shac);
+
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            assert_eq!(shac.open_transactions(), vec![8]);
+            shac.end_transaction(8, Marker::Commit).unwrap();
+            assert_eq!(read(&shac, Isolation::ReadCommitted), (vec![0, 2, 3], 7));
+            assert_eq!(read(&shac, Isolation::ReadUncommitted), (vec![0, 1, 2, 3, 4], 7));
+        });
+    }
+
+    #[test]
+    fn stream_encryption_is_transparent_to_readers_and_survives_restarts() {
+        with_tmp_dir(|mount_dir| {
+
+            let shard_dir = ShardDir {mount_dir: mount_dir.clone()};
+            shard_dir.assert_mount_path();
+            std::fs::write(mount_dir.join("keys"), "k1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n").unwrap();
+            let config = StreamConfig {
+                cleanup_policy: CleanupPolicy::Compact,
+                keyfile: Some(mount_dir.join("keys")),
+                ..StreamConfig::default()
+            };
+            let record = |i: usize| Record::keyed(format!("user-{}", i % 3), format!("meucu_tem_oculos_{}", i).into_bytes());
+
+            let shac = ShardController::new(shard_dir.clone(), config.clone()).unwrap();
+            shac.segments.write().unwrap().max_segment_size = 200;
+            assert!(shac.start_stream_encryption("k2".to_string()).is_err());
+            shac.put_records(vec![record(0)]).unwrap();
+            shac.start_stream_encryption("k1".to_string()).unwrap();
+            for i in 1..30 {
+                shac.put_records(vec![record(i)]).unwrap();
+            }
+            compact(&shac.segments, shac.config.tombstone_retention_ms).unwrap();
+            drop(shac);
+
+            for base_offset in shard_dir.list_segments().unwrap() {
+                let data = std::fs::read(shard_dir.path_to_segment(base_offset)).unwrap();
+                let plaintext = data.windows(16).filter(|w| w == b"meucu_tem_oculos").count();
+                assert!(plaintext <= (base_offset == 0) as usize);
+            }
+
+            let shac = ShardController::new(shard_dir.clone(), config.clone()).unwrap();
+            shac.put_records(vec![record(30)]).unwrap();
+            shac.stop_stream_encryption().unwrap();
+            shac.put_records(vec![record(31)]).unwrap();
+
+            let mut read = Vec::new();
+            let mut shard_iterator = 0;
+            loop {
+                let result = shac.get_records(shard_iterator).unwrap();
+                if result.records.is_empty() {
+                    break;
+                }
+                read.extend(result.sequence_numbers.into_iter().zip(result.records));
+                shard_iterator = result.next_shard_iterator;
+            }
+            for (sequence, data) in read.iter() {
+                assert_eq!(data, &record(*sequence as usize).as_string());
+            }
+            assert_eq!(read.last().unwrap().0, 31);
+
+            // without the keys encrypted batches cannot be read
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            assert!(shac.get_records(0).is_err());
+        });
+    }
+
+    /// a leader in the same process
+    struct LocalLeader(Arc<ShardController>);
+
+    impl Leader for LocalLeader {
+        fn fetch(&self, replica_id: &str, leader_epoch: u64, offset: u64, max_bytes: u64) -> std::io::Result<FetchResponse> {
+            self.0.fetch(replica_id, leader_epoch, offset, max_bytes)
+        }
+    }
+
+    #[test]
+    fn followers_get_identical_segments_and_puts_wait_for_them() {
+        with_tmp_dir(|mount_dir| {
+
+            std::fs::create_dir_all(&mount_dir).unwrap();
+            let leader_dir = ShardDir {mount_dir: mount_dir.join("leader")};
+            let follower_dir = ShardDir {mount_dir: mount_dir.join("follower")};
+            leader_dir.assert_mount_path();
+            follower_dir.assert_mount_path();
+            let replication = ReplicationConfig { min_insync_replicas: 2, ack_timeout_ms: 5000, ..ReplicationConfig::default() };
+            let leader = Arc::new(ShardController::new(leader_dir.clone(), StreamConfig {
+                compression: Compression::Zstd,
+                replication: replication.clone(),
+                ..StreamConfig::default()
+            }).unwrap());
+            let follower = ShardController::new(follower_dir.clone(), StreamConfig {
+                replication: ReplicationConfig { leader: Some("leader".to_string()), replica_id: "f1".to_string(), ..replication },
+                ..StreamConfig::default()
+            }).unwrap();
+            leader.segments.write().unwrap().max_segment_size = 200;
+            follower.segments.write().unwrap().max_segment_size = 200;
+            let record = |i: u64| Record::new(format!("meucu_tem_oculos_{}", i).into_bytes());
+            let local = LocalLeader(leader.clone());
+            let fetch = || {
+                let offset = follower.segments.read().unwrap().end_offset();
+                follower.replicate(local.fetch("f1", 0, offset, 150).unwrap()).unwrap()
+            };
+
+            // the follower is not known yet
+            assert!(leader.put_records(vec![record(0)]).is_err());
+            fetch();
+
+            for i in 0..20 {
+                let put = {
+                    let leader = leader.clone();
+                    thread::spawn(move || leader.put_records((i * 3..i * 3 + 3).map(record).collect()))
+                };
+                while leader.segments.read().unwrap().end_offset() < i * 3 + 3 {
+                    wait_a_bit();
+                }
+                assert!(leader.get_records(i * 3).unwrap().records.is_empty());
+
+                assert_eq!(fetch(), i * 3 + 3);
+                assert_eq!(follower.replicas.high_watermark(), i * 3);
+                fetch();
+                assert_eq!(put.join().unwrap().unwrap().sequence_numbers, vec![i * 3, i * 3 + 1, i * 3 + 2]);
+                assert_eq!(follower.replicas.high_watermark(), i * 3 + 3);
+            }
+
+            let segments = leader_dir.list_segments().unwrap();
+            assert!(segments.len() > 1);
+            assert_eq!(follower_dir.list_segments().unwrap(), segments);
+            for base_offset in segments {
+                assert_eq!(
+                    std::fs::read(follower_dir.path_to_segment(base_offset)).unwrap(),
+                    std::fs::read(leader_dir.path_to_segment(base_offset)).unwrap(),
+                );
+            }
+            assert_eq!(follower.get_records(57).unwrap().records, vec![record(57).as_string(), record(58).as_string(), record(59).as_string()]);
+            assert_eq!(leader.metrics().replication.in_sync_replicas, 2);
+            assert!(follower.put_records(vec![record(60)]).is_err());
+        });
+    }
+
+    #[test]
+    fn a_demoted_leader_drops_its_divergent_tail_and_follows_the_new_one() {
+        with_tmp_dir(|mount_dir| {
+
+            std::fs::create_dir_all(&mount_dir).unwrap();
+            let old_dir = ShardDir {mount_dir: mount_dir.join("old")};
+            let new_dir = ShardDir {mount_dir: mount_dir.join("new")};
+            old_dir.assert_mount_path();
+            new_dir.assert_mount_path();
+            let old = Arc::new(ShardController::new(old_dir.clone(), StreamConfig::default()).unwrap());
+            let new = Arc::new(ShardController::new(new_dir.clone(), StreamConfig {
+                replication: ReplicationConfig { leader: Some("old".to_string()), replica_id: "new".to_string(), ..ReplicationConfig::default() },
+                ..StreamConfig::default()
+            }).unwrap());
+            old.segments.write().unwrap().max_segment_size = 200;
+            new.segments.write().unwrap().max_segment_size = 200;
+            let record = |i: u64| Record::new(format!("meucu_tem_oculos_{}", i).into_bytes());
+            let catch_up = |follower: &ShardController, leader: &Arc<ShardController>| {
+                let leader = LocalLeader(leader.clone());
+                let replica_id = follower.config.replication.replica_id.clone();
+                loop {
+                    let offset = follower.segments.read().unwrap().end_offset();
+                    let fetched = leader.fetch(&replica_id, follower.leadership().leader_epoch, offset, 150).unwrap();
+                    if fetched.frames.is_empty() {
+                        return follower.replicate(fetched).unwrap();
+                    }
+                    follower.replicate(fetched).unwrap();
+                }
+            };
+
+            for i in 0..6 {
+                old.put_records(vec![record(i * 2), record(i * 2 + 1)]).unwrap();
+            }
+            assert_eq!(catch_up(&new, &old), 12);
+            assert!(new.replica_status().in_sync);
+            // these never make it to the new leader
+            for i in 6..9 {
+                old.put_records(vec![record(i * 2), record(i * 2 + 1)]).unwrap();
+            }
+
+            new.set_leader(None, 1, None).unwrap();
+            old.set_leader(Some("new".to_string()), 1, Some(12)).unwrap();
+            assert_eq!(old.replica_status().log_end_offset, 12);
+            assert!(old.put_records(vec![record(100)]).is_err());
+            // fetches from before the change of leaders are turned away
+            assert!(new.fetch("old", 0, 12, 150).is_err());
+            assert!(old.replicate(FetchResponse { leader_epoch: 0, high_watermark: 0, frames: vec![] }).is_err());
+
+            assert_eq!(new.put_records(vec![record(100), record(101)]).unwrap().sequence_numbers, vec![12, 13]);
+            assert_eq!(catch_up(&old, &new), 14);
+            let segments = new_dir.list_segments().unwrap();
+            assert_eq!(old_dir.list_segments().unwrap(), segments);
+            for base_offset in segments {
+                assert_eq!(
+                    std::fs::read(old_dir.path_to_segment(base_offset)).unwrap(),
+                    std::fs::read(new_dir.path_to_segment(base_offset)).unwrap(),
+                );
+            }
+            assert_eq!(old.get_records(12).unwrap().records, vec![record(100).as_string(), record(101).as_string()]);
+
+            drop(old);
+            let old = ShardController::new(old_dir, StreamConfig::default()).unwrap();
+            assert_eq!(old.leadership().leader_epoch, 1);
+        });
+    }
+
+    #[test]
+    fn iterators_from_before_a_legacy_migration_read_the_records_they_pointed_at() {
+        with_tmp_dir(|mount_dir| {
+            fs::create_dir(&mount_dir).unwrap();
+            let shard_dir = ShardDir { mount_dir };
+            let lines: Vec<String> = (0..3).map(|i| format!("{}\n", base64::encode(format!("meucu_tem_oculos_{}", i).as_bytes()))).collect();
+            fs::write(shard_dir.path_to_segment(0), lines.concat()).unwrap();
+            migrate_legacy(&shard_dir).unwrap();
+            let second_line = lines[0].len() as u64;
+            let legacy_end = lines.concat().len() as u64;
+
+            let shac = ShardController::new(shard_dir, StreamConfig::default()).unwrap();
+            let record = |i: u64| Record::new(format!("meucu_tem_oculos_{}", i).into_bytes());
+            let result = shac.get_records(second_line).unwrap();
+            assert_eq!(result.records, vec![record(1).as_string(), record(2).as_string()]);
+            assert_eq!(result.sequence_numbers, vec![legacy_end + 2, legacy_end + 3]);
+            assert_eq!(result.next_shard_iterator, legacy_end + 4);
+
+            assert_eq!(shac.put_records(vec![record(3)]).unwrap().sequence_numbers, vec![legacy_end + 4]);
+            assert_eq!(shac.get_records(legacy_end).unwrap().records, vec![record(3).as_string()]);
+            assert_eq!(shac.get_records(result.next_shard_iterator).unwrap().records, vec![record(3).as_string()]);
+        });
+    }
+}
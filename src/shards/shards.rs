@@ -1,23 +1,35 @@
-use std::{fs, thread};
-use std::fs::{DirEntry, File, OpenOptions};
-use std::io::{BufRead, Seek, Write};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, Write};
 use std::io::BufReader;
 use std::io::SeekFrom;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use memmap2::Mmap;
 use serde_derive::{Deserialize, Serialize};
-
-use crate::Response;
-
-#[derive(Serialize, Deserialize)]
+use tracing::{debug, info, instrument};
+
+use crate::shards::batch::{BATCH_HEADER_SIZE, BatchHeader, Compression, RecordBatch};
+use crate::shards::durability::{FsyncPolicy, FsyncStats, timed_sync};
+use crate::shards::encryption::{BatchKey, KeyProvider};
+use crate::shards::index::IndexEntry;
+use crate::shards::producers::{Dedup, ProducerBatch, ProducerWindow};
+use crate::shards::transactions::{AbortedTransaction, Marker, ShardTransactions};
+use crate::shards::segments::{Segment, SegmentManager, SegmentState};
+
+/// Where to start reading a shard, given as `{"iterator_type": "Oldest"}` and alike.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "iterator_type")]
 pub enum ShardIteratorType {
     Latest,
     Oldest,
+    /// the first batch appended at or after `timestamp_ms`, or the log end if there is none
+    AtTimestamp { timestamp_ms: u64 },
 }
 
 pub enum Request {
@@ -27,199 +39,487 @@ pub enum Request {
 }
 
 
+/// One record, as the producer sent it. The data travels base64 encoded over the API.
+///
+/// In compacted streams only the latest record of each partition key is kept, and a record with
+/// empty data is a tombstone that deletes its key.
 #[derive(Deserialize, Serialize)]
 #[derive(Debug, Clone, PartialEq)]
-pub struct Record(pub Vec<u8>);
+pub struct Record {
+    pub partition_key: Option<String>,
+    pub data: Vec<u8>,
+    /// application metadata stored along with the record, never looked at by rinites
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
 
 impl Record {
+    pub fn new(data: Vec<u8>) -> Record {
+        Record { partition_key: None, data, headers: BTreeMap::new() }
+    }
 
-    pub fn serialized(mut self) -> Vec<u8> {
-        let mut res = self.0;
+    /// an empty key is the same as no key
+    pub fn keyed(partition_key: String, data: Vec<u8>) -> Record {
+        Record {
+            partition_key: Some(partition_key).filter(|k| !k.is_empty()),
+            data,
+            headers: BTreeMap::new(),
+        }
+    }
 
-        res.push(b'\n');
-        res
+    pub fn with_headers(self, headers: BTreeMap<String, String>) -> Record {
+        Record { headers, ..self }
     }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.partition_key.is_some() && self.data.is_empty()
+    }
+
     pub fn as_string(&self) -> String {
-        std::str::from_utf8(&self.0).expect("data was corrupt").to_string()
+        base64::encode(&self.data)
     }
 
     pub fn from_string(s: String) -> Result<Record, failure::Error> {
-
-        base64::decode(&s)?;
-        Ok(Record(s.as_bytes().to_vec()))
+        Ok(Record::new(base64::decode(&s)?))
     }
 
 }
 
 pub type SegmentId = u64;
 
-type ShardOffset = u64;
-
 pub trait ShaW {
-    fn write(&mut self, record: Record) -> std::io::Result<()>;
+    /// Appends `records` as one batch, returning the sequence number of the first one.
+    fn write(&mut self, records: Vec<Record>) -> std::io::Result<u64>;
 }
 
 impl ShaW for ShardWriter {
-    fn write(& mut self, record: Record) -> std::io::Result<()> {
-        self.writez(record)
+    fn write(& mut self, records: Vec<Record>) -> std::io::Result<u64> {
+        self.writez(records, None)
     }
 }
 
+/// Appends to the active segment of `segments`. Callers make sure there is only one writer per
+/// shard at a time.
 pub struct ShardWriter {
-    pub latest_segment: u64,
-    pub shard_dir: ShardDir,
-    pub offset: ShardOffset,
-    pub max_segment_size: u64,
+    pub segments: Arc<RwLock<SegmentManager>>,
+    pub compression: Compression,
+    /// key new batches are encrypted with, if the stream is encrypted
+    pub encryption: Option<BatchKey>,
+    pub fsync_policy: FsyncPolicy,
+    /// records appended since the last fsync, shared with the interval flusher
+    pub unsynced_records: Arc<AtomicUsize>,
+    pub fsync_stats: Arc<FsyncStats>,
+    /// latest batches of the idempotent producers, kept up to date with every append
+    pub producers: Arc<Mutex<ProducerWindow>>,
+    pub transactions: Arc<Mutex<ShardTransactions>>,
 }
 
 impl ShardWriter {
-    fn writez(&mut self, record: Record) -> std::io::Result<()> {
+    /// Appends `records` as one batch of `producer`, unless it already is in the log. Either way,
+    /// returns the sequence number of the first record.
+    pub fn write_idempotent(&mut self, records: Vec<Record>, producer: ProducerBatch) -> std::io::Result<u64> {
+        match self.producers.lock().unwrap().check(&producer, records.len() as u32)? {
+            Dedup::Duplicate(base_sequence) => return Ok(base_sequence),
+            Dedup::Append => {}
+        }
+        self.writez(records, Some(producer))
+    }
+
+    /// Appends the marker ending transaction `transaction_id`, returning its sequence number.
+    /// Markers are neither compressed nor encrypted.
+    pub fn write_marker(&mut self, transaction_id: u64, marker: Marker) -> std::io::Result<u64> {
+        let (path, base_sequence, position) = {
+            let segments = self.segments.read().unwrap();
+            let active = segments.active();
+            (segments.path_to(active), active.next_offset, active.size)
+        };
+        let frame = RecordBatch::marker(base_sequence, now_ms(), transaction_id, marker).encode(Compression::None)?;
+        let header = BatchHeader::parse(&frame)?;
+        self.append_frame(path, position, &frame, &header)?;
+        Ok(base_sequence)
+    }
+
+    #[instrument(level = "debug", name = "write", skip_all, fields(records = records.len()))]
+    fn writez(&mut self, records: Vec<Record>, producer: Option<ProducerBatch>) -> std::io::Result<u64> {
+        let (path, base_sequence, position) = {
+            let segments = self.segments.read().unwrap();
+            let active = segments.active();
+            (segments.path_to(active), active.next_offset, active.size)
+        };
+        if records.is_empty() {
+            return Ok(base_sequence);
+        }
+
+        let batch = RecordBatch::new(base_sequence, now_ms(), records).with_producer(producer);
+        let frame = batch.encode_with(self.compression, self.encryption.as_ref())?;
+        let header = BatchHeader::parse(&frame)?;
+        self.append_frame(path, position, &frame, &header)?;
+        Ok(base_sequence)
+    }
+
+    /// Appends frames written by another node, byte for byte, once their checksums check out.
+    /// Their sequence numbers have to start at or after the log end. Returns the new log end.
+    pub fn append_frames(&mut self, frames: &[u8]) -> std::io::Result<u64> {
+        let mut start = 0;
+        while start < frames.len() {
+            let header = RecordBatch::verify(&frames[start..])?;
+            let frame = &frames[start..start + header.frame_len() as usize];
+            let (path, end_offset, position) = {
+                let segments = self.segments.read().unwrap();
+                let active = segments.active();
+                (segments.path_to(active), active.end_offset(), active.size)
+            };
+            if header.base_sequence < end_offset {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("batch {} comes before the log end {}", header.base_sequence, end_offset),
+                ));
+            }
+            self.append_frame(path, position, frame, &header)?;
+            start += frame.len();
+        }
+        Ok(self.segments.read().unwrap().end_offset())
+    }
+
+    fn append_frame(&mut self, path: PathBuf, position: u64, frame: &[u8], header: &BatchHeader) -> std::io::Result<()> {
+        let record_count = header.record_count as usize;
         let mut file = OpenOptions::new()
             .create(true)
             .truncate(false)
-            .write(true)
             .append(true)
-            .open(
-                self.shard_dir
-                    .path_to_segment(self.latest_segment),
-            )
-            .expect("could not open file to append");
-
-        let written = file.write(&record.serialized())?;
-        self.offset += written as u64;
-        if self.offset > self.max_segment_size {
-            self.latest_segment += self.offset;
-            self.offset = 0;
-            println!("releasing lock for new partition");
+            .open(path)?;
+        file.write_all(frame)?;
+
+        let unsynced = self.unsynced_records.fetch_add(record_count, Ordering::AcqRel) + record_count;
+        if self.fsync_policy.must_sync(unsynced as u64) {
+            self.sync(&file)?;
+        }
+
+        // readers never go past the sizes in the segment catalog, so the batch only becomes
+        // visible here, once it was fully written
+        let mut segments = self.segments.write().unwrap();
+        segments.append_batch(position, header)?;
+        debug!(base_sequence = header.base_sequence, records = record_count, bytes = frame.len(), position, "appended batch");
+        if let Some(producer) = header.producer(frame)? {
+            if !header.control {
+                self.producers.lock().unwrap().appended(producer, header.span, header.base_sequence);
+            }
+            self.transactions.lock().unwrap().appended(header, producer);
+        }
+        if segments.should_roll() {
+            // the interval flusher only knows about the active segment, so never leave
+            // unsynced data behind in the one being rolled away from
+            if self.fsync_policy != FsyncPolicy::None {
+                self.sync(&file)?;
+            }
+            let new_segment = segments.roll()?;
+            info!(base_offset = new_segment.base_offset, "rolled to a new segment");
         }
         Ok(())
     }
+
+    fn sync(&self, file: &File) -> std::io::Result<()> {
+        let pending = self.unsynced_records.swap(0, Ordering::AcqRel);
+        timed_sync(file, &self.fsync_stats).inspect_err(|_| {
+            self.unsynced_records.fetch_add(pending, Ordering::AcqRel);
+        })
+    }
 }
 
+/// the map of a sealed or remote segment, or why it could not be mapped
+type MappedSegment = std::io::Result<Arc<Mmap>>;
+
+/// Reads up to `chunk_size` records starting at the sequence number `position`, following the
+/// segments of the catalog in order. Batches are decrypted and decompressed here, so callers only
+/// ever see individual records. Transaction markers, and the batches of the transactions in
+/// `aborted`, are stepped over.
 pub struct ShardReader {
-    pub segment_id: SegmentId,
-    pub latest_log_offset: usize,
-    pub offset: ShardOffset,
+    pub segments: Arc<RwLock<SegmentManager>>,
+    pub position: u64,
     pub chunk_size: usize,
     pub shard_dir: ShardDir,
+    pub aborted: Vec<AbortedTransaction>,
 }
 
 impl ShardReader {
-    // FIXME this will read a partially written line
     pub fn read(&mut self) -> std::io::Result<Vec<Record>> {
-        let path = self.shard_dir.path_to_segment(self.segment_id);
-
-        dbg!(&path);
+        Ok(self.read_sequenced()?.into_iter().map(|(_, record)| record).collect())
+    }
 
+    /// Like `read`, along with the sequence number of each record. Those of compacted streams
+    /// can have gaps.
+    #[instrument(level = "debug", name = "read", skip(self), fields(position = self.position))]
+    pub fn read_sequenced(&mut self) -> std::io::Result<Vec<(u64, Record)>> {
         let mut res = Vec::new();
+        let keys = self.segments.read().unwrap().keys.clone();
+        let keys = keys.as_deref();
 
-        let mut f = File::open(path)?;
-        let mut reader = BufReader::new(f);
-        reader.seek(SeekFrom::Start(self.offset));
-
-        // FIXME this is unreadable
-        loop {
-            let mut b = String::new();
-            let added_offset = reader.read_line(&mut b)?;
-
-            if added_offset == 0 && self.segment_id < (self.latest_log_offset as u64) {
-                println!("rolling to next file");
-                f = File::open(
-                    self.shard_dir
-                        .path_to_segment(self.segment_id + self.offset),
-                )
-                .unwrap();
-                reader = BufReader::new(f);
-                continue;
+        while res.len() < self.chunk_size {
+            let (segment, entry, map) = match self.next_segment() {
+                Some(next) => next,
+                None => break,
+            };
+            let read = match map {
+                Some(Ok(map)) => self.read_mapped(&segment, entry.position, &map, keys, &mut res),
+                Some(Err(e)) => Err(e),
+                None => self.read_file(&segment, entry.position, keys, &mut res),
+            };
+            match read {
+                Ok(()) => {}
+                // deleted since the lookup, the next lookup skips past it
+                Err(e) if e.kind() == ErrorKind::NotFound && !self.still_cataloged(&segment) => continue,
+                Err(e) => return Err(e),
             }
+        }
 
-            if added_offset == 0 {
-                break;
-            }
-            self.offset += added_offset as u64;
+        debug!(records = res.len(), next_position = self.position, "read records");
+        Ok(res)
+    }
 
-            if b.ends_with('\n') {
-                println!("removed trailing newline");
-                b.pop();
+    /// The raw frames of the whole batches from `position` on, exactly as they are stored, up to
+    /// `max_bytes` long but at least one batch. Empty at the log end. This is what followers
+    /// append to their own segments.
+    #[instrument(level = "debug", skip(self), fields(position = self.position))]
+    pub fn read_frames(&mut self, max_bytes: u64) -> std::io::Result<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        while (frames.len() as u64) < max_bytes {
+            let (segment, entry, map) = match self.next_segment() {
+                Some(next) => next,
+                None => break,
+            };
+            let read = match map {
+                Some(Ok(map)) => {
+                    let end = (segment.size as usize).min(map.len());
+                    self.take_frames(&segment, &map[(entry.position as usize).min(end)..end], max_bytes, &mut frames)
+                }
+                Some(Err(e)) => Err(e),
+                None => read_file_range(&self.shard_dir.path_to_segment(segment.base_offset), entry.position, segment.size)
+                    .and_then(|data| self.take_frames(&segment, &data, max_bytes, &mut frames)),
+            };
+            match read {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound && !self.still_cataloged(&segment) => continue,
+                Err(e) => return Err(e),
             }
+        }
 
-            res.push(Record(b.as_bytes().to_vec()));
+        Ok(frames)
+    }
 
-            if res.len() >= self.chunk_size {
-                break;
+    /// Copies the frames of `data`, the batches of `segment` from the one holding `position` on,
+    /// to `frames`. Returns true once `max_bytes` is reached.
+    fn take_frames(&mut self, segment: &Segment, data: &[u8], max_bytes: u64, frames: &mut Vec<u8>) -> std::io::Result<bool> {
+        let mut position = 0;
+        while position < data.len() {
+            let header = BatchHeader::parse(&data[position..])?;
+            let frame_len = header.frame_len() as usize;
+            if header.next_sequence() > self.position {
+                if !frames.is_empty() && (frames.len() + frame_len) as u64 > max_bytes {
+                    return Ok(true);
+                }
+                frames.extend_from_slice(&data[position..position + frame_len]);
+                self.position = header.next_sequence();
             }
+            position += frame_len;
+        }
+        self.skip_to_end_of(segment, segment.size);
+        Ok(false)
+    }
+
+    /// The segment to read from next, the batch holding `position` in it, and its map unless it
+    /// is the active segment. `None` at the log end.
+    fn next_segment(&mut self) -> Option<(Segment, IndexEntry, Option<MappedSegment>)> {
+        // the catalog is only locked for the lookup; the segment size it returns bounds the
+        // read, so batches appended meanwhile are left for the next read
+        let (segment, entry, map, tiered) = {
+            let segments = self.segments.read().unwrap();
+            let (segment, entry) = segments.locate(self.position)?;
+            let map = match segment.state {
+                SegmentState::Sealed => Some(segments.mmap(&segment)),
+                SegmentState::Active | SegmentState::Remote => None,
+            };
+            (segment, entry, map, segments.tiered.clone())
+        };
+        if self.position < segment.base_offset {
+            self.position = segment.base_offset;
+        }
+        // cold data is fetched without holding the catalog lock
+        let map = match (segment.state, tiered) {
+            (SegmentState::Remote, Some(tiered)) => Some(tiered.fetch(&segment)),
+            (SegmentState::Remote, None) => Some(Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("segment {} is remote but the stream has no tiered storage", segment.base_offset),
+            ))),
+            _ => map,
+        };
+        Some((segment, entry, map))
+    }
+
+    fn still_cataloged(&self, segment: &Segment) -> bool {
+        let segments = self.segments.read().unwrap();
+        segments.find(segment.base_offset).map(|s| s.base_offset) == Some(segment.base_offset)
+    }
+
+    fn read_mapped(&mut self, segment: &Segment, position: u64, map: &[u8], keys: Option<&dyn KeyProvider>, res: &mut Vec<(u64, Record)>) -> std::io::Result<()> {
+        let end = (segment.size as usize).min(map.len());
+        let mut position = position as usize;
+
+        while res.len() < self.chunk_size && position < end {
+            let header = BatchHeader::parse(&map[position..end])?;
+            self.take_batch(&map[position..end], keys, res)?;
+            position += header.frame_len() as usize;
+        }
+        if res.len() < self.chunk_size {
+            self.skip_to_end_of(segment, position as u64);
+        }
+        Ok(())
+    }
 
-            if self.offset + self.segment_id >= (self.latest_log_offset as u64) {
-                break;
+    fn read_file(&mut self, segment: &Segment, position: u64, keys: Option<&dyn KeyProvider>, res: &mut Vec<(u64, Record)>) -> std::io::Result<()> {
+        let f = File::open(self.shard_dir.path_to_segment(segment.base_offset))?;
+        let mut reader = BufReader::new(f);
+        reader.seek(SeekFrom::Start(position))?;
+        let mut position = position;
+
+        while res.len() < self.chunk_size && position < segment.size {
+            let mut frame = vec![0; BATCH_HEADER_SIZE];
+            reader.read_exact(&mut frame)?;
+            let header = BatchHeader::parse(&frame)?;
+            frame.resize(header.frame_len() as usize, 0);
+            reader.read_exact(&mut frame[BATCH_HEADER_SIZE..])?;
+
+            self.take_batch(&frame, keys, res)?;
+            position += header.frame_len();
+        }
+        if res.len() < self.chunk_size {
+            self.skip_to_end_of(segment, position);
+        }
+        Ok(())
+    }
+
+    /// Decodes the batch in `frame` and adds its records from `position` on to `res`, until
+    /// `chunk_size` is reached. Sequence numbers missing from the batch are stepped over.
+    fn take_batch(&mut self, frame: &[u8], keys: Option<&dyn KeyProvider>, res: &mut Vec<(u64, Record)>) -> std::io::Result<()> {
+        let batch = RecordBatch::decode_with(frame, keys)?;
+        let next_sequence = batch.next_sequence();
+        if next_sequence <= self.position {
+            return Ok(());
+        }
+        if batch.control || self.aborted.iter().any(|a| a.covers(&batch)) {
+            self.position = next_sequence;
+            return Ok(());
+        }
+
+        for (sequence, record) in batch.sequenced() {
+            if sequence < self.position {
+                continue;
             }
+            if res.len() >= self.chunk_size {
+                return Ok(());
+            }
+            res.push((sequence, record));
+            self.position = sequence + 1;
         }
+        self.position = self.position.max(next_sequence);
+        Ok(())
+    }
 
-        Ok(res)
+    /// moves on to the next segment once every batch of `segment` was read
+    fn skip_to_end_of(&mut self, segment: &Segment, position: u64) {
+        if position >= segment.size {
+            self.position = self.position.max(segment.next_offset);
+        }
     }
 }
 
+fn read_file_range(path: &Path, from: u64, to: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(from))?;
+    let mut data = Vec::with_capacity(to.saturating_sub(from) as usize);
+    file.take(to.saturating_sub(from)).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 #[derive(Clone, Debug)]
 pub struct ShardDir {
     pub mount_dir: PathBuf,
 }
 
+impl fmt::Display for ShardDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mount_dir.display())
+    }
+}
+
 impl ShardDir {
     pub fn path_to_segment(&self, shard_id: SegmentId) -> PathBuf {
         self.mount_dir.join(format!("{:08}", shard_id))
     }
 
-    pub fn get_latest_segment(&self) -> SegmentId {
-        let paths = fs::read_dir(&self.mount_dir).unwrap();
-
-        let path = paths
-            .map(|p| p.unwrap().file_name().into_string().unwrap())
-            .max();
+    pub fn path_to_index(&self, shard_id: SegmentId) -> PathBuf {
+        self.mount_dir.join(format!("{:08}.index", shard_id))
+    }
 
-        path.expect("missing shards!!!!!! none found")
-            .parse()
-            .expect("file not in shard format1111")
+    /// holds the key id of an encrypted stream
+    pub fn path_to_encryption(&self) -> PathBuf {
+        self.mount_dir.join("encryption")
     }
 
-    pub fn get_oldest_segment(&self) -> SegmentId {
-        let paths = fs::read_dir(&self.mount_dir).unwrap();
+    /// holds the leader epoch the shard was last led or followed in
+    pub fn path_to_leader_epoch(&self) -> PathBuf {
+        self.mount_dir.join("leader-epoch")
+    }
 
-        let path = paths
-            .map(|p| p.unwrap().file_name().into_string().unwrap())
-            .min();
+    /// left by a clean shutdown, see `ShardController::shut_down`
+    pub fn path_to_clean_shutdown(&self) -> PathBuf {
+        self.mount_dir.join("clean-shutdown")
+    }
 
-        path.expect("missing shards!!!!!! none found")
-            .parse()
-            .expect("not in shard style")
+    /// holds the format version of the shard dir
+    pub fn path_to_format_version(&self) -> PathBuf {
+        self.mount_dir.join("format-version")
     }
 
-    pub fn find_belonging_segment(&self, shard_iterator: u64) -> (SegmentId, ShardOffset) {
-        let paths = fs::read_dir(&self.mount_dir).unwrap();
-        dbg!(&paths);
-        let mut candidate_shard_id: u64 = 0;
-        let mut candidate_shard_id_offset: i64 = i64::max_value();
+    /// where the compactor writes the compacted copy of a segment before swapping it in
+    pub fn path_to_compacted(&self, shard_id: SegmentId) -> PathBuf {
+        self.mount_dir.join(format!("{:08}.compacted", shard_id))
+    }
 
-        for p in paths.map(|x| x.unwrap()) {
-            dbg!(&p);
-            let n: i64 = p.file_name().into_string().unwrap().parse().unwrap();
-            let diff = (shard_iterator as i64) - n;
-            dbg!(diff);
-            if diff >= 0 && diff <= candidate_shard_id_offset {
-                candidate_shard_id = n as u64;
-                candidate_shard_id_offset = diff;
+    /// Base offsets of the segment files in the mount dir. Anything whose name is not a number,
+    /// like index sidecars or `.DS_Store`, is not a segment and is skipped.
+    pub fn list_segments(&self) -> std::io::Result<Vec<SegmentId>> {
+        let mut segments = Vec::new();
+        for p in fs::read_dir(&self.mount_dir)? {
+            let p = p?;
+            if !p.file_type()?.is_file() {
+                continue;
+            }
+            let base_offset = p.file_name()
+                .to_str()
+                .filter(|name| name.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|name| name.parse().ok());
+            if let Some(base_offset) = base_offset {
+                segments.push(base_offset);
             }
-            dbg!(candidate_shard_id);
-            dbg!(candidate_shard_id_offset);
         }
-
-        (candidate_shard_id, candidate_shard_id_offset as u64)
+        Ok(segments)
     }
 
     pub fn create_first_segment(&self) {
         let path = self.path_to_segment(0);
-        dbg!(&path);
-        File::create(&path).expect(&format!(
-            "could not create file {}",
-            &path.to_string_lossy()
+        debug!(path = %path.display(), "creating the first segment");
+        File::create(&path).unwrap_or_else(|e| panic!(
+            "could not create file {}: {}",
+            &path.to_string_lossy(), e
         ));
     }
 
@@ -229,33 +529,19 @@ impl ShardDir {
         }
 
         if !self.mount_dir.exists() {
-            println!(
-                "creating mounting dir in {}",
-                &self.mount_dir.to_string_lossy()
-            );
-            fs::create_dir(&self.mount_dir);
+            info!(mount_dir = %self.mount_dir.display(), "creating the mount dir");
+            fs::create_dir(&self.mount_dir).expect("could not create mounting dir");
         }
 
-        let paths = fs::read_dir(&self.mount_dir).expect("Could not read dir entries");
-        let valid_paths: Vec<DirEntry> = paths.map(|x| x.expect("invalid record")).collect();
-        match valid_paths.len() {
-            0 => {
-                println!("about to create first segment");
-                self.create_first_segment()
-            }
-            _x => println!("all is ok, found segment"),
+        let segments = self.list_segments().expect("Could not read dir entries");
+        if segments.is_empty() {
+            self.create_first_segment()
         }
     }
-
-    pub fn get_end_offset(&self, shard_id: SegmentId) -> u64 {
-        let f = File::open(self.path_to_segment(shard_id)).unwrap();
-        let mut reader = BufReader::new(f);
-        reader.seek(SeekFrom::End(0)).unwrap()
-    }
 }
 
 pub fn assert_recordable(data: &[u8]) -> Result<(), failure::Error> {
-    let xxx = std::str::from_utf8(&data)?;
+    let xxx = std::str::from_utf8(data)?;
     base64::decode(xxx)?;
     Ok(())
 }
@@ -264,40 +550,18 @@ pub fn assert_recordable(data: &[u8]) -> Result<(), failure::Error> {
 
 #[cfg(test)]
 mod tests {
-    use std::{env, panic, thread, time};
+    use std::{thread, time};
     use std::fs::{create_dir, File};
-    use std::io::BufReader;
     use std::io::prelude::*;
     use std::path::PathBuf;
-    use std::sync::Arc;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-
-    use rand::{Rng, thread_rng};
-    use rand::distributions::Alphanumeric;
+    use std::sync::{Arc, RwLock};
+    use std::sync::atomic::AtomicUsize;
 
+    use crate::shards::batch::{Compression, RecordBatch};
+    use crate::shards::durability::{FsyncPolicy, FsyncStats};
+    use crate::shards::segments::SegmentManager;
     use crate::shards::shards::{Record, ShardDir, ShardReader, ShardWriter, ShaW};
-
-    fn with_tmp_dir<T>(test: T) -> ()
-        where T: FnOnce(PathBuf) -> () + panic::UnwindSafe
-    {
-        let rand_string: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .collect();
-        let mount_dir = env::temp_dir().join(format!("to_mount-test-{}", rand_string));
-        std::fs::remove_dir_all(&mount_dir);
-        wait_a_bit();
-
-        let result = panic::catch_unwind(|| {
-            test(mount_dir.clone())
-        });
-
-        std::fs::remove_dir_all(mount_dir);
-
-        wait_a_bit();
-        assert!(result.is_ok())
-    }
-
+    use crate::test_util::with_tmp_dir;
 
     fn wait_a_bit() {
         let ten_millis = time::Duration::from_millis(10);
@@ -344,13 +608,56 @@ mod tests {
 
             shard_dir.assert_mount_path();
             wait_a_bit();
-            let latest_segment = shard_dir.get_latest_segment();
+            let segments = shard_dir.list_segments().unwrap();
 
-            assert_eq!(latest_segment, 0);
+            assert_eq!(segments, vec![0]);
 
         });
     }
 
+    fn shard_writer(shard_dir: &ShardDir, max_segment_size: u64, fsync_policy: FsyncPolicy) -> ShardWriter {
+        ShardWriter {
+            segments: Arc::new(RwLock::new(SegmentManager::open(shard_dir.clone(), max_segment_size).unwrap())),
+            compression: Compression::None,
+            encryption: None,
+            fsync_policy,
+            unsynced_records: Arc::new(AtomicUsize::new(0)),
+            fsync_stats: Arc::new(FsyncStats::default()),
+            producers: Arc::default(),
+            transactions: Arc::default(),
+        }
+    }
+
+    fn shard_reader(shard_writer: &ShardWriter, position: u64, chunk_size: usize) -> ShardReader {
+        ShardReader {
+            segments: shard_writer.segments.clone(),
+            position,
+            chunk_size,
+            shard_dir: shard_writer.segments.read().unwrap().shard_dir.clone(),
+            aborted: vec![],
+        }
+    }
+
+    fn record(i: usize) -> Record {
+        Record::new(format!("meucu_tem_oculos_{}", i).into_bytes())
+    }
+
+    fn read_all(shard_writer: &ShardWriter, chunk_size: usize) -> Vec<Record> {
+        let mut read = Vec::new();
+        let mut position = 0;
+        loop {
+            let mut shard_reader = shard_reader(shard_writer, position, chunk_size);
+            let records = shard_reader.read().unwrap();
+            if records.is_empty() {
+                return read;
+            }
+            assert!(records.len() <= chunk_size);
+            assert_eq!(shard_reader.position, position + records.len() as u64);
+            position = shard_reader.position;
+            read.extend(records);
+        }
+    }
+
     #[test]
     fn shard_writer2_writes_to_shard() {
         with_tmp_dir(|mount_dir| {
@@ -358,31 +665,25 @@ mod tests {
 
             shard_dir.assert_mount_path();
 
-            let latest_segment = shard_dir.get_latest_segment();
-            let latest_shard_offset = shard_dir.get_end_offset(latest_segment);
-            let mut shard_writer = ShardWriter {
-                latest_segment: latest_segment,
-                shard_dir: shard_dir.clone(),
-                offset: latest_shard_offset,
-                max_segment_size: 1000000,
-            };
-            let string_data = base64::encode("meucu_tem_oculos".as_bytes());
-            let record = Record(string_data.clone().into_bytes());
+            let mut shard_writer = shard_writer(&shard_dir, 1000000, FsyncPolicy::None);
+            let record = Record::new("meucu_tem_oculos".as_bytes().to_vec());
 
-            shard_writer.write(record);
+            let sequence_number = shard_writer.write(vec![record.clone()]).unwrap();
 
-            let path = mount_dir.join(shard_dir.path_to_segment(shard_writer.latest_segment));
-            let expected = format!("{}\n", &string_data);
+            let path = mount_dir.join(shard_dir.path_to_segment(0));
             let res = {
                 let mut f = File::open(path).unwrap();
-                let mut res = String::new();
-                f.read_to_string(& mut res);
+                let mut res = Vec::new();
+                f.read_to_end(& mut res).unwrap();
                 res
             };
-            let string_data_size_in_bytes = string_data.into_bytes().len() as u64;
-            let new_offset =  shard_writer.offset;
-            assert_eq!(res, expected);
-            assert_eq!(new_offset, string_data_size_in_bytes + 1)
+            let batch = RecordBatch::decode(&res).unwrap();
+            let segments = shard_writer.segments.read().unwrap();
+            assert_eq!(sequence_number, 0);
+            assert_eq!(batch, RecordBatch::new(0, batch.timestamp_ms, vec![record]));
+            assert_eq!(segments.end_offset(), 1);
+            assert_eq!(segments.active().size, res.len() as u64);
+            assert_eq!(segments.index(segments.active()).unwrap().entries().len(), 1);
         })
     }
 
@@ -393,32 +694,41 @@ mod tests {
 
             shard_dir.assert_mount_path();
 
-            wait_a_bit();
+            let mut shard_writer = shard_writer(&shard_dir, 10, FsyncPolicy::None);
 
-            let original_latest_segment = shard_dir.get_latest_segment();
-            let latest_shard_offset = shard_dir.get_end_offset(original_latest_segment);
-            let mut shard_writer = ShardWriter {
-                latest_segment: latest_shard_offset,
-                shard_dir: shard_dir.clone(),
-                offset: latest_shard_offset,
-                max_segment_size: 10,
-            };
-            let string_data = base64::encode("meucu_tem_oculos".as_bytes());
-            let record = Record(string_data.clone().into_bytes());
+            assert_eq!(shard_writer.write(vec![record(0), record(1)]).unwrap(), 0);
+            assert_eq!(shard_writer.write(vec![record(2)]).unwrap(), 2);
+            let segments = shard_writer.segments.read().unwrap();
 
-            shard_writer.write(record.clone());
+            let base_offsets: Vec<u64> = segments.segments().map(|s| s.base_offset).collect();
+            assert_eq!(base_offsets, vec![0, 2, 3]);
+            assert!(shard_dir.path_to_segment(2).exists());
+            assert!(shard_dir.path_to_index(2).exists());
+        })
+    }
 
-            shard_writer.write(record.clone());
-            let new_latest_segment = shard_dir.get_latest_segment();
-            assert!(original_latest_segment < new_latest_segment);
-            let string_data_size_in_bytes = string_data.into_bytes().len() as u64;
+    #[test]
+    fn shard_writer_syncs_according_to_fsync_policy() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir: mount_dir.clone() };
+
+            shard_dir.assert_mount_path();
 
+            let fsync_count = |fsync_policy: FsyncPolicy, writes: usize| {
+                let mut shard_writer = shard_writer(&shard_dir, 1000000, fsync_policy);
+                for i in 0..writes {
+                    shard_writer.write(vec![record(i)]).unwrap();
+                }
+                shard_writer.fsync_stats.metrics().fsyncs
+            };
 
-            assert_eq!(new_latest_segment, string_data_size_in_bytes + 1); // \n is 1 byte long
+            assert_eq!(fsync_count(FsyncPolicy::None, 5), 0);
+            assert_eq!(fsync_count(FsyncPolicy::Interval(1000), 5), 0);
+            assert_eq!(fsync_count(FsyncPolicy::EveryNRecords(2), 5), 2);
+            assert_eq!(fsync_count(FsyncPolicy::Always, 5), 5);
         })
     }
 
-
     #[test]
     fn shard_reader_read_empty() {
         with_tmp_dir(|mount_dir| {
@@ -427,17 +737,13 @@ mod tests {
             shard_dir.assert_mount_path();
             wait_a_bit();
 
-            let mut shard_reader = ShardReader {
-                segment_id: shard_dir.get_latest_segment(),
-                latest_log_offset: shard_dir.get_latest_segment() as usize,
-                offset: 0,
-                chunk_size: 10,
-                shard_dir
-            };
+            let shard_writer = shard_writer(&shard_dir, 1000000, FsyncPolicy::None);
+            let mut shard_reader = shard_reader(&shard_writer, 0, 10);
 
             let res = shard_reader.read();
             assert!(res.is_ok());
             assert_eq!(res.unwrap().len(), 0);
+            assert_eq!(shard_reader.position, 0);
         })
     }
 
@@ -449,38 +755,118 @@ mod tests {
             shard_dir.assert_mount_path();
             wait_a_bit();
 
-            let original_latest_segment = shard_dir.get_latest_segment();
-            let latest_shard_offset = shard_dir.get_end_offset(original_latest_segment);
-            let mut shard_writer = ShardWriter {
-                latest_segment: latest_shard_offset,
-                shard_dir: shard_dir.clone(),
-                offset: latest_shard_offset,
-                max_segment_size: 100,
-            };
-            let string_data_1 = base64::encode("meucu_tem_oculos_1".as_bytes());
-            let record_1 = Record(string_data_1.clone().into_bytes());
+            let mut shard_writer = shard_writer(&shard_dir, 100, FsyncPolicy::None);
+            let record_1 = Record::new("meucu_tem_oculos_1".as_bytes().to_vec());
+            let record_2 = Record::new("meucu_tem_oculos_2".as_bytes().to_vec());
+
+            shard_writer.write(vec![record_1.clone()]).unwrap();
+
+            shard_writer.write(vec![record_2.clone()]).unwrap();
+
+            let mut shard_reader = shard_reader(&shard_writer, 0, 10);
+
+            let res = shard_reader.read();
+            assert!(res.is_ok());
+            assert_eq!(res.unwrap(), vec![record_1.clone(), record_2.clone()]);
+            assert_eq!(shard_reader.position, 2);
+        })
+    }
+
+    #[test]
+    fn shard_reader_reads_continuously_across_many_rolls() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir: mount_dir.clone() };
+
+            shard_dir.assert_mount_path();
+
+            let mut shard_writer = shard_writer(&shard_dir, 150, FsyncPolicy::None);
+            let codecs = [Compression::None, Compression::Zstd, Compression::Lz4, Compression::Snappy];
+            let written: Vec<Record> = (0..400).map(record).collect();
+            let mut next = 0;
+            for (i, size) in (1..6).cycle().enumerate() {
+                if next >= written.len() {
+                    break;
+                }
+                let end = (next + size).min(written.len());
+                shard_writer.compression = codecs[i % codecs.len()];
+                assert_eq!(shard_writer.write(written[next..end].to_vec()).unwrap(), next as u64);
+                next = end;
+            }
+            assert!(shard_writer.segments.read().unwrap().len() > 50);
+
+            for chunk_size in [1, 3, 7, 10].iter() {
+                assert_eq!(read_all(&shard_writer, *chunk_size), written);
+            }
 
-            let string_data_2 = base64::encode("meucu_tem_oculos_2".as_bytes());
-            let record_2 = Record(string_data_1.clone().into_bytes());
+            let mut shard_reader = shard_reader(&shard_writer, 333, 4);
+            assert_eq!(shard_reader.read().unwrap(), written[333..337].to_vec());
+        })
+    }
+
+    #[test]
+    fn shard_reader_tails_a_writer_that_keeps_rolling() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir: mount_dir.clone() };
 
-            shard_writer.write(record_1.clone());
+            shard_dir.assert_mount_path();
+
+            let mut writer = shard_writer(&shard_dir, 64, FsyncPolicy::None);
+            let segments = writer.segments.clone();
+            let producer = thread::spawn(move || {
+                for i in 0..500 {
+                    writer.write(vec![record(i)]).unwrap();
+                }
+            });
+
+            let mut read = Vec::new();
+            let mut position = 0;
+            while read.len() < 500 {
+                let mut shard_reader = ShardReader {
+                    segments: segments.clone(),
+                    position,
+                    chunk_size: 10,
+                    shard_dir: shard_dir.clone(),
+                    aborted: vec![],
+                };
+                read.extend(shard_reader.read().unwrap());
+                position = shard_reader.position;
+            }
+            producer.join().unwrap();
+
+            assert_eq!(read, (0..500).map(record).collect::<Vec<Record>>());
+        })
+    }
 
-            shard_writer.write(record_2.clone());
+    #[test]
+    fn shard_writer_recovers_torn_batches_and_lost_indexes_on_reopen() {
+        with_tmp_dir(|mount_dir| {
+            let shard_dir = ShardDir { mount_dir: mount_dir.clone() };
 
+            shard_dir.assert_mount_path();
 
-            let segment_id = shard_dir.get_oldest_segment();
-            let latest_log_offset = shard_dir.get_end_offset(shard_dir.get_latest_segment()) as usize;
-            let mut shard_reader = ShardReader {
-                segment_id,
-                latest_log_offset,
-                offset: shard_dir.get_oldest_segment(),
-                chunk_size: 10,
-                shard_dir
+            let mut writer = shard_writer(&shard_dir, 300, FsyncPolicy::None);
+            for i in 0..30 {
+                writer.write(vec![record(i)]).unwrap();
+            }
+            let (active, sealed) = {
+                let segments = writer.segments.read().unwrap();
+                let sealed = segments.segments().next().unwrap().base_offset;
+                (segments.active().base_offset, sealed)
             };
+            drop(writer);
 
-            let res = shard_reader.read();
-            assert!(res.is_ok());
-            assert_eq!(res.unwrap(), vec![record_1.clone(), record_2.clone()])
+            // a crash mid-append leaves half a batch behind and index entries missing
+            let torn = RecordBatch::new(30, 0, vec![record(30)]).encode(Compression::None).unwrap();
+            let mut f = std::fs::OpenOptions::new().append(true).open(shard_dir.path_to_segment(active)).unwrap();
+            f.write_all(&torn[..torn.len() / 2]).unwrap();
+            std::fs::remove_file(shard_dir.path_to_index(sealed)).unwrap();
+            std::fs::write(shard_dir.path_to_index(active), b"").unwrap();
+
+            let mut writer = shard_writer(&shard_dir, 300, FsyncPolicy::None);
+            assert_eq!(writer.segments.read().unwrap().end_offset(), 30);
+            assert_eq!(writer.write(vec![record(30)]).unwrap(), 30);
+
+            assert_eq!(read_all(&writer, 10), (0..31).map(record).collect::<Vec<Record>>());
         })
     }
-}
\ No newline at end of file
+}
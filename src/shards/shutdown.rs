@@ -0,0 +1,94 @@
+use std::fs;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::shards::producers::ProducerWindow;
+use crate::shards::transactions::ShardTransactions;
+
+/// What a shard leaves behind once it was shut down cleanly, with every write synced: the state
+/// otherwise rebuilt by scanning the batch headers of all its local segments when it is opened.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CleanShutdown {
+    /// the log end the state below goes with; a log that ends elsewhere was changed since
+    pub log_end_offset: u64,
+    pub producers: ProducerWindow,
+    pub transactions: ShardTransactions,
+}
+
+/// Raised when a shard shuts down, for the background threads that change its segments to stop
+/// before the last sync. It wakes them from their sleep, so they stop once the pass they are in,
+/// if any, is done.
+#[derive(Default)]
+pub struct StopSignal {
+    raised: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl StopSignal {
+    /// Sleeps for `duration`, or until the signal is raised. Returns whether the thread should go
+    /// on, that is false once the signal was raised.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let raised = self.raised.lock().unwrap();
+        let (raised, _) = self.wake.wait_timeout_while(raised, duration, |raised| !*raised).unwrap();
+        !*raised
+    }
+
+    pub fn raise(&self) {
+        *self.raised.lock().unwrap() = true;
+        self.wake.notify_all();
+    }
+}
+
+/// Writes `marker` to `path` through a synced temporary file, so the marker is either all there
+/// or not at all.
+pub fn store_clean_shutdown(path: &Path, marker: &CleanShutdown) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(marker)?)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    sync_parent(path)
+}
+
+/// Removes the marker at `path` and returns it, if there was one. The removal is synced before
+/// the shard takes any write, so a crash later on is never mistaken for a clean shutdown. A
+/// marker that cannot be read is dropped like a missing one, the shard only has to be scanned.
+pub fn take_clean_shutdown(path: &Path) -> std::io::Result<Option<CleanShutdown>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    discard_clean_shutdown(path)?;
+    match serde_json::from_slice(&bytes) {
+        Ok(marker) => Ok(Some(marker)),
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "ignoring a clean-shutdown marker that cannot be read");
+            Ok(None)
+        }
+    }
+}
+
+/// Removes the marker at `path`, if any, for tools changing the segments of a shard that is not
+/// open.
+pub fn discard_clean_shutdown(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => sync_parent(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// makes a file created, renamed or removed in the dir of `path` durable
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
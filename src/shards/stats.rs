@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::metrics::{Exposition, Histogram, HistogramSnapshot};
+use crate::shards::durability::FsyncMetrics;
+use crate::shards::replication::ReplicationMetrics;
+use crate::shards::segments::{Segment, SegmentManager, SegmentState};
+use crate::shards::shards::now_ms;
+
+/// how long a shard iterator nobody reads from counts as active
+pub const ITERATOR_EXPIRY_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ShardMetrics {
+    pub records_in: u64,
+    pub bytes_in: u64,
+    pub records_out: u64,
+    pub bytes_out: u64,
+    pub throttled_puts: u64,
+    pub put_latency: HistogramSnapshot,
+    pub get_latency: HistogramSnapshot,
+    pub active_iterators: usize,
+    /// on the mount path, the active one included
+    pub local_segments: usize,
+    /// only left in the object store
+    pub remote_segments: usize,
+    /// size of the local segments
+    pub disk_bytes: u64,
+    pub fsync: FsyncMetrics,
+    pub replication: ReplicationMetrics,
+}
+
+/// Traffic of a shard since it was opened, updated without locking on the put and get paths.
+#[derive(Default, Debug)]
+pub struct ShardStats {
+    records_in: AtomicU64,
+    bytes_in: AtomicU64,
+    records_out: AtomicU64,
+    bytes_out: AtomicU64,
+    throttled_puts: AtomicU64,
+    put_latency: Histogram,
+    get_latency: Histogram,
+    pub iterators: ActiveIterators,
+}
+
+impl ShardStats {
+    /// an acknowledged put of `records` records holding `bytes` bytes of data
+    pub fn put(&self, records: u64, bytes: u64, elapsed: Duration) {
+        self.records_in.fetch_add(records, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+        self.put_latency.observe(elapsed);
+    }
+
+    /// a put turned away for now, because too few replicas are in sync or it went over the quota
+    pub fn throttled(&self) {
+        self.throttled_puts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a get that returned `records` records holding `bytes` bytes of data
+    pub fn got(&self, records: u64, bytes: u64, elapsed: Duration) {
+        self.records_out.fetch_add(records, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+        self.get_latency.observe(elapsed);
+    }
+
+    pub fn metrics(&self, segments: &SegmentManager, fsync: FsyncMetrics, replication: ReplicationMetrics) -> ShardMetrics {
+        let (remote, local): (Vec<&Segment>, Vec<&Segment>) = segments.segments().partition(|s| s.state == SegmentState::Remote);
+        ShardMetrics {
+            records_in: self.records_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            records_out: self.records_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            throttled_puts: self.throttled_puts.load(Ordering::Relaxed),
+            put_latency: self.put_latency.snapshot(),
+            get_latency: self.get_latency.snapshot(),
+            active_iterators: self.iterators.count(),
+            local_segments: local.len(),
+            remote_segments: remote.len(),
+            disk_bytes: local.iter().map(|s| s.size).sum(),
+            fsync,
+            replication,
+        }
+    }
+}
+
+/// The shard iterators consumers are reading from. Shard iterators are plain sequence numbers,
+/// so a consumer is followed from the iterator it read from to the next one it was given, and
+/// consumers at the same sequence number count once.
+#[derive(Default, Debug)]
+pub struct ActiveIterators {
+    last_read_ms: Mutex<HashMap<u64, u64>>,
+}
+
+impl ActiveIterators {
+    pub fn handed_out(&self, shard_iterator: u64) {
+        self.last_read_ms.lock().unwrap().insert(shard_iterator, now_ms());
+    }
+
+    /// a get from `shard_iterator` that was told to go on from `next_shard_iterator`
+    pub fn read(&self, shard_iterator: u64, next_shard_iterator: u64) {
+        let now = now_ms();
+        let mut last_read_ms = self.last_read_ms.lock().unwrap();
+        last_read_ms.remove(&shard_iterator);
+        last_read_ms.insert(next_shard_iterator, now);
+        last_read_ms.retain(|_, read_ms| now.saturating_sub(*read_ms) < ITERATOR_EXPIRY_MS);
+    }
+
+    pub fn count(&self) -> usize {
+        let now = now_ms();
+        let mut last_read_ms = self.last_read_ms.lock().unwrap();
+        last_read_ms.retain(|_, read_ms| now.saturating_sub(*read_ms) < ITERATOR_EXPIRY_MS);
+        last_read_ms.len()
+    }
+}
+
+impl ShardMetrics {
+    /// Adds the metrics of the shard to `out`, every one of them labelled with `labels`.
+    pub fn expose(&self, out: &mut Exposition, labels: &[(&str, &str)]) {
+        out.counter("rinites_records_in_total", "Records put in the shard.", labels, self.records_in);
+        out.counter("rinites_bytes_in_total", "Bytes of record data put in the shard.", labels, self.bytes_in);
+        out.counter("rinites_records_out_total", "Records read from the shard.", labels, self.records_out);
+        out.counter("rinites_bytes_out_total", "Bytes of record data read from the shard.", labels, self.bytes_out);
+        out.counter("rinites_throttled_puts_total", "Puts turned away because too few replicas were in sync or the quota was used up.", labels, self.throttled_puts);
+        out.histogram("rinites_put_latency_seconds", "Time to acknowledge a put, replicas included.", labels, &self.put_latency);
+        out.histogram("rinites_get_latency_seconds", "Time to serve a get.", labels, &self.get_latency);
+        out.gauge("rinites_active_iterators", "Shard iterators read from in the last five minutes.", labels, self.active_iterators);
+        out.gauge("rinites_segments", "Segments on the mount path, the active one included.", labels, self.local_segments);
+        out.gauge("rinites_remote_segments", "Segments only left in the object store.", labels, self.remote_segments);
+        out.gauge("rinites_disk_bytes", "Size of the segments on the mount path.", labels, self.disk_bytes);
+        out.histogram("rinites_fsync_latency_seconds", "Time fsyncing the active segment took.", labels, &self.fsync.latency);
+        out.gauge("rinites_log_end_offset", "Sequence number the next record appended gets.", labels, self.replication.log_end_offset);
+        out.gauge("rinites_high_watermark", "Sequence number up to which records are readable.", labels, self.replication.high_watermark);
+        out.gauge("rinites_in_sync_replicas", "Replicas in sync with the leader, the leader included.", labels, self.replication.in_sync_replicas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::stats::ActiveIterators;
+
+    #[test]
+    fn consumers_are_followed_from_iterator_to_iterator() {
+        let iterators = ActiveIterators::default();
+        iterators.handed_out(0);
+        iterators.handed_out(0);
+        iterators.handed_out(7);
+        assert_eq!(iterators.count(), 2);
+
+        iterators.read(0, 3);
+        iterators.read(7, 9);
+        assert_eq!(iterators.count(), 2);
+        // caught up, so told to read from the same iterator again
+        iterators.read(9, 9);
+        assert_eq!(iterators.count(), 2);
+        // a consumer reading from an iterator it was given earlier
+        iterators.read(42, 50);
+        assert_eq!(iterators.count(), 3);
+
+        iterators.last_read_ms.lock().unwrap().values_mut().for_each(|read_ms| *read_ms = 0);
+        assert_eq!(iterators.count(), 0);
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use memmap2::Mmap;
+use tracing::{debug, info, Span, warn};
+
+use crate::shards::index::SegmentIndex;
+use crate::shards::segments::{Segment, SegmentManager, SegmentState};
+use crate::shards::shards::SegmentId;
+use crate::shards::shutdown::StopSignal;
+
+/// how long sealed segments stay on the mount path once uploaded, unless the stream says
+pub const DEFAULT_HOT_RETENTION_MS: u64 = 60 * 60 * 1000;
+
+pub const DEFAULT_CACHE_SEGMENTS: usize = 16;
+
+/// Where sealed segments go once they leave the mount path. Objects are whole files named after
+/// the segment files, and a put must either store the whole object or nothing.
+pub trait ObjectStore: Send + Sync {
+    fn put(&self, name: &str, from: &Path) -> std::io::Result<()>;
+
+    fn get(&self, name: &str, to: &Path) -> std::io::Result<()>;
+
+    /// names and sizes of every object in the store
+    fn list(&self) -> std::io::Result<Vec<(String, u64)>>;
+
+    /// deletes the object, when there is one
+    fn delete(&self, name: &str) -> std::io::Result<()>;
+}
+
+/// An `ObjectStore` backed by a directory, usually on a bigger and slower disk than the mount
+/// path.
+pub struct LocalDirStore {
+    pub dir: PathBuf,
+}
+
+impl LocalDirStore {
+    pub fn new(dir: PathBuf) -> std::io::Result<LocalDirStore> {
+        fs::create_dir_all(&dir)?;
+        Ok(LocalDirStore { dir })
+    }
+}
+
+impl ObjectStore for LocalDirStore {
+    fn put(&self, name: &str, from: &Path) -> std::io::Result<()> {
+        let partial = self.dir.join(format!("{}.part", name));
+        fs::copy(from, &partial)?;
+        File::open(&partial)?.sync_all()?;
+        fs::rename(partial, self.dir.join(name))
+    }
+
+    fn get(&self, name: &str, to: &Path) -> std::io::Result<()> {
+        fs::copy(self.dir.join(name), to).map(|_| ())
+    }
+
+    fn list(&self) -> std::io::Result<Vec<(String, u64)>> {
+        let mut objects = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if let (true, Some(name)) = (metadata.is_file(), entry.file_name().to_str()) {
+                if !name.ends_with(".part") {
+                    objects.push((name.to_string(), metadata.len()));
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    fn delete(&self, name: &str) -> std::io::Result<()> {
+        match fs::remove_file(self.dir.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TieringConfig {
+    /// directory standing in for the object store
+    pub remote_dir: PathBuf,
+    /// how long a sealed segment stays on the mount path after it was last written
+    pub hot_retention_ms: u64,
+    /// how many remote segments are kept in the local cache
+    pub cache_segments: usize,
+}
+
+/// Remote segments that were fetched for readers, least recently used first.
+#[derive(Default)]
+struct SegmentCache {
+    order: VecDeque<SegmentId>,
+    maps: HashMap<SegmentId, Arc<Mmap>>,
+}
+
+/// Uploads sealed segments to an object store and fetches them back into a local cache when a
+/// reader needs one that was deleted from the mount path.
+pub struct TieredStorage {
+    pub store: Box<dyn ObjectStore>,
+    pub cache_dir: PathBuf,
+    pub hot_retention_ms: u64,
+    pub cache_segments: usize,
+    cache: Mutex<SegmentCache>,
+}
+
+impl TieredStorage {
+    pub fn new(store: Box<dyn ObjectStore>, cache_dir: PathBuf, hot_retention_ms: u64, cache_segments: usize) -> std::io::Result<TieredStorage> {
+        // the cache does not outlive the process, whatever is left in it is stale
+        match fs::remove_dir_all(&cache_dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::create_dir_all(&cache_dir)?;
+        Ok(TieredStorage {
+            store,
+            cache_dir,
+            hot_retention_ms,
+            cache_segments: cache_segments.max(1),
+            cache: Mutex::new(SegmentCache::default()),
+        })
+    }
+
+    /// Uploads a sealed segment along with its index and the timestamp of its last batch, which
+    /// retention goes by. Those go first, so a segment object in the store always has them.
+    pub fn upload(&self, segments: &RwLock<SegmentManager>, segment: &Segment) -> std::io::Result<()> {
+        let shard_dir = segments.read().unwrap().shard_dir.clone();
+        let name = object_name(segment.base_offset);
+        self.store.put(&format!("{}.index", name), &shard_dir.path_to_index(segment.base_offset))?;
+        if let Some(last_put_ms) = segment.last_put_ms {
+            let last_put_path = self.cache_dir.join(format!("{}.last-put", name));
+            fs::write(&last_put_path, last_put_ms.to_string())?;
+            self.store.put(&format!("{}.last-put", name), &last_put_path)?;
+            fs::remove_file(last_put_path)?;
+        }
+        self.store.put(&name, &shard_dir.path_to_segment(segment.base_offset))
+    }
+
+    /// Deletes a segment, its index and timestamp from the store, and from the cache when it was
+    /// fetched. The segment goes first, so a segment object in the store always has the others.
+    pub fn delete(&self, base_offset: SegmentId) -> std::io::Result<()> {
+        let name = object_name(base_offset);
+        self.store.delete(&name)?;
+        self.store.delete(&format!("{}.last-put", name))?;
+        self.store.delete(&format!("{}.index", name))?;
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.maps.remove(&base_offset).is_some() {
+            cache.order.retain(|b| *b != base_offset);
+            fs::remove_file(self.cache_dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// The segments found in the store, as remote segments, with their indexes.
+    pub fn remote_segments(&self) -> std::io::Result<Vec<(Segment, SegmentIndex)>> {
+        let mut remote = Vec::new();
+        for (name, size) in self.store.list()? {
+            let base_offset = match parse_object_name(&name) {
+                Some(base_offset) => base_offset,
+                None => continue,
+            };
+            let index_path = self.cache_dir.join(format!("{}.index", name));
+            self.store.get(&format!("{}.index", name), &index_path)?;
+            let index = SegmentIndex::load(&index_path)?;
+            fs::remove_file(index_path)?;
+
+            let last_put_path = self.cache_dir.join(format!("{}.last-put", name));
+            let last_put_ms = match self.store.get(&format!("{}.last-put", name), &last_put_path) {
+                Ok(()) => {
+                    let last_put = fs::read_to_string(&last_put_path)?;
+                    fs::remove_file(&last_put_path)?;
+                    Some(last_put.trim().parse().map_err(|e| std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("timestamp of remote segment {} is not a number: {}", base_offset, e),
+                    ))?)
+                }
+                // empty segments have none
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            let segment = Segment { base_offset, next_offset: base_offset, size, state: SegmentState::Remote, last_put_ms };
+            remote.push((segment, index));
+        }
+        remote.sort_unstable_by_key(|(segment, _)| segment.base_offset);
+        Ok(remote)
+    }
+
+    /// Read-only map of a remote segment, downloading it into the cache unless it is there
+    /// already. The cache is locked during the download, so concurrent readers of cold data
+    /// wait for each other.
+    pub fn fetch(&self, segment: &Segment) -> std::io::Result<Arc<Mmap>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(map) = cache.maps.get(&segment.base_offset).cloned() {
+            cache.order.retain(|b| *b != segment.base_offset);
+            cache.order.push_back(segment.base_offset);
+            return Ok(map);
+        }
+
+        let name = object_name(segment.base_offset);
+        let path = self.cache_dir.join(&name);
+        self.store.get(&name, &path)?;
+        let map = Arc::new(unsafe { Mmap::map(&File::open(&path)?)? });
+        debug!(base_offset = segment.base_offset, "fetched remote segment");
+
+        cache.maps.insert(segment.base_offset, map.clone());
+        cache.order.push_back(segment.base_offset);
+        while cache.order.len() > self.cache_segments {
+            if let Some(evicted) = cache.order.pop_front() {
+                cache.maps.remove(&evicted);
+                // readers still holding the map keep reading the unlinked file
+                fs::remove_file(self.cache_dir.join(object_name(evicted)))?;
+            }
+        }
+        Ok(map)
+    }
+}
+
+fn object_name(base_offset: SegmentId) -> String {
+    format!("{:08}", base_offset)
+}
+
+fn parse_object_name(name: &str) -> Option<SegmentId> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// One tiering pass: uploads the sealed segments that are not in the store yet, then deletes from
+/// the mount path the uploaded ones last written more than `hot_retention_ms` ago. Returns how
+/// many segments were uploaded and offloaded.
+pub fn tier(segments: &RwLock<SegmentManager>, tiered: &TieredStorage) -> std::io::Result<(usize, usize)> {
+    let (pending, uploaded): (Vec<Segment>, Vec<Segment>) = {
+        let segments = segments.read().unwrap();
+        segments
+            .segments()
+            .filter(|s| s.state == SegmentState::Sealed)
+            .cloned()
+            .partition(|s| !segments.is_uploaded(s.base_offset))
+    };
+
+    for segment in pending.iter() {
+        tiered.upload(segments, segment)?;
+        segments.write().unwrap().mark_uploaded(segment);
+    }
+
+    let hot_retention = Duration::from_millis(tiered.hot_retention_ms);
+    let mut offloaded = 0;
+    for segment in uploaded.iter() {
+        let path = segments.read().unwrap().path_to(segment);
+        let last_written = match fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let age = SystemTime::now().duration_since(last_written).unwrap_or_default();
+        if age >= hot_retention && segments.write().unwrap().offload(segment.base_offset)? {
+            offloaded += 1;
+        }
+    }
+    Ok((pending.len(), offloaded))
+}
+
+/// Background thread of tiered streams: every `interval_ms` it runs a tiering pass, until the
+/// shard is dropped or `stop` is raised.
+pub fn spawn_tierer(segments: Arc<RwLock<SegmentManager>>, tiered: Arc<TieredStorage>, interval_ms: u64, stop: Arc<StopSignal>) -> JoinHandle<()> {
+    let span = Span::current();
+    thread::spawn(move || while Arc::strong_count(&segments) > 1 && stop.sleep(Duration::from_millis(interval_ms)) {
+
+        let _entered = span.enter();
+        match tier(&segments, &tiered) {
+            Ok((0, 0)) => {}
+            Ok((uploaded, offloaded)) => info!(uploaded, offloaded, "tiered segments"),
+            Err(e) => warn!(error = %e, "tiering failed"),
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::shards::batch::Compression;
+    use crate::shards::durability::{FsyncPolicy, FsyncStats};
+    use crate::shards::segments::{SegmentManager, SegmentState};
+    use crate::shards::shards::{Record, ShardDir, ShardReader, ShardWriter, ShaW};
+    use crate::shards::tiering::{LocalDirStore, tier, TieredStorage};
+    use crate::test_util::with_tmp_dir;
+
+    fn tiered_segments(tmp_dir: &PathBuf, hot_retention_ms: u64) -> Arc<RwLock<SegmentManager>> {
+        fs::create_dir_all(tmp_dir).unwrap();
+        let shard_dir = ShardDir { mount_dir: tmp_dir.join("mount") };
+        shard_dir.assert_mount_path();
+        let tiered = TieredStorage::new(
+            Box::new(LocalDirStore::new(tmp_dir.join("remote")).unwrap()),
+            tmp_dir.join("mount").join("remote-cache"),
+            hot_retention_ms,
+            2,
+        ).unwrap();
+
+        let mut segments = SegmentManager::open(shard_dir, 200).unwrap();
+        segments.attach_tiered(Arc::new(tiered)).unwrap();
+        Arc::new(RwLock::new(segments))
+    }
+
+    fn write(segments: &Arc<RwLock<SegmentManager>>, records: &[Record]) {
+        let mut writer = ShardWriter {
+            segments: segments.clone(),
+            compression: Compression::None,
+            encryption: None,
+            fsync_policy: FsyncPolicy::None,
+            unsynced_records: Arc::new(AtomicUsize::new(0)),
+            fsync_stats: Arc::new(FsyncStats::default()),
+            producers: Arc::default(),
+            transactions: Arc::default(),
+        };
+        for record in records {
+            writer.write(vec![record.clone()]).unwrap();
+        }
+    }
+
+    fn read_all(segments: &Arc<RwLock<SegmentManager>>) -> Vec<Record> {
+        let mut reader = ShardReader {
+            segments: segments.clone(),
+            position: 0,
+            chunk_size: 7,
+            shard_dir: segments.read().unwrap().shard_dir.clone(),
+            aborted: vec![],
+        };
+        let mut read = Vec::new();
+        loop {
+            let records = reader.read().unwrap();
+            if records.is_empty() {
+                return read;
+            }
+            read.extend(records);
+        }
+    }
+
+    fn records() -> Vec<Record> {
+        (0..100).map(|i| Record::new(format!("meucu_tem_oculos_{}", i).into_bytes())).collect()
+    }
+
+    #[test]
+    fn tier_offloads_sealed_segments_and_readers_fetch_them_back() {
+        with_tmp_dir(|tmp_dir| {
+            let segments = tiered_segments(&tmp_dir, 0);
+            write(&segments, &records());
+            let tiered = segments.read().unwrap().tiered.clone().unwrap();
+            let sealed = segments.read().unwrap().len() - 1;
+            assert!(sealed > 5);
+
+            assert_eq!(tier(&segments, &tiered).unwrap(), (sealed, 0));
+            assert_eq!(tier(&segments, &tiered).unwrap(), (0, sealed));
+            let shard_dir = segments.read().unwrap().shard_dir.clone();
+            assert_eq!(shard_dir.list_segments().unwrap().len(), 1);
+            assert!(segments.read().unwrap().segments().take(sealed).all(|s| s.state == SegmentState::Remote));
+
+            assert_eq!(read_all(&segments), records());
+            assert_eq!(fs::read_dir(tmp_dir.join("mount").join("remote-cache")).unwrap().count(), 2);
+
+            // a restart finds the offloaded segments in the store, timestamps included, without
+            // fetching them
+            let last_puts: Vec<Option<u64>> = segments.read().unwrap().segments().take(sealed).map(|s| s.last_put_ms).collect();
+            assert!(last_puts.iter().all(Option::is_some));
+            drop(tiered);
+            drop(segments);
+            let segments = tiered_segments(&tmp_dir, 0);
+            assert_eq!(segments.read().unwrap().oldest_offset(), 0);
+            assert_eq!(segments.read().unwrap().len(), sealed + 1);
+            assert_eq!(segments.read().unwrap().segments().take(sealed).map(|s| s.last_put_ms).collect::<Vec<Option<u64>>>(), last_puts);
+            assert_eq!(fs::read_dir(tmp_dir.join("mount").join("remote-cache")).unwrap().count(), 0);
+            assert_eq!(read_all(&segments), records());
+        })
+    }
+
+    #[test]
+    fn tier_keeps_hot_segments_on_the_mount_path() {
+        with_tmp_dir(|tmp_dir| {
+            let segments = tiered_segments(&tmp_dir, 60 * 60 * 1000);
+            write(&segments, &records());
+            let tiered = segments.read().unwrap().tiered.clone().unwrap();
+            let sealed = segments.read().unwrap().len() - 1;
+            assert_eq!(tier(&segments, &tiered).unwrap(), (sealed, 0));
+            assert_eq!(tier(&segments, &tiered).unwrap(), (0, 0));
+
+            assert_eq!(segments.read().unwrap().shard_dir.list_segments().unwrap().len(), sealed + 1);
+            assert_eq!(fs::read_dir(tmp_dir.join("remote")).unwrap().count(), 3 * sealed);
+            assert_eq!(read_all(&segments), records());
+        })
+    }
+
+    #[test]
+    fn delete_before_deletes_segments_from_the_store_for_good() {
+        with_tmp_dir(|tmp_dir| {
+            let segments = tiered_segments(&tmp_dir, 0);
+            write(&segments, &records());
+            let tiered = segments.read().unwrap().tiered.clone().unwrap();
+            let sealed = segments.read().unwrap().len() - 1;
+            tier(&segments, &tiered).unwrap();
+            tier(&segments, &tiered).unwrap();
+            assert_eq!(read_all(&segments), records());
+
+            let oldest = segments.read().unwrap().segments().nth(3).unwrap().base_offset;
+            assert_eq!(segments.write().unwrap().delete_before(oldest).unwrap().len(), 3);
+            assert_eq!(fs::read_dir(tmp_dir.join("remote")).unwrap().count(), 3 * (sealed - 3));
+            assert_eq!(read_all(&segments), records()[oldest as usize..].to_vec());
+
+            drop(tiered);
+            drop(segments);
+            let segments = tiered_segments(&tmp_dir, 0);
+            assert_eq!(segments.read().unwrap().oldest_offset(), oldest);
+            assert_eq!(read_all(&segments), records()[oldest as usize..].to_vec());
+        })
+    }
+}
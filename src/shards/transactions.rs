@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::shards::batch::{BatchHeader, RecordBatch};
+use crate::shards::producers::{ProducerBatch, scan_producer_batches};
+use crate::shards::segments::SegmentManager;
+
+/// How a transaction ended, written to every shard it put records in.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Marker {
+    Commit,
+    Abort,
+}
+
+impl Marker {
+    /// what the marker batch carries as producer sequence
+    pub fn id(&self) -> u64 {
+        match self {
+            Marker::Abort => 0,
+            Marker::Commit => 1,
+        }
+    }
+
+    pub fn from_id(id: u64) -> Option<Marker> {
+        match id {
+            0 => Some(Marker::Abort),
+            1 => Some(Marker::Commit),
+            _ => None,
+        }
+    }
+}
+
+/// `read_uncommitted` readers get every record as soon as it is acknowledged. `read_committed`
+/// readers stop before the first record of a transaction that is still open and skip the
+/// records of aborted ones.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    #[default]
+    ReadUncommitted,
+    ReadCommitted,
+}
+
+/// The records of transaction `transaction_id` in `[first_sequence, marker_sequence)`, which
+/// was aborted.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct AbortedTransaction {
+    pub transaction_id: u64,
+    pub first_sequence: u64,
+    pub marker_sequence: u64,
+}
+
+impl AbortedTransaction {
+    /// whether `batch` holds records of the transaction
+    pub fn covers(&self, batch: &RecordBatch) -> bool {
+        batch.producer.is_some_and(|p| p.transactional && p.producer_id == self.transaction_id)
+            && self.first_sequence <= batch.base_sequence
+            && batch.base_sequence < self.marker_sequence
+    }
+}
+
+/// The transactions of a shard: those with records but no marker yet, and those that were
+/// aborted. Like the producer window, it is rebuilt from the batch headers when the shard is
+/// opened, unless it was shut down cleanly, and kept up to date by every append, followers
+/// included.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ShardTransactions {
+    /// the first sequence number of each open transaction
+    pub open: BTreeMap<u64, u64>,
+    pub aborted: Vec<AbortedTransaction>,
+}
+
+impl ShardTransactions {
+    pub fn load(segments: &SegmentManager) -> std::io::Result<ShardTransactions> {
+        let mut transactions = ShardTransactions::default();
+        scan_producer_batches(segments, |header, producer| transactions.appended(header, producer))?;
+        Ok(transactions)
+    }
+
+    /// Accounts a batch of `producer` that was just appended.
+    pub fn appended(&mut self, header: &BatchHeader, producer: ProducerBatch) {
+        if !producer.transactional {
+            return;
+        }
+        if !header.control {
+            self.open.entry(producer.producer_id).or_insert(header.base_sequence);
+            return;
+        }
+        let first_sequence = self.open.remove(&producer.producer_id);
+        if let (Some(first_sequence), Some(Marker::Abort)) = (first_sequence, Marker::from_id(producer.producer_sequence)) {
+            self.aborted.push(AbortedTransaction {
+                transaction_id: producer.producer_id,
+                first_sequence,
+                marker_sequence: header.base_sequence,
+            });
+        }
+    }
+
+    /// where `read_committed` readers stop: the first record of an open transaction, or the
+    /// high-watermark
+    pub fn last_stable_offset(&self, high_watermark: u64) -> u64 {
+        self.open.values().copied().min().unwrap_or(high_watermark).min(high_watermark)
+    }
+
+    /// the aborted transactions with records from sequence number `from` on
+    pub fn aborted_from(&self, from: u64) -> Vec<AbortedTransaction> {
+        self.aborted.iter().filter(|a| a.marker_sequence > from).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shards::batch::{BatchHeader, Compression, RecordBatch};
+    use crate::shards::shards::Record;
+    use crate::shards::producers::ProducerBatch;
+    use crate::shards::transactions::{Marker, ShardTransactions};
+
+    fn append(transactions: &mut ShardTransactions, batch: RecordBatch) {
+        let frame = batch.encode(Compression::None).unwrap();
+        let header = BatchHeader::parse(&frame).unwrap();
+        if let Some(producer) = header.producer(&frame).unwrap() {
+            transactions.appended(&header, producer);
+        }
+    }
+
+    fn put(base_sequence: u64, transaction_id: Option<u64>) -> RecordBatch {
+        let producer = transaction_id.map(|producer_id| ProducerBatch { producer_id, producer_sequence: 0, transactional: true });
+        RecordBatch::new(base_sequence, 0, vec![Record::new(b"meucu_tem_oculos".to_vec())]).with_producer(producer)
+    }
+
+    #[test]
+    fn shard_transactions_track_open_and_aborted_transactions() {
+        let mut transactions = ShardTransactions::default();
+        append(&mut transactions, put(0, None));
+        append(&mut transactions, put(1, Some(7)));
+        append(&mut transactions, put(2, Some(8)));
+        append(&mut transactions, put(3, Some(7)));
+        assert_eq!(transactions.last_stable_offset(10), 1);
+
+        append(&mut transactions, RecordBatch::marker(4, 0, 7, Marker::Abort));
+        assert_eq!(transactions.last_stable_offset(10), 2);
+        append(&mut transactions, RecordBatch::marker(5, 0, 8, Marker::Commit));
+        assert_eq!(transactions.last_stable_offset(10), 10);
+        assert_eq!(transactions.last_stable_offset(4), 4);
+
+        let aborted = transactions.aborted_from(0);
+        assert_eq!(aborted.len(), 1);
+        assert!(aborted[0].covers(&put(3, Some(7))));
+        assert!(!aborted[0].covers(&put(2, Some(8))));
+        assert!(!aborted[0].covers(&put(3, None)));
+        assert!(transactions.aborted_from(5).is_empty());
+    }
+}
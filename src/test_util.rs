@@ -0,0 +1,37 @@
+use std::{env, panic, thread};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+use rand::distributions::Alphanumeric;
+
+/// Runs `test` with a dir of its own under the temp dir, which is not created, and removes the
+/// dir afterwards whether the test passed or not.
+pub fn with_tmp_dir<T>(test: T)
+    where T: FnOnce(PathBuf) + panic::UnwindSafe
+{
+    let rand_string: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .collect();
+    let dir = env::temp_dir().join(format!("to_mount-test-{}", rand_string));
+
+    let result = panic::catch_unwind(|| {
+        test(dir.clone())
+    });
+
+    let _ = std::fs::remove_dir_all(dir);
+    assert!(result.is_ok())
+}
+
+/// Polls `f` until it returns something, failing the test after ten seconds.
+pub fn eventually<T>(what: &str, f: impl Fn() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(t) = f() {
+            return t;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("timed out waiting for {}", what)
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::executor::block_on;
+use futures::future::join_all;
+use futures::StreamExt;
+
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::shards::shard_controller::GetRecordsResponse;
+use rinites_client::connection::{Backoff, Connection};
+use rinites_client::consumer::{ConsumedRecord, Consumer, ConsumerConfig, ServerCheckpointStore};
+use rinites_client::producer::{Producer, ProducerConfig, ProducerRecord, RecordMetadata};
+
+use common::LocalCluster;
+
+mod common;
+
+/// every record of a shard by sequence number, with its partition key
+fn read_shard(cluster: &LocalCluster, node: usize, shard_id: u32) -> BTreeMap<u64, (String, Option<String>)> {
+    let mut records = BTreeMap::new();
+    let mut shard_iterator = 0;
+    loop {
+        let path = format!("/streams/orders/shards/{}/get-records/{}", shard_id, shard_iterator);
+        let read: GetRecordsResponse = serde_json::from_slice(&cluster.request_ok(node, "GET", &path, "").body).unwrap();
+        if read.records.is_empty() {
+            return records;
+        }
+        shard_iterator = read.next_shard_iterator;
+        for ((data, sequence_number), partition_key) in read.records.iter().zip(read.sequence_numbers).zip(read.partition_keys) {
+            let data = String::from_utf8(base64::decode(data).unwrap()).unwrap();
+            records.insert(sequence_number, (data, partition_key));
+        }
+    }
+}
+
+#[test]
+fn the_producer_routes_batches_and_retries_records_through_a_failover() {
+    let mut cluster = LocalCluster::start(3, &["--min-insync-replicas", "2"]);
+    cluster.leader();
+    let created = cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":2,"replication_factor":3}"#);
+    let stream: StreamMetadata = serde_json::from_slice(&created.body).unwrap();
+
+    let connection = Connection::new(cluster.addrs.clone(), Duration::from_secs(15)).unwrap();
+    let producer = Producer::start(connection, ProducerConfig { max_batch_records: 7, ..ProducerConfig::new("orders") }).unwrap();
+    let record = |i: usize| ProducerRecord::keyed(&format!("user-{}", i % 10), format!("meucu_tem_oculos_{}", i).into_bytes());
+
+    let mut sent: Vec<RecordMetadata> = block_on(join_all((0..100).map(|i| producer.send(record(i)))))
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect();
+    let dead = stream.shards[0].leader as usize - 1;
+    cluster.kill(dead);
+    let after_failover = block_on(join_all((100..200).map(|i| producer.send(record(i)))));
+    sent.extend(after_failover.into_iter().map(|r| r.unwrap()));
+    drop(producer);
+
+    let live = cluster.live().next().unwrap();
+    let shards: Vec<_> = (0..2).map(|shard_id| read_shard(&cluster, live, shard_id)).collect();
+    assert_eq!(shards.iter().map(|s| s.len()).sum::<usize>(), 200);
+    for (i, metadata) in sent.iter().enumerate() {
+        let (data, partition_key) = &shards[metadata.shard_id as usize][&metadata.sequence_number];
+        assert_eq!(data, &format!("meucu_tem_oculos_{}", i));
+        assert_eq!(stream.shard_for_key(partition_key.as_ref().unwrap()).unwrap().shard_id, metadata.shard_id);
+    }
+}
+
+#[test]
+fn the_consumer_reads_parents_before_children_and_resumes_from_its_group_checkpoints() {
+    let cluster = LocalCluster::start(1, &[]);
+    cluster.leader();
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1,"replication_factor":1}"#);
+
+    let connection = Connection::new(cluster.addrs.clone(), Duration::from_secs(15)).unwrap();
+    let producer = Producer::start(connection, ProducerConfig::new("orders")).unwrap();
+    let send = |range: std::ops::Range<usize>| {
+        let records = range.map(|i| producer.send(ProducerRecord::keyed(&format!("user-{}", i % 5), i.to_string().into_bytes())));
+        for sent in block_on(join_all(records)) {
+            sent.unwrap();
+        }
+    };
+    // the producer keeps putting in the closed shards and is sent to their children
+    send(0..30);
+    cluster.request_ok(0, "POST", "/streams/orders/shards/0/split", "");
+    send(30..60);
+    cluster.request_ok(0, "POST", "/streams/orders/shards/1/merge", r#"{"adjacent_shard_id":2}"#);
+    send(60..90);
+    drop(producer);
+
+    let connection = Arc::new(Connection::new(cluster.addrs.clone(), Duration::from_secs(15)).unwrap());
+    let consumer = || {
+        let store = ServerCheckpointStore { connection: connection.clone(), group: "billing".to_string(), retries: 3, backoff: Backoff::default() };
+        Consumer::new(connection.clone(), ConsumerConfig::new("orders"), Box::new(store)).unwrap()
+    };
+    let number = |record: &ConsumedRecord| String::from_utf8(record.data.clone()).unwrap().parse::<usize>().unwrap();
+
+    let first = consumer();
+    let checkpointer = first.checkpointer();
+    let records: Vec<ConsumedRecord> = block_on(first.into_stream().take(90).map(|r| r.unwrap()).collect());
+    let mut by_key: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for record in &records {
+        by_key.entry(record.partition_key.clone().unwrap()).or_default().push(number(record));
+        let expected_shards: &[u32] = match number(record) {
+            0..=29 => &[0],
+            30..=59 => &[1, 2],
+            _ => &[3],
+        };
+        assert!(expected_shards.contains(&record.shard_id));
+    }
+    for (key, numbers) in by_key {
+        assert_eq!(numbers.len(), 18, "{}", key);
+        assert!(numbers.windows(2).all(|w| w[0] < w[1]), "{} came out of order: {:?}", key, numbers);
+    }
+
+    for record in &records[..51] {
+        checkpointer.commit(record).unwrap();
+    }
+    checkpointer.flush().unwrap();
+    let mut left: Vec<usize> = records[51..].iter().map(number).collect();
+    let mut resumed: Vec<usize> = block_on(consumer().into_stream().take(39).map(|r| number(&r.unwrap())).collect());
+    left.sort();
+    resumed.sort();
+    assert_eq!(resumed, left);
+}
@@ -0,0 +1,258 @@
+use std::{env, fs};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use rinites::auth::keys::{ApiKey, KeySummary};
+use rinites::auth::sigv4::Credentials;
+use rinites::cluster::api::BeginTransactionResponse;
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::config::StreamOverrides;
+use rinites::health::Readiness;
+use rinites::http;
+use rinites::shards::replication::ReplicaStatus;
+use rinites::shards::shard_controller::{AdminShardsResponse, GetRecordsResponse, PutRecordsResponse};
+
+use common::{eventually, LocalCluster};
+
+mod common;
+
+#[test]
+fn any_node_serves_any_shard_and_metadata_survives_losing_its_leader() {
+    let mut cluster = LocalCluster::start(3, &[]);
+    cluster.leader();
+
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":3,"replication_factor":2}"#);
+    let created = cluster.request(1, "POST", "/streams", r#"{"stream_name":"orders","shard_count":3}"#).unwrap();
+    assert_eq!(created.status, 409);
+
+    for shard_id in 0..3 {
+        let put = format!(r#"{{"records":["{}"]}}"#, base64::encode(format!("meucu_tem_oculos_{}", shard_id).as_bytes()));
+        cluster.request_ok(1, "POST", &format!("/streams/orders/shards/{}/put-records", shard_id), &put);
+    }
+    for shard_id in 0..3 {
+        let response = cluster.request_ok(2, "GET", &format!("/streams/orders/shards/{}/get-records/0", shard_id), "");
+        let records: GetRecordsResponse = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(records.records, vec![base64::encode(format!("meucu_tem_oculos_{}", shard_id).as_bytes())]);
+    }
+
+    let leader = cluster.leader();
+    cluster.kill(leader);
+    let survivor = cluster.live().next().unwrap();
+    cluster.request_ok(survivor, "POST", "/streams", r#"{"stream_name":"payments","shard_count":1,"replication_factor":1}"#);
+    let listed = cluster.request_ok(cluster.live().last().unwrap(), "GET", "/streams", "");
+    assert_eq!(String::from_utf8(listed.body).unwrap(), r#"{"stream_names":["orders","payments"]}"#);
+}
+
+#[test]
+fn shards_fail_over_to_an_in_sync_replica_and_the_old_leader_rejoins_as_a_follower() {
+    let mut cluster = LocalCluster::start(3, &["--min-insync-replicas", "2"]);
+    cluster.leader();
+    let created = cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1,"replication_factor":3}"#);
+    let stream: StreamMetadata = serde_json::from_slice(&created.body).unwrap();
+    let old_leader = stream.shards[0].leader as usize - 1;
+    let other = (old_leader + 1) % 3;
+    let put = |i: usize| format!(r#"{{"records":["{}"]}}"#, base64::encode(format!("meucu_tem_oculos_{}", i).as_bytes()));
+    let put_path = "/streams/orders/shards/0/put-records";
+
+    for i in 0..5 {
+        let response = cluster.request_ok(other, "POST", put_path, &put(i));
+        let put: PutRecordsResponse = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(put.sequence_numbers, vec![i as u64]);
+    }
+    cluster.kill(old_leader);
+
+    // while nobody leads the shard, puts are turned away with errors worth retrying
+    let refused = Mutex::new(HashSet::new());
+    let response = eventually("a new shard leader to take puts", || {
+        let response = cluster.request(other, "POST", put_path, &put(5)).ok()?;
+        if !response.is_success() {
+            refused.lock().unwrap().insert(response.status);
+            return None;
+        }
+        Some(response)
+    });
+    let put: PutRecordsResponse = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(put.sequence_numbers, vec![5]);
+    assert!(refused.into_inner().unwrap().iter().all(|status| *status == 503 || *status == 307));
+
+    let described = cluster.request_ok(other, "GET", "/streams/orders", "");
+    let stream: StreamMetadata = serde_json::from_slice(&described.body).unwrap();
+    assert_ne!(stream.shards[0].leader as usize - 1, old_leader);
+    assert_eq!(stream.shards[0].leader_epoch, 1);
+
+    // back up, the old leader follows the new one and catches up
+    cluster.restart(old_leader);
+    eventually("the old leader to catch up", || {
+        let response = cluster.request(old_leader, "GET", "/streams/orders/shards/0/replica", "").ok()?;
+        let status: ReplicaStatus = serde_json::from_slice(&response.body).ok()?;
+        Some(()).filter(|_| status.leader_epoch == 1 && status.log_end_offset == 6)
+    });
+    let response = cluster.request_ok(old_leader, "GET", "/streams/orders/shards/0/get-records/0", "");
+    let records: GetRecordsResponse = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(records.sequence_numbers, (0..6).collect::<Vec<u64>>());
+}
+
+#[test]
+fn every_node_exposes_its_replicas_and_shard_leaders_the_lag_of_consumer_groups() {
+    let cluster = LocalCluster::start(2, &[]);
+    cluster.leader();
+    let created = cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1,"replication_factor":2}"#);
+    let stream: StreamMetadata = serde_json::from_slice(&created.body).unwrap();
+    let shard_leader = stream.shards[0].leader as usize - 1;
+    let follower = 1 - shard_leader;
+
+    let put = format!(r#"{{"records":["{}","{}","{}"]}}"#, base64::encode(b"meucu_tem_oculos"), base64::encode(b"meucu"), base64::encode(b"oculos"));
+    cluster.request_ok(follower, "POST", "/streams/orders/shards/0/put-records", &put);
+    cluster.request_ok(follower, "PUT", "/groups/billing/checkpoints/orders", r#"{"checkpoints":{"0":1}}"#);
+
+    let scrape = |node: usize| {
+        let response = cluster.request_ok(node, "GET", "/metrics", "");
+        assert!(response.headers.iter().any(|(name, value)| name.eq_ignore_ascii_case("content-type") && value.starts_with("text/plain")));
+        assert!(response.headers.iter().any(|(name, value)| name.eq_ignore_ascii_case("x-request-id") && value.len() == 16));
+        String::from_utf8(response.body).unwrap()
+    };
+    let lag = "rinites_consumer_group_lag{group=\"billing\",stream=\"orders\",shard=\"0\"} 2\n";
+    let metrics = eventually("the shard leader to learn the checkpoint", || Some(scrape(shard_leader)).filter(|metrics| metrics.contains(lag)));
+    assert!(metrics.contains("rinites_records_in_total{stream=\"orders\",shard=\"0\"} 3\n"));
+    assert!(metrics.contains("rinites_bytes_in_total{stream=\"orders\",shard=\"0\"} 27\n"));
+    assert!(metrics.contains("rinites_put_latency_seconds_count{stream=\"orders\",shard=\"0\"} 1\n"));
+    assert!(metrics.contains("rinites_segments{stream=\"orders\",shard=\"0\"} 1\n"));
+
+    let metrics = eventually("the follower to catch up", || Some(scrape(follower)).filter(|metrics| metrics.contains("rinites_log_end_offset{stream=\"orders\",shard=\"0\"} 3\n")));
+    assert!(!metrics.contains("rinites_consumer_group_lag"));
+}
+
+#[test]
+fn nodes_answer_health_checks_are_ready_once_their_shards_are_open_and_list_them() {
+    let cluster = LocalCluster::start(2, &[]);
+    assert_eq!(cluster.request_ok(0, "GET", "/health", "").status, 200);
+    cluster.leader();
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":2,"replication_factor":2}"#);
+    for node in 0..2 {
+        let ready: Readiness = serde_json::from_slice(&cluster.request_ok(node, "GET", "/ready", "").body).unwrap();
+        assert!(ready.recovered && ready.disk_writable && ready.in_sync);
+    }
+
+    let put = format!(r#"{{"records":["{}"]}}"#, base64::encode(b"meucu_tem_oculos"));
+    cluster.request_ok(0, "POST", "/streams/orders/shards/1/put-records", &put);
+    let listed: AdminShardsResponse = serde_json::from_slice(&cluster.request_ok(1, "GET", "/admin/shards", "").body).unwrap();
+    let shards: Vec<(Option<String>, Option<u32>)> = listed.shards.iter().map(|s| (s.stream.clone(), s.shard_id)).collect();
+    assert_eq!(shards, vec![(Some("orders".to_string()), Some(0)), (Some("orders".to_string()), Some(1))]);
+    let shard = &listed.shards[1];
+    assert_eq!((shard.segments.len(), shard.segments[0].base_offset), (1, 0));
+    eventually("the follower to have the record", || {
+        let listed: AdminShardsResponse = serde_json::from_slice(&cluster.request_ok(1, "GET", "/admin/shards", "").body).ok()?;
+        Some(()).filter(|_| listed.shards[1].log_end_offset == 1 && listed.shards[1].segments[0].size > 0)
+    });
+}
+
+#[test]
+fn a_node_stopped_with_sigterm_leaves_clean_shutdown_markers_its_restart_takes() {
+    let mut cluster = LocalCluster::start(1, &[]);
+    cluster.leader();
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1}"#);
+    let put = format!(r#"{{"record":"{}","producer_id":7,"producer_sequence":0}}"#, base64::encode(b"meucu_tem_oculos"));
+    cluster.request_ok(0, "POST", "/streams/orders/shards/0/put-records", &put);
+
+    assert!(cluster.stop(0));
+    let marker = cluster.dir.join("node-1").join("streams").join("orders").join("0").join("clean-shutdown");
+    assert!(marker.exists());
+
+    cluster.restart(0);
+    let response = cluster.request_ok(0, "POST", "/streams/orders/shards/0/put-records", &put);
+    let put: PutRecordsResponse = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(put.sequence_numbers, vec![0]);
+    assert!(!marker.exists());
+}
+
+#[test]
+fn streams_are_created_with_the_config_of_the_nodes_unless_they_override_it() {
+    let config_file = env::temp_dir().join(format!("rinites-config-{}.toml", std::process::id()));
+    fs::write(&config_file, "[cluster]\ndefault_shard_count = 2\n\n[streams]\nput_records_per_sec = 1000\n").unwrap();
+    let cluster = LocalCluster::start(1, &["--config", config_file.to_str().unwrap()]);
+    cluster.leader();
+
+    let created = cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders"}"#);
+    let stream: StreamMetadata = serde_json::from_slice(&created.body).unwrap();
+    assert_eq!(stream.shards.len(), 2);
+    assert!(stream.config.is_empty());
+
+    let created = cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"audit","shard_count":1,"config":{"put_records_per_sec":1,"compression":"lz4"}}"#);
+    let stream: StreamMetadata = serde_json::from_slice(&created.body).unwrap();
+    assert_eq!(stream.config, StreamOverrides { put_records_per_sec: Some(1), compression: Some("lz4".parse().unwrap()), ..StreamOverrides::default() });
+    let typo = cluster.request(0, "POST", "/streams", r#"{"stream_name":"typo","config":{"compresion":"lz4"}}"#).unwrap();
+    assert_eq!(typo.status, 400);
+
+    let put = format!(r#"{{"record":"{}"}}"#, base64::encode(b"meucu_tem_oculos"));
+    eventually("the shard to open", || Some(()).filter(|_| cluster.request(0, "POST", "/streams/audit/shards/0/put-records", &put).unwrap().is_success()));
+    let throttled = cluster.request(0, "POST", "/streams/audit/shards/0/put-records", &put).unwrap();
+    assert_eq!(throttled.status, 429);
+    assert!(throttled.header("retry-after").is_some());
+    for _ in 0..3 {
+        cluster.request_ok(0, "POST", "/streams/orders/shards/0/put-records", &put);
+    }
+    let _ = fs::remove_file(config_file);
+}
+
+#[test]
+fn with_a_root_key_requests_must_be_signed_by_keys_granted_what_they_do() {
+    let config_file = env::temp_dir().join(format!("rinites-auth-{}.toml", std::process::id()));
+    fs::write(&config_file, "[auth]\nroot_access_key_id = \"root\"\nroot_secret_access_key = \"meucu_tem_oculos_root\"\n").unwrap();
+    let mut cluster = LocalCluster::start(3, &["--config", config_file.to_str().unwrap()]);
+    let unsigned = |path: &str| http::request(&cluster.addrs[0], "GET", path, b"", std::time::Duration::from_secs(5)).unwrap();
+    assert_eq!(eventually("the node to serve", || http::request(&cluster.addrs[0], "GET", "/health", b"", std::time::Duration::from_secs(5)).ok()).status, 200);
+    assert_eq!(unsigned("/streams").status, 401);
+
+    cluster.credentials = Some(Credentials { access_key_id: "root".to_string(), secret_access_key: "meucu_tem_oculos_root".to_string() });
+    cluster.leader();
+    // the followers forward these to the raft leader, and replicate the shards, signed as root
+    cluster.request_ok(1, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1,"replication_factor":3}"#);
+    let created = cluster.request_ok(2, "POST", "/admin/keys", r#"{"grants":{"orders":["write"]}}"#);
+    let key: ApiKey = serde_json::from_slice(&created.body).unwrap();
+    let root = cluster.credentials.replace(Credentials { access_key_id: key.access_key_id.clone(), secret_access_key: key.secret_access_key.clone() });
+
+    let put = format!(r#"{{"records":["{}"]}}"#, base64::encode(b"meucu_tem_oculos"));
+    for node in 0..3 {
+        cluster.request_ok(node, "POST", "/streams/orders/shards/0/put-records", &put);
+    }
+    // grants are checked on the stream a request is routed to, whatever the encoding of its path
+    cluster.request_ok(1, "POST", "/streams/ord%65rs/shards/0/put-records", &put);
+    for node in 0..3 {
+        let ready: Readiness = serde_json::from_slice(&cluster.request_ok(node, "GET", "/ready", "").body).unwrap();
+        assert!(ready.in_sync);
+    }
+    let iterator = r#"{"iterator_type":"Oldest"}"#;
+    assert_eq!(cluster.request(0, "POST", "/streams/orders/shards/0/get-shard-iterator", iterator).unwrap().status, 403);
+    assert_eq!(cluster.request(0, "POST", "/streams", r#"{"stream_name":"payments"}"#).unwrap().status, 403);
+    assert_eq!(cluster.request(0, "GET", "/admin/keys", "").unwrap().status, 403);
+    assert_eq!(cluster.request(0, "GET", "/streams/orders/shards/0/fetch/0?replica_id=x&leader_epoch=0", "").unwrap().status, 403);
+
+    cluster.credentials = root;
+    let granted = cluster.request_ok(0, "PUT", &format!("/admin/keys/{}/grants/orders-*", key.access_key_id), r#"{"permissions":["admin"]}"#);
+    let granted: KeySummary = serde_json::from_slice(&granted.body).unwrap();
+    assert_eq!(granted.grants.len(), 2);
+    cluster.request_ok(0, "PUT", &format!("/admin/keys/{}/grants/orders", key.access_key_id), r#"{"permissions":["read"]}"#);
+    cluster.credentials = Some(Credentials { access_key_id: key.access_key_id.clone(), secret_access_key: key.secret_access_key.clone() });
+    cluster.request_ok(1, "POST", "/streams/orders/shards/0/get-shard-iterator", iterator);
+    cluster.request_ok(1, "POST", "/streams", r#"{"stream_name":"orders-eu","shard_count":1}"#);
+    assert_eq!(cluster.request(1, "POST", "/streams/orders/shards/0/put-records", &put).unwrap().status, 403);
+    assert_eq!(cluster.request(1, "POST", "/streams/ord%65rs/shards/0/put-records", &put).unwrap().status, 403);
+
+    // a key only ends the transactions it began, even those of streams it can read
+    let begun = |cluster: &LocalCluster, node| {
+        let response = cluster.request_ok(node, "POST", "/transactions", "");
+        serde_json::from_slice::<BeginTransactionResponse>(&response.body).unwrap().transaction_id
+    };
+    let own = begun(&cluster, 2);
+    cluster.request_ok(0, "POST", &format!("/transactions/{}/abort", own), "");
+    let key_credentials = cluster.credentials.replace(Credentials { access_key_id: "root".to_string(), secret_access_key: "meucu_tem_oculos_root".to_string() });
+    let other = begun(&cluster, 1);
+    cluster.credentials = key_credentials;
+    for node in 0..3 {
+        assert_eq!(cluster.request(node, "POST", &format!("/transactions/{}/commit", other), "").unwrap().status, 403);
+    }
+
+    cluster.credentials = Some(Credentials { access_key_id: key.access_key_id.clone(), secret_access_key: "meucu_tem_oculos".to_string() });
+    assert_eq!(cluster.request(0, "GET", "/streams", "").unwrap().status, 401);
+    let _ = fs::remove_file(config_file);
+}
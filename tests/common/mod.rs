@@ -0,0 +1,136 @@
+//! Helpers shared by the integration tests, which run `rinites_tcp` processes.
+#![allow(dead_code)]
+
+use std::{env, fs, thread};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use rand::{Rng, thread_rng};
+use rand::distributions::Alphanumeric;
+
+use rinites::auth::sigv4::Credentials;
+use rinites::cluster::node::ClusterStatus;
+use rinites::http;
+use rinites::http::HttpResponse;
+
+/// `rinites_tcp` processes forming a cluster on localhost, killed when dropped
+pub struct LocalCluster {
+    pub dir: PathBuf,
+    pub addrs: Vec<String>,
+    /// the key `request` signs with, for nodes with auth on
+    pub credentials: Option<Credentials>,
+    /// extra arguments every node gets
+    args: Vec<String>,
+    nodes: Vec<Option<Child>>,
+}
+
+impl LocalCluster {
+    pub fn start(size: usize, args: &[&str]) -> LocalCluster {
+        let rand_string: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .collect();
+        let dir = env::temp_dir().join(format!("to_mount-cluster-{}", rand_string));
+        let ports: Vec<u16> = (0..size)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port())
+            .collect();
+        let addrs: Vec<String> = ports.iter().map(|port| format!("127.0.0.1:{}", port)).collect();
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        let mut cluster = LocalCluster { dir, addrs, credentials: None, args, nodes: (0..size).map(|_| None).collect() };
+        for node in 0..size {
+            cluster.restart(node);
+        }
+        cluster
+    }
+
+    /// starts `node` again, on the same mount path, if it is not running
+    pub fn restart(&mut self, node: usize) {
+        if self.nodes[node].is_some() {
+            return;
+        }
+        let peers: Vec<String> = self.addrs.iter().enumerate().map(|(i, addr)| format!("{}={}", i + 1, addr)).collect();
+        let mount_path = self.dir.join(format!("node-{}", node + 1));
+        fs::create_dir_all(&mount_path).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_rinites_tcp"))
+            .arg("--mount-path").arg(&mount_path)
+            .arg("--port").arg(self.addrs[node].rsplit(':').next().unwrap())
+            .arg("--node-id").arg((node + 1).to_string())
+            .arg("--peers").arg(peers.join(","))
+            .arg("--election-timeout-ms").arg("300")
+            .arg("--heartbeat-ms").arg("50")
+            .arg("--failure-timeout-ms").arg("1000")
+            .args(&self.args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        self.nodes[node] = Some(child);
+    }
+
+    /// Stops `node` with SIGTERM, like an orchestrator would, and returns whether it exited
+    /// cleanly.
+    pub fn stop(&mut self, node: usize) -> bool {
+        let mut child = match self.nodes[node].take() {
+            Some(child) => child,
+            None => return true,
+        };
+        Command::new("kill").arg("-TERM").arg(child.id().to_string()).status().unwrap();
+        child.wait().unwrap().success()
+    }
+
+    pub fn kill(&mut self, node: usize) {
+        if let Some(mut child) = self.nodes[node].take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// index of the node leading the metadata, as the live nodes see it
+    pub fn leader(&self) -> usize {
+        eventually("a metadata leader", || {
+            self.live().find_map(|node| {
+                let response = self.request(node, "GET", "/cluster", "").ok()?;
+                let status: ClusterStatus = serde_json::from_slice(&response.body).ok()?;
+                let leader = status.raft.leader? as usize - 1;
+                Some(leader).filter(|l| self.nodes[*l].is_some())
+            })
+        })
+    }
+
+    pub fn live(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(move |node| self.nodes[*node].is_some())
+    }
+
+    pub fn request(&self, node: usize, method: &str, path: &str, body: &str) -> std::io::Result<HttpResponse> {
+        http::signed_request(&self.addrs[node], method, path, body.as_bytes(), Duration::from_secs(15), self.credentials.as_ref())
+    }
+
+    /// retries while the node answers with a retriable error
+    pub fn request_ok(&self, node: usize, method: &str, path: &str, body: &str) -> HttpResponse {
+        eventually(&format!("{} {} to succeed", method, path), || {
+            self.request(node, method, path, body).ok().filter(|r| r.is_success())
+        })
+    }
+}
+
+impl Drop for LocalCluster {
+    fn drop(&mut self) {
+        for node in 0..self.nodes.len() {
+            self.kill(node);
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn eventually<T>(what: &str, f: impl Fn() -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while Instant::now() < deadline {
+        if let Some(t) = f() {
+            return t;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("timed out waiting for {}", what)
+}
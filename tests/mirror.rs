@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use rinites::cluster::api::ListStreamsResponse;
+use rinites::cluster::metadata::StreamMetadata;
+use rinites::shards::shard_controller::GetRecordsResponse;
+
+use common::{eventually, LocalCluster};
+
+mod common;
+
+/// data, partition key and headers of a record as the API shows it
+type Mirrored = (String, Option<String>, BTreeMap<String, String>);
+
+fn put(cluster: &LocalCluster, stream: &str, shard_id: u32, i: usize) {
+    let data = base64::encode(format!("meucu_tem_oculos_{}", i).as_bytes());
+    let record = match i % 3 {
+        0 => format!(r#""{}""#, data),
+        _ => format!(r#"{{"data":"{}","partition_key":"user-{}","headers":{{"source":"eu-west","i":"{}"}}}}"#, data, i % 4, i),
+    };
+    cluster.request_ok(0, "POST", &format!("/streams/{}/shards/{}/put-records", stream, shard_id), &format!(r#"{{"records":[{}]}}"#, record));
+}
+
+/// every record of `stream`, along with the shard it is on
+fn read_all(cluster: &LocalCluster, stream: &str) -> Option<Vec<(u32, Mirrored)>> {
+    let described = cluster.request(0, "GET", &format!("/streams/{}", stream), "").ok().filter(|r| r.is_success())?;
+    let metadata: StreamMetadata = serde_json::from_slice(&described.body).unwrap();
+    let mut records = Vec::new();
+    for shard in metadata.shards.iter() {
+        let mut shard_iterator = 0;
+        loop {
+            let path = format!("/streams/{}/shards/{}/get-records/{}", stream, shard.shard_id, shard_iterator);
+            let read: GetRecordsResponse = serde_json::from_slice(&cluster.request_ok(0, "GET", &path, "").body).unwrap();
+            if read.records.is_empty() {
+                break;
+            }
+            shard_iterator = read.next_shard_iterator;
+            let mirrored = read.records.into_iter().zip(read.partition_keys).zip(read.headers);
+            records.extend(mirrored.map(|((data, key), headers)| (shard.shard_id, (data, key, headers))));
+        }
+    }
+    Some(records)
+}
+
+fn mirrored(cluster: &LocalCluster, stream: &str, records: usize) -> Vec<(u32, Mirrored)> {
+    eventually("the records to be mirrored", || {
+        read_all(cluster, stream).filter(|mirrored| mirrored.len() == records)
+    })
+}
+
+#[test]
+fn mirroring_copies_mapped_streams_with_keys_and_headers_and_resumes_after_a_restart() {
+    let source = LocalCluster::start(1, &[]);
+    source.leader();
+    source.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":2}"#);
+    source.request_ok(0, "POST", "/streams", r#"{"stream_name":"clicks","shard_count":1}"#);
+    for i in 0..12 {
+        put(&source, "orders", (i % 2) as u32, i);
+    }
+    put(&source, "clicks", 0, 0);
+
+    let mut destination = LocalCluster::start(1, &["--mirror-from", &source.addrs[0], "--mirror-streams", "ord*=dr-ord*"]);
+    destination.leader();
+    let expected = |records: usize| {
+        let mut expected: Vec<Mirrored> = read_all(&source, "orders").unwrap().into_iter().map(|(_, r)| r).collect();
+        expected.sort();
+        assert_eq!(expected.len(), records);
+        expected
+    };
+
+    let copied = mirrored(&destination, "dr-orders", 12);
+    let stream: StreamMetadata = serde_json::from_slice(&destination.request_ok(0, "GET", "/streams/dr-orders", "").body).unwrap();
+    for (shard_id, (_, key, _)) in copied.iter() {
+        if let Some(key) = key {
+            assert_eq!(stream.shard_for_key(key).unwrap().shard_id, *shard_id);
+        }
+    }
+    let mut copied: Vec<Mirrored> = copied.into_iter().map(|(_, r)| r).collect();
+    copied.sort();
+    assert_eq!(copied, expected(12));
+    let listed: ListStreamsResponse = serde_json::from_slice(&destination.request_ok(0, "GET", "/streams", "").body).unwrap();
+    assert_eq!(listed.stream_names, vec!["dr-orders".to_string()]);
+
+    // the mirror picks up where its checkpoints say once the destination is back
+    let checkpoints = destination.dir.join("node-1").join("mirror-checkpoints.json");
+    eventually("the mirror to checkpoint", || {
+        let checkpoints: BTreeMap<String, u64> = serde_json::from_slice(&fs::read(&checkpoints).ok()?).ok()?;
+        Some(()).filter(|_| checkpoints.get("orders/0") == Some(&6) && checkpoints.get("orders/1") == Some(&6))
+    });
+    destination.kill(0);
+    for i in 12..20 {
+        put(&source, "orders", (i % 2) as u32, i);
+    }
+    destination.restart(0);
+
+    let mut copied: Vec<Mirrored> = mirrored(&destination, "dr-orders", 20).into_iter().map(|(_, r)| r).collect();
+    copied.sort();
+    assert_eq!(copied, expected(20));
+}
@@ -0,0 +1,87 @@
+use rinites::cluster::api::BeginTransactionResponse;
+use rinites::cluster::metadata::{TransactionMetadata, TransactionState};
+use rinites::shards::shard_controller::GetRecordsResponse;
+
+use common::{eventually, LocalCluster};
+
+mod common;
+
+fn begin(cluster: &LocalCluster, body: &str) -> u64 {
+    let begun: BeginTransactionResponse = serde_json::from_slice(&cluster.request_ok(0, "POST", "/transactions", body).body).unwrap();
+    begun.transaction_id
+}
+
+/// puts a record in `transaction_id`, retrying while the shard leader has not heard of it yet
+fn put(cluster: &LocalCluster, stream: &str, shard_id: u32, transaction_id: u64, i: usize) {
+    let data = base64::encode(format!("meucu_tem_oculos_{}", i).as_bytes());
+    let body = format!(r#"{{"records":["{}"],"transaction_id":{},"producer_sequence":0}}"#, data, transaction_id);
+    let path = format!("/streams/{}/shards/{}/put-records", stream, shard_id);
+    eventually("the transaction to take records", || {
+        Some(()).filter(|_| cluster.request(0, "POST", &path, &body).is_ok_and(|r| r.is_success()))
+    });
+}
+
+/// the records of a shard, all of them or only the committed ones
+fn read(cluster: &LocalCluster, stream: &str, shard_id: u32, isolation: &str) -> Vec<String> {
+    let path = format!("/streams/{}/shards/{}/get-records/0?isolation={}", stream, shard_id, isolation);
+    let read: GetRecordsResponse = serde_json::from_slice(&cluster.request_ok(0, "GET", &path, "").body).unwrap();
+    read.records
+        .iter()
+        .map(|data| String::from_utf8(base64::decode(data).unwrap()).unwrap())
+        .collect()
+}
+
+#[test]
+fn transactions_show_their_records_to_read_committed_readers_only_once_committed() {
+    let cluster = LocalCluster::start(2, &[]);
+    cluster.leader();
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":2,"replication_factor":1}"#);
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"payments","shard_count":1,"replication_factor":1}"#);
+    let shards = [("orders", 0), ("orders", 1), ("payments", 0)];
+
+    let committed = begin(&cluster, "");
+    let aborted = begin(&cluster, r#"{"timeout_ms":30000}"#);
+    for (i, (stream, shard_id)) in shards.iter().enumerate() {
+        put(&cluster, stream, *shard_id, aborted, i + 10);
+        put(&cluster, stream, *shard_id, committed, i);
+    }
+    for (stream, shard_id) in shards.iter() {
+        assert_eq!(read(&cluster, stream, *shard_id, "read_uncommitted").len(), 2);
+        assert!(read(&cluster, stream, *shard_id, "read_committed").is_empty());
+    }
+
+    cluster.request_ok(0, "POST", &format!("/transactions/{}/abort", aborted), "");
+    cluster.request_ok(0, "POST", &format!("/transactions/{}/commit", committed), "");
+    cluster.request_ok(1, "POST", &format!("/transactions/{}/commit", committed), "");
+    assert_eq!(cluster.request(0, "POST", &format!("/transactions/{}/commit", aborted), "").unwrap().status, 400);
+    for (i, (stream, shard_id)) in shards.iter().enumerate() {
+        eventually("the markers to be written", || {
+            Some(()).filter(|_| read(&cluster, stream, *shard_id, "read_committed") == vec![format!("meucu_tem_oculos_{}", i)])
+        });
+    }
+
+    // puts after the decision are turned away
+    let data = base64::encode(b"meucu_tem_oculos");
+    let late = format!(r#"{{"records":["{}"],"transaction_id":{},"producer_sequence":1}}"#, data, committed);
+    assert_eq!(cluster.request(0, "POST", "/streams/orders/shards/0/put-records", &late).unwrap().status, 400);
+}
+
+#[test]
+fn transactions_that_outlive_their_timeout_are_aborted() {
+    let cluster = LocalCluster::start(1, &[]);
+    cluster.leader();
+    cluster.request_ok(0, "POST", "/streams", r#"{"stream_name":"orders","shard_count":1}"#);
+
+    let transaction_id = begin(&cluster, r#"{"timeout_ms":500}"#);
+    put(&cluster, "orders", 0, transaction_id, 0);
+    let path = format!("/transactions/{}", transaction_id);
+    eventually("the transaction to time out", || {
+        let transaction: TransactionMetadata = serde_json::from_slice(&cluster.request_ok(0, "GET", &path, "").body).ok()?;
+        Some(()).filter(|_| transaction.state == TransactionState::Aborted)
+    });
+    assert_eq!(cluster.request(0, "POST", &format!("{}/commit", path), "").unwrap().status, 400);
+    eventually("the abort marker to be written", || {
+        let read: GetRecordsResponse = serde_json::from_slice(&cluster.request_ok(0, "GET", "/streams/orders/shards/0/get-records/0?isolation=read_committed", "").body).ok()?;
+        Some(()).filter(|_| read.next_shard_iterator == 2 && read.records.is_empty())
+    });
+}